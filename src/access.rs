@@ -0,0 +1,102 @@
+//! Connection policy by client address. `[access]` lists networks in CIDR
+//! notation, clients in `deny` are always refused and when `allow` isn't empty
+//! only clients in it are accepted.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use mailin::Response;
+
+use std::net::IpAddr;
+
+use crate::server::Screen;
+
+/// `Net` is address with prefix length, like "10.0.0.0/8"
+#[derive(Clone, Debug, PartialEq)]
+pub struct Net {
+	pub addr: IpAddr,
+	pub prefix: u32,
+}
+
+impl Net {
+	/// Parse network, plain address is a single host
+	pub fn parse(value: &str) -> Option<Net> {
+		let (addr, prefix) = match value.split_once('/') {
+			Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse().ok()?)),
+			None => (value.parse::<IpAddr>().ok()?, None),
+		};
+		let bits = match addr {
+			IpAddr::V4(_) => 32,
+			IpAddr::V6(_) => 128,
+		};
+		let prefix = prefix.unwrap_or(bits);
+		(prefix <= bits).then_some(Net {
+			addr,
+			prefix,
+		})
+	}
+
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match (self.addr, ip.to_canonical()) {
+			(IpAddr::V4(net), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+				u32::from(net) & mask == u32::from(ip) & mask
+			},
+			(IpAddr::V6(net), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+				u128::from(net) & mask == u128::from(ip) & mask
+			},
+			_ => false,
+		}
+	}
+}
+
+/// Read list of networks
+pub fn nets(settings: &config::Config, name: &str) -> Result<Vec<Net>> {
+	let values = match settings.get_array(name) {
+		Ok(values) => values,
+		Err(config::ConfigError::NotFound(_)) => return Ok(vec![]),
+		Err(err) => bail!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err),
+	};
+	values.into_iter().map(|value| {
+		let value = value.into_string()
+			.map_err(|_| anyhow!("[smtp2tg.toml] \"{}\" should list networks", name))?;
+		Net::parse(&value)
+			.ok_or_else(|| anyhow!("[smtp2tg.toml] \"{}\" has bad network \"{}\", should look like \"10.0.0.0/8\"", name, value))
+	}).collect()
+}
+
+/// `Access` holds allowed and denied networks
+#[derive(Clone, Debug)]
+pub struct Access {
+	allow: Vec<Net>,
+	deny: Vec<Net>,
+}
+
+impl Access {
+	/// Read `[access]` table, it's optional
+	pub fn new(settings: &config::Config) -> Result<Option<Access>> {
+		let access = Access {
+			allow: nets(settings, "access.allow")?,
+			deny: nets(settings, "access.deny")?,
+		};
+		Ok((!access.allow.is_empty() || !access.deny.is_empty()).then_some(access))
+	}
+
+	/// Whether client at address may talk to us
+	pub fn allowed(&self, ip: IpAddr) -> bool {
+		!self.deny.iter().any(|net| net.contains(ip))
+			&& (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+	}
+}
+
+impl Screen for Access {
+	fn screen(&self, remote: IpAddr) -> Option<Response> {
+		(!self.allowed(remote)).then(|| {
+			info!("Connection from {} refused by access list", remote);
+			Response::custom(554, format!("Connections from {} are not accepted", remote))
+		})
+	}
+}
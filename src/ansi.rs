@@ -0,0 +1,153 @@
+//! Terminal output in mail bodies. Cron and CI mail is full of ANSI escape
+//! sequences and control characters, which Telegram shows as garbage; they are
+//! dropped, colors can be kept as emoji markers.
+
+/// `Colors` sets what happens to ANSI colors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Colors {
+	Strip,
+	/// Put emoji in front of colored text
+	Emoji,
+}
+
+/// Marker for SGR color code, foreground or background
+fn marker(code: u32) -> Option<&'static str> {
+	Some(match code % 10 {
+		0 => "⚫",
+		1 => "🔴",
+		2 => "🟢",
+		3 => "🟡",
+		4 | 6 => "🔵",
+		5 => "🟣",
+		7 => "⚪",
+		_ => return None,
+	})
+}
+
+/// Apply SGR parameters, returns marker when color changes
+fn sgr(params: &str, color: &mut Option<u32>) -> Option<&'static str> {
+	let mut codes = params.split([';', ':']).map(|code| code.parse::<u32>().unwrap_or(0));
+	let before = *color;
+	while let Some(code) = codes.next() {
+		match code {
+			0 | 39 | 49 => *color = None,
+			30..=37 | 40..=47 | 90..=97 | 100..=107 => *color = Some(code),
+			// 256 colors and RGB have no marker
+			38 | 48 => match codes.next() {
+				Some(5) => {
+					codes.next();
+				},
+				Some(2) => {
+					codes.nth(2);
+				},
+				_ => {},
+			},
+			_ => {},
+		};
+	}
+	match *color {
+		Some(code) if before != *color => marker(code),
+		_ => None,
+	}
+}
+
+/// Drop escape sequences and control characters, keeping newlines and tabs.
+/// Carriage return not followed by newline, like in progress bars, starts
+/// new line and backspace erases character before it, like in man pages
+pub fn clean(text: &str, colors: Colors) -> String {
+	let mut clean = String::with_capacity(text.len());
+	let mut color = None;
+	let mut chars = text.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'\x1b' => match chars.next() {
+				// CSI: parameters, intermediates and final byte
+				Some('[') => {
+					let mut params = String::new();
+					for c in chars.by_ref() {
+						if ('\x40'..='\x7e').contains(&c) {
+							if c == 'm' && colors == Colors::Emoji {
+								clean.extend(sgr(&params, &mut color));
+							}
+							break;
+						}
+						params.push(c);
+					}
+				},
+				// OSC, like titles and hyperlinks, ends with BEL or ST
+				Some(']') => while let Some(c) = chars.next() {
+					if c == '\x07' || (c == '\x1b' && chars.next_if_eq(&'\\').is_some()) {
+						break;
+					}
+				},
+				// charset selection and such: intermediates and final byte
+				Some(' '..='/') => while chars.next().is_some_and(|c| (' '..='/').contains(&c)) {},
+				_ => {},
+			},
+			'\r' if chars.peek() == Some(&'\n') => {},
+			'\r' => clean.push('\n'),
+			'\x08' => if clean.ends_with(|c| c != '\n') {
+				clean.pop();
+			},
+			'\n' | '\t' => clean.push(c),
+			c if c.is_control() => {},
+			c => clean.push(c),
+		};
+	}
+	clean
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn csi_is_dropped() {
+		assert_eq!(clean("\x1b[1;31mred\x1b[0m text", Colors::Strip), "red text");
+		assert_eq!(clean("\x1b[2K\x1b[?25lprogress\x1b[?25h", Colors::Strip), "progress");
+		assert_eq!(clean("\x1b[38;2;255;0;0mrgb\x1b[m", Colors::Strip), "rgb");
+	}
+
+	#[test]
+	fn osc_is_dropped() {
+		assert_eq!(clean("\x1b]0;title\x07body", Colors::Strip), "body");
+		assert_eq!(clean("\x1b]8;;https://host/\x1b\\link\x1b]8;;\x1b\\ here", Colors::Strip), "link here");
+		// unterminated one eats the rest
+		assert_eq!(clean("text\x1b]0;title", Colors::Strip), "text");
+	}
+
+	#[test]
+	fn lone_esc_is_dropped() {
+		assert_eq!(clean("done\x1b", Colors::Strip), "done");
+		assert_eq!(clean("\x1b7saved\x1b8", Colors::Strip), "saved");
+		assert_eq!(clean("\x1b(Bplain", Colors::Strip), "plain");
+	}
+
+	#[test]
+	fn control_characters() {
+		assert_eq!(clean("a\x00b\x07c\x7f", Colors::Strip), "abc");
+		assert_eq!(clean("tab\there\r\nline", Colors::Strip), "tab\there\nline");
+		assert_eq!(clean("10%\r50%\r100%\r\n", Colors::Strip), "10%\n50%\n100%\n");
+		assert_eq!(clean("b\x08bo\x08ol\x08ld", Colors::Strip), "bold");
+		assert_eq!(clean("\x08\n\x08x", Colors::Strip), "\nx");
+	}
+
+	#[test]
+	fn colors_become_emoji() {
+		assert_eq!(clean("\x1b[31merror\x1b[0m ok", Colors::Emoji), "🔴error ok");
+		assert_eq!(clean("\x1b[1;32mok\x1b[32m still", Colors::Emoji), "🟢ok still");
+		assert_eq!(clean("\x1b[31ma\x1b[0mb\x1b[31mc", Colors::Emoji), "🔴ab🔴c");
+		assert_eq!(clean("\x1b[93mwarn \x1b[44mbg", Colors::Emoji), "🟡warn 🔵bg");
+		assert_eq!(clean("\x1b[90mgray\x1b[39m", Colors::Emoji), "⚫gray");
+		assert_eq!(clean("\x1b[35m\x1b[36m\x1b[37m", Colors::Emoji), "🟣🔵⚪");
+	}
+
+	#[test]
+	fn extended_colors_have_no_emoji() {
+		assert_eq!(clean("\x1b[38;5;196mx", Colors::Emoji), "x");
+		assert_eq!(clean("\x1b[48;2;0;0;255mx", Colors::Emoji), "x");
+		// parameters of extended color are not taken for colors
+		assert_eq!(clean("\x1b[38;5;31mx", Colors::Emoji), "x");
+		assert_eq!(clean("\x1b[38;5;196;31mx", Colors::Emoji), "🔴x");
+	}
+}
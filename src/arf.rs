@@ -0,0 +1,129 @@
+//! Abuse Reporting Format (RFC 5965): complaints sent by mailbox providers.
+//! They are `multipart/report` with machine readable part and complained
+//! about message, we show key facts of both instead of nested attachments.
+
+use mail_parser::{
+	Message,
+	MessageParser,
+	MimeHeaders,
+};
+use teloxide::utils::markdown;
+
+/// Fields of machine readable part we show, in order
+const FIELDS: &[&str] = &[
+	"Feedback-Type",
+	"User-Agent",
+	"Source-IP",
+	"Arrival-Date",
+	"Reported-Domain",
+	"Reported-Uri",
+	"Original-Mail-From",
+	"Original-Rcpt-To",
+];
+
+/// `Report` is what we could get out of abuse complaint
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+	/// Fields from `message/feedback-report` part, as listed in FIELDS
+	pub feedback: Vec<(String, String)>,
+	/// Headers of complained about message
+	pub original: Vec<(&'static str, String)>,
+}
+
+/// Read "Name: value" lines, continuation lines are joined
+fn fields(text: &str) -> Vec<(String, String)> {
+	let mut fields: Vec<(String, String)> = vec![];
+	for line in text.lines() {
+		if line.starts_with([' ', '\t']) {
+			if let Some((_, value)) = fields.last_mut() {
+				value.push(' ');
+				value.push_str(line.trim());
+			}
+		} else if let Some((name, value)) = line.split_once(':') {
+			fields.push((name.trim().to_owned(), value.trim().to_owned()));
+		}
+	}
+	fields
+}
+
+/// Key headers of a message
+fn headers(mail: &Message) -> Vec<(&'static str, String)> {
+	let mut found = vec![];
+	let address = |addr: Option<&mail_parser::Address>| addr.map(|addr| addr.iter()
+		.filter_map(|addr| addr.address())
+		.collect::<Vec<_>>().join(", "));
+	if let Some(from) = address(mail.from()) {
+		found.push(("From", from));
+	}
+	if let Some(to) = address(mail.to()) {
+		found.push(("To", to));
+	}
+	if let Some(subject) = mail.subject() {
+		found.push(("Subject", subject.to_owned()));
+	}
+	if let Some(date) = mail.date() {
+		found.push(("Date", date.to_rfc3339()));
+	}
+	if let Some(id) = mail.message_id() {
+		found.push(("Message-ID", id.to_owned()));
+	}
+	found
+}
+
+impl Report {
+	/// Get report out of mail, if it's one
+	pub fn parse(mail: &Message) -> Option<Report> {
+		let ctype = mail.content_type()?;
+		if !ctype.ctype().eq_ignore_ascii_case("multipart")
+			|| !ctype.subtype().is_some_and(|subtype| subtype.eq_ignore_ascii_case("report"))
+			|| !ctype.attribute("report-type").is_some_and(|kind| kind.eq_ignore_ascii_case("feedback-report"))
+		{
+			return None;
+		}
+		let mut report = Report::default();
+		for part in &mail.parts {
+			let (ctype, subtype) = match part.content_type() {
+				Some(ctype) => (ctype.ctype().to_lowercase(), ctype.subtype().unwrap_or("").to_lowercase()),
+				None => continue,
+			};
+			match (ctype.as_str(), subtype.as_str()) {
+				("message", "feedback-report") => {
+					let found = fields(&String::from_utf8_lossy(part.contents()));
+					report.feedback = FIELDS.iter().flat_map(|name| found.iter()
+						.filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+						.map(|(_, value)| (name.to_string(), value.clone())))
+						.collect();
+				},
+				("message", "rfc822") => if let Some(original) = part.message() {
+					report.original = headers(original);
+				},
+				// some providers send only headers of original message
+				("text", "rfc822-headers") => {
+					let mut data = part.contents().to_vec();
+					data.extend_from_slice(b"\r\n\r\n");
+					if let Some(original) = MessageParser::new().parse(&data) {
+						report.original = headers(&original);
+					}
+				},
+				_ => {},
+			};
+		}
+		Some(report)
+	}
+
+	/// Render as lines in the same style as message header
+	pub fn lines(&self) -> Vec<String> {
+		let mut lines = vec![];
+		for (name, value) in &self.feedback {
+			lines.push(format!("**{}:** `{}`", markdown::escape(name), markdown::escape_code(value)));
+		}
+		if !self.original.is_empty() {
+			lines.push("".into());
+			lines.push("**Reported message**".into());
+		}
+		for (name, value) in &self.original {
+			lines.push(format!("**{}:** `{}`", markdown::escape(name), markdown::escape_code(value)));
+		}
+		lines
+	}
+}
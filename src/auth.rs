@@ -0,0 +1,117 @@
+//! SMTP AUTH credentials from `[auth]` table. Passwords are either written as
+//! is or as PBKDF2 hashes made with `smtp2tg --hash-password`, which look like
+//! "pbkdf2-sha256$<iterations>$<salt>$<hash>" with salt and hash in hex.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use ring::{
+	constant_time,
+	pbkdf2,
+	rand::{
+		SecureRandom,
+		SystemRandom,
+	},
+};
+
+use std::{
+	collections::HashMap,
+	num::NonZeroU32,
+};
+
+const PBKDF2: &str = "pbkdf2-sha256";
+const ITERATIONS: u32 = 100_000;
+
+/// `Secret` is password as stored in configuration
+#[derive(Clone, Debug)]
+enum Secret {
+	Plain(String),
+	Pbkdf2 {
+		iterations: NonZeroU32,
+		salt: Vec<u8>,
+		hash: Vec<u8>,
+	},
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+	text.as_bytes().chunks(2)
+		.map(|pair| match pair {
+			[high, low] => u8::from_str_radix(std::str::from_utf8(&[*high, *low]).ok()?, 16).ok(),
+			_ => None,
+		})
+		.collect()
+}
+
+impl Secret {
+	fn new(value: &str) -> Option<Secret> {
+		let Some(hashed) = value.strip_prefix(PBKDF2) else {
+			return Some(Secret::Plain(value.to_owned()));
+		};
+		let mut parts = hashed.strip_prefix('$')?.split('$');
+		let secret = Secret::Pbkdf2 {
+			iterations: parts.next()?.parse().ok()?,
+			salt: unhex(parts.next()?)?,
+			hash: unhex(parts.next()?)?,
+		};
+		match parts.next() {
+			Some(_) => None,
+			None => Some(secret),
+		}
+	}
+
+	fn verify(&self, password: &str) -> bool {
+		match self {
+			Secret::Plain(secret) =>
+				constant_time::verify_slices_are_equal(secret.as_bytes(), password.as_bytes()).is_ok(),
+			Secret::Pbkdf2 { iterations, salt, hash } =>
+				pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, *iterations, salt, password.as_bytes(), hash).is_ok(),
+		}
+	}
+}
+
+/// `Credentials` are users allowed to authenticate
+#[derive(Clone, Debug)]
+pub struct Credentials {
+	users: HashMap<String, Secret>,
+}
+
+impl Credentials {
+	/// Read `[auth]` table, there's no SMTP AUTH without it
+	pub fn new(settings: &config::Config) -> Result<Option<Credentials>> {
+		let table = match settings.get_table("auth") {
+			Ok(table) => table,
+			Err(config::ConfigError::NotFound(_)) => return Ok(None),
+			Err(err) => bail!("[smtp2tg.toml] can't get \"auth\":\n {}", err),
+		};
+		let mut users = HashMap::new();
+		for (user, value) in table {
+			let secret = value.into_string().ok().as_deref().and_then(Secret::new)
+				.ok_or(anyhow!("[smtp2tg.toml] \"auth.{}\" should be a password or a hash from \"smtp2tg --hash-password\"", user))?;
+			users.insert(user, secret);
+		}
+		Ok(Some(Credentials {
+			users,
+		}))
+	}
+
+	/// Check user's password
+	pub fn verify(&self, user: &str, password: &str) -> bool {
+		self.users.get(user).is_some_and(|secret| secret.verify(password))
+	}
+}
+
+/// Hash password for `[auth]` table
+pub fn hash(password: &str) -> Result<String> {
+	let mut salt = [0u8; 16];
+	SystemRandom::new().fill(&mut salt).map_err(|_| anyhow!("Failed to get random salt"))?;
+	let mut hash = [0u8; 32];
+	let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+	pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &mut hash);
+	Ok(format!("{}${}${}${}", PBKDF2, iterations, hex(&salt), hex(&hash)))
+}
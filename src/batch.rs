@@ -0,0 +1,111 @@
+//! Merging of small text messages to the same chat. Groups only get 20
+//! messages a minute, so under burst load messages arriving within a short
+//! window are joined into one, with separators, as long as it fits into a
+//! single message. Sender of the first message waits out the window and sends
+//! the batch, others wait for it's result.
+
+use async_std::channel::{
+	self,
+	Receiver,
+	Sender,
+};
+use teloxide::types::{
+	ChatId,
+	ParseMode,
+	ThreadId,
+};
+
+use std::{
+	collections::HashMap,
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::Duration,
+};
+
+use crate::compose::MESSAGE_LIMIT;
+
+/// Goes between merged messages, needs no escaping in MarkdownV2
+const SEPARATOR: &str = "\n\n— — —\n\n";
+
+/// Messages are only merged when they go the same way, as (tenant, chat,
+/// topic, silent, parse mode)
+pub type Key = (String, ChatId, Option<ThreadId>, bool, ParseMode);
+
+/// `Batch` is a message being collected
+struct Batch {
+	text: String,
+	/// Senders of merged messages but the first one
+	waiters: Vec<Sender<bool>>,
+}
+
+/// `Joined` tells what to do with message after offering it to batch
+pub enum Joined {
+	/// Message started a batch, wait for window and send it
+	Lead,
+	/// Message was merged, wait for leader to tell whether batch was sent
+	Wait(Receiver<bool>),
+	/// Batch has no room left, send message alone
+	Alone,
+}
+
+/// `Batcher` keeps batches being collected
+#[derive(Clone)]
+pub struct Batcher {
+	pending: Arc<Mutex<HashMap<Key, Batch>>>,
+	/// How long first message waits for others
+	pub window: Duration,
+}
+
+impl Batcher {
+	/// Read `batch_window`, there's no merging without it
+	pub fn new(settings: &config::Config) -> Option<Batcher> {
+		let window = match settings.get_string("batch_window") {
+			Ok(value) => crate::parse_duration(&value).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"batch_window\" should be a duration like \"5s\" or \"1m\".");
+				panic!("bad setting");
+			}),
+			Err(config::ConfigError::NotFound(_)) => return None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"batch_window\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		if window.is_zero() {
+			return None;
+		}
+		Some(Batcher {
+			pending: Arc::default(),
+			window,
+		})
+	}
+
+	/// Offer message to batch for key
+	pub fn join(&self, key: &Key, text: &str) -> Joined {
+		let mut pending = self.pending.lock().unwrap();
+		match pending.get_mut(key) {
+			None => {
+				pending.insert(key.clone(), Batch {
+					text: text.to_owned(),
+					waiters: vec![],
+				});
+				Joined::Lead
+			},
+			Some(batch) if batch.text.len() + SEPARATOR.len() + text.len() <= MESSAGE_LIMIT => {
+				batch.text.push_str(SEPARATOR);
+				batch.text.push_str(text);
+				let (sender, receiver) = channel::bounded(1);
+				batch.waiters.push(sender);
+				Joined::Wait(receiver)
+			},
+			Some(_) => Joined::Alone,
+		}
+	}
+
+	/// Close batch for key, returns merged text and whoever waits for it
+	pub fn take(&self, key: &Key) -> (String, Vec<Sender<bool>>) {
+		let batch = self.pending.lock().unwrap().remove(key);
+		batch.map(|batch| (batch.text, batch.waiters)).unwrap_or_default()
+	}
+}
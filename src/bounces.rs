@@ -0,0 +1,116 @@
+//! Chats that keep refusing messages, like ones that blocked the bot or were
+//! deleted. After `disable_after` refusals in a row chat is disabled for
+//! `disable_for` and it's mail goes to default chat instead, so it doesn't
+//! fail every message forever. Disabled chats are kept in persistent state.
+
+use teloxide::types::ChatId;
+
+use std::{
+	collections::HashMap,
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::{
+		Duration,
+		SystemTime,
+	},
+};
+
+use crate::state::{
+	Disabled,
+	Store,
+};
+
+/// Unix time now
+fn now() -> u64 {
+	SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+		.map_or(0, |stamp| stamp.as_secs())
+}
+
+/// `Bounces` counts refusals and keeps disabled chats
+#[derive(Clone)]
+pub struct Bounces {
+	store: Store,
+	/// Refusals in a row per chat
+	refusals: Arc<Mutex<HashMap<i64, u32>>>,
+	/// Refusals disabling chat, 0 never disables
+	after: u32,
+	/// How long chat stays disabled
+	period: Duration,
+}
+
+impl Bounces {
+	/// Read `disable_after` and `disable_for`
+	pub fn new(settings: &config::Config, store: Store) -> Bounces {
+		let after = match settings.get_int("disable_after") {
+			Ok(after) if after >= 0 => after as u32,
+			Ok(_) => {
+				error!("[smtp2tg.toml] \"disable_after\" can't be negative.");
+				panic!("bad setting");
+			},
+			Err(config::ConfigError::NotFound(_)) => 3,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"disable_after\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let period = match settings.get_string("disable_for") {
+			Ok(value) => crate::parse_duration(&value).filter(|period| !period.is_zero()).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"disable_for\" should be a duration like \"12h\" or \"1d\".");
+				panic!("bad setting");
+			}),
+			Err(config::ConfigError::NotFound(_)) => Duration::from_secs(86400),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"disable_for\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		Bounces {
+			store,
+			refusals: Arc::default(),
+			after,
+			period,
+		}
+	}
+
+	/// Keep counting refusals of instance being replaced on reload
+	pub fn keep(&mut self, old: &Bounces) {
+		self.refusals = old.refusals.clone();
+	}
+
+	/// Last refusal of disabled chat
+	pub fn disabled(&self, chat: ChatId) -> Option<String> {
+		let now = now();
+		self.store.read(|snapshot| snapshot.disabled.iter()
+			.find(|disabled| disabled.chat == chat.0 && disabled.until > now)
+			.map(|disabled| disabled.reason.clone()))
+	}
+
+	/// Count refusal, returns how long chat is disabled when this one
+	/// disabled it
+	pub fn refused(&self, chat: ChatId, reason: &str) -> Option<Duration> {
+		let mut refusals = self.refusals.lock().unwrap();
+		let count = refusals.entry(chat.0).or_default();
+		*count += 1;
+		if self.after == 0 || *count < self.after {
+			return None;
+		}
+		refusals.remove(&chat.0);
+		let now = now();
+		self.store.update(|snapshot| {
+			snapshot.disabled.retain(|disabled| disabled.chat != chat.0 && disabled.until > now);
+			snapshot.disabled.push(Disabled {
+				chat: chat.0,
+				reason: reason.to_owned(),
+				until: now + self.period.as_secs(),
+			});
+		});
+		Some(self.period)
+	}
+
+	/// Chat took message, it's refusals start over
+	pub fn delivered(&self, chat: ChatId) {
+		self.refusals.lock().unwrap().remove(&chat.0);
+	}
+}
@@ -0,0 +1,96 @@
+//! Failure injection for staging: Telegram calls randomly fail, get throttled
+//! or stall, so retry handling can be checked end to end. Enabled by `[chaos]`
+//! table, which is deliberately left out of example configuration.
+
+use anyhow::{
+	bail,
+	Result,
+};
+use ring::rand::{
+	SecureRandom,
+	SystemRandom,
+};
+use teloxide::{
+	types::Seconds,
+	RequestError,
+};
+
+use std::{
+	io,
+	time::Duration,
+};
+
+/// `Chaos` holds probabilities of simulated troubles
+#[derive(Clone, Debug)]
+pub struct Chaos {
+	/// Network failure
+	error: f64,
+	/// 429 with `retry_after`
+	throttle: f64,
+	/// Slow response
+	delay: f64,
+	delay_for: Duration,
+	random: SystemRandom,
+}
+
+/// Read probability in range 0..=1
+fn probability(settings: &config::Config, name: &str) -> Result<f64> {
+	let key = format!("chaos.{}", name);
+	match settings.get_float(&key) {
+		Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+		Ok(_) => bail!("[smtp2tg.toml] \"{}\" should be between 0 and 1", key),
+		Err(config::ConfigError::NotFound(_)) => Ok(0.0),
+		Err(err) => bail!("[smtp2tg.toml] can't get \"{}\":\n {}", key, err),
+	}
+}
+
+impl Chaos {
+	/// Read `[chaos]` table, it's off when there's none
+	pub fn new(settings: &config::Config) -> Result<Option<Chaos>> {
+		if let Err(config::ConfigError::NotFound(_)) = settings.get_table("chaos") {
+			return Ok(None);
+		}
+		let delay_for = match settings.get_int("chaos.delay_ms") {
+			Ok(ms) if ms >= 0 => Duration::from_millis(ms as u64),
+			Ok(_) => bail!("[smtp2tg.toml] \"chaos.delay_ms\" can't be negative"),
+			Err(config::ConfigError::NotFound(_)) => Duration::from_secs(5),
+			Err(err) => bail!("[smtp2tg.toml] can't get \"chaos.delay_ms\":\n {}", err),
+		};
+		let chaos = Chaos {
+			error: probability(settings, "error")?,
+			throttle: probability(settings, "throttle")?,
+			delay: probability(settings, "delay")?,
+			delay_for,
+			random: SystemRandom::new(),
+		};
+		warn!("Chaos mode is on: {:?} errors, {:?} throttling, {:?} delays",
+			chaos.error, chaos.throttle, chaos.delay);
+		Ok(Some(chaos))
+	}
+
+	/// Roll a dice
+	fn happens(&self, probability: f64) -> bool {
+		let mut bytes = [0u8; 4];
+		if probability <= 0.0 || self.random.fill(&mut bytes).is_err() {
+			return false;
+		}
+		(u32::from_le_bytes(bytes) as f64) < probability * u32::MAX as f64
+	}
+
+	/// Called before Telegram request, may stall or fail it
+	pub async fn strike(&self) -> Result<()> {
+		if self.happens(self.delay) {
+			warn!("Chaos: delaying request by {:?}", self.delay_for);
+			async_std::task::sleep(self.delay_for).await;
+		}
+		if self.happens(self.throttle) {
+			warn!("Chaos: throttling request");
+			return Err(RequestError::RetryAfter(Seconds::from_seconds(5)).into());
+		}
+		if self.happens(self.error) {
+			warn!("Chaos: failing request");
+			return Err(RequestError::Io(io::Error::new(io::ErrorKind::ConnectionReset, "chaos")).into());
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,154 @@
+//! Command line handling. Without arguments we just serve SMTP, everything
+//! else is a helper for checking configuration.
+
+use anyhow::{
+	bail,
+	Result,
+};
+
+/// `Command` is what we were asked to do
+#[derive(Debug, PartialEq)]
+pub enum Command {
+	/// Run gateway
+	Serve,
+	/// Run gateway configured from environment, logging JSON to stdout and
+	/// answering health checks
+	Container,
+	/// Resolve routes for a fake envelope and print them
+	RouteTest {
+		from: String,
+		to: Vec<String>,
+		subject: Option<String>,
+		/// Authenticated client
+		identity: Option<String>,
+	},
+	/// Run saved mail through routing and formatting and print the result
+	Render {
+		file: String,
+		/// Envelope recipients, mail headers are used when empty
+		to: Vec<String>,
+	},
+	/// Print persistent state as JSON
+	StateExport {
+		output: Option<String>,
+	},
+	/// Replace persistent state with exported one
+	StateImport {
+		input: String,
+	},
+	/// Read password from stdin and print it's hash for `[auth]`
+	HashPassword,
+	/// Write commented example configuration
+	DumpExample {
+		output: Option<String>,
+	},
+	/// Print known settings with types and defaults as JSON
+	DescribeJson,
+}
+
+pub const USAGE: &str = "\
+Usage:
+	smtp2tg
+	smtp2tg --container
+	smtp2tg --route-test --from <address> --to <address> [--to <address>...] [--subject <text>] [--identity <name>]
+	smtp2tg --render <file.eml> [--route <address>...]
+	smtp2tg state export [<file>]
+	smtp2tg state import <file>
+	smtp2tg --hash-password < <file>
+	smtp2tg --dump-example-config [<file>]
+	smtp2tg --describe-json";
+
+/// Fetch value for an option
+fn value<I>(args: &mut I, name: &str) -> Result<String>
+where I: Iterator<Item = String> {
+	match args.next() {
+		Some(value) => Ok(value),
+		None => bail!("option \"{}\" requires a value\n{}", name, USAGE),
+	}
+}
+
+/// Parse command line, first item should be an argument, not program name
+pub fn parse<I>(args: I) -> Result<Command>
+where I: IntoIterator<Item = String> {
+	let mut args = args.into_iter().peekable();
+	if args.peek().map(String::as_str) == Some("--hash-password") {
+		args.next();
+		if let Some(arg) = args.next() {
+			bail!("\"--hash-password\" reads password from stdin, unexpected \"{}\"\n{}", arg, USAGE);
+		}
+		return Ok(Command::HashPassword);
+	}
+	if args.peek().map(String::as_str) == Some("--dump-example-config") {
+		args.next();
+		let output = args.next();
+		if let Some(arg) = args.next() {
+			bail!("unknown argument \"{}\"\n{}", arg, USAGE);
+		}
+		return Ok(Command::DumpExample { output });
+	}
+	if args.peek().map(String::as_str) == Some("--container") {
+		args.next();
+		if let Some(arg) = args.next() {
+			bail!("\"--container\" is configured from environment, unexpected \"{}\"\n{}", arg, USAGE);
+		}
+		return Ok(Command::Container);
+	}
+	if args.peek().map(String::as_str) == Some("--describe-json") {
+		args.next();
+		if let Some(arg) = args.next() {
+			bail!("unknown argument \"{}\"\n{}", arg, USAGE);
+		}
+		return Ok(Command::DescribeJson);
+	}
+	if args.peek().map(String::as_str) == Some("state") {
+		args.next();
+		let command = match (args.next().as_deref(), args.next()) {
+			(Some("export"), output) => Command::StateExport { output },
+			(Some("import"), Some(input)) => Command::StateImport { input },
+			_ => bail!("\"state\" needs either \"export [<file>]\" or \"import <file>\"\n{}", USAGE),
+		};
+		if let Some(arg) = args.next() {
+			bail!("unknown argument \"{}\"\n{}", arg, USAGE);
+		}
+		return Ok(command);
+	}
+	let mut route_test = false;
+	let mut from = None;
+	let mut to = vec![];
+	let mut subject = None;
+	let mut identity = None;
+	let mut render = None;
+	let mut routes = vec![];
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--route-test" => route_test = true,
+			"--from" => from = Some(value(&mut args, &arg)?),
+			"--to" => to.push(value(&mut args, &arg)?),
+			"--subject" => subject = Some(value(&mut args, &arg)?),
+			"--identity" => identity = Some(value(&mut args, &arg)?),
+			"--render" => render = Some(value(&mut args, &arg)?),
+			"--route" => routes.push(value(&mut args, &arg)?),
+			"-h" | "--help" => bail!("{}", USAGE),
+			_ => bail!("unknown argument \"{}\"\n{}", arg, USAGE),
+		}
+	}
+	if let Some(file) = render {
+		match route_test {
+			true => bail!("\"--render\" and \"--route-test\" can't be used together\n{}", USAGE),
+			false if from.is_some() || !to.is_empty() || subject.is_some() || identity.is_some() =>
+				bail!("\"--render\" takes envelope from the mail, use \"--route\" for recipients\n{}", USAGE),
+			false => Ok(Command::Render { file, to: routes }),
+		}
+	} else if !routes.is_empty() {
+		bail!("\"--route\" only works with \"--render\"\n{}", USAGE);
+	} else if route_test {
+		match from {
+			Some(from) if !to.is_empty() => Ok(Command::RouteTest { from, to, subject, identity }),
+			_ => bail!("\"--route-test\" needs \"--from\" and at least one \"--to\"\n{}", USAGE),
+		}
+	} else if from.is_some() || !to.is_empty() || subject.is_some() || identity.is_some() {
+		bail!("\"--from\", \"--to\", \"--subject\" and \"--identity\" only work with \"--route-test\"\n{}", USAGE);
+	} else {
+		Ok(Command::Serve)
+	}
+}
@@ -0,0 +1,1599 @@
+//! Message composition: turns parsed email into something that can be sent to
+//! Telegram. Nothing here talks to the network so it can be tested on it's own.
+
+use anyhow::{
+	anyhow,
+	Result,
+};
+use mail_parser::{
+	HeaderValue,
+	MessagePart,
+	MimeHeaders,
+};
+use ring::digest;
+use teloxide::{
+	types::ParseMode,
+	utils::markdown,
+};
+
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+};
+
+use crate::{
+	ansi::{
+		self,
+		Colors,
+	},
+	arf,
+	html,
+	tnef,
+};
+
+/// Maximum length of a Telegram text message
+pub const MESSAGE_LIMIT: usize = 4096;
+/// Maximum length of a media caption
+pub const CAPTION_LIMIT: usize = 1024;
+/// Longest file name we pass on, in characters
+const NAME_LIMIT: usize = 128;
+/// Longest extension we try to keep when shortening file names
+const EXTENSION_LIMIT: usize = 16;
+/// Longest poll question
+const QUESTION_LIMIT: usize = 300;
+/// Longest poll option
+const OPTION_LIMIT: usize = 100;
+/// Most options poll can have
+const OPTIONS_LIMIT: usize = 10;
+/// Largest photo Telegram accepts
+const PHOTO_LIMIT: usize = 10 << 20;
+
+/// `Kind` sets how attachment is sent
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+	Document,
+	/// Music player in chat
+	Audio,
+	/// Voice message
+	Voice,
+	/// Streamable video
+	Video,
+	/// Picture that is the mail itself, text goes as it's caption
+	Photo,
+}
+
+impl Kind {
+	/// Pick how to send file of lowercase content type like "audio/ogg",
+	/// formats Telegram can't play stay documents
+	fn new(ctype: &str, format: &Format) -> Kind {
+		match ctype.split_once('/').unwrap_or((ctype, "")) {
+			("audio", "ogg" | "opus") if format.play_audio => Kind::Voice,
+			("audio", "mpeg" | "mp3" | "mp4" | "m4a" | "x-m4a" | "aac") if format.play_audio => Kind::Audio,
+			// only MP4 plays inline, Telegram makes previews itself
+			("video", "mp4") if format.play_video => Kind::Video,
+			_ => Kind::Document,
+		}
+	}
+}
+
+/// `Attachment` is a file to be uploaded along with the message
+#[derive(Clone, Debug)]
+pub struct Attachment {
+	pub name: String,
+	pub data: Vec<u8>,
+	pub kind: Kind,
+	/// Image of first page, sent as photo before the file
+	pub preview: Option<Vec<u8>>,
+}
+
+impl Attachment {
+	/// Create attachment with sanitized name
+	pub fn new(name: &str, data: Vec<u8>) -> Attachment {
+		Attachment {
+			name: sanitize(name),
+			data,
+			kind: Kind::Document,
+			preview: None,
+		}
+	}
+}
+
+/// Make file name safe to show and save: only letters, digits and a few
+/// punctuation marks are kept, no leading dots, limited length with extension
+/// preserved
+fn sanitize(name: &str) -> String {
+	let name: String = name.chars()
+		.filter(|c| !c.is_control() && !matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}'))
+		.map(|c| if c.is_alphanumeric() || " ._-+()[],@=#".contains(c) { c } else { '_' })
+		.collect();
+	let name = name.trim().trim_start_matches('.').trim_start();
+	let name = if name.is_empty() {
+		"Attachment.txt"
+	} else {
+		name
+	};
+	if name.chars().count() <= NAME_LIMIT {
+		return name.to_owned();
+	}
+	let (stem, extension) = match name.rsplit_once('.') {
+		Some((stem, ext)) if !stem.is_empty() && ext.chars().count() <= EXTENSION_LIMIT => (stem, format!(".{}", ext)),
+		_ => (name, String::new()),
+	};
+	let stem: String = stem.chars().take(NAME_LIMIT - extension.chars().count()).collect();
+	stem + &extension
+}
+
+/// `ExtraText` sets what to do with text parts after the first one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExtraText {
+	/// Add them to the body if there's enough space, attach otherwise
+	Append,
+	/// Send as files
+	Attach,
+	/// Just drop them
+	Ignore,
+}
+
+/// `BodyType` is a kind of alternative that can become message body
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyType {
+	Plain,
+	Html,
+}
+
+/// `LongCaption` sets what to do when message is too long to be a caption
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LongCaption {
+	/// Send text as a separate message, caption files with subject only
+	Split,
+	/// Cut the body so everything fits in the caption
+	Truncate,
+}
+
+/// `Markup` is what messages are sent as, they are composed in MarkdownV2
+/// and turned into others right before sending
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Markup {
+	MarkdownV2,
+	Html,
+	/// No formatting at all, text is shown as is
+	Plain,
+}
+
+/// `Field` is a mail header shown on top of message
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Field {
+	Date,
+	From,
+	/// Subject, or thread name when there's none
+	Subject,
+}
+
+impl Field {
+	/// Read list like `["subject", "from"]`, nothing when it has anything else
+	pub fn list(values: Vec<config::Value>) -> Option<Vec<Field>> {
+		values.into_iter().map(|value| match value.into_string().ok()?.as_str() {
+			"date" => Some(Field::Date),
+			"from" => Some(Field::From),
+			"subject" => Some(Field::Subject),
+			_ => None,
+		}).collect()
+	}
+}
+
+/// `Format` holds configuration for composing messages
+#[derive(Clone, Debug)]
+pub struct Format {
+	pub body_preference: Vec<BodyType>,
+	/// Cut body to this many bytes
+	pub body_limit: Option<usize>,
+	/// Send only files captioned with subject, dropping body
+	pub attachments_only: bool,
+	/// Drop all files, noting how many there were
+	pub ignore_attachments: bool,
+	/// Send structured mail as poll
+	pub polls: bool,
+	/// Send location pin when mail has coordinates
+	pub locations: bool,
+	/// Send audio files as playable media
+	pub play_audio: bool,
+	/// Send video files as streamable media
+	pub play_video: bool,
+	/// Send mail that is mostly a picture as photo
+	pub photos: bool,
+	/// Send every picture as photo instead of file
+	pub inline_images: bool,
+	/// List SHA-256 of forwarded files
+	pub checksums: bool,
+	/// Send text as message and files as reply to it, instead of caption
+	pub text_first: bool,
+	/// Attach HTML alternative as `message.html` when text doesn't have it all
+	pub attach_html: bool,
+	pub extra_text_parts: ExtraText,
+	pub long_caption: LongCaption,
+	pub markup: Markup,
+	/// Whether ANSI colors in body become emoji markers
+	pub colors: Colors,
+	/// Headers shown on top of message, in this order
+	pub fields: Vec<Field>,
+	/// Content types of files sent, everything when empty; exact or like
+	/// "image/*"
+	pub attachments_allow: Vec<String>,
+	/// Content types of files dropped, wins over allowed ones
+	pub attachments_deny: Vec<String>,
+	/// Friendly names of senders, keyed by address or "@domain"
+	pub addressbook: HashMap<String, String>,
+	/// Parts of mail turned into text and files, the rest is skipped
+	pub max_parts: Option<usize>,
+}
+
+/// Read `[addressbook]`, it's the same for every profile
+fn addressbook(settings: &config::Config) -> HashMap<String, String> {
+	match settings.get_table("addressbook") {
+		Ok(table) => table.into_iter().map(|(addr, label)| match label.into_string() {
+			Ok(label) => (addr.to_lowercase(), label),
+			Err(_) => {
+				error!("[smtp2tg.toml] \"addressbook\" label for \"{}\" should be a string.", addr);
+				panic!("bad setting");
+			},
+		}).collect(),
+		Err(config::ConfigError::NotFound(_)) => HashMap::new(),
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"addressbook\":\n {}", err);
+			panic!("bad setting");
+		},
+	}
+}
+
+/// Read list of content types, lowercased
+fn types(settings: &config::Config, name: &str) -> Vec<String> {
+	match settings.get_array(name) {
+		Err(config::ConfigError::NotFound(_)) => vec![],
+		Ok(values) => values.into_iter().map(|value| match value.into_string() {
+			Ok(pattern) if pattern == "*" || pattern.contains('/') => pattern.to_lowercase(),
+			_ => {
+				error!("[smtp2tg.toml] \"{}\" should list content types like \"application/pdf\" or \"image/*\".", name);
+				panic!("bad setting");
+			},
+		}).collect(),
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+			panic!("bad setting");
+		},
+	}
+}
+
+/// Check content type against pattern list
+fn listed(patterns: &[String], ctype: &str) -> bool {
+	patterns.iter().any(|pattern| match pattern.strip_suffix("/*") {
+		Some(prefix) => ctype.split_once('/').is_some_and(|(kind, _)| kind == prefix),
+		None => pattern == "*" || pattern == ctype,
+	})
+}
+
+/// Read "max_parts", it's the same for every profile
+fn max_parts(settings: &config::Config) -> Option<usize> {
+	match settings.get_int("max_parts") {
+		Ok(0) => None,
+		Ok(limit) if limit > 0 => Some(limit as usize),
+		Err(config::ConfigError::NotFound(_)) => Some(100),
+		_ => {
+			error!("[smtp2tg.toml] \"max_parts\" should be a positive integer or 0.");
+			panic!("bad setting");
+		},
+	}
+}
+
+/// Setting name, inside profile table when profile has it
+fn key(settings: &config::Config, profile: Option<&str>, name: &str) -> String {
+	if let Some(profile) = profile {
+		let key = format!("profiles.{}.{}", profile, name);
+		if settings.get::<config::Value>(&key).is_ok() {
+			return key;
+		}
+	}
+	name.to_owned()
+}
+
+/// Read boolean setting, looking into profile table first
+fn flag(settings: &config::Config, profile: Option<&str>, name: &str, default: bool) -> bool {
+	let name = key(settings, profile, name);
+	match settings.get_bool(&name) {
+		Err(config::ConfigError::NotFound(_)) => default,
+		Ok(value) => value,
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+			panic!("bad setting");
+		},
+	}
+}
+
+impl Format {
+	/// Read formatting settings from configuration
+	pub fn new(settings: &config::Config) -> Format {
+		Format::read(settings, None)
+	}
+
+	/// Read global format and every `[profiles.<name>]` overriding it,
+	/// global one is named ""
+	pub fn profiles(settings: &config::Config) -> HashMap<String, Format> {
+		let mut formats = HashMap::from([("".to_owned(), Format::new(settings))]);
+		match settings.get_table("profiles") {
+			Ok(profiles) => for name in profiles.into_keys() {
+				let format = Format::read(settings, Some(&name));
+				formats.insert(name, format);
+			},
+			Err(config::ConfigError::NotFound(_)) => {},
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"profiles\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		formats
+	}
+
+	/// Read settings, looking into profile table first
+	fn read(settings: &config::Config, profile: Option<&str>) -> Format {
+		let name = key(settings, profile, "extra_text_parts");
+		let extra_text_parts = match settings.get_string(&name) {
+			Err(config::ConfigError::NotFound(_)) => ExtraText::Attach,
+			Ok(value) => match value.as_str() {
+				"append" => ExtraText::Append,
+				"attach" => ExtraText::Attach,
+				"ignore" => ExtraText::Ignore,
+				_ => {
+					error!("[smtp2tg.toml] \"{}\" should be either \"append\", \"attach\" or \"ignore\".", name);
+					panic!("bad setting");
+				},
+			},
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+				panic!("bad setting");
+			},
+		};
+		let name = key(settings, profile, "body_preference");
+		let body_preference = match settings.get_array(&name) {
+			Err(config::ConfigError::NotFound(_)) => vec![BodyType::Plain, BodyType::Html],
+			Ok(values) => values.into_iter().map(|value| match value.into_string().as_deref() {
+				Ok("text/plain") => BodyType::Plain,
+				Ok("text/html") => BodyType::Html,
+				_ => {
+					error!("[smtp2tg.toml] \"{}\" should only list \"text/plain\" and \"text/html\".", name);
+					panic!("bad setting");
+				},
+			}).collect(),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+				panic!("bad setting");
+			},
+		};
+		let name = key(settings, profile, "long_caption");
+		let long_caption = match settings.get_string(&name) {
+			Err(config::ConfigError::NotFound(_)) => LongCaption::Split,
+			Ok(value) => match value.as_str() {
+				"split" => LongCaption::Split,
+				"truncate" => LongCaption::Truncate,
+				_ => {
+					error!("[smtp2tg.toml] \"{}\" should be either \"split\" or \"truncate\".", name);
+					panic!("bad setting");
+				},
+			},
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+				panic!("bad setting");
+			},
+		};
+		let name = key(settings, profile, "parse_mode");
+		let markup = match settings.get_string(&name) {
+			Err(config::ConfigError::NotFound(_)) => Markup::MarkdownV2,
+			Ok(value) => match value.as_str() {
+				"MarkdownV2" => Markup::MarkdownV2,
+				"HTML" => Markup::Html,
+				"plain" => Markup::Plain,
+				_ => {
+					error!("[smtp2tg.toml] \"{}\" should be either \"MarkdownV2\", \"HTML\" or \"plain\".", name);
+					panic!("bad setting");
+				},
+			},
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+				panic!("bad setting");
+			},
+		};
+		let name = key(settings, profile, "ansi_colors");
+		let colors = match settings.get_string(&name) {
+			Err(config::ConfigError::NotFound(_)) => Colors::Strip,
+			Ok(value) => match value.as_str() {
+				"strip" => Colors::Strip,
+				"emoji" => Colors::Emoji,
+				_ => {
+					error!("[smtp2tg.toml] \"{}\" should be either \"strip\" or \"emoji\".", name);
+					panic!("bad setting");
+				},
+			},
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+				panic!("bad setting");
+			},
+		};
+		let name = key(settings, profile, "fields");
+		let fields = match settings.get_array(&name) {
+			Err(config::ConfigError::NotFound(_)) => vec![Field::Subject, Field::From],
+			Ok(values) => Field::list(values).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"{}\" should only list \"date\", \"from\" and \"subject\".", name);
+				panic!("bad setting");
+			}),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+				panic!("bad setting");
+			},
+		};
+		let attachments_allow = types(settings, &key(settings, profile, "attachments_allow"));
+		let attachments_deny = types(settings, &key(settings, profile, "attachments_deny"));
+		let name = key(settings, profile, "body_limit");
+		let body_limit = match settings.get_int(&name) {
+			Err(config::ConfigError::NotFound(_)) => None,
+			Ok(limit) if limit >= 0 => Some(limit as usize),
+			_ => {
+				error!("[smtp2tg.toml] \"{}\" should be a positive integer.", name);
+				panic!("bad setting");
+			},
+		};
+		let attachments_only = flag(settings, profile, "attachments_only", false);
+		let ignore_attachments = flag(settings, profile, "ignore_attachments", false);
+		if attachments_only && ignore_attachments {
+			error!("[smtp2tg.toml] \"attachments_only\" and \"ignore_attachments\" can't be both set{}.",
+				profile.map(|profile| format!(" in profile \"{}\"", profile)).unwrap_or_default());
+			panic!("bad setting");
+		}
+		let polls = flag(settings, profile, "polls", false);
+		let locations = flag(settings, profile, "locations", false);
+		let play_audio = flag(settings, profile, "play_audio", false);
+		let play_video = flag(settings, profile, "play_video", false);
+		let inline_images = flag(settings, profile, "inline_images", false);
+		let photos = flag(settings, profile, "photos", true);
+		let attach_html = flag(settings, profile, "attach_html", false);
+		let text_first = flag(settings, profile, "text_first", false);
+		let checksums = flag(settings, profile, "checksums", false);
+		Format {
+			attachments_only,
+			checksums,
+			text_first,
+			attach_html,
+			ignore_attachments,
+			locations,
+			play_audio,
+			photos,
+			inline_images,
+			play_video,
+			polls,
+			body_preference,
+			body_limit,
+			extra_text_parts,
+			long_caption,
+			markup,
+			colors,
+			fields,
+			attachments_allow,
+			attachments_deny,
+			addressbook: addressbook(settings),
+			max_parts: max_parts(settings),
+		}
+	}
+
+	/// Friendly name of sender, exact address wins over domain
+	fn label(&self, addr: &str) -> Option<&str> {
+		let addr = addr.to_lowercase();
+		self.addressbook.get(&addr)
+			.or_else(|| addr.rsplit_once('@').and_then(|(_, domain)| self.addressbook.get(&format!("@{}", domain))))
+			.map(String::as_str)
+	}
+
+	/// Whether files of content type are sent
+	fn keeps(&self, ctype: &str) -> bool {
+		!listed(&self.attachments_deny, ctype)
+			&& (self.attachments_allow.is_empty() || listed(&self.attachments_allow, ctype))
+	}
+}
+
+/// `Options` affect how composed message should be sent
+#[derive(Clone, Debug)]
+pub struct Options {
+	pub parse_mode: ParseMode,
+	/// What text should be turned into before sending
+	pub markup: Markup,
+	/// Short caption for attachments, when set text is sent separately
+	pub caption: Option<String>,
+	/// Deliver without notification
+	pub silent: bool,
+	/// Send text before files, files reply to it
+	pub text_first: bool,
+}
+
+impl Default for Options {
+	fn default() -> Options {
+		Options {
+			parse_mode: ParseMode::MarkdownV2,
+			markup: Markup::MarkdownV2,
+			caption: None,
+			silent: false,
+			text_first: false,
+		}
+	}
+}
+
+/// `Poll` is a question with answer options
+#[derive(Clone, Debug, PartialEq)]
+pub struct Poll {
+	pub question: String,
+	pub options: Vec<String>,
+}
+
+/// `OutgoingMessage` is a fully rendered mail ready for delivery
+#[derive(Clone, Debug)]
+pub struct OutgoingMessage {
+	/// Text messages to send in order, first one can become caption
+	pub text_chunks: Vec<String>,
+	/// Sent instead of text
+	pub poll: Option<Poll>,
+	/// Location pin sent after text, as (latitude, longitude)
+	pub location: Option<(f64, f64)>,
+	pub attachments: Vec<Attachment>,
+	pub options: Options,
+	/// Things that went not quite right while composing, for debug chat
+	pub notes: Vec<String>,
+}
+
+/// Cut text into pieces of no more than limit bytes, on line ends when
+/// possible
+fn split(text: &str, limit: usize) -> Vec<String> {
+	let mut pieces = vec![];
+	let mut rest = text;
+	while rest.len() > limit {
+		let mut cut = limit;
+		while !rest.is_char_boundary(cut) {
+			cut -= 1;
+		}
+		// backslash can't end a piece, it escapes next char
+		while cut > 1 && rest[..cut].ends_with('\\') {
+			cut -= 1;
+		}
+		let cut = match rest[..cut].rfind('\n') {
+			Some(line) if line > 0 => line + 1,
+			_ => cut,
+		};
+		pieces.push(rest[..cut].to_owned());
+		rest = &rest[cut..];
+	}
+	if !rest.is_empty() || pieces.is_empty() {
+		pieces.push(rest.to_owned());
+	}
+	pieces
+}
+
+/// Whether unescaped `[` at `from` starts a link, that is `](` follows
+fn link_at(chars: &[char], from: usize) -> bool {
+	let mut at = from + 1;
+	while let Some(c) = chars.get(at) {
+		match c {
+			'\\' => at += 1,
+			']' => return chars.get(at + 1) == Some(&'('),
+			'[' | '\n' => return false,
+			_ => {},
+		};
+		at += 1;
+	}
+	false
+}
+
+/// Drop our own markup from MarkdownV2 text, links keep their text. Markup
+/// characters left without pair were not escaped by mistake and are kept
+fn unmark(text: &str) -> String {
+	let chars: Vec<char> = text.chars().collect();
+	// where markup characters are, by character
+	let mut marks: HashMap<char, Vec<usize>> = HashMap::new();
+	let mut code = false;
+	let mut at = 0;
+	while let Some(&c) = chars.get(at) {
+		match c {
+			'\\' => at += 1,
+			'`' => {
+				code = !code;
+				marks.entry(c).or_default().push(at);
+			},
+			'*' | '_' | '~' if !code => marks.entry(c).or_default().push(at),
+			'|' if !code && chars.get(at + 1) == Some(&'|') => {
+				marks.entry(c).or_default().push(at);
+				at += 1;
+			},
+			_ => {},
+		};
+		at += 1;
+	}
+	let literal: Vec<usize> = marks.values()
+		.filter(|found| found.len() % 2 == 1)
+		.filter_map(|found| found.last().copied())
+		.collect();
+	let mut plain = String::with_capacity(text.len());
+	let mut code = false;
+	let mut link = false;
+	let mut at = 0;
+	while let Some(&c) = chars.get(at) {
+		at += 1;
+		match c {
+			_ if literal.contains(&(at - 1)) => plain.push(c),
+			// markdown::escape leaves lone backslashes as they are, only
+			// punctuation is escaped
+			'\\' if !chars.get(at).is_some_and(char::is_ascii_punctuation) => plain.push(c),
+			'\\' => {
+				plain.extend(chars.get(at));
+				at += 1;
+			},
+			'`' => code = !code,
+			c if code => plain.push(c),
+			'*' | '_' | '~' => {},
+			'|' if chars.get(at) == Some(&'|') => at += 1,
+			'!' if chars.get(at) == Some(&'[') && link_at(&chars, at) => {},
+			'[' if link_at(&chars, at - 1) => link = true,
+			'>' if plain.is_empty() || plain.ends_with('\n') => {},
+			']' if link && chars.get(at) == Some(&'(') => {
+				link = false;
+				while let Some(&c) = chars.get(at) {
+					at += 1;
+					match c {
+						'\\' => at += 1,
+						')' => break,
+						_ => {},
+					};
+				}
+			},
+			c => plain.push(c),
+		};
+	}
+	plain
+}
+
+/// Turn our MarkdownV2 into Telegram HTML, tags left open are closed at the
+/// end
+fn to_html(text: &str) -> String {
+	let mut html = String::with_capacity(text.len());
+	let mut chars = text.chars().peekable();
+	// open tags, innermost last
+	let mut open: Vec<&str> = vec![];
+	// where in output text of link or custom emoji started
+	let mut link: Option<(usize, bool)> = None;
+	let mut line_start = true;
+	while let Some(c) = chars.next() {
+		let start = std::mem::replace(&mut line_start, c == '\n');
+		let code = matches!(open.last(), Some(&"code" | &"pre"));
+		match c {
+			'\\' => if let Some(c) = chars.next() {
+				escape_html(&mut html, c);
+			},
+			'`' if chars.peek() == Some(&'`') => {
+				chars.next();
+				chars.next_if_eq(&'`');
+				if open.last() == Some(&"pre") {
+					if html.ends_with('\n') {
+						html.pop();
+					}
+					toggle(&mut html, &mut open, "pre");
+				} else {
+					chars.next_if_eq(&'\n');
+					toggle(&mut html, &mut open, "pre");
+				}
+			},
+			'`' if open.last() != Some(&"pre") => toggle(&mut html, &mut open, "code"),
+			c if code => escape_html(&mut html, c),
+			'*' => {
+				let double = chars.next_if_eq(&'*').is_some();
+				match double && start && chars.next_if_eq(&'>').is_some() {
+					true => toggle(&mut html, &mut open, "blockquote expandable"),
+					false => toggle(&mut html, &mut open, "b"),
+				};
+			},
+			'_' => toggle(&mut html, &mut open, "i"),
+			'~' => toggle(&mut html, &mut open, "s"),
+			'|' if chars.next_if_eq(&'|').is_some() => match open.contains(&"blockquote expandable") {
+				true => toggle(&mut html, &mut open, "blockquote expandable"),
+				false => toggle(&mut html, &mut open, "tg-spoiler"),
+			},
+			'>' if start => if !open.iter().any(|tag| tag.starts_with("blockquote")) {
+				toggle(&mut html, &mut open, "blockquote");
+			},
+			'\n' => {
+				if open.contains(&"blockquote") && chars.peek() != Some(&'>') {
+					toggle(&mut html, &mut open, "blockquote");
+				}
+				html.push('\n');
+			},
+			'!' if chars.peek() == Some(&'[') => {
+				chars.next();
+				link = Some((html.len(), true));
+			},
+			'[' => link = Some((html.len(), false)),
+			']' if link.is_some() && chars.next_if_eq(&'(').is_some() => {
+				let mut url = String::new();
+				while let Some(c) = chars.next() {
+					match c {
+						'\\' => url.extend(chars.next()),
+						')' => break,
+						c => url.push(c),
+					};
+				}
+				let Some((at, emoji)) = link.take() else {
+					continue;
+				};
+				let mut tag = String::new();
+				match emoji {
+					true => {
+						tag.push_str("<tg-emoji emoji-id=\"");
+						let id = url.split_once("id=").map_or("", |(_, id)| id);
+						id.chars().for_each(|c| escape_html(&mut tag, c));
+						tag.push_str("\">");
+						html.push_str("</tg-emoji>");
+					},
+					false => {
+						tag.push_str("<a href=\"");
+						url.chars().for_each(|c| escape_html(&mut tag, c));
+						tag.push_str("\">");
+						html.push_str("</a>");
+					},
+				};
+				html.insert_str(at, &tag);
+			},
+			c => escape_html(&mut html, c),
+		};
+	}
+	while let Some(tag) = open.pop() {
+		close(&mut html, tag);
+	}
+	html
+}
+
+/// Open tag, or close it with everything opened after it
+fn toggle<'a>(html: &mut String, open: &mut Vec<&'a str>, tag: &'a str) {
+	if !open.contains(&tag) {
+		html.push_str(&format!("<{}>", tag));
+		open.push(tag);
+		return;
+	}
+	while let Some(last) = open.pop() {
+		close(html, last);
+		if last == tag {
+			break;
+		}
+	}
+}
+
+/// Closing tag, attributes dropped
+fn close(html: &mut String, tag: &str) {
+	let name = tag.split(' ').next().unwrap_or(tag);
+	html.push_str(&format!("</{}>", name));
+}
+
+fn escape_html(html: &mut String, c: char) {
+	match c {
+		'<' => html.push_str("&lt;"),
+		'>' => html.push_str("&gt;"),
+		'&' => html.push_str("&amp;"),
+		'"' => html.push_str("&quot;"),
+		c => html.push(c),
+	};
+}
+
+/// Render sender from From header as display name and address linked with
+/// mailto, envelope sender is used when there's no header; address book
+/// labels replace display names
+fn sender(mail: &mail_parser::Message, from: &str, format: &Format) -> String {
+	let addresses: Vec<String> = mail.from().map(|from| from.iter()
+		.filter_map(|addr| {
+			let name = addr.address().and_then(|address| format.label(address)).or(addr.name());
+			let address = addr.address().filter(|address| address.contains('@'))
+				.map(|address| format!("\\<[{}](mailto:{})\\>", markdown::escape(address), markdown::escape_link_url(address)));
+			match (name, address) {
+				(Some(name), Some(address)) => Some(format!("*{}* {}", markdown::escape(name), address)),
+				(Some(name), None) => Some(format!("*{}*", markdown::escape(name))),
+				(None, address) => address,
+			}
+		})
+		.collect()).unwrap_or_default();
+	match (addresses.is_empty(), format.label(from)) {
+		(true, Some(label)) => format!("*{}* `{}`", markdown::escape(label), from),
+		(true, None) => format!("`{}`", from),
+		(false, _) => addresses.join(", "),
+	}
+}
+
+impl OutgoingMessage {
+	/// Same message as escaped plain text in smaller pieces, for when
+	/// Telegram refuses to parse or accept it
+	pub fn escaped(&self) -> OutgoingMessage {
+		let text: Vec<String> = self.text_chunks.iter().map(|chunk| unmark(chunk)).collect();
+		let mut outgoing = self.clone();
+		outgoing.text_chunks = split(&markdown::escape(&text.join("\n")), CAPTION_LIMIT);
+		outgoing.options.caption = self.options.caption.as_ref()
+			.map(|caption| split(&markdown::escape(&unmark(caption)), CAPTION_LIMIT).swap_remove(0));
+		outgoing
+	}
+
+	/// Same message in markup it should be sent with
+	pub fn encoded(&self) -> OutgoingMessage {
+		let encode = |text: &String| match self.options.markup {
+			Markup::MarkdownV2 => text.clone(),
+			Markup::Html => to_html(text),
+			Markup::Plain => markdown::escape(&unmark(text)),
+		};
+		let mut outgoing = self.clone();
+		outgoing.text_chunks = self.text_chunks.iter().map(encode).collect();
+		outgoing.options.caption = self.options.caption.as_ref().map(encode);
+		if self.options.markup == Markup::Html {
+			outgoing.options.parse_mode = ParseMode::Html;
+		}
+		outgoing
+	}
+
+	/// Bytes this message takes to send
+	pub fn upload_size(&self) -> usize {
+		self.text_chunks.iter().map(|chunk| chunk.len()).sum::<usize>()
+			+ self.attachments.iter().map(|file| file.data.len() + file.preview.as_ref().map_or(0, |preview| preview.len())).sum::<usize>()
+	}
+
+	/// Text of message that is nothing but a single text message
+	pub fn text_only(&self) -> Option<&str> {
+		match self.text_chunks.as_slice() {
+			[text] if self.poll.is_none() && self.location.is_none() && self.attachments.is_empty() => Some(text),
+			_ => None,
+		}
+	}
+
+	/// Same message with files listed instead of sent
+	pub fn without_files(&self, reason: &str) -> OutgoingMessage {
+		let mut outgoing = self.clone();
+		let mut list = vec![format!("\\({}, files not sent:\\)", markdown::escape(reason))];
+		list.extend(self.attachments.iter()
+			.map(|file| format!("`{}` {}", file.data.len(), markdown::escape(&file.name))));
+		let last = outgoing.text_chunks.pop().unwrap_or_default();
+		outgoing.text_chunks.extend(append(last, Some(list.join("\n")), MESSAGE_LIMIT));
+		outgoing.attachments.clear();
+		outgoing.options.caption = None;
+		outgoing
+	}
+}
+
+/// Extract file name from part headers, if there's any
+fn part_name(part: &MessagePart, notes: &mut Vec<String>) -> Option<String> {
+	let mut filename = None;
+	for header in part.headers() {
+		if header.name() == "Content-Type" {
+			match header.value() {
+				HeaderValue::ContentType(contenttype) => {
+					if let Some(fname) = contenttype.attribute("name") {
+						filename = Some(fname.to_owned());
+					}
+				},
+				_ => {
+					notes.push("Attachment has bad ContentType header\\.".into());
+				},
+			};
+		};
+	};
+	filename
+}
+
+/// Check whether part is Outlook TNEF blob
+fn is_tnef(part: &MessagePart, name: &str) -> bool {
+	let tnef_type = part.content_type().is_some_and(|ctype|
+		ctype.ctype().eq_ignore_ascii_case("application") &&
+		matches!(ctype.subtype().map(str::to_ascii_lowercase).as_deref(), Some("ms-tnef" | "vnd.ms-tnef")));
+	tnef_type || name.eq_ignore_ascii_case("winmail.dat")
+}
+
+/// Guess file extension from MIME type
+fn extension(ctype: &str, subtype: &str) -> String {
+	match (ctype, subtype) {
+		("text", "plain") => "txt",
+		("text", "html") => "html",
+		("text", "calendar") => "ics",
+		("image", "jpeg") => "jpg",
+		("image", "svg+xml") => "svg",
+		("message", "rfc822") => "eml",
+		("message", "delivery-status") => "txt",
+		("application", "octet-stream") => "bin",
+		("application", "x-zip-compressed") => "zip",
+		(_, subtype) if !subtype.is_empty() && subtype.len() <= 8 && subtype.chars().all(|c| c.is_ascii_alphanumeric()) => subtype,
+		_ => "bin",
+	}.to_owned()
+}
+
+/// Guess content type from file name, for files that come without one
+fn guess_type(name: &str) -> &'static str {
+	let extension = name.rsplit_once('.').map_or("", |(_, extension)| extension).to_ascii_lowercase();
+	match extension.as_str() {
+		"txt" | "log" => "text/plain",
+		"htm" | "html" => "text/html",
+		"csv" => "text/csv",
+		"ics" => "text/calendar",
+		"rtf" => "application/rtf",
+		"jpg" | "jpeg" => "image/jpeg",
+		"png" => "image/png",
+		"gif" => "image/gif",
+		"webp" => "image/webp",
+		"pdf" => "application/pdf",
+		"zip" => "application/zip",
+		"xml" => "application/xml",
+		"doc" => "application/msword",
+		"xls" => "application/vnd.ms-excel",
+		"docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+		"xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+		"exe" | "dll" => "application/x-msdownload",
+		"ogg" | "opus" => "audio/ogg",
+		"mp3" => "audio/mpeg",
+		"m4a" => "audio/mp4",
+		"mp4" => "video/mp4",
+		"eml" => "message/rfc822",
+		_ => "application/octet-stream",
+	}
+}
+
+/// Generate name for a part without one, based on it's type and position:
+/// `part-2.html`, `inline-1.png`, `message-3.eml`
+fn unnamed(part: &MessagePart, index: usize) -> String {
+	let (ctype, subtype) = match part.content_type() {
+		Some(ct) => (ct.ctype().to_lowercase(), ct.subtype().unwrap_or("").to_lowercase()),
+		None => ("text".into(), "plain".into()),
+	};
+	let kind = if part.is_message() {
+		"message"
+	} else if part.content_disposition().is_some_and(|cd| cd.is_inline()) && !part.is_text() {
+		"inline"
+	} else {
+		"part"
+	};
+	format!("{}-{}.{}", kind, index, extension(&ctype, &subtype))
+}
+
+/// Find poll in mail: question is either in `X-Poll` header or on a first
+/// line starting with "Poll:", options are lines starting with "- " or "* "
+/// (any line when question is in header)
+fn poll(mail: &mail_parser::Message, notes: &mut Vec<String>) -> Option<Poll> {
+	let body = mail.body_text(0).unwrap_or_default();
+	let mut lines = body.lines().map(str::trim).filter(|line| !line.is_empty());
+	let (question, bullets_only) = match mail.header("X-Poll").and_then(|value| value.as_text()) {
+		Some(question) => (question.trim().to_owned(), false),
+		None => (lines.next()?.strip_prefix("Poll:")?.trim().to_owned(), true),
+	};
+	let options: Vec<String> = lines.filter_map(|line| match line.strip_prefix("- ").or(line.strip_prefix("* ")) {
+		Some(option) => Some(option.trim().to_owned()),
+		None if !bullets_only => Some(line.to_owned()),
+		None => None,
+	}).collect();
+	let problem = if question.is_empty() || question.chars().count() > QUESTION_LIMIT {
+		Some(format!("question should be 1\\-{} characters", QUESTION_LIMIT))
+	} else if options.len() < 2 || options.len() > OPTIONS_LIMIT {
+		Some(format!("there should be 2\\-{} options", OPTIONS_LIMIT))
+	} else if options.iter().any(|option| option.chars().count() > OPTION_LIMIT) {
+		Some(format!("options should be up to {} characters", OPTION_LIMIT))
+	} else {
+		None
+	};
+	match problem {
+		Some(problem) => {
+			notes.push(format!("Mail looks like a poll, but {}, sending as text\\.", problem));
+			None
+		},
+		None => Some(Poll {
+			question,
+			options,
+		}),
+	}
+}
+
+/// Parse "<latitude>,<longitude>", both should have decimal point so we
+/// don't mistake every pair of numbers for coordinates
+fn pair(text: &str) -> Option<(f64, f64)> {
+	let (lat, lon) = text.split_once(',')?;
+	let (lat, lon) = (lat.trim(), lon.trim());
+	if !lat.contains('.') || !lon.contains('.') {
+		return None;
+	}
+	let (lat, lon) = (lat.parse::<f64>().ok()?, lon.parse::<f64>().ok()?);
+	((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)).then_some((lat, lon))
+}
+
+/// Find coordinates in `X-Geo` header or in body, either as "geo:" URI or as
+/// "<latitude>, <longitude>"
+fn location(mail: &mail_parser::Message) -> Option<(f64, f64)> {
+	if let Some(value) = mail.header("X-Geo").and_then(|value| value.as_text()) {
+		return pair(value.trim().trim_start_matches("geo:").split(';').next()?);
+	}
+	let body = mail.body_text(0)?;
+	let words: Vec<&str> = body.split_whitespace()
+		.map(|word| word.find("geo:").map_or(word, |start| &word[start + 4..]))
+		.map(|word| word.split(';').next().unwrap_or(word))
+		.map(|word| word.trim_matches(|c: char| !(c.is_ascii_digit() || c == '-' || c == '.' || c == ',')))
+		.collect();
+	for (index, word) in words.iter().enumerate() {
+		// coordinates could be split by space after comma
+		let found = match (word.strip_suffix(','), words.get(index + 1)) {
+			(Some(lat), Some(lon)) => pair(&format!("{},{}", lat, lon)),
+			_ => pair(word),
+		};
+		if found.is_some() {
+			return found;
+		}
+	}
+	None
+}
+
+/// Check whether data is JPEG, PNG or WebP picture Telegram can show
+fn is_image(data: &[u8]) -> bool {
+	data.len() <= PHOTO_LIMIT && (data.starts_with(&[0xff, 0xd8, 0xff])
+		|| data.starts_with(b"\x89PNG\r\n\x1a\n")
+		|| (data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP")))
+}
+
+/// HTML alternative worth attaching: mail is HTML only or it's text says
+/// something else
+fn original_html<'a>(mail: &'a mail_parser::Message) -> Option<&'a MessagePart<'a>> {
+	let html = mail.html_part(0).filter(|part| part.is_text_html())?;
+	let words = |text: &str| text.split_whitespace().collect::<Vec<_>>().join(" ");
+	let differs = match mail.text_part(0).filter(|part| !part.is_text_html()) {
+		Some(text) => words(&html::to_text(html.text_contents()?)) != words(text.text_contents()?),
+		None => true,
+	};
+	differs.then_some(html)
+}
+
+/// SHA-256 of every file, one line each
+fn checksums(files: &[Attachment]) -> String {
+	let mut lines = vec!["SHA\\-256:".to_owned()];
+	for file in files {
+		let hash: String = digest::digest(&digest::SHA256, &file.data).as_ref().iter()
+			.map(|byte| format!("{:02x}", byte)).collect();
+		lines.push(format!("`{}` {}", hash, markdown::escape(&file.name)));
+	}
+	lines.join("\n")
+}
+
+/// Add extra lines to text when it stays within limit, otherwise make them
+/// a separate message
+fn append(text: String, extra: Option<String>, limit: usize) -> Vec<String> {
+	match extra {
+		Some(extra) if text.len() + 1 + extra.len() <= limit => vec![format!("{}\n{}", text, extra)],
+		Some(extra) => vec![text, extra],
+		None => vec![text],
+	}
+}
+
+/// Render mail as Telegram message with attachments
+pub fn compose(mail: &mail_parser::Message, from: &str, format: &Format) -> Result<OutgoingMessage> {
+	let mut notes = vec![];
+
+	// prepating message header
+	let mut reply: Vec<Cow<'_, str>> = vec![];
+	for field in &format.fields {
+		match field {
+			Field::Date => if let Some(date) = mail.date() {
+				reply.push(format!("**Date:** `{}`", date.to_rfc822()).into());
+			},
+			Field::From => reply.push(format!("**From:** {}", sender(mail, from, format)).into()),
+			Field::Subject => if let Some(subject) = mail.subject() {
+				reply.push(format!("**Subject:** `{}`", subject).into());
+			} else if let Some(thread) = mail.thread_name() {
+				reply.push(format!("**Thread:** `{}`", thread).into());
+			},
+		};
+	}
+	if !reply.is_empty() {
+		reply.push("".into());
+	}
+	let header_size = reply.join("\n").len() + 1;
+
+	// abuse complaints are shown as facts, nested parts are just noise
+	if let Some(report) = arf::Report::parse(mail) {
+		reply.extend(report.lines().into_iter().map(Cow::from));
+		return Ok(OutgoingMessage {
+			text_chunks: vec![reply.join("\n")],
+			poll: None,
+			location: None,
+			attachments: vec![],
+			options: Options {
+				markup: format.markup,
+				..Options::default()
+			},
+			notes,
+		});
+	}
+
+	let html_parts = mail.html_body_count();
+	let text_parts = mail.text_body_count();
+	let attachments = mail.attachment_count();
+	if html_parts != text_parts {
+		notes.push(format!("Hm, we have {} HTML parts and {} text parts\\.", html_parts, text_parts));
+	}
+	let mut text_num = 0;
+	let mut file_num = 0;
+	// let's display preferred alternative as body
+	let mut body: Cow<'_, str> = "".into();
+	let mut found = None;
+	for kind in &format.body_preference {
+		found = match kind {
+			BodyType::Plain if text_parts > 0 && mail.text_part(0).is_some_and(|p| !p.is_text_html()) =>
+				mail.body_text(0),
+			BodyType::Html if html_parts > 0 && mail.html_part(0).is_some_and(|p| p.is_text_html()) =>
+				mail.body_html(0).map(|html| html::to_text(&html).into()),
+			_ => None,
+		};
+		if found.is_some() {
+			break;
+		}
+	}
+	// nothing we prefer, just get whatever text is there
+	if found.is_none() && text_parts > 0 {
+		found = Some(mail.body_text(0)
+			.ok_or(anyhow!("Failed to extract text from message."))?);
+	}
+	if let Some(text) = found {
+		// terminal output from cron and CI
+		let mut text: Cow<'_, str> = ansi::clean(&text, format.colors).into();
+		if let Some(limit) = format.body_limit.filter(|limit| text.len() > *limit) {
+			let mut cut = limit;
+			while !text.is_char_boundary(cut) {
+				cut -= 1;
+			}
+			text = format!("{}\n[…]", &text[..cut]).into();
+		}
+		if text.len() < MESSAGE_LIMIT - header_size {
+			body = text;
+			text_num = 1;
+		}
+	};
+
+	// and let's collect all other attachment parts, html parts just
+	// duplicate text so they are skipped
+	// mail with hundreds of parts is cut short, whatever is left over is
+	// just counted
+	let max_parts = format.max_parts.unwrap_or(usize::MAX);
+	let mut parts = text_num;
+	let mut skipped = 0;
+	let mut files_to_send = vec![];
+	let mut size = header_size + body.len();
+	let mut body = body.into_owned();
+	while text_num < text_parts {
+		// broken structure can list parts that are not there
+		let Some(part) = mail.text_part(text_num) else {
+			skipped += 1;
+			text_num += 1;
+			continue;
+		};
+		// inline binary parts are listed as attachments too
+		if !part.is_text() {
+			text_num += 1;
+			continue;
+		}
+		if parts >= max_parts {
+			skipped += 1;
+			text_num += 1;
+			continue;
+		}
+		parts += 1;
+		// first part is not extra, it's just too big for the body
+		match (text_num, format.extra_text_parts) {
+			(0, _) | (_, ExtraText::Attach) => files_to_send.push(part),
+			(_, ExtraText::Ignore) => {},
+			(_, ExtraText::Append) => {
+				let text = mail.body_text(text_num)
+					.ok_or(anyhow!("Failed to extract text from message."))?;
+				let text = ansi::clean(&text, format.colors);
+				let separator = format!("\n\n--- part {} ---\n", text_num + 1);
+				if size + separator.len() + text.len() < MESSAGE_LIMIT {
+					size += separator.len() + text.len();
+					body.push_str(&separator);
+					body.push_str(&text);
+				} else {
+					files_to_send.push(part);
+				}
+			},
+		};
+		text_num += 1;
+	}
+	while file_num < attachments {
+		match mail.attachment(file_num) {
+			Some(part) if parts < max_parts => {
+				files_to_send.push(part);
+				parts += 1;
+			},
+			_ => skipped += 1,
+		};
+		file_num += 1;
+	}
+
+	// HTML too big for body is attached already
+	let html = original_html(mail)
+		.filter(|html| format.attach_html && !files_to_send.iter().any(|part| std::ptr::eq(*part, *html)));
+
+	let mut files = vec![];
+	let mut filtered = 0;
+	for (index, chunk) in files_to_send.into_iter().enumerate() {
+		// parts without type are plain text
+		let ctype = chunk.content_type()
+			.map_or("text/plain".into(), |ct| format!("{}/{}", ct.ctype(), ct.subtype().unwrap_or("")).to_lowercase());
+		if !format.keeps(&ctype) {
+			filtered += 1;
+			continue;
+		}
+		let name = part_name(chunk, &mut notes)
+			.unwrap_or_else(|| unnamed(chunk, index + 1));
+		if is_tnef(chunk, &name) {
+			match tnef::decode(chunk.contents()) {
+				Ok(decoded) => {
+					// wrapper is not sent, files inside it are
+					parts -= 1;
+					let inner = decoded.files.into_iter().enumerate()
+						.map(|(index, inner)| match inner.name.is_empty() {
+							true => (format!("attachment {}.bin", index + 1), inner.data),
+							false => (inner.name, inner.data),
+						})
+						.chain(decoded.rtf.map(|rtf| ("body.rtf".to_owned(), rtf)));
+					for (name, data) in inner {
+						let ctype = guess_type(&name);
+						if !format.keeps(ctype) {
+							filtered += 1;
+							continue;
+						}
+						if parts >= max_parts {
+							skipped += 1;
+							continue;
+						}
+						parts += 1;
+						let mut file = Attachment::new(&name, data);
+						file.kind = Kind::new(ctype, format);
+						files.push(file);
+					}
+					continue;
+				},
+				Err(err) => notes.push(format!("Can't unpack {}, forwarding as is: {}",
+					markdown::escape(&name), markdown::escape(&err.to_string()))),
+			};
+		}
+		let mut file = Attachment::new(&name, chunk.contents().to_vec());
+		file.kind = Kind::new(&ctype, format);
+		files.push(file);
+	}
+
+	if let Some(part) = html.filter(|_| format.keeps("text/html")) {
+		files.push(Attachment::new("message.html", part.contents().to_vec()));
+	}
+
+	let mut omitted = None;
+	if format.ignore_attachments && !files.is_empty() {
+		omitted = Some(match files.len() {
+			1 => "\\(1 attachment omitted\\)".to_owned(),
+			count => format!("\\({} attachments omitted\\)", count),
+		});
+		files.clear();
+	}
+
+	let skipped = (skipped > 0).then(|| format!("\\(plus {} more parts skipped\\)", skipped));
+	let filtered = match filtered {
+		0 => None,
+		1 => Some("\\(1 file of filtered type dropped\\)".to_owned()),
+		count => Some(format!("\\({} files of filtered types dropped\\)", count)),
+	};
+
+	let sums = (format.checksums && !files.is_empty()).then(|| checksums(&files));
+
+	let mut options = Options {
+		markup: format.markup,
+		..Options::default()
+	};
+	if format.attachments_only {
+		if files.is_empty() {
+			notes.push("Nothing to send to attachments only route, mail has no files\\.".into());
+		}
+		notes.extend(skipped);
+		notes.extend(filtered);
+		return Ok(OutgoingMessage {
+			text_chunks: match mail.subject() {
+				_ if files.is_empty() => vec![],
+				Some(subject) => append(markdown::escape(subject), sums, CAPTION_LIMIT),
+				None => sums.into_iter().collect(),
+			},
+			poll: None,
+			location: None,
+			attachments: files,
+			options,
+			notes,
+		});
+	}
+	// fences are lines of their own, around body
+	let fences = "```\n\n```".len();
+	// scans and snapshots come with a line of text at most, so the picture is
+	// shown with text as caption
+	let photo = format.photos && header_size + fences + body.len() <= CAPTION_LIMIT;
+	let photo = photo && match files.iter_mut().find(|file| is_image(&file.data)) {
+		Some(file) => {
+			file.kind = Kind::Photo;
+			true
+		},
+		None => false,
+	};
+	// screenshots from monitoring are better seen than downloaded
+	if format.inline_images {
+		for file in files.iter_mut().filter(|file| file.kind == Kind::Document && is_image(&file.data)) {
+			file.kind = Kind::Photo;
+		}
+	}
+	// only documents get caption, playable media is sent on it's own
+	let documents = files.iter().any(|file| file.kind == Kind::Document);
+	// text sent on it's own is never too long for caption
+	options.text_first = documents && format.text_first;
+	if documents && !options.text_first && header_size + fences + body.len() > CAPTION_LIMIT {
+		match format.long_caption {
+			LongCaption::Split => options.caption = reply.first().map(|line| line.to_string()),
+			LongCaption::Truncate => {
+				let marker = "\n[…]";
+				let mut cut = CAPTION_LIMIT.saturating_sub(header_size + fences + marker.len());
+				while !body.is_char_boundary(cut) {
+					cut -= 1;
+				}
+				body.truncate(cut);
+				body.push_str(marker);
+			},
+		};
+	}
+	reply.push("```".into());
+	reply.extend(body.lines().map(|x| x.to_owned().into()));
+	reply.push("```".into());
+	if let Some(omitted) = omitted {
+		reply.push(omitted.into());
+	}
+	if let Some(filtered) = filtered {
+		reply.push(filtered.into());
+	}
+	if let Some(skipped) = skipped {
+		reply.push(skipped.into());
+	}
+
+	// whole text becomes caption when it fits
+	let limit = match photo || (documents && options.caption.is_none() && !options.text_first) {
+		true => CAPTION_LIMIT,
+		false => MESSAGE_LIMIT,
+	};
+	let poll = if format.polls { poll(mail, &mut notes) } else { None };
+	Ok(OutgoingMessage {
+		text_chunks: match poll {
+			Some(_) => sums.into_iter().collect(),
+			None => append(reply.join("\n"), sums, limit),
+		},
+		poll,
+		location: if format.locations { location(mail) } else { None },
+		attachments: files,
+		options,
+		notes,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use base64::Engine;
+
+	/// Format from TOML text, like in smtp2tg.toml
+	fn format(toml: &str) -> Format {
+		let settings = config::Config::builder()
+			.add_source(config::File::from_str(toml, config::FileFormat::Toml))
+			.build().unwrap();
+		Format::new(&settings)
+	}
+
+	/// Mail with body and a single text file attached
+	fn with_file(body: &str) -> Vec<u8> {
+		format!("From: a@host\r\nSubject: report\r\nContent-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+			--b\r\nContent-Type: text/plain\r\n\r\n{}\r\n\
+			--b\r\nContent-Type: application/octet-stream\r\nContent-Disposition: attachment; filename=\"data.bin\"\r\n\r\nxyz\r\n\
+			--b--\r\n", body).into_bytes()
+	}
+
+	/// Mail with winmail.dat holding named files
+	fn with_tnef(files: &[(&str, &[u8])]) -> Vec<u8> {
+		let attribute = |id: u16, value: &[u8]| {
+			let mut data = vec![2];
+			data.extend_from_slice(&u32::from(id).to_le_bytes());
+			data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+			data.extend_from_slice(value);
+			data.extend_from_slice(&[0, 0]);
+			data
+		};
+		let mut tnef = 0x223e9f78u32.to_le_bytes().to_vec();
+		tnef.extend_from_slice(&[1, 0]);
+		for (name, data) in files {
+			tnef.extend(attribute(0x9002, &[0; 14]));
+			tnef.extend(attribute(0x8010, name.as_bytes()));
+			tnef.extend(attribute(0x800f, data));
+		}
+		let mut mail = b"From: a@host\r\nSubject: outlook\r\nContent-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+			--b\r\nContent-Type: text/plain\r\n\r\nsee attached\r\n\
+			--b\r\nContent-Type: application/ms-tnef\r\nContent-Disposition: attachment; filename=\"winmail.dat\"\r\n\
+			Content-Transfer-Encoding: base64\r\n\r\n".to_vec();
+		mail.extend_from_slice(base64::engine::general_purpose::STANDARD.encode(&tnef).as_bytes());
+		mail.extend_from_slice(b"\r\n--b--\r\n");
+		mail
+	}
+
+	#[test]
+	fn attachments_only_captions_with_subject() {
+		let data = with_file("ignored body");
+		let mail = mail_parser::MessageParser::new().parse(&data).unwrap();
+		let format = format("attachments_only = true\nfields = [\"from\", \"date\", \"subject\"]\nchecksums = true");
+		let outgoing = compose(&mail, "a@host", &format).unwrap();
+		assert_eq!(outgoing.attachments.len(), 1);
+		assert_eq!(outgoing.text_chunks.len(), 1);
+		assert!(outgoing.text_chunks[0].starts_with("report\nSHA\\-256:"));
+		assert!(!outgoing.text_chunks[0].contains("ignored body"));
+	}
+
+	#[test]
+	fn snapshot_is_photo() {
+		let data = b"From: camera@host\r\nSubject: motion\r\nContent-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+			--b\r\nContent-Type: text/plain\r\n\r\nfront door\r\n\
+			--b\r\nContent-Type: image/jpeg\r\nContent-Disposition: attachment; filename=\"snap.jpg\"\r\n\
+			Content-Transfer-Encoding: base64\r\n\r\n/9j/4AAQ\r\n--b--\r\n";
+		let mail = mail_parser::MessageParser::new().parse(&data[..]).unwrap();
+		let kinds = |toml: &str| compose(&mail, "camera@host", &format(toml)).unwrap()
+			.attachments.iter().map(|file| file.kind).collect::<Vec<_>>();
+		assert_eq!(kinds(""), vec![Kind::Photo]);
+		assert_eq!(kinds("photos = false"), vec![Kind::Document]);
+	}
+
+	#[test]
+	fn tnef_files_are_filtered_and_counted() {
+		let data = with_tnef(&[("notes.txt", b"text"), ("tool.exe", b"MZ"), ("song.mp3", b"ID3"), ("more.txt", b"more")]);
+		let mail = mail_parser::MessageParser::new().parse(&data).unwrap();
+		let format = format("attachments_deny = [\"application/x-msdownload\"]\nplay_audio = true\nmax_parts = 3");
+		let outgoing = compose(&mail, "a@host", &format).unwrap();
+		let files: Vec<(&str, Kind)> = outgoing.attachments.iter().map(|file| (file.name.as_str(), file.kind)).collect();
+		assert_eq!(files, vec![("notes.txt", Kind::Document), ("song.mp3", Kind::Audio)]);
+		let text = outgoing.text_chunks.concat();
+		assert!(text.contains("1 file of filtered type dropped"));
+		assert!(text.contains("plus 1 more parts skipped"));
+	}
+
+	#[test]
+	fn unmark_drops_markup() {
+		for (text, plain) in [
+			("**From:** *Name*", "From: Name"),
+			("\\<[a@b](mailto:a@b)\\>", "<a@b>"),
+			("`code \\` here`", "code ` here"),
+			("```\nline *1*\n```", "\nline *1*\n"),
+			("||spoiler||", "spoiler"),
+			(">quote\nnot \\> quote", "quote\nnot > quote"),
+			("1\\.5 \\- x", "1.5 - x"),
+		] {
+			assert_eq!(unmark(text), plain, "{}", text);
+		}
+	}
+
+	#[test]
+	fn unmark_keeps_unpaired_markup() {
+		for (text, plain) in [
+			("2 * 3 = 6", "2 * 3 = 6"),
+			("snake_case and *bold*", "snake_case and bold"),
+			("a | b ||spoiler||", "a | b spoiler"),
+			("[not a link] !wow", "[not a link] !wow"),
+			("*bold\\**", "bold*"),
+			("![👍](tg://emoji?id=1) ok", "👍 ok"),
+			("x > y\n>quote", "x > y\nquote"),
+		] {
+			assert_eq!(unmark(text), plain, "{}", text);
+		}
+	}
+
+	#[test]
+	fn unmark_reverts_escape() {
+		for text in [
+			"plain text",
+			"_*[]()~`>#+-=|{}.!",
+			"C:\\path\\file",
+			"**not bold** and `not code`",
+			"[text](https://host/) ![x](y)",
+			"line\n> not quote\n```\nnot fence\n```",
+			"ünïcode — 😀",
+		] {
+			assert_eq!(unmark(&markdown::escape(text)), text);
+		}
+	}
+
+	#[test]
+	fn plain_markup_keeps_text() {
+		let outgoing = OutgoingMessage {
+			text_chunks: vec![
+				"**Subject:** `a_b*c`\n**From:** *Name* \\<[a@host](mailto:a@host)\\>\n".to_owned(),
+				"```\n2 * 3 [x] \\` _y_\n```\nwatch 1\\.5 * 2".to_owned(),
+			],
+			poll: None,
+			location: None,
+			attachments: vec![],
+			options: Options {
+				markup: Markup::Plain,
+				caption: Some("*caption* ~x".to_owned()),
+				..Options::default()
+			},
+			notes: vec![],
+		};
+		let encoded = outgoing.encoded();
+		let plain: Vec<String> = encoded.text_chunks.iter().map(|chunk| unmark(chunk)).collect();
+		assert_eq!(plain, vec![
+			"Subject: a_b*c\nFrom: Name <a@host>\n",
+			"\n2 * 3 [x] ` _y_\n\nwatch 1.5 * 2",
+		]);
+		assert_eq!(encoded.options.caption.as_deref().map(unmark).as_deref(), Some("caption ~x"));
+		assert_eq!(encoded.options.parse_mode, ParseMode::MarkdownV2);
+	}
+
+	#[test]
+	fn to_html_converts_markup() {
+		for (text, html) in [
+			("**From:** `x`", "<b>From:</b> <code>x</code>"),
+			("_i_ ~s~ ||sp||", "<i>i</i> <s>s</s> <tg-spoiler>sp</tg-spoiler>"),
+			("a < b & \\*", "a &lt; b &amp; *"),
+			("[text](https://h/?a=1&b=2)", "<a href=\"https://h/?a=1&amp;b=2\">text</a>"),
+			("```\ncode <x> *\n```", "<pre>code &lt;x&gt; *</pre>"),
+			(">quote\nnext", "<blockquote>quote</blockquote>\nnext"),
+			("*unclosed _both", "<b>unclosed <i>both</i></b>"),
+		] {
+			assert_eq!(to_html(text), html, "{}", text);
+		}
+	}
+
+	#[test]
+	fn split_keeps_lines_and_escapes() {
+		assert_eq!(split("a\nb\nc", 3), vec!["a\n", "b\nc"]);
+		assert_eq!(split("abc\\.def", 4), vec!["abc", "\\.de", "f"]);
+		assert_eq!(split("ééé", 3), vec!["é", "é", "é"]);
+		assert_eq!(split("", 10), vec![""]);
+	}
+
+	#[test]
+	fn append_fits_or_splits() {
+		assert_eq!(append("text".into(), Some("sum".into()), 8), vec!["text\nsum"]);
+		assert_eq!(append("text".into(), Some("sum".into()), 7), vec!["text", "sum"]);
+		assert_eq!(append("text".into(), None, 1), vec!["text"]);
+	}
+
+	#[test]
+	fn short_text_is_caption() {
+		let data = with_file("all good");
+		let mail = mail_parser::MessageParser::new().parse(&data).unwrap();
+		let outgoing = compose(&mail, "a@host", &format("")).unwrap();
+		assert_eq!(outgoing.attachments.len(), 1);
+		assert_eq!(outgoing.text_chunks.len(), 1);
+		assert!(outgoing.text_chunks[0].len() <= CAPTION_LIMIT);
+		assert!(outgoing.text_chunks[0].contains("all good"));
+		assert_eq!(outgoing.options.caption, None);
+	}
+
+	#[test]
+	fn long_caption_is_split() {
+		let data = with_file(&"line of text\n".repeat(200));
+		let mail = mail_parser::MessageParser::new().parse(&data).unwrap();
+		let outgoing = compose(&mail, "a@host", &format("")).unwrap();
+		let caption = outgoing.options.caption.unwrap();
+		assert!(caption.len() <= CAPTION_LIMIT);
+		assert!(!caption.contains("line of text"));
+		assert!(outgoing.text_chunks.iter().all(|chunk| chunk.len() <= MESSAGE_LIMIT));
+		assert_eq!(outgoing.text_chunks.concat().matches("line of text").count(), 200);
+	}
+
+	#[test]
+	fn long_caption_is_truncated() {
+		let data = with_file(&"line of text\n".repeat(200));
+		let mail = mail_parser::MessageParser::new().parse(&data).unwrap();
+		let outgoing = compose(&mail, "a@host", &format("long_caption = \"truncate\"")).unwrap();
+		assert_eq!(outgoing.options.caption, None);
+		assert_eq!(outgoing.text_chunks.len(), 1);
+		assert!(outgoing.text_chunks[0].len() <= CAPTION_LIMIT);
+		assert!(outgoing.text_chunks[0].ends_with("[…]\n```"));
+	}
+}
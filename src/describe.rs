@@ -0,0 +1,196 @@
+//! Machine readable description of settings this binary understands, for
+//! configuration management tools to check rendered configs against. Keep it
+//! in sync with `smtp2tg.toml.example`.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use Value::{
+	Flag,
+	List,
+	Number,
+	Text,
+};
+
+/// `Value` is default of a setting
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum Value {
+	Text(&'static str),
+	Number(i64),
+	Flag(bool),
+	List(&'static [&'static str]),
+}
+
+/// `Setting` is one configuration key, "*" stands for any name in tables
+#[derive(Debug, Serialize)]
+struct Setting {
+	key: &'static str,
+	/// string, integer, number, boolean, duration ("1h"), size ("10M"), address,
+	/// chat (id or table like in [recipients]), array or table
+	#[serde(rename = "type")]
+	kind: &'static str,
+	/// What is used when setting is missing, null when feature is off
+	default: Option<Value>,
+	/// Whether it's only read on start, SIGHUP doesn't apply it
+	restart: bool,
+	description: &'static str,
+}
+
+/// `Description` is the whole document
+#[derive(Debug, Serialize)]
+struct Description {
+	name: &'static str,
+	version: &'static str,
+	settings: &'static [Setting],
+}
+
+const fn setting(key: &'static str, kind: &'static str, default: Option<Value>, restart: bool, description: &'static str) -> Setting {
+	Setting {
+		key,
+		kind,
+		default,
+		restart,
+		description,
+	}
+}
+
+const SETTINGS: &[Setting] = &[
+	setting("api_key", "string", None, true, "Telegram API key"),
+	setting("listen_on", "address", Some(Text("0.0.0.0:1025")), true, "address or array of addresses to listen on, \"unix:\" prefix for Unix sockets"),
+	setting("socket_mode", "string", None, true, "permissions of Unix sockets, like \"0660\""),
+	setting("listen_on_tls", "address", None, true, "address or array of addresses speaking TLS from the first byte, needs [tls]"),
+	setting("hostname", "string", Some(Text("smtp.2.tg")), true, "name in greeting"),
+	setting("unknown", "string", Some(Text("relay")), false, "what to do with unknown addresses: \"relay\" or \"deny\""),
+	setting("deny_code", "integer", Some(Number(550)), false, "reply code for recipients we don't accept"),
+	setting("deny_text", "string", Some(Text("Mailbox unavailable")), false, "reply text for recipients we don't accept"),
+	setting("reject_domains", "array", None, false, "domains rejected at RCPT even when unknown addresses are relayed"),
+	setting("vrfy", "boolean", Some(Flag(true)), true, "answer VRFY and EXPN from recipients tables"),
+	setting("pregreet", "integer", Some(Number(0)), true, "milliseconds to wait before greeting, 0 disables the check"),
+	setting("max_connections", "integer", Some(Number(0)), true, "sessions in progress over this get 421, 0 doesn't limit them"),
+	setting("syslog", "string", None, true, "syslog destination: \"udp://\", \"tcp://\" or \"unix://\" URL"),
+	setting("syslog_facility", "string", Some(Text("mail")), true, "syslog facility: user, mail, daemon or local0-7"),
+	setting("log_level", "string", Some(Text("info")), true, "error, warn, info, debug or trace"),
+	setting("deadletter", "string", None, false, "directory, \"s3://\" or WebDAV location for mail that can't be parsed or has expired"),
+	setting("attachments_store", "string", None, false, "\"s3://\" or WebDAV location for files Telegram can't take, they are linked instead"),
+	setting("expire_after", "duration", None, false, "don't deliver mail spooled longer than this"),
+	setting("spool", "string", None, true, "directory to keep mail that failed to deliver in"),
+	setting("spool_interval", "duration", Some(Text("1m")), true, "how often spooled mail is retried"),
+	setting("latency_footer", "boolean", Some(Flag(false)), false, "append time message spent in gateway"),
+	setting("latency_warn", "duration", None, false, "warn in debug chat about messages slower than this"),
+	setting("skew_warn", "duration", None, false, "flag messages whose Date header is this far from receiving time"),
+	setting("max_size", "size", Some(Text("100M")), false, "largest message accepted, \"0\" lifts the limit"),
+	setting("max_depth", "integer", Some(Number(10)), false, "deepest nesting of multiparts and attached messages, 0 lifts the limit"),
+	setting("parse_time", "duration", Some(Text("5s")), false, "longest time to parse message, 0 lifts the limit"),
+	setting("drop_refused", "boolean", Some(Flag(true)), false, "leave files Telegram refuses out of media groups with a note"),
+	setting("disable_after", "integer", Some(Number(3)), false, "refused messages in a row before chat is disabled, 0 never disables"),
+	setting("disable_for", "duration", Some(Text("1d")), false, "how long refusing chat stays disabled"),
+	setting("batch_window", "duration", None, false, "merge short messages to the same chat arriving within this window"),
+	setting("export_jsonl", "string", None, false, "file to append JSON record of every message to"),
+	setting("state_file", "string", None, true, "file to keep runtime state in"),
+	setting("upload_cap", "size", None, false, "monthly upload volume cap per chat"),
+	setting("pdf_preview", "array", None, false, "command rendering first page of PDF attachments into image"),
+	setting("lock_file", "string", None, false, "lock file shared by instances running side by side"),
+	setting("trace_routes", "string", Some(Text("off")), false, "explain why each chat got the message: off, log or footer"),
+	setting("silent_header", "string", Some(Text("X-SMTP2TG-Silent")), false, "header asking for delivery without notification, empty disables it"),
+	setting("chat_header", "string", Some(Text("X-SMTP2TG-Chat")), false, "header trusted senders use to pick chat, empty disables it"),
+	setting("trusted_senders", "array", None, false, "addresses or \"@domain\" allowed to use chat header"),
+	setting("forwarders", "array", None, false, "upstream MTA networks whose mail is routed by X-Original-To or Delivered-To headers"),
+	setting("body_preference", "array", Some(List(&["text/plain", "text/html"])), false, "which alternative becomes message body"),
+	setting("extra_text_parts", "string", Some(Text("attach")), false, "text parts after the first one: attach, append or ignore"),
+	setting("long_caption", "string", Some(Text("split")), false, "text too long for caption: split or truncate"),
+	setting("parse_mode", "string", Some(Text("MarkdownV2")), false, "message markup: MarkdownV2, HTML or plain"),
+	setting("ansi_colors", "string", Some(Text("strip")), false, "ANSI colors in body: strip, or emoji to keep them as markers"),
+	setting("body_limit", "integer", None, false, "cut message body to this many bytes"),
+	setting("max_parts", "integer", Some(Number(100)), false, "mail parts turned into text and files, 0 lifts the limit"),
+	setting("attachments_allow", "array", None, false, "content types of files sent, like \"image/*\", everything when empty"),
+	setting("attachments_deny", "array", None, false, "content types of files dropped"),
+	setting("fields", "array", Some(List(&["subject", "from"])), false, "headers shown on top of message: date, from and subject"),
+	setting("extension_separator", "string", None, false, "address extension separator selecting formatting profile"),
+	setting("tag_separator", "string", Some(Text("+")), false, "subaddress separator, tag is shown in front of message, empty disables it"),
+	setting("delivery", "string", Some(Text("at-least-once")), false, "at-least-once or at-most-once"),
+	setting("updates", "string", Some(Text("off")), false, "how to receive updates from Telegram: off, polling or webhook; bot commands need them"),
+	setting("signing_key", "string", None, false, "key to sign delivered messages with"),
+	setting("tls.cert", "string", None, true, "certificate file"),
+	setting("tls.key", "string", None, true, "private key file"),
+	setting("tls.client_ca", "string", None, true, "CA bundle to verify client certificates against"),
+	setting("tls.require_client_cert", "boolean", Some(Flag(true)), true, "whether clients without certificate are refused"),
+	setting("retry.attempts", "integer", Some(Number(3)), false, "attempts of every Telegram request"),
+	setting("retry.delay_ms", "integer", Some(Number(1000)), false, "delay before first retry, doubled for every next one"),
+	setting("retry.max_wait", "integer", Some(Number(300)), false, "seconds of rate limit waits before giving up"),
+	setting("webhook.listen", "address", None, false, "where built-in HTTP listener for updates listens"),
+	setting("webhook.url", "string", None, false, "public URL of webhook"),
+	setting("webhook.secret", "string", None, false, "secret Telegram sends with updates"),
+	setting("chaos.error", "number", Some(Number(0)), false, "probability of failing Telegram request, for testing"),
+	setting("chaos.throttle", "number", Some(Number(0)), false, "probability of throttled Telegram request, for testing"),
+	setting("chaos.delay", "number", Some(Number(0)), false, "probability of slow Telegram request, for testing"),
+	setting("chaos.delay_ms", "integer", Some(Number(5000)), false, "how slow slow requests are"),
+	setting("profiles.*.attachments_allow", "array", None, false, "override \"attachments_allow\""),
+	setting("profiles.*.attachments_deny", "array", None, false, "override \"attachments_deny\""),
+	setting("profiles.*.attach_html", "boolean", Some(Flag(false)), false, "attach HTML part as message.html when text doesn't have it all"),
+	setting("profiles.*.attachments_only", "boolean", Some(Flag(false)), false, "send just files captioned with subject"),
+	setting("profiles.*.body_limit", "integer", None, false, "override \"body_limit\""),
+	setting("profiles.*.body_preference", "array", None, false, "override \"body_preference\""),
+	setting("profiles.*.checksums", "boolean", Some(Flag(false)), false, "list SHA-256 of every forwarded file"),
+	setting("profiles.*.extra_text_parts", "string", None, false, "override \"extra_text_parts\""),
+	setting("profiles.*.fields", "array", None, false, "override \"fields\""),
+	setting("profiles.*.ignore_attachments", "boolean", Some(Flag(false)), false, "drop all files noting how many were there"),
+	setting("profiles.*.locations", "boolean", Some(Flag(false)), false, "add location pin for coordinates in mail"),
+	setting("profiles.*.long_caption", "string", None, false, "override \"long_caption\""),
+	setting("profiles.*.parse_mode", "string", None, false, "override \"parse_mode\""),
+	setting("profiles.*.ansi_colors", "string", None, false, "override \"ansi_colors\""),
+	setting("profiles.*.inline_images", "boolean", Some(Flag(false)), false, "send every JPEG/PNG/WebP file as photo"),
+	setting("profiles.*.photos", "boolean", Some(Flag(true)), false, "send mail that is mostly a picture as photo"),
+	setting("profiles.*.play_audio", "boolean", Some(Flag(false)), false, "send MP3/M4A as audio and OGG/Opus as voice"),
+	setting("profiles.*.play_video", "boolean", Some(Flag(false)), false, "send MP4 as streamable video"),
+	setting("profiles.*.text_first", "boolean", Some(Flag(false)), false, "send text as message and files as reply to it"),
+	setting("profiles.*.polls", "boolean", Some(Flag(false)), false, "send \"Poll:\" mail as Telegram poll"),
+	setting("addressbook.*", "string", None, false, "friendly name for address or \"@domain\""),
+	setting("access.allow", "array", None, false, "client networks allowed to connect"),
+	setting("access.deny", "array", None, false, "client networks refused"),
+	setting("geoip.databases", "array", None, true, "MMDB files to look client addresses up in"),
+	setting("geoip.reject", "array", None, false, "countries and ASNs dropped before greeting"),
+	setting("geoip.suspicious", "array", None, false, "countries and ASNs whose mail is marked suspicious"),
+	setting("geoip.footer", "boolean", Some(Flag(false)), false, "show origin of suspicious mail"),
+	setting("dkim.resolver", "address", None, false, "resolver for DKIM keys, first one from /etc/resolv.conf without it"),
+	setting("spf.action", "string", Some(Text("annotate")), false, "mail failing SPF: annotate, quarantine or reject"),
+	setting("spf.resolver", "address", None, false, "resolver for SPF records, first one from /etc/resolv.conf without it"),
+	setting("storage.endpoint", "string", None, false, "S3-compatible service URL for \"s3://\" locations"),
+	setting("storage.region", "string", Some(Text("us-east-1")), false, "S3 region"),
+	setting("storage.access_key", "string", None, false, "S3 access key"),
+	setting("storage.secret_key", "string", None, false, "S3 secret key"),
+	setting("storage.username", "string", None, false, "WebDAV user"),
+	setting("storage.password", "string", None, false, "WebDAV password"),
+	setting("storage.public_url", "string", None, false, "base of links to stored objects instead of presigned or WebDAV ones"),
+	setting("dmarc.authserv_id", "string", None, false, "only trust Authentication-Results from this server"),
+	setting("dmarc.footer", "boolean", Some(Flag(true)), false, "show DMARC verdict in message"),
+	setting("dmarc.reports", "chat", None, false, "chat for summaries of aggregate reports"),
+	setting("upload_caps.*", "size", None, false, "upload cap for chat, overriding \"upload_cap\""),
+	setting("policies.*.auth_required", "boolean", Some(Flag(false)), false, "reject MAIL FROM until client authenticates"),
+	setting("policies.*.relay", "boolean", None, false, "override \"unknown\" for listener"),
+	setting("policies.*.max_size", "size", None, false, "override \"max_size\" for listener"),
+	setting("policies.*.senders", "array", None, false, "envelope senders allowed, addresses or \"@domain\""),
+	setting("policies.*.never_reject", "boolean", Some(Flag(false)), false, "always answer 250"),
+	setting("policies.*.plaintext_auth", "boolean", Some(Flag(false)), false, "offer AUTH before STARTTLS"),
+	setting("recipients.*", "chat", None, false, "chat for address, \"_\" is default recipient; table can have chat, topic, profile, priority, silent, emoji, emoji_id, fields and links; array of those delivers to each, strings name groups"),
+	setting("groups.*", "array", None, false, "chats recipients can refer to by group name; strings name other groups or global recipients"),
+	setting("auth.*", "string", None, true, "password or hash of SMTP AUTH user"),
+	setting("identities.*", "chat", None, false, "chat for mail of authenticated client"),
+	setting("classify", "array", None, false, "rules sorting mail of default recipient by keywords"),
+	setting("routes", "array", None, false, "rules routing mail by from, to and subject regexes, checked before recipients tables"),
+	setting("suppress", "array", None, false, "rules dropping mail not worth reading"),
+	setting("tenants.*.domains", "array", None, false, "domains routed with tenant's recipients"),
+	setting("tenants.*.unknown", "string", None, false, "override \"unknown\" for tenant"),
+	setting("tenants.*.api_key", "string", None, true, "tenant's own Telegram API key"),
+	setting("tenants.*.quota", "integer", None, false, "messages per day"),
+	setting("tenants.*.recipients.*", "chat", None, false, "tenant's recipients table"),
+];
+
+/// Settings, their types and defaults as JSON
+pub fn json() -> Result<String> {
+	Ok(serde_json::to_string_pretty(&Description {
+		name: env!("CARGO_PKG_NAME"),
+		version: env!("CARGO_PKG_VERSION"),
+		settings: SETTINGS,
+	})?)
+}
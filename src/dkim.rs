@@ -0,0 +1,310 @@
+//! DKIM signature verification (RFC 6376) for mail that comes from outside,
+//! so spoofed mail stands out in Telegram.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use base64::{
+	engine::general_purpose::STANDARD,
+	Engine,
+};
+use ring::{
+	digest,
+	signature,
+};
+
+use std::{
+	fmt,
+	time::SystemTime,
+};
+
+use crate::{
+	dns::Resolver,
+	x509,
+};
+
+/// `Verdict` is the outcome of checking all signatures of a message
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+	/// Signature of domain verified
+	Pass(String),
+	/// Signatures are there but none verified, with reason for the first one
+	Fail(String),
+	/// Message is not signed at all
+	None,
+}
+
+impl fmt::Display for Verdict {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Verdict::Pass(domain) => write!(f, "✅ DKIM: signed by {}", domain),
+			Verdict::Fail(reason) => write!(f, "❌ DKIM: {}", reason),
+			Verdict::None => write!(f, "❌ DKIM: not signed"),
+		}
+	}
+}
+
+/// `Canon` is canonicalization algorithm
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Canon {
+	Simple,
+	Relaxed,
+}
+
+/// `Signature` is parsed DKIM-Signature header
+#[derive(Debug)]
+struct Signature {
+	/// Header as it came, needed to hash it
+	raw: String,
+	algorithm: String,
+	signature: Vec<u8>,
+	body_hash: Vec<u8>,
+	header_canon: Canon,
+	body_canon: Canon,
+	domain: String,
+	headers: Vec<String>,
+	length: Option<usize>,
+	selector: String,
+	expires: Option<u64>,
+}
+
+/// Split tag list into tag and value pairs
+fn tags(value: &str) -> Vec<(String, String)> {
+	value.split(';')
+		.filter_map(|tag| tag.split_once('='))
+		.map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+		.collect()
+}
+
+/// Drop all whitespace, base64 values can be folded anywhere
+fn squeeze(value: &str) -> String {
+	value.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+impl Signature {
+	fn parse(raw: &str) -> Result<Signature> {
+		let value = raw.split_once(':').map_or("", |(_, value)| value);
+		let tags = tags(value);
+		let tag = |name: &str| tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str());
+		let need = |name: &str| tag(name).ok_or_else(|| anyhow!("signature has no \"{}\" tag", name));
+		if need("v")? != "1" {
+			bail!("unknown signature version");
+		}
+		let (header_canon, body_canon) = match tag("c").unwrap_or("simple/simple").split_once('/') {
+			Some((header, body)) => (canon(header)?, canon(body)?),
+			None => (canon(tag("c").unwrap_or("simple"))?, Canon::Simple),
+		};
+		Ok(Signature {
+			raw: raw.to_owned(),
+			algorithm: need("a")?.to_lowercase(),
+			signature: STANDARD.decode(squeeze(need("b")?)).map_err(|_| anyhow!("signature is not base64"))?,
+			body_hash: STANDARD.decode(squeeze(need("bh")?)).map_err(|_| anyhow!("body hash is not base64"))?,
+			header_canon,
+			body_canon,
+			domain: need("d")?.to_lowercase(),
+			headers: need("h")?.split(':').map(|name| name.trim().to_lowercase()).collect(),
+			length: tag("l").map(|length| length.parse()).transpose().map_err(|_| anyhow!("bad body length"))?,
+			selector: need("s")?.to_owned(),
+			expires: tag("x").and_then(|stamp| stamp.parse().ok()),
+		})
+	}
+
+	/// Signature header with "b" tag emptied, that's how it was hashed
+	fn unsigned(&self) -> String {
+		let (name, value) = self.raw.split_once(':').unwrap_or((&self.raw, ""));
+		let value: Vec<String> = value.split(';').map(|tag| match tag.split_once('=') {
+			Some((name, _)) if name.trim() == "b" => format!("{}=", name),
+			_ => tag.to_owned(),
+		}).collect();
+		format!("{}:{}", name, value.join(";"))
+	}
+}
+
+fn canon(name: &str) -> Result<Canon> {
+	match name.trim() {
+		"simple" => Ok(Canon::Simple),
+		"relaxed" => Ok(Canon::Relaxed),
+		_ => bail!("unknown canonicalization \"{}\"", name),
+	}
+}
+
+/// Turn whitespace runs into single spaces
+fn compact(text: &str) -> String {
+	let mut compacted = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			' ' | '\t' if compacted.ends_with(' ') => {},
+			' ' | '\t' => compacted.push(' '),
+			c => compacted.push(c),
+		};
+	}
+	compacted
+}
+
+/// Header canonicalized, without line end
+fn canon_header(raw: &str, canon: Canon) -> String {
+	match canon {
+		Canon::Simple => raw.to_owned(),
+		Canon::Relaxed => {
+			let (name, value) = raw.split_once(':').unwrap_or((raw, ""));
+			let value = value.replace("\r\n", "").replace('\n', "");
+			format!("{}:{}", name.trim().to_lowercase(), compact(&value).trim())
+		},
+	}
+}
+
+/// Body canonicalized, trailing empty lines dropped
+fn canon_body(body: &[u8], canon: Canon) -> Vec<u8> {
+	let text = String::from_utf8_lossy(body);
+	let mut lines: Vec<String> = text.split('\n')
+		.map(|line| line.strip_suffix('\r').unwrap_or(line))
+		.map(|line| match canon {
+			Canon::Simple => line.to_owned(),
+			Canon::Relaxed => compact(line).trim_end().to_owned(),
+		})
+		.collect();
+	while lines.last().is_some_and(|line| line.is_empty()) {
+		lines.pop();
+	}
+	if lines.is_empty() {
+		return match canon {
+			Canon::Simple => b"\r\n".to_vec(),
+			Canon::Relaxed => vec![],
+		};
+	}
+	lines.into_iter().flat_map(|line| line.into_bytes().into_iter().chain(*b"\r\n")).collect()
+}
+
+/// Split message into unfolded raw headers and body
+fn split(data: &[u8]) -> (Vec<String>, &[u8]) {
+	let end = data.windows(4).position(|window| window == b"\r\n\r\n").map(|at| (at + 2, at + 4))
+		.or_else(|| data.windows(2).position(|window| window == b"\n\n").map(|at| (at + 1, at + 2)));
+	let (head, body) = match end {
+		Some((head, body)) => (&data[..head], &data[body..]),
+		None => (data, &data[data.len()..]),
+	};
+	let mut headers: Vec<String> = vec![];
+	for line in String::from_utf8_lossy(head).split_inclusive('\n') {
+		match headers.last_mut() {
+			Some(last) if line.starts_with([' ', '\t']) => last.push_str(line),
+			_ => headers.push(line.to_owned()),
+		}
+	}
+	// simple canonicalization hashes headers without final line end
+	let headers = headers.into_iter()
+		.map(|header| header.strip_suffix('\n').map(|header| header.strip_suffix('\r').unwrap_or(header)).unwrap_or(&header).to_owned())
+		.collect();
+	(headers, body)
+}
+
+/// Header name, lowercased
+fn name(header: &str) -> String {
+	header.split_once(':').map_or("", |(name, _)| name).trim().to_lowercase()
+}
+
+/// Public key out of SubjectPublicKeyInfo, keys can also be bare PKCS#1
+fn rsa_key(key: &[u8]) -> &[u8] {
+	let spki = x509::element(key)
+		.and_then(|(_, info, _)| x509::element(info))
+		.and_then(|(_, _, rest)| x509::element(rest))
+		.filter(|(tag, _, _)| *tag == 0x03)
+		.and_then(|(_, bits, _)| bits.split_first())
+		.map(|(_, key)| key);
+	spki.unwrap_or(key)
+}
+
+/// `Dkim` holds resolver to look keys up with
+#[derive(Clone, Debug)]
+pub struct Dkim {
+	resolver: Resolver,
+}
+
+impl Dkim {
+	/// Read `[dkim]` table, it's optional
+	pub fn new(settings: &config::Config) -> Result<Option<Dkim>> {
+		if let Err(config::ConfigError::NotFound(_)) = settings.get_table("dkim") {
+			return Ok(None);
+		}
+		Ok(Some(Dkim {
+			resolver: Resolver::new(settings, "dkim.resolver")?,
+		}))
+	}
+
+	/// Check all signatures of message, one that verifies is enough
+	pub async fn verify(&self, data: &[u8]) -> Verdict {
+		let (headers, body) = split(data);
+		let mut failure = None;
+		for raw in headers.iter().filter(|header| name(header) == "dkim-signature") {
+			let result = match Signature::parse(raw) {
+				Ok(signature) => self.check(&signature, &headers, body).await.map(|_| signature.domain),
+				Err(err) => Err(err),
+			};
+			match result {
+				Ok(domain) => return Verdict::Pass(domain),
+				Err(err) => {
+					debug!("DKIM signature failed: {}", err);
+					failure.get_or_insert_with(|| err.to_string());
+				},
+			};
+		}
+		failure.map_or(Verdict::None, Verdict::Fail)
+	}
+
+	/// Verify single signature
+	async fn check(&self, signature: &Signature, headers: &[String], body: &[u8]) -> Result<()> {
+		let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+		if signature.expires.is_some_and(|expires| expires < now) {
+			bail!("signature of {} expired", signature.domain);
+		}
+		if !signature.headers.iter().any(|header| header == "from") {
+			bail!("signature of {} doesn't cover From", signature.domain);
+		}
+		let mut body = canon_body(body, signature.body_canon);
+		if let Some(length) = signature.length {
+			body.truncate(length);
+		}
+		if digest::digest(&digest::SHA256, &body).as_ref() != signature.body_hash {
+			bail!("body hash of {} doesn't match", signature.domain);
+		}
+
+		// every listed header is taken from the bottom, once
+		let mut used = vec![false; headers.len()];
+		let mut hashed = String::new();
+		for wanted in &signature.headers {
+			let found = (0..headers.len()).rev().find(|at| !used[*at] && name(&headers[*at]) == *wanted);
+			if let Some(at) = found {
+				used[at] = true;
+				hashed.push_str(&canon_header(&headers[at], signature.header_canon));
+				hashed.push_str("\r\n");
+			}
+		}
+		hashed.push_str(&canon_header(&signature.unsigned(), signature.header_canon));
+
+		let record = self.key(signature).await?;
+		let tags = tags(&record);
+		let tag = |name: &str| tags.iter().find(|(tag, _)| tag == name).map(|(_, value)| value.as_str());
+		let key = STANDARD.decode(squeeze(tag("p").unwrap_or("")))
+			.map_err(|_| anyhow!("key of {} is not base64", signature.domain))?;
+		if key.is_empty() {
+			bail!("key of {} is revoked", signature.domain);
+		}
+		let verified = match (signature.algorithm.as_str(), tag("k").unwrap_or("rsa")) {
+			("rsa-sha256", "rsa") => signature::UnparsedPublicKey::new(&signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY, rsa_key(&key))
+				.verify(hashed.as_bytes(), &signature.signature),
+			("ed25519-sha256", "ed25519") => signature::UnparsedPublicKey::new(&signature::ED25519, &key)
+				.verify(digest::digest(&digest::SHA256, hashed.as_bytes()).as_ref(), &signature.signature),
+			(algorithm, kind) => bail!("can't verify {} with {} key of {}", algorithm, kind, signature.domain),
+		};
+		verified.map_err(|_| anyhow!("signature of {} doesn't match", signature.domain))
+	}
+
+	/// Key record of signature
+	async fn key(&self, signature: &Signature) -> Result<String> {
+		let name = format!("{}._domainkey.{}", signature.selector, signature.domain);
+		self.resolver.txt(&name).await?
+			.into_iter().find(|record| record.contains("p="))
+			.ok_or_else(|| anyhow!("there's no key {}", name))
+	}
+}
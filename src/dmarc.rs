@@ -0,0 +1,407 @@
+//! DMARC: verdict from SPF and DKIM results that upstream MTA left in
+//! `Authentication-Results`, and summaries of aggregate reports.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use flate2::read::{
+	DeflateDecoder,
+	GzDecoder,
+};
+use mail_parser::{
+	Message,
+	MimeHeaders,
+};
+use serde::Deserialize;
+use teloxide::{
+	types::ChatId,
+	utils::markdown,
+};
+
+use std::{
+	fmt,
+	io::Read,
+};
+
+use crate::stats;
+
+/// Largest unpacked report we read
+const REPORT_LIMIT: u64 = 10 * 1024 * 1024;
+/// How many failing sources are listed in summary
+const SOURCES_LIMIT: usize = 20;
+
+/// `Verdict` is DMARC evaluation result
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+	Pass,
+	Fail,
+	/// No SPF or DKIM results to judge by
+	None,
+}
+
+/// `Check` is one SPF or DKIM result
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+	/// "spf" or "dkim"
+	pub method: String,
+	pub result: String,
+	/// Domain that was checked
+	pub domain: Option<String>,
+	/// Domain is aligned with From header
+	pub aligned: bool,
+}
+
+/// `Evaluation` is verdict with results it's based on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+	pub verdict: Verdict,
+	pub checks: Vec<Check>,
+}
+
+impl fmt::Display for Evaluation {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let verdict = match self.verdict {
+			Verdict::Pass => "pass",
+			Verdict::Fail => "fail",
+			Verdict::None => "none",
+		};
+		let checks: Vec<String> = self.checks.iter().map(|check| {
+			let mut text = format!("{}={}", check.method, check.result);
+			if let Some(domain) = &check.domain {
+				text.push_str(&format!(" {}", domain));
+			}
+			if !check.aligned {
+				text.push_str(" (not aligned)");
+			}
+			text
+		}).collect();
+		match checks.is_empty() {
+			true => write!(f, "DMARC {}", verdict),
+			false => write!(f, "DMARC {}: {}", verdict, checks.join(", ")),
+		}
+	}
+}
+
+/// Domain part of address, lowercased
+fn domain(addr: &str) -> String {
+	addr.rsplit('@').next().unwrap_or(addr).trim_end_matches('.').to_lowercase()
+}
+
+/// Organizational domain, approximated without public suffix list: two last
+/// labels, or three when it's like "example.co.uk"
+fn organizational(domain: &str) -> &str {
+	let labels: Vec<&str> = domain.rsplit('.').collect();
+	let keep = match labels.as_slice() {
+		[tld, second, _, ..] if tld.len() == 2 && second.len() <= 3 => 3,
+		_ => 2,
+	};
+	match domain.rmatch_indices('.').nth(keep - 1) {
+		Some((index, _)) => &domain[index + 1..],
+		None => domain,
+	}
+}
+
+/// Drop RFC 5322 comments, they can contain anything
+fn uncomment(text: &str) -> String {
+	let mut depth = 0;
+	text.chars().filter(|c| {
+		match c {
+			'(' => depth += 1,
+			')' if depth > 0 => { depth -= 1; return false; },
+			_ => {},
+		};
+		depth == 0
+	}).collect()
+}
+
+/// Parse `Authentication-Results` header value, returns authserv-id and
+/// checks as (method, result, domain)
+fn parse(value: &str) -> (String, Vec<(String, String, Option<String>)>) {
+	let value = uncomment(value);
+	let mut statements = value.split(';');
+	let server = statements.next().unwrap_or("")
+		.split_whitespace().next().unwrap_or("").to_lowercase();
+	let mut checks = vec![];
+	for statement in statements {
+		let mut tokens = statement.split_whitespace();
+		let (method, result) = match tokens.next().and_then(|token| token.split_once('=')) {
+			Some((method, result)) => (method.to_lowercase(), result.to_lowercase()),
+			None => continue,
+		};
+		let mut found = None;
+		for (key, value) in tokens.filter_map(|token| token.split_once('=')) {
+			let value = value.trim_matches('"');
+			match (method.as_str(), key.to_lowercase().as_str()) {
+				("spf", "smtp.mailfrom") | ("dkim", "header.d") => found = Some(domain(value)),
+				("spf", "smtp.helo") | ("dkim", "header.i") if found.is_none() => found = Some(domain(value)),
+				_ => {},
+			};
+		}
+		if method == "spf" || method == "dkim" {
+			checks.push((method, result, found));
+		}
+	}
+	(server, checks)
+}
+
+/// `Dmarc` holds settings
+#[derive(Clone, Debug)]
+pub struct Dmarc {
+	/// Only trust results from this server
+	authserv_id: Option<String>,
+	/// Show verdict in Telegram message
+	pub footer: bool,
+	/// Chat for aggregate report summaries
+	pub reports: Option<ChatId>,
+}
+
+impl Dmarc {
+	/// Read `[dmarc]` table, it's optional
+	pub fn new(settings: &config::Config) -> Result<Option<Dmarc>> {
+		if let Err(config::ConfigError::NotFound(_)) = settings.get_table("dmarc") {
+			return Ok(None);
+		}
+		let authserv_id = match settings.get_string("dmarc.authserv_id") {
+			Ok(id) => Some(id.to_lowercase()),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => bail!("[smtp2tg.toml] can't get \"dmarc.authserv_id\":\n {}", err),
+		};
+		let footer = match settings.get_bool("dmarc.footer") {
+			Ok(footer) => footer,
+			Err(config::ConfigError::NotFound(_)) => true,
+			Err(err) => bail!("[smtp2tg.toml] can't get \"dmarc.footer\":\n {}", err),
+		};
+		let reports = match settings.get_int("dmarc.reports") {
+			Ok(chat) => Some(ChatId(chat)),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => bail!("[smtp2tg.toml] can't get \"dmarc.reports\":\n {}", err),
+		};
+		Ok(Some(Dmarc {
+			authserv_id,
+			footer,
+			reports,
+		}))
+	}
+
+	/// Evaluate DMARC for mail: it passes when SPF or DKIM passes for a
+	/// domain aligned with From header
+	pub fn evaluate(&self, mail: &Message) -> Evaluation {
+		let from = mail.from().and_then(|from| from.first())
+			.and_then(|addr| addr.address()).map(domain);
+		let mut checks = vec![];
+		for value in mail.header_values("Authentication-Results").filter_map(|value| value.as_text()) {
+			let (server, found) = parse(value);
+			if self.authserv_id.as_ref().is_some_and(|id| *id != server) {
+				continue;
+			}
+			for (method, result, domain) in found {
+				let aligned = match (&from, &domain) {
+					(Some(from), Some(domain)) => organizational(from) == organizational(domain),
+					_ => false,
+				};
+				checks.push(Check { method, result, domain, aligned });
+			}
+		}
+		let verdict = match checks.is_empty() {
+			true => Verdict::None,
+			false if checks.iter().any(|check| check.aligned && check.result == "pass") => Verdict::Pass,
+			false => Verdict::Fail,
+		};
+		Evaluation { verdict, checks }
+	}
+
+	/// Footer line for Telegram message, nothing when there's nothing to say
+	pub fn footer(&self, mail: &Message) -> Option<String> {
+		let evaluation = self.evaluate(mail);
+		let icon = match evaluation.verdict {
+			Verdict::Pass => "🛡",
+			Verdict::Fail => "⚠️",
+			Verdict::None => return None,
+		};
+		Some(markdown::escape(&format!("{} {}", icon, evaluation)))
+	}
+
+	/// Summaries of all aggregate reports attached to mail, reports are
+	/// recognized by RFC 7489 subject
+	pub fn summaries(&self, mail: &Message) -> Vec<Result<String>> {
+		if !mail.subject().is_some_and(|subject| subject.to_lowercase().contains("report domain:")) {
+			return vec![];
+		}
+		mail.attachments().filter_map(|part| {
+			let name = part.attachment_name().unwrap_or("").to_lowercase();
+			let ctype = part.content_type().map(|ctype| format!("{}/{}",
+				ctype.ctype(), ctype.subtype().unwrap_or(""))).unwrap_or_default().to_lowercase();
+			let report = matches!(ctype.as_str(), "application/zip" | "application/x-zip-compressed"
+				| "application/gzip" | "application/x-gzip" | "application/xml" | "text/xml")
+				|| name.ends_with(".zip") || name.ends_with(".gz") || name.ends_with(".xml");
+			report.then(|| unpack(part.contents()).and_then(|xml| summary(&xml)))
+		}).collect()
+	}
+}
+
+/// Get report XML out of zip or gzip archive
+fn unpack(data: &[u8]) -> Result<Vec<u8>> {
+	let mut xml = vec![];
+	match data {
+		[0x1f, 0x8b, ..] => { GzDecoder::new(data).take(REPORT_LIMIT).read_to_end(&mut xml)?; },
+		[b'P', b'K', 3, 4, ..] => {
+			let (method, compressed) = first_entry(data)?;
+			match method {
+				0 => xml.extend_from_slice(compressed),
+				8 => { DeflateDecoder::new(compressed).take(REPORT_LIMIT).read_to_end(&mut xml)?; },
+				_ => bail!("Unsupported zip compression method {}", method),
+			};
+		},
+		_ => xml.extend_from_slice(data),
+	};
+	Ok(xml)
+}
+
+/// Compression method and data of first file in zip archive, sizes are
+/// taken from central directory as local headers may not have them
+fn first_entry(data: &[u8]) -> Result<(u16, &[u8])> {
+	let u16_at = |at: usize| data.get(at..at + 2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+	let u32_at = |at: usize| data.get(at..at + 4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+	let end = data.windows(4).rposition(|window| window == b"PK\x05\x06")
+		.ok_or(anyhow!("No zip central directory"))?;
+	let central = u32_at(end + 16).ok_or(anyhow!("Truncated zip"))?;
+	if data.get(central..central + 4) != Some(b"PK\x01\x02") {
+		bail!("Bad zip central directory");
+	}
+	let (method, size, local) = match (u16_at(central + 10), u32_at(central + 20), u32_at(central + 42)) {
+		(Some(method), Some(size), Some(local)) => (method, size, local),
+		_ => bail!("Truncated zip"),
+	};
+	let start = match (u16_at(local + 26), u16_at(local + 28)) {
+		(Some(name), Some(extra)) => local + 30 + name as usize + extra as usize,
+		_ => bail!("Truncated zip"),
+	};
+	let compressed = data.get(start..start + size).ok_or(anyhow!("Truncated zip"))?;
+	Ok((method, compressed))
+}
+
+/// `Feedback` is the part of aggregate report we show
+#[derive(Debug, Deserialize)]
+struct Feedback {
+	report_metadata: Metadata,
+	policy_published: Policy,
+	#[serde(default)]
+	record: Vec<Record>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata {
+	org_name: String,
+	date_range: DateRange,
+}
+
+#[derive(Debug, Deserialize)]
+struct DateRange {
+	begin: u64,
+	end: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Policy {
+	domain: String,
+	p: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+	row: Row,
+}
+
+#[derive(Debug, Deserialize)]
+struct Row {
+	source_ip: String,
+	count: u64,
+	policy_evaluated: Evaluated,
+}
+
+#[derive(Debug, Deserialize)]
+struct Evaluated {
+	disposition: String,
+	dkim: Option<String>,
+	spf: Option<String>,
+}
+
+impl Evaluated {
+	/// DMARC passes when either aligned result passes
+	fn passed(&self) -> bool {
+		self.dkim.as_deref() == Some("pass") || self.spf.as_deref() == Some("pass")
+	}
+}
+
+/// Unix time as UTC date
+fn date(stamp: u64) -> String {
+	let (year, month, day) = stats::civil(stamp);
+	format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Short text about aggregate report, ready to send
+fn summary(xml: &[u8]) -> Result<String> {
+	let feedback: Feedback = quick_xml::de::from_str(std::str::from_utf8(xml)?)
+		.map_err(|err| anyhow!("Can't parse DMARC report: {}", err))?;
+	let total: u64 = feedback.record.iter().map(|record| record.row.count).sum();
+	let mut failing: Vec<&Row> = feedback.record.iter()
+		.map(|record| &record.row)
+		.filter(|row| !row.policy_evaluated.passed())
+		.collect();
+	failing.sort_by_key(|row| std::cmp::Reverse(row.count));
+	let failed: u64 = failing.iter().map(|row| row.count).sum();
+	let mut lines = vec![
+		format!("DMARC report from {} for {} (p={})", feedback.report_metadata.org_name,
+			feedback.policy_published.domain, feedback.policy_published.p),
+		format!("{} to {}", date(feedback.report_metadata.date_range.begin), date(feedback.report_metadata.date_range.end)),
+		format!("{} messages: {} passed, {} failed", total, total - failed, failed),
+	];
+	for row in failing.iter().take(SOURCES_LIMIT) {
+		let evaluated = &row.policy_evaluated;
+		lines.push(format!("{}: {} ({}, dkim {}, spf {})", row.source_ip, row.count, evaluated.disposition,
+			evaluated.dkim.as_deref().unwrap_or("none"), evaluated.spf.as_deref().unwrap_or("none")));
+	}
+	if failing.len() > SOURCES_LIMIT {
+		lines.push(format!("…and {} more failing sources", failing.len() - SOURCES_LIMIT));
+	}
+	Ok(markdown::escape(&lines.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn organizational_domains() {
+		for (domain, expected) in [
+			("example.com", "example.com"),
+			("mail.example.com", "example.com"),
+			("a.b.example.co.uk", "example.co.uk"),
+			("example.co.uk", "example.co.uk"),
+			("localhost", "localhost"),
+		] {
+			assert_eq!(organizational(domain), expected, "{}", domain);
+		}
+	}
+
+	#[test]
+	fn comments_are_dropped() {
+		assert_eq!(uncomment("mx.host (nested (comment; spf=fail)); spf=pass"), "mx.host ; spf=pass");
+		assert_eq!(uncomment("a) b"), "a) b");
+	}
+
+	#[test]
+	fn results_are_parsed() {
+		let (server, checks) = parse("MX.Host; spf=pass (sender ok) smtp.mailfrom=user@Mail.Example.com; \
+			dkim=fail header.i=@other.org header.d=example.com; dmarc=pass header.from=example.com; \
+			spf=none smtp.helo=helo.example.net");
+		assert_eq!(server, "mx.host");
+		assert_eq!(checks, vec![
+			("spf".to_string(), "pass".to_string(), Some("mail.example.com".to_string())),
+			("dkim".to_string(), "fail".to_string(), Some("example.com".to_string())),
+			("spf".to_string(), "none".to_string(), Some("helo.example.net".to_string())),
+		]);
+	}
+}
@@ -0,0 +1,237 @@
+//! Just enough of DNS client for DKIM keys and SPF records: plain queries to
+//! resolver from settings or `/etc/resolv.conf`, over TCP when answer doesn't
+//! fit UDP.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use async_std::{
+	io,
+	net::{
+		TcpStream,
+		UdpSocket,
+	},
+	prelude::*,
+};
+
+use std::{
+	fs,
+	net::{
+		IpAddr,
+		Ipv4Addr,
+		Ipv6Addr,
+		SocketAddr,
+	},
+	time::{
+		Duration,
+		SystemTime,
+	},
+};
+
+/// How long resolver has to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const A: u16 = 1;
+const MX: u16 = 15;
+const TXT: u16 = 16;
+const AAAA: u16 = 28;
+
+/// `Resolver` is recursive DNS server we ask
+#[derive(Clone, Debug)]
+pub struct Resolver {
+	addr: SocketAddr,
+}
+
+impl Resolver {
+	/// Read resolver address from setting `name`, first nameserver from
+	/// `/etc/resolv.conf` is used without it
+	pub fn new(settings: &config::Config, name: &str) -> Result<Resolver> {
+		let addr = match settings.get_string(name) {
+			Ok(addr) => addr,
+			Err(config::ConfigError::NotFound(_)) => fs::read_to_string("/etc/resolv.conf").ok()
+				.and_then(|conf| conf.lines()
+					.find_map(|line| line.trim().strip_prefix("nameserver").map(|addr| addr.trim().to_owned())))
+				.unwrap_or_else(|| "127.0.0.1".into()),
+			Err(err) => bail!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err),
+		};
+		let addr = addr.parse::<SocketAddr>()
+			.or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+			.map_err(|_| anyhow!("[smtp2tg.toml] \"{}\" should be an address like \"192.0.2.53\" or \"[2001:db8::53]:53\"", name))?;
+		Ok(Resolver {
+			addr,
+		})
+	}
+
+	/// Records of kind for name, nothing when name doesn't exist
+	async fn lookup(&self, name: &str, kind: u16) -> Result<Answer> {
+		let message = io::timeout(TIMEOUT, self.query(name, kind)).await
+			.map_err(|err| anyhow!("can't look {} up: {}", name, err))?;
+		Answer::parse(message).ok_or_else(|| anyhow!("can't look {} up: bad DNS answer", name))
+	}
+
+	/// TXT records, strings of each record joined
+	pub async fn txt(&self, name: &str) -> Result<Vec<String>> {
+		let answer = self.lookup(name, TXT).await?;
+		Ok(answer.records(TXT).map(|data| {
+			let mut record = vec![];
+			let mut rest = data;
+			while let Some((&len, tail)) = rest.split_first() {
+				let (chunk, tail) = tail.split_at(tail.len().min(len as usize));
+				record.extend_from_slice(chunk);
+				rest = tail;
+			}
+			String::from_utf8_lossy(&record).into_owned()
+		}).collect())
+	}
+
+	/// IPv4 and IPv6 addresses
+	pub async fn ips(&self, name: &str) -> Result<Vec<IpAddr>> {
+		let mut ips: Vec<IpAddr> = self.lookup(name, A).await?.records(A)
+			.filter_map(|data| <[u8; 4]>::try_from(data).ok())
+			.map(|octets| Ipv4Addr::from(octets).into())
+			.collect();
+		ips.extend(self.lookup(name, AAAA).await?.records(AAAA)
+			.filter_map(|data| <[u8; 16]>::try_from(data).ok())
+			.map(|octets| IpAddr::from(Ipv6Addr::from(octets))));
+		Ok(ips)
+	}
+
+	/// Mail exchangers, in no particular order
+	pub async fn mx(&self, name: &str) -> Result<Vec<String>> {
+		let answer = self.lookup(name, MX).await?;
+		Ok(answer.offsets(MX)
+			.filter_map(|at| answer.name(at + 2))
+			.collect())
+	}
+
+	/// Ask resolver, over TCP when answer is truncated
+	async fn query(&self, name: &str, kind: u16) -> io::Result<Vec<u8>> {
+		let id = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |stamp| stamp.subsec_nanos()) as u16;
+		let mut query = vec![];
+		query.extend_from_slice(&id.to_be_bytes());
+		// recursion desired, one question
+		query.extend_from_slice(&[1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+		for label in name.trim_end_matches('.').split('.') {
+			if label.is_empty() || label.len() > 63 {
+				return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad domain name"));
+			}
+			query.push(label.len() as u8);
+			query.extend_from_slice(label.as_bytes());
+		}
+		query.push(0);
+		query.extend_from_slice(&kind.to_be_bytes());
+		// IN
+		query.extend_from_slice(&[0, 1]);
+
+		let local: SocketAddr = match self.addr {
+			SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+			SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+		};
+		let socket = UdpSocket::bind(local).await?;
+		socket.send_to(&query, self.addr).await?;
+		let mut answer = vec![0; 4096];
+		loop {
+			let (size, from) = socket.recv_from(&mut answer).await?;
+			if from == self.addr && size >= 12 && answer[..2] == id.to_be_bytes() {
+				answer.truncate(size);
+				break;
+			}
+		}
+		// truncated
+		if answer[2] & 2 == 0 {
+			return Ok(answer);
+		}
+		let mut stream = TcpStream::connect(self.addr).await?;
+		stream.write_all(&(query.len() as u16).to_be_bytes()).await?;
+		stream.write_all(&query).await?;
+		let mut size = [0; 2];
+		stream.read_exact(&mut size).await?;
+		let mut answer = vec![0; u16::from_be_bytes(size) as usize];
+		stream.read_exact(&mut answer).await?;
+		Ok(answer)
+	}
+}
+
+/// `Answer` is DNS response with records located
+struct Answer {
+	message: Vec<u8>,
+	/// Kind and data position of every answer record
+	records: Vec<(u16, usize, usize)>,
+}
+
+impl Answer {
+	fn parse(message: Vec<u8>) -> Option<Answer> {
+		let word = |at: usize| message.get(at..at + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize);
+		// rcode, nonexistent domain just has no records
+		match message.get(3)? & 0x0f {
+			0 => {},
+			3 => return Some(Answer { message, records: vec![] }),
+			_ => return None,
+		};
+		let questions = word(4)?;
+		let answers = word(6)?;
+		let mut at = 12;
+		for _ in 0..questions {
+			at = skip_name(&message, at)? + 4;
+		}
+		let mut records = vec![];
+		for _ in 0..answers {
+			at = skip_name(&message, at)?;
+			let kind = word(at)? as u16;
+			let size = word(at + 8)?;
+			message.get(at + 10..at + 10 + size)?;
+			records.push((kind, at + 10, size));
+			at += 10 + size;
+		}
+		Some(Answer {
+			message,
+			records,
+		})
+	}
+
+	/// Data of records of kind
+	fn records(&self, kind: u16) -> impl Iterator<Item = &[u8]> {
+		self.records.iter()
+			.filter(move |(found, _, _)| *found == kind)
+			.map(|(_, at, size)| &self.message[*at..*at + *size])
+	}
+
+	/// Where data of records of kind starts, names in it can point anywhere
+	fn offsets(&self, kind: u16) -> impl Iterator<Item = usize> + '_ {
+		self.records.iter()
+			.filter(move |(found, _, _)| *found == kind)
+			.map(|(_, at, _)| *at)
+	}
+
+	/// Read possibly compressed name
+	fn name(&self, mut at: usize) -> Option<String> {
+		let mut labels = vec![];
+		// pointers only go back, but loops are possible in broken answers
+		for _ in 0..128 {
+			let len = *self.message.get(at)? as usize;
+			match len {
+				0 => return Some(labels.join(".")),
+				0xc0.. => at = (len & 0x3f) << 8 | *self.message.get(at + 1)? as usize,
+				_ => {
+					labels.push(String::from_utf8_lossy(self.message.get(at + 1..at + 1 + len)?).into_owned());
+					at += 1 + len;
+				},
+			};
+		}
+		None
+	}
+}
+
+/// Skip domain name in DNS message, returns position after it
+fn skip_name(message: &[u8], mut at: usize) -> Option<usize> {
+	loop {
+		let len = *message.get(at)?;
+		match len {
+			0 => return Some(at + 1),
+			0xc0.. => return Some(at + 2),
+			_ => at += 1 + len as usize,
+		};
+	}
+}
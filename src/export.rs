@@ -0,0 +1,90 @@
+//! Delivery log in JSON lines, one record per message, for feeding gateway
+//! activity into external analytics.
+
+use anyhow::Result;
+use serde::Serialize;
+
+use std::{
+	fs::OpenOptions,
+	io::Write,
+	path::PathBuf,
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::SystemTime,
+};
+
+/// `Status` is how message handling ended
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+	#[default]
+	Delivered,
+	/// Temporary failure, sender should retry
+	Failed,
+	/// Mail can't be parsed
+	Rejected,
+	/// Mail was past delivery deadline
+	Expired,
+	/// Mail matched suppression rule
+	Suppressed,
+}
+
+/// `Record` is one line of export
+#[derive(Debug, Default, Serialize)]
+pub struct Record<'a> {
+	/// Unix time
+	pub timestamp: u64,
+	/// Delivery id, see `journal::delivery_id`
+	pub id: String,
+	pub from: &'a str,
+	pub to: &'a [String],
+	pub subject: Option<&'a str>,
+	/// Chats that got the message
+	pub chats: Vec<i64>,
+	/// Disabled chats, their copy went to default chat
+	pub rerouted: Vec<i64>,
+	pub status: Status,
+	/// Raw mail size
+	pub size: usize,
+	/// Size of text we sent
+	pub text_size: usize,
+	pub attachment_sizes: Vec<usize>,
+}
+
+/// `Export` appends records to a file, shared between all SMTP sessions
+#[derive(Clone)]
+pub struct Export {
+	path: PathBuf,
+	lock: Arc<Mutex<()>>,
+}
+
+impl Export {
+	/// Read `export_jsonl` path, export is off when it's unset
+	pub fn new(settings: &config::Config) -> Option<Export> {
+		match settings.get_string("export_jsonl") {
+			Ok(path) => Some(Export {
+				path: PathBuf::from(path),
+				lock: Arc::default(),
+			}),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"export_jsonl\":\n {}", err);
+				panic!("bad setting");
+			},
+		}
+	}
+
+	/// Append record, stamping it with current time
+	pub fn write(&self, mut record: Record) -> Result<()> {
+		record.timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+		let mut line = serde_json::to_vec(&record)?;
+		line.push(b'\n');
+		let _guard = self.lock.lock().unwrap();
+		// single write so lines stay whole even when file is shared
+		OpenOptions::new().create(true).append(true).open(&self.path)?
+			.write_all(&line)?;
+		Ok(())
+	}
+}
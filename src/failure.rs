@@ -0,0 +1,62 @@
+//! Telegram API errors sorted by what we should do about them, instead of
+//! retrying every one of them.
+
+use teloxide::{
+	types::ChatId,
+	ApiError,
+	RequestError,
+};
+
+use std::time::Duration;
+
+/// `Failure` is how delivery error should be handled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+	/// Chat can't get messages from us, retrying won't help
+	Drop,
+	/// Temporary problem, sender should retry
+	Retry,
+	/// Text was rejected, it can be sent escaped and split
+	Reformat,
+	/// Telegram refused a file, request fails again as is
+	Refused,
+	/// Group was upgraded to supergroup and got new id
+	Migrate(ChatId),
+	/// Flood control, request can be repeated after a while
+	Wait(Duration),
+}
+
+impl Failure {
+	/// Sort out delivery error, anything unknown is retried
+	pub fn of(err: &anyhow::Error) -> Failure {
+		let err = match err.downcast_ref::<RequestError>() {
+			Some(err) => err,
+			None => return Failure::Retry,
+		};
+		match err {
+			RequestError::MigrateToChatId(chat) => Failure::Migrate(*chat),
+			RequestError::RetryAfter(seconds) => Failure::Wait(seconds.duration()),
+			RequestError::Api(ApiError::BotBlocked
+				| ApiError::BotKicked
+				| ApiError::BotKickedFromSupergroup
+				| ApiError::CantInitiateConversation
+				| ApiError::CantTalkWithBots
+				| ApiError::ChatNotFound
+				| ApiError::GroupDeactivated
+				| ApiError::NotEnoughRightsToPostMessages
+				| ApiError::UserDeactivated
+				| ApiError::UserNotFound) => Failure::Drop,
+			RequestError::Api(ApiError::CantParseEntities(_)
+				| ApiError::CantParseUrl
+				| ApiError::MessageIsTooLong) => Failure::Reformat,
+			RequestError::Api(ApiError::Unknown(text)) if text.contains("caption is too long") => Failure::Reformat,
+			RequestError::Api(ApiError::ImageProcessFailed
+				| ApiError::PhotoAsInputFileRequired
+				| ApiError::RequestEntityTooLarge
+				| ApiError::WrongFileIdOrUrl) => Failure::Refused,
+			RequestError::Api(ApiError::Unknown(text)) if ["failed to send message #", "PHOTO_", "MEDIA_", "file must be non-empty"]
+				.iter().any(|known| text.contains(known)) => Failure::Refused,
+			_ => Failure::Retry,
+		}
+	}
+}
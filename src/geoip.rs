@@ -0,0 +1,167 @@
+//! Connection policy by client origin, looked up in MaxMind or IPinfo MMDB
+//! files.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use mailin::Response;
+use maxminddb::Reader;
+use serde::Deserialize;
+
+use std::{
+	fmt,
+	net::IpAddr,
+	sync::Arc,
+};
+
+use crate::server::Screen;
+
+/// Fields we understand, MaxMind and IPinfo name them differently
+#[derive(Debug, Default, Deserialize)]
+struct Record {
+	/// MaxMind country
+	country: Option<Country>,
+	/// MaxMind ASN
+	autonomous_system_number: Option<u32>,
+	/// IPinfo country
+	country_code: Option<String>,
+	/// IPinfo ASN, like "AS13335"
+	asn: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Country {
+	iso_code: Option<String>,
+}
+
+/// `Origin` is what we know about client address
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Origin {
+	/// ISO country code
+	pub country: Option<String>,
+	pub asn: Option<u32>,
+}
+
+impl fmt::Display for Origin {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut parts = vec![];
+		if let Some(country) = &self.country {
+			parts.push(format!("country {}", country));
+		}
+		if let Some(asn) = self.asn {
+			parts.push(format!("AS{}", asn));
+		}
+		match parts.is_empty() {
+			true => write!(f, "unknown origin"),
+			false => write!(f, "{}", parts.join(", ")),
+		}
+	}
+}
+
+/// `Matcher` is a list of countries and ASNs, like `["CN", "AS4134"]`
+#[derive(Clone, Debug, Default)]
+struct Matcher {
+	countries: Vec<String>,
+	asns: Vec<u32>,
+}
+
+impl Matcher {
+	fn new(settings: &config::Config, name: &str) -> Result<Matcher> {
+		let mut matcher = Matcher::default();
+		let values = match settings.get_array(name) {
+			Ok(values) => values,
+			Err(config::ConfigError::NotFound(_)) => return Ok(matcher),
+			Err(err) => bail!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err),
+		};
+		for value in values {
+			let value = value.into_string()
+				.map_err(|_| anyhow!("[smtp2tg.toml] \"{}\" should list strings", name))?;
+			match value.strip_prefix("AS") {
+				Some(asn) => matcher.asns.push(asn.parse()
+					.map_err(|_| anyhow!("[smtp2tg.toml] \"{}\" has bad ASN \"{}\"", name, value))?),
+				None => matcher.countries.push(value.to_uppercase()),
+			}
+		}
+		Ok(matcher)
+	}
+
+	fn matches(&self, origin: &Origin) -> bool {
+		origin.country.as_ref().is_some_and(|country| self.countries.contains(country))
+			|| origin.asn.is_some_and(|asn| self.asns.contains(&asn))
+	}
+}
+
+/// `GeoIp` holds databases and policy
+#[derive(Clone)]
+pub struct GeoIp {
+	readers: Arc<Vec<Reader<Vec<u8>>>>,
+	reject: Matcher,
+	suspicious: Matcher,
+	/// Show origin of suspicious mail in Telegram message
+	pub footer: bool,
+}
+
+impl GeoIp {
+	/// Read `[geoip]` table, it's optional
+	pub fn new(settings: &config::Config) -> Result<Option<GeoIp>> {
+		let databases = match settings.get_array("geoip.databases") {
+			Ok(databases) => databases,
+			Err(config::ConfigError::NotFound(_)) => return Ok(None),
+			Err(err) => bail!("[smtp2tg.toml] can't get \"geoip.databases\":\n {}", err),
+		};
+		let mut readers = vec![];
+		for path in databases {
+			let path = path.into_string()
+				.map_err(|_| anyhow!("[smtp2tg.toml] \"geoip.databases\" should list paths"))?;
+			readers.push(Reader::open_readfile(&path)
+				.map_err(|err| anyhow!("Can't open {}: {}", path, err))?);
+		}
+		let footer = match settings.get_bool("geoip.footer") {
+			Ok(footer) => footer,
+			Err(config::ConfigError::NotFound(_)) => false,
+			Err(err) => bail!("[smtp2tg.toml] can't get \"geoip.footer\":\n {}", err),
+		};
+		Ok(Some(GeoIp {
+			readers: Arc::new(readers),
+			reject: Matcher::new(settings, "geoip.reject")?,
+			suspicious: Matcher::new(settings, "geoip.suspicious")?,
+			footer,
+		}))
+	}
+
+	/// Look address up in all databases, first answer wins
+	pub fn origin(&self, ip: IpAddr) -> Origin {
+		let mut origin = Origin::default();
+		for reader in self.readers.iter() {
+			let record: Record = match reader.lookup(ip) {
+				Ok(record) => record,
+				Err(_) => continue,
+			};
+			if origin.country.is_none() {
+				origin.country = record.country.and_then(|country| country.iso_code).or(record.country_code);
+			}
+			if origin.asn.is_none() {
+				origin.asn = record.autonomous_system_number
+					.or(record.asn.and_then(|asn| asn.trim_start_matches("AS").parse().ok()));
+			}
+		}
+		origin
+	}
+
+	/// Origin of address if it's suspicious
+	pub fn suspicious(&self, ip: IpAddr) -> Option<Origin> {
+		let origin = self.origin(ip);
+		self.suspicious.matches(&origin).then_some(origin)
+	}
+}
+
+impl Screen for GeoIp {
+	fn screen(&self, remote: IpAddr) -> Option<Response> {
+		let origin = self.origin(remote);
+		info!("Connection from {} ({})", remote, origin);
+		self.reject.matches(&origin)
+			.then(|| Response::custom(554, format!("Connections from {} are not accepted", origin)))
+	}
+}
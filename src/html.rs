@@ -0,0 +1,207 @@
+//! HTML bodies as readable text. Unlike plain tag stripping this keeps
+//! paragraphs, line breaks, list items and link targets, which is most of what
+//! notifications from web services have to say.
+
+use mail_parser::decoders::html::add_html_token;
+
+/// Elements starting on a new line
+const BLOCKS: &[&str] = &[
+	"address", "article", "aside", "blockquote", "center", "dd", "div", "dl", "dt", "footer", "form",
+	"h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "main", "nav", "ol", "p", "pre", "section",
+	"table", "tr", "ul",
+];
+/// Elements with nothing worth showing inside
+const HIDDEN: &[&str] = &["head", "script", "style", "template", "title"];
+
+/// `Tag` is one parsed tag
+struct Tag<'a> {
+	name: String,
+	closing: bool,
+	/// Everything after the name
+	attributes: &'a str,
+}
+
+impl<'a> Tag<'a> {
+	fn new(inner: &'a str) -> Tag<'a> {
+		let (closing, inner) = match inner.strip_prefix('/') {
+			Some(inner) => (true, inner),
+			None => (false, inner),
+		};
+		let end = inner.find(|c: char| c.is_ascii_whitespace() || c == '/').unwrap_or(inner.len());
+		Tag {
+			name: inner[..end].to_ascii_lowercase(),
+			closing,
+			attributes: &inner[end..],
+		}
+	}
+
+	/// Attribute value, quoted or not
+	fn attribute(&self, name: &str) -> Option<String> {
+		let lower = self.attributes.to_ascii_lowercase();
+		let mut from = 0;
+		while let Some(found) = lower[from..].find(name) {
+			let start = from + found;
+			from = start + name.len();
+			let standalone = lower[..start].ends_with(|c: char| c.is_ascii_whitespace());
+			let rest = self.attributes[from..].trim_start();
+			let Some(rest) = rest.strip_prefix('=').filter(|_| standalone) else {
+				continue;
+			};
+			let rest = rest.trim_start();
+			let value = match rest.chars().next() {
+				Some(quote @ ('"' | '\'')) => rest[1..].split(quote).next().unwrap_or_default(),
+				_ => rest.split(|c: char| c.is_ascii_whitespace()).next().unwrap_or_default(),
+			};
+			return Some(decode(value));
+		}
+		None
+	}
+}
+
+/// Replace entities
+fn decode(text: &str) -> String {
+	let mut result = String::with_capacity(text.len());
+	let mut rest = text;
+	while let Some(start) = rest.find('&') {
+		result.push_str(&rest[..start]);
+		rest = &rest[start..];
+		match rest.find(';').filter(|end| *end <= 32 && !rest[1..*end].contains(['&', ' '])) {
+			Some(end) => {
+				add_html_token(&mut result, &rest.as_bytes()[..=end], false);
+				rest = &rest[end + 1..];
+			},
+			None => {
+				result.push('&');
+				rest = &rest[1..];
+			},
+		}
+	}
+	result.push_str(rest);
+	result
+}
+
+/// `Writer` collects text, squeezing whitespace outside of `pre`
+#[derive(Default)]
+struct Writer {
+	text: String,
+	/// Preformatted elements we are in
+	pre: usize,
+	/// Space is pending before next word
+	space: bool,
+}
+
+impl Writer {
+	fn word(&mut self, text: &str) {
+		if self.pre > 0 {
+			self.text.push_str(text);
+			return;
+		}
+		for (index, word) in text.split(|c: char| c.is_ascii_whitespace()).enumerate() {
+			if index > 0 {
+				self.space = true;
+			}
+			if word.is_empty() {
+				continue;
+			}
+			if self.space && !self.text.is_empty() && !self.text.ends_with(['\n', ' ']) {
+				self.text.push(' ');
+			}
+			self.space = false;
+			self.text.push_str(word);
+		}
+	}
+
+	/// Start new line, `blank` leaves empty line in between
+	fn line(&mut self, blank: bool) {
+		while self.text.ends_with(' ') {
+			self.text.pop();
+		}
+		self.space = false;
+		if self.text.is_empty() {
+			return;
+		}
+		let wanted = if blank { "\n\n" } else { "\n" };
+		while !self.text.ends_with(wanted) {
+			self.text.push('\n');
+		}
+	}
+}
+
+/// Render HTML as text
+pub fn to_text(html: &str) -> String {
+	let mut out = Writer::default();
+	// anchors being written, as (href, where text starts)
+	let mut links: Vec<(Option<String>, usize)> = vec![];
+	let mut hidden: usize = 0;
+	let mut rest = html;
+	while !rest.is_empty() {
+		let Some(start) = rest.find('<') else {
+			if hidden == 0 {
+				out.word(&decode(rest));
+			}
+			break;
+		};
+		if hidden == 0 {
+			out.word(&decode(&rest[..start]));
+		}
+		rest = &rest[start..];
+		if let Some(comment) = rest.strip_prefix("<!--") {
+			rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+			continue;
+		}
+		let Some(end) = rest.find('>') else {
+			break;
+		};
+		let tag = Tag::new(&rest[1..end]);
+		rest = &rest[end + 1..];
+		if HIDDEN.contains(&tag.name.as_str()) {
+			hidden = match tag.closing {
+				true => hidden.saturating_sub(1),
+				false => hidden + 1,
+			};
+			continue;
+		}
+		if hidden > 0 {
+			continue;
+		}
+		match (tag.name.as_str(), tag.closing) {
+			("br", _) => {
+				out.line(false);
+			},
+			("li", false) => {
+				out.line(false);
+				out.text.push_str("- ");
+			},
+			("td" | "th", true) => {
+				out.space = true;
+			},
+			("a", false) => links.push((tag.attribute("href"), out.text.len())),
+			("a", true) => if let Some((Some(href), start)) = links.pop() {
+				let label = out.text.get(start..).unwrap_or_default().trim();
+				let target = href.strip_prefix("mailto:").unwrap_or(&href);
+				if !label.is_empty() && label != target && href.contains(':') && !href.starts_with("javascript:") {
+					out.word(&format!(" ({})", target));
+				}
+			},
+			("img", _) => if let Some(alt) = tag.attribute("alt").filter(|alt| !alt.trim().is_empty()) {
+				out.word(&format!(" [{}] ", alt.trim()));
+			},
+			(name, closing) if BLOCKS.contains(&name) => {
+				out.line(name != "tr" && name != "div");
+				if name == "pre" {
+					out.pre = match closing {
+						true => out.pre.saturating_sub(1),
+						false => out.pre + 1,
+					};
+				}
+				if name == "hr" {
+					out.text.push_str("---");
+					out.line(true);
+				}
+			},
+			_ => {},
+		}
+	}
+	out.line(false);
+	out.text.trim_end().to_owned()
+}
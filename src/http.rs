@@ -0,0 +1,144 @@
+//! Minimal HTTP/1.1 listener, just enough to receive webhooks. Every request
+//! is handled in it's own thread and connection is closed after reply.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+
+use std::{
+	collections::HashMap,
+	io::{
+		BufRead,
+		BufReader,
+		Write,
+	},
+	net::{
+		TcpListener,
+		TcpStream,
+	},
+	sync::Arc,
+	thread,
+	time::Duration,
+};
+
+/// Largest request body we are willing to read
+const BODY_LIMIT: usize = 1024 * 1024;
+
+/// `Request` is a parsed HTTP request
+#[derive(Debug)]
+pub struct Request {
+	pub method: String,
+	pub path: String,
+	/// Header names are lowercase
+	pub headers: HashMap<String, String>,
+	pub body: Vec<u8>,
+}
+
+/// `Reply` is status with text body
+#[derive(Debug)]
+pub struct Reply {
+	pub status: u16,
+	pub body: String,
+}
+
+impl Reply {
+	pub fn new<S>(status: u16, body: S) -> Reply
+	where S: Into<String> {
+		Reply {
+			status,
+			body: body.into(),
+		}
+	}
+}
+
+/// Bind address and serve requests in background
+pub fn serve<F>(addr: &str, handler: F) -> Result<()>
+where F: Fn(Request) -> Reply + Send + Sync + 'static {
+	let socket = TcpListener::bind(addr)
+		.map_err(|err| anyhow!("Can't listen on {}: {}", addr, err))?;
+	let handler = Arc::new(handler);
+	thread::spawn(move || {
+		for conn in socket.incoming() {
+			match conn {
+				Ok(stream) => {
+					let handler = handler.clone();
+					thread::spawn(move || {
+						if let Err(err) = connection(stream, handler.as_ref()) {
+							error!("HTTP request failed: {:?}", err);
+						}
+					});
+				},
+				Err(err) => error!("HTTP connection failed: {}", err),
+			}
+		}
+	});
+	Ok(())
+}
+
+/// Read one request and write reply
+fn connection<F>(stream: TcpStream, handler: &F) -> Result<()>
+where F: Fn(Request) -> Reply {
+	stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+	let mut reader = BufReader::new(stream);
+	let reply = match read_request(&mut reader) {
+		Ok(request) => handler(request),
+		Err(err) => Reply::new(400, format!("{}", err)),
+	};
+	let reason = match reply.status {
+		200 => "OK",
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		503 => "Service Unavailable",
+		_ => "Unknown",
+	};
+	let stream = reader.get_mut();
+	write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		reply.status, reason, reply.body.len(), reply.body)?;
+	stream.flush()?;
+	Ok(())
+}
+
+/// Parse request line, headers and body
+fn read_request<R>(reader: &mut R) -> Result<Request>
+where R: BufRead {
+	let mut line = String::new();
+	reader.read_line(&mut line)?;
+	let mut parts = line.split_whitespace();
+	let (method, path) = match (parts.next(), parts.next()) {
+		(Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+		_ => bail!("Malformed request line"),
+	};
+	let mut headers = HashMap::new();
+	loop {
+		line.clear();
+		if reader.read_line(&mut line)? == 0 {
+			bail!("Unexpected EOF");
+		}
+		let header = line.trim_end();
+		if header.is_empty() {
+			break;
+		}
+		if let Some((name, value)) = header.split_once(':') {
+			headers.insert(name.trim().to_lowercase(), value.trim().to_owned());
+		}
+	}
+	let length = match headers.get("content-length") {
+		Some(length) => length.parse::<usize>()?,
+		None => 0,
+	};
+	if length > BODY_LIMIT {
+		bail!("Request body too large");
+	}
+	let mut body = vec![0; length];
+	reader.read_exact(&mut body)?;
+	Ok(Request {
+		method,
+		path,
+		headers,
+		body,
+	})
+}
@@ -0,0 +1,120 @@
+//! Journal of completed deliveries. When sender retries a message after a
+//! partial failure chats that already got it are skipped. Journal is saved in
+//! state file when it's configured.
+
+use ring::digest;
+use teloxide::{
+	types::{
+		ChatId,
+		MessageId,
+		ThreadId,
+	},
+	RequestError,
+};
+
+use std::{
+	collections::HashMap,
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::{
+		Duration,
+		SystemTime,
+	},
+};
+
+use crate::state::{
+	self,
+	Store,
+};
+
+/// How long we remember deliveries
+const KEEP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `Semantics` sets what to do when we can't know whether Telegram got the message
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Semantics {
+	/// Report failure so message is retried, possibly producing a duplicate
+	AtLeastOnce,
+	/// Consider message delivered, possibly losing it
+	AtMostOnce,
+}
+
+impl Semantics {
+	pub fn new(settings: &config::Config) -> Semantics {
+		match settings.get_string("delivery") {
+			Err(config::ConfigError::NotFound(_)) => Semantics::AtLeastOnce,
+			Ok(value) => match value.as_str() {
+				"at-least-once" => Semantics::AtLeastOnce,
+				"at-most-once" => Semantics::AtMostOnce,
+				_ => {
+					error!("[smtp2tg.toml] \"delivery\" should be either \"at-least-once\" or \"at-most-once\".");
+					panic!("bad setting");
+				},
+			},
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"delivery\":\n {}", err);
+				panic!("bad setting");
+			},
+		}
+	}
+
+	/// Check whether failure leaves us not knowing if message was accepted
+	/// and it should be considered delivered
+	pub fn assume_sent(&self, err: &anyhow::Error) -> bool {
+		*self == Semantics::AtMostOnce && matches!(err.downcast_ref::<RequestError>(),
+			Some(RequestError::Network(_) | RequestError::Io(_)))
+	}
+}
+
+/// Internal delivery id: digest of raw message, same mail retried by sender
+/// gets same id
+pub fn delivery_id(data: &[u8]) -> String {
+	digest::digest(&digest::SHA256, data).as_ref()[..16].iter()
+		.map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Delivery id with chat and topic it went to
+type Key = (String, ChatId, Option<ThreadId>);
+
+/// `Journal` is shared between all SMTP sessions
+#[derive(Clone, Default)]
+pub struct Journal {
+	sent: Arc<Mutex<HashMap<Key, SystemTime>>>,
+	/// Persistent state to keep journal in
+	store: Store,
+}
+
+impl Journal {
+	/// Create journal, restoring it from persistent state
+	pub fn new(store: Store) -> Journal {
+		let sent = store.read(|snapshot| snapshot.sent.iter()
+			.map(|entry| ((entry.id.clone(), ChatId(entry.chat), entry.topic.map(|topic| ThreadId(MessageId(topic)))), SystemTime::UNIX_EPOCH + Duration::from_secs(entry.stamp)))
+			.collect());
+		Journal {
+			sent: Arc::new(Mutex::new(sent)),
+			store,
+		}
+	}
+
+	/// Check whether this message was already delivered to this chat (or
+	/// topic)
+	pub fn contains(&self, id: &str, chat: ChatId, topic: Option<ThreadId>) -> bool {
+		self.sent.lock().unwrap().contains_key(&(id.to_owned(), chat, topic))
+	}
+
+	/// Remember delivery, forgetting outdated ones
+	pub fn record(&self, id: &str, chat: ChatId, topic: Option<ThreadId>) {
+		let now = SystemTime::now();
+		let mut sent = self.sent.lock().unwrap();
+		sent.retain(|_, stamp| now.duration_since(*stamp).map_or(true, |age| age < KEEP));
+		sent.insert((id.to_owned(), chat, topic), now);
+		self.store.update(|snapshot| snapshot.sent = sent.iter().map(|((id, chat, topic), stamp)| state::Sent {
+			id: id.clone(),
+			chat: chat.0,
+			topic: topic.map(|topic| topic.0 .0),
+			stamp: stamp.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |stamp| stamp.as_secs()),
+		}).collect());
+	}
+}
@@ -0,0 +1,115 @@
+//! Leader election between instances sharing a state directory. Every instance
+//! accepts SMTP, but only leader does exclusive work (like polling Telegram
+//! for updates). Leadership is a lease in a lock file: leader refreshes it
+//! periodically, anyone can take it over once it's stale.
+
+use anyhow::Result;
+
+use std::{
+	fs,
+	io::ErrorKind,
+	path::PathBuf,
+	sync::{
+		atomic::{
+			AtomicBool,
+			Ordering,
+		},
+		Arc,
+	},
+	thread,
+	time::{
+		Duration,
+		SystemTime,
+	},
+};
+
+/// How often leader refreshes lease and others check it
+const REFRESH: Duration = Duration::from_secs(5);
+/// Lease not refreshed for this long is up for grabs
+const STALE: Duration = Duration::from_secs(20);
+
+/// `Leader` tells whether this instance currently leads
+#[derive(Clone)]
+pub struct Leader {
+	/// Lock file, without it we are the only instance and always lead
+	file: Option<PathBuf>,
+	/// Our name in lock file
+	me: String,
+	leading: Arc<AtomicBool>,
+}
+
+impl Leader {
+	pub fn new(settings: &config::Config) -> Leader {
+		let file = match settings.get_string("lock_file") {
+			Ok(path) => Some(PathBuf::from(path)),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"lock_file\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let host = fs::read_to_string("/etc/hostname").unwrap_or_default();
+		Leader {
+			leading: Arc::new(AtomicBool::new(file.is_none())),
+			file,
+			me: format!("{}:{}", host.trim(), std::process::id()),
+		}
+	}
+
+	pub fn leading(&self) -> bool {
+		self.leading.load(Ordering::Relaxed)
+	}
+
+	/// Keep lease in background
+	pub fn start(&self) {
+		if self.file.is_none() {
+			return;
+		}
+		let leader = self.clone();
+		thread::spawn(move || loop {
+			let leading = match leader.tick() {
+				Ok(leading) => leading,
+				Err(err) => {
+					error!("Failed to check lock file: {:?}", err);
+					false
+				},
+			};
+			if leading != leader.leading() {
+				warn!("{} leadership", if leading { "Acquired" } else { "Lost" });
+				leader.leading.store(leading, Ordering::Relaxed);
+			}
+			thread::sleep(REFRESH);
+		});
+	}
+
+	/// Read current lease as (owner, age)
+	fn lease(&self, path: &PathBuf) -> Result<Option<(String, Duration)>> {
+		let data = match fs::read_to_string(path) {
+			Ok(data) => data,
+			Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+		let stamp = fs::metadata(path)?.modified()?;
+		let age = SystemTime::now().duration_since(stamp).unwrap_or_default();
+		Ok(Some((data.trim().to_owned(), age)))
+	}
+
+	/// Refresh or take over lease when possible, return whether we lead
+	fn tick(&self) -> Result<bool> {
+		let path = match &self.file {
+			Some(path) => path,
+			None => return Ok(true),
+		};
+		match self.lease(path)? {
+			Some((owner, age)) if owner != self.me && age < STALE => return Ok(false),
+			_ => {},
+		}
+		let mut temp = path.as_os_str().to_owned();
+		temp.push(format!(".{}", std::process::id()));
+		fs::write(&temp, &self.me)?;
+		fs::rename(&temp, path)?;
+		// somebody could have taken it over at the same time, last rename wins
+		thread::sleep(Duration::from_millis(100));
+		Ok(matches!(self.lease(path)?, Some((owner, _)) if owner == self.me))
+	}
+}
@@ -0,0 +1,2446 @@
+//! Simple SMTP-to-Telegram gateway. Can parse email and send them as telegram
+//! messages to specified chats, generally you specify which email address is
+//! available in configuration, everything else is sent to default address.
+//!
+//! The gateway can be embedded into other daemons: read configuration with
+//! [`settings`] or build your own [`config::Config`], then either run whole
+//! gateway with [`serve`] or feed mail to [`TelegramTransport`], which is a
+//! [`mailin::Handler`].
+
+// macros, declared before modules using them
+#[macro_use]
+mod logging;
+
+mod access;
+mod ansi;
+mod arf;
+mod auth;
+mod batch;
+mod bounces;
+mod chaos;
+mod cli;
+mod compose;
+mod describe;
+mod dkim;
+mod dmarc;
+mod dns;
+mod export;
+mod failure;
+mod geoip;
+mod html;
+mod http;
+mod journal;
+mod leader;
+mod order;
+mod policy;
+mod retry;
+mod routing;
+mod schedule;
+mod server;
+mod preview;
+mod signing;
+mod socket;
+mod spf;
+mod spool;
+mod state;
+mod stats;
+mod storage;
+mod tnef;
+mod updates;
+mod uploads;
+mod x509;
+
+pub use config;
+pub use mailin;
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use access::{
+	Access,
+	Net,
+};
+use auth::Credentials;
+use batch::{
+	Batcher,
+	Joined,
+};
+use bounces::Bounces;
+use async_std::{
+	io::Error,
+	task,
+};
+use chaos::Chaos;
+use compose::{
+	Attachment,
+	Field,
+	Format,
+	Kind,
+	Markup,
+	OutgoingMessage,
+};
+use dkim::Dkim;
+use dmarc::Dmarc;
+use export::{
+	Export,
+	Status,
+};
+use failure::Failure;
+use geoip::{
+	GeoIp,
+	Origin,
+};
+use journal::{
+	Journal,
+	Semantics,
+};
+use mailin::{
+	Response,
+	response::*,
+};
+use order::Order;
+use policy::Policy;
+use preview::Previewer;
+use retry::Retry;
+use routing::{
+	Content,
+	Emoji,
+	Link,
+	Priority,
+	Route,
+	Router,
+	Routing,
+	Silent,
+	Trace,
+};
+use schedule::Schedule;
+use signing::Signer;
+use spf::{
+	Action,
+	Spf,
+};
+use spool::{
+	Envelope,
+	Spool,
+};
+use stats::Stats;
+use storage::Storage;
+use teloxide::{
+	ApiError,
+	Bot,
+	RequestError,
+	payloads::{
+		SendPhotoSetters,
+		SendVideoSetters,
+	},
+	prelude::{
+		Requester,
+		RequesterExt,
+	},
+	requests::{
+		HasPayload,
+		Output,
+		Request,
+	},
+	types::{
+		ChatId,
+		InputFile,
+		InputMedia,
+		InputMediaDocument,
+		InputMediaPhoto,
+		Message,
+		MessageId,
+		ParseMode::{
+			self,
+			MarkdownV2,
+		},
+		ReplyParameters,
+	},
+	utils::markdown,
+};
+use tracing::Instrument;
+use uploads::Uploads;
+
+use std::{
+	borrow::Cow,
+	collections::{
+		hash_map::Entry,
+		HashMap,
+		HashSet,
+	},
+	net::IpAddr,
+	panic::{
+		self,
+		AssertUnwindSafe,
+	},
+	path::PathBuf,
+	sync::{
+		atomic::{
+			AtomicBool,
+			Ordering,
+		},
+		Arc,
+	},
+	thread,
+	time::{
+		Duration,
+		Instant,
+		SystemTime,
+	},
+	vec::Vec,
+};
+
+/// Telegram API client with all adaptors we use
+pub type Tg = teloxide::adaptors::DefaultParseMode<teloxide::adaptors::Throttle<Bot>>;
+
+/// Create API client
+fn new_bot(api_key: String) -> Tg {
+	Bot::new(api_key)
+		.throttle(teloxide::adaptors::throttle::Limits::default())
+		.parse_mode(MarkdownV2)
+}
+
+/// Health checks of container mode are answered here
+const HEALTH_ADDR: &str = "0.0.0.0:8081";
+
+/// How long sessions in progress get to finish on SIGTERM, container
+/// runtimes usually kill in 30s
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(25);
+
+/// Largest file bots can upload
+const UPLOAD_LIMIT: usize = 50 << 20;
+
+/// Media group of files, first one gets caption
+fn group(files: &[&Attachment], caption: Option<(&str, ParseMode)>) -> Vec<InputMedia> {
+	let mut caption = caption;
+	files.iter().map(|file| {
+		let input = InputFile::memory(file.data.clone()).file_name(file.name.clone());
+		match file.kind {
+			Kind::Photo => InputMedia::Photo(match caption.take() {
+				Some((text, parse_mode)) => InputMediaPhoto::new(input).caption(text).parse_mode(parse_mode),
+				None => InputMediaPhoto::new(input),
+			}),
+			_ => InputMedia::Document(match caption.take() {
+				Some((text, parse_mode)) => InputMediaDocument::new(input).caption(text).parse_mode(parse_mode),
+				None => InputMediaDocument::new(input),
+			}),
+		}
+	}).collect()
+}
+
+/// Which file of media group Telegram refused, when it can be told
+fn refused_file(err: &anyhow::Error, files: &[&Attachment]) -> Option<usize> {
+	if files.len() == 1 {
+		return Some(0);
+	}
+	match err.downcast_ref::<RequestError>()? {
+		// whole request is too large, largest file goes
+		RequestError::Api(ApiError::RequestEntityTooLarge) => files.iter().enumerate()
+			.max_by_key(|(_, file)| file.data.len())
+			.map(|(at, _)| at),
+		// "failed to send message #2 with the error message ..."
+		RequestError::Api(ApiError::Unknown(text)) => {
+			let (_, rest) = text.split_once("message #")?;
+			let number: usize = rest.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()?;
+			number.checked_sub(1).filter(|at| *at < files.len())
+		},
+		_ => None,
+	}
+}
+
+/// Parse duration like "90s", "15m", "1h" or "2d"
+fn parse_duration(value: &str) -> Option<Duration> {
+	let value = value.trim();
+	let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+	let (number, unit) = value.split_at(split);
+	let number: u64 = number.parse().ok()?;
+	let unit = match unit.trim() {
+		"s" | "" => 1,
+		"m" => 60,
+		"h" => 3600,
+		"d" => 86400,
+		_ => return None,
+	};
+	Some(Duration::from_secs(number * unit))
+}
+
+/// How deep multiparts and attached messages are nested, every part is
+/// visited once so broken structure can't loop
+fn depth(mail: &mail_parser::Message) -> usize {
+	let mut deepest = 0;
+	let mut seen = HashSet::new();
+	let mut stack = vec![(mail, 0, 1)];
+	while let Some((message, id, level)) = stack.pop() {
+		if !seen.insert((message as *const mail_parser::Message, id)) {
+			continue;
+		}
+		match message.parts.get(id).map(|part| &part.body) {
+			Some(mail_parser::PartType::Multipart(children)) => {
+				deepest = deepest.max(level);
+				stack.extend(children.iter().map(|child| (message, *child, level + 1)));
+			},
+			Some(mail_parser::PartType::Message(inner)) => {
+				deepest = deepest.max(level);
+				stack.push((inner, 0, level + 1));
+			},
+			_ => {},
+		};
+	}
+	deepest
+}
+
+/// `SomeHeaders` object to store data through SMTP session
+#[derive(Clone, Debug)]
+struct SomeHeaders {
+	from: String,
+	to: Vec<String>,
+	/// When client started sending data
+	received: SystemTime,
+}
+
+/// Append expandable quote explaining why this chat was selected
+fn with_trace(outgoing: &OutgoingMessage, route: &Route) -> OutgoingMessage {
+	let mut outgoing = outgoing.clone();
+	let mut footer = vec![format!("**>{}", markdown::escape(&format!("Routed to {}:", route.destination())))];
+	footer.extend(route.reasons.iter().map(|reason| format!(">{}", markdown::escape(reason))));
+	if let Some(last) = outgoing.text_chunks.last_mut() {
+		last.push('\n');
+		last.push_str(&footer.join("\n"));
+		last.push_str("||");
+	}
+	outgoing
+}
+
+/// Append time message spent in gateway
+fn with_latency(outgoing: &OutgoingMessage, latency: Duration) -> OutgoingMessage {
+	let mut outgoing = outgoing.clone();
+	if let Some(last) = outgoing.text_chunks.last_mut() {
+		last.push_str(&format!("\n_{}_", markdown::escape(&format!("⏱ {:.1}s in gateway", latency.as_secs_f64()))));
+	}
+	outgoing
+}
+
+/// Append links of route, like runbook or dashboard
+fn with_links(outgoing: &OutgoingMessage, links: &[Link]) -> OutgoingMessage {
+	let mut outgoing = outgoing.clone();
+	let line = links.iter()
+		.map(|link| format!("[{}]({})", markdown::escape(&link.text), markdown::escape_link_url(&link.url)))
+		.collect::<Vec<_>>().join(" \\| ");
+	if let Some(last) = outgoing.text_chunks.last_mut() {
+		last.push_str(&format!("\n🔗 {}", line));
+	} else if let Some(caption) = &mut outgoing.options.caption {
+		caption.push_str(&format!("\n🔗 {}", line));
+	} else {
+		outgoing.text_chunks.push(format!("🔗 {}", line));
+	}
+	outgoing
+}
+
+/// Duration for humans, in it's largest unit, like "3 days"
+fn human(duration: Duration) -> String {
+	let secs = duration.as_secs();
+	let (count, unit) = match secs {
+		86400.. => (secs / 86400, "day"),
+		3600.. => (secs / 3600, "hour"),
+		60.. => (secs / 60, "minute"),
+		_ => (secs, "second"),
+	};
+	format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+/// Append warning about Date header being far from time message was received
+fn with_skew(outgoing: &mut OutgoingMessage, skew: &str) {
+	let warning = format!("\n_{}_", markdown::escape(&format!("⚠ {}", skew)));
+	if let Some(last) = outgoing.text_chunks.last_mut() {
+		last.push_str(&warning);
+	} else if let Some(caption) = &mut outgoing.options.caption {
+		caption.push_str(&warning);
+	}
+}
+
+/// Put DKIM verdict line in front of message
+fn with_verdict(outgoing: &mut OutgoingMessage, verdict: &str) {
+	let line = format!("{}\n", markdown::escape(verdict));
+	if let Some(first) = outgoing.text_chunks.first_mut() {
+		first.insert_str(0, &line);
+	} else if let Some(caption) = &mut outgoing.options.caption {
+		caption.insert_str(0, &line);
+	}
+}
+
+/// Put emoji in front of message, custom one when it has id
+fn with_emoji(outgoing: &OutgoingMessage, emoji: &Emoji) -> OutgoingMessage {
+	let prefix = match &emoji.id {
+		Some(id) => format!("![{}](tg://emoji?id={}) ", markdown::escape(&emoji.text), id),
+		None => format!("{} ", markdown::escape(&emoji.text)),
+	};
+	with_prefix(outgoing, &prefix)
+}
+
+/// Put subaddress tag in front of message, like "[db01]"
+fn with_tag(outgoing: &OutgoingMessage, tag: &str) -> OutgoingMessage {
+	with_prefix(outgoing, &format!("*{}* ", markdown::escape(&format!("[{}]", tag))))
+}
+
+/// Put already escaped text in front of message, or it's caption
+fn with_prefix(outgoing: &OutgoingMessage, prefix: &str) -> OutgoingMessage {
+	let mut outgoing = outgoing.clone();
+	if let Some(first) = outgoing.text_chunks.first_mut() {
+		first.insert_str(0, prefix);
+	} else if let Some(caption) = &mut outgoing.options.caption {
+		caption.insert_str(0, prefix);
+	}
+	outgoing
+}
+
+/// `TelegramTransport` Central object with TG api and configuration
+#[derive(Clone)]
+pub struct TelegramTransport {
+	/// Networks allowed to connect
+	access: Option<Access>,
+	/// Files Telegram can't take are stored here and linked instead
+	attachments_store: Option<Storage>,
+	/// Merges short messages to the same chat
+	batcher: Option<Batcher>,
+	/// Bots for tenants with their own API keys
+	bots: HashMap<String, Tg>,
+	/// Chats that keep refusing messages
+	bounces: Bounces,
+	chaos: Option<Chaos>,
+	/// SMTP AUTH users
+	credentials: Option<Credentials>,
+	data: Vec<u8>,
+	deadletter: Option<Storage>,
+	/// Verifies DKIM signatures
+	dkim: Option<Dkim>,
+	dmarc: Option<Dmarc>,
+	/// Recipients we pretended to accept, with replies they should have got
+	dropped: Vec<(String, String)>,
+	/// Files Telegram refuses are left out of media groups with a note
+	drop_refused: bool,
+	/// Mail older than this is not delivered
+	expire_after: Option<Duration>,
+	export: Option<Export>,
+	/// Formatting profiles, default one is ""
+	formats: HashMap<String, Format>,
+	/// Client of current transaction is one of forwarders
+	forwarded: bool,
+	/// Upstream MTAs whose original recipient headers are routed by
+	forwarders: Vec<Net>,
+	geoip: Option<GeoIp>,
+	headers: Option<SomeHeaders>,
+	/// Authenticated client, kept through transactions
+	identity: Option<String>,
+	journal: Journal,
+	/// Append time spent in gateway to messages
+	latency_footer: bool,
+	/// Time in gateway worth a warning in debug chat
+	latency_warn: Option<Duration>,
+	/// Date header this far from receipt gets flagged
+	skew_warn: Option<Duration>,
+	/// Mail with parts nested deeper is rejected
+	max_depth: Option<usize>,
+	/// Mail taking longer to parse is rejected
+	parse_time: Option<Duration>,
+	/// Keeps mail to each chat in order it was received
+	order: Order,
+	/// Client origin, when it's suspicious
+	origin: Option<Origin>,
+	/// Message grew over `max_size` of listener policy, rest of data is
+	/// dropped
+	oversized: bool,
+	policy: Arc<Policy>,
+	previewer: Option<Previewer>,
+	/// Reply transaction should have got, it's only set when policy hides
+	/// failures
+	refused: Option<String>,
+	retry: Retry,
+	router: Router,
+	/// Flood control pauses, shared by all sessions
+	schedule: Schedule,
+	semantics: Semantics,
+	signer: Option<Signer>,
+	/// Header sender sets to deliver message without notification
+	silent_header: Option<String>,
+	/// Checks whether client may send mail for sender domain
+	spf: Option<Spf>,
+	/// SPF result of current transaction
+	spf_check: Option<spf::Check>,
+	/// Where mail we failed to deliver waits for another attempt
+	spool: Option<Spool>,
+	stats: Stats,
+	/// Persistent state, shared with journal and upload counters
+	store: state::Store,
+	tg: Tg,
+	trace: Trace,
+	uploads: Uploads,
+}
+
+impl TelegramTransport {
+	/// Initialize API and read configuration
+	pub fn new(settings: config::Config) -> TelegramTransport {
+		let store = state::Store::new(&settings);
+		TelegramTransport::with_store(settings, store)
+	}
+
+	/// Same transport with new configuration, runtime state is kept
+	fn reload(&self, settings: config::Config) -> TelegramTransport {
+		let mut core = TelegramTransport::with_store(settings, self.store.clone());
+		core.bounces.keep(&self.bounces);
+		core.journal = self.journal.clone();
+		core.order = self.order.clone();
+		core.schedule = self.schedule.clone();
+		core.stats = self.stats.clone();
+		core
+	}
+
+	/// Initialize API and read configuration, keeping state in `store`
+	fn with_store(settings: config::Config, store: state::Store) -> TelegramTransport {
+		let tg = new_bot(settings.get_string("api_key")
+			.expect("[smtp2tg.toml] missing \"api_key\" parameter.\n"));
+		let router = Router::new(&settings);
+		let bots = router.api_keys().into_iter()
+			.map(|(tenant, key)| (tenant, new_bot(key)))
+			.collect();
+		let deadletter = Storage::new(&settings, "deadletter").unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let attachments_store = Storage::new(&settings, "attachments_store").unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		if attachments_store.as_ref().is_some_and(|store| !store.remote()) {
+			error!("[smtp2tg.toml] \"attachments_store\" should be \"s3://\" or WebDAV location, Telegram can't link local files.");
+			panic!("bad setting");
+		}
+		let expire_after = match settings.get_string("expire_after") {
+			Ok(value) => Some(parse_duration(&value).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"expire_after\" should be a duration like \"30m\" or \"1h\".");
+				panic!("bad setting");
+			})),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"expire_after\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let drop_refused = match settings.get_bool("drop_refused") {
+			Ok(drop) => drop,
+			Err(config::ConfigError::NotFound(_)) => true,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"drop_refused\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let latency_footer = match settings.get_bool("latency_footer") {
+			Ok(footer) => footer,
+			Err(config::ConfigError::NotFound(_)) => false,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"latency_footer\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let latency_warn = match settings.get_string("latency_warn") {
+			Ok(value) => Some(parse_duration(&value).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"latency_warn\" should be a duration like \"30s\" or \"5m\".");
+				panic!("bad setting");
+			})),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"latency_warn\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let skew_warn = match settings.get_string("skew_warn") {
+			Ok(value) => Some(parse_duration(&value).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"skew_warn\" should be a duration like \"1h\" or \"1d\".");
+				panic!("bad setting");
+			})).filter(|skew| !skew.is_zero()),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"skew_warn\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let max_depth = match settings.get_int("max_depth") {
+			Ok(0) => None,
+			Ok(depth) if depth > 0 => Some(depth as usize),
+			Err(config::ConfigError::NotFound(_)) => Some(10),
+			_ => {
+				error!("[smtp2tg.toml] \"max_depth\" should be a positive integer or 0.");
+				panic!("bad setting");
+			},
+		};
+		let parse_time = match settings.get_string("parse_time") {
+			Ok(value) => Some(parse_duration(&value).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"parse_time\" should be a duration like \"5s\".");
+				panic!("bad setting");
+			})).filter(|time| !time.is_zero()),
+			Err(config::ConfigError::NotFound(_)) => Some(Duration::from_secs(5)),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"parse_time\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let silent_header = match settings.get_string("silent_header") {
+			Ok(name) if name.is_empty() => None,
+			Ok(name) => Some(name),
+			Err(config::ConfigError::NotFound(_)) => Some("X-SMTP2TG-Silent".into()),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"silent_header\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let trace = Trace::new(&settings);
+		let formats = Format::profiles(&settings);
+		let signer = Signer::new(&settings);
+		let spf = Spf::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let semantics = Semantics::new(&settings);
+		let export = Export::new(&settings);
+		let access = Access::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let forwarders = access::nets(&settings, "forwarders").unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let geoip = GeoIp::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let dkim = Dkim::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let dmarc = Dmarc::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let chaos = Chaos::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let credentials = Credentials::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+		let retry = Retry::new(&settings).unwrap_or_else(|err| {
+			error!("{}", err);
+			panic!("bad setting");
+		});
+
+		TelegramTransport {
+			access,
+			attachments_store,
+			batcher: Batcher::new(&settings),
+			bots,
+			bounces: Bounces::new(&settings, store.clone()),
+			chaos,
+			credentials,
+			data: vec!(),
+			deadletter,
+			dkim,
+			dmarc,
+			dropped: vec![],
+			drop_refused,
+			expire_after,
+			export,
+			formats,
+			forwarded: false,
+			forwarders,
+			geoip,
+			headers: None,
+			identity: None,
+			journal: Journal::new(store.clone()),
+			latency_footer,
+			latency_warn,
+			skew_warn,
+			max_depth,
+			parse_time,
+			order: Order::default(),
+			origin: None,
+			oversized: false,
+			policy: Arc::default(),
+			previewer: Previewer::new(&settings),
+			refused: None,
+			retry,
+			router,
+			schedule: Schedule::default(),
+			semantics,
+			signer,
+			silent_header,
+			spf,
+			spf_check: None,
+			spool: Spool::new(&settings),
+			stats: Stats::default(),
+			store: store.clone(),
+			tg,
+			trace,
+			uploads: Uploads::new(&settings, store),
+		}
+	}
+
+	/// Forget current transaction
+	fn reset(&mut self) {
+		self.data.clear();
+		self.headers = None;
+		self.forwarded = false;
+		self.origin = None;
+		self.spf_check = None;
+		self.oversized = false;
+		self.dropped.clear();
+		self.refused = None;
+	}
+
+	/// Reply to client, unless policy says failures are hidden from it
+	fn reply(&mut self, res: Response) -> Response {
+		if res.is_error && self.policy.never_reject {
+			let reply = res.buffer().unwrap_or_default();
+			self.refused = Some(String::from_utf8_lossy(&reply).trim().to_owned());
+			OK
+		} else {
+			res
+		}
+	}
+
+	/// Same as `reply` for one recipient
+	fn reply_rcpt(&mut self, to: &str, res: Response) -> Response {
+		if res.is_error && self.policy.never_reject {
+			let reply = res.buffer().unwrap_or_default();
+			self.dropped.push((to.to_owned(), String::from_utf8_lossy(&reply).trim().to_owned()));
+			OK
+		} else {
+			res
+		}
+	}
+
+	/// Store mail we pretended to accept and tell default recipient about it
+	async fn swallow(&self, note: &str) {
+		let stored = match self.archive(Some(note)).await {
+			Ok(Some(location)) => format!("stored as {}", location),
+			Ok(None) => "dropped".into(),
+			Err(err) => format!("failed to store: {:?}", err),
+		};
+		let from = self.headers.as_ref().map_or("", |headers| headers.from.as_str());
+		warn!("Mail from {} accepted but not delivered: {}, {}", from, note, stored);
+		if let Err(err) = self.debug(markdown::escape(&format!("Mail from {} accepted but not delivered: {}, {}", from, note, stored))).await {
+			error!("Failed to contact Telegram:\n{:?}", err);
+		};
+	}
+
+	/// Send message to default user, used for debug/log/info purposes
+	async fn debug<S>(&self, msg: S) -> Result<Message>
+	where S: Into<String> {
+		self.tell("", msg).await
+	}
+
+	/// Send message to default chat of a tenant with its own bot
+	async fn tell<S>(&self, tenant: &str, msg: S) -> Result<Message>
+	where S: Into<String> {
+		let msg = msg.into();
+		let chat = self.router.tenant_chat(tenant);
+		debug!("Telling default chat of {:?}: {}", tenant, msg);
+		self.schedule.ready(tenant, chat).await;
+		Ok(self.bot(tenant).send_message(chat, msg).await?)
+	}
+
+	/// Bot serving specified tenant
+	fn bot(&self, tenant: &str) -> &Tg {
+		self.bots.get(tenant).unwrap_or(&self.tg)
+	}
+
+	/// Give chaos mode a chance to break next request
+	async fn chaos(&self) -> Result<()> {
+		match &self.chaos {
+			Some(chaos) => chaos.strike().await,
+			None => Ok(()),
+		}
+	}
+
+	/// Make Telegram request for route, repeating it after temporary failures
+	/// and waiting out flood control pauses of it's chat
+	async fn call<R>(&self, route: &Route, request: R) -> Result<Output<R>>
+	where R: Request<Err = RequestError> {
+		let mut attempt = 1;
+		let mut waited = Duration::ZERO;
+		loop {
+			self.schedule.ready(&route.tenant, route.chat).await;
+			let turn = self.schedule.turn(&route.tenant, route.priority).await;
+			let result = match self.chaos().await {
+				Ok(()) => request.send_ref().await.map_err(anyhow::Error::from),
+				Err(err) => Err(err),
+			};
+			drop(turn);
+			if let Some(Failure::Wait(delay)) = result.as_ref().err().map(Failure::of) {
+				// others wait too, even when this request gives up
+				self.schedule.pause(&route.tenant, route.chat, delay);
+				// flood waits don't count as attempts, they only add up
+				if waited + delay <= self.retry.max_wait {
+					warn!("Telegram asked to wait {:?} before next request to {}", delay, route.destination());
+					waited += delay;
+					continue;
+				}
+			}
+			match result {
+				Err(err) if attempt < self.retry.attempts && Failure::of(&err) == Failure::Retry
+					&& !self.semantics.assume_sent(&err) =>
+				{
+					let delay = self.retry.delay(attempt);
+					warn!("Telegram request failed, attempt {} of {}, retrying in {:?}: {}", attempt, self.retry.attempts, delay, err);
+					task::sleep(delay).await;
+					attempt += 1;
+				},
+				result => return result,
+			}
+		}
+	}
+
+	/// Send message to specified user
+	async fn send<S>(&self, route: &Route, msg: S, silent: bool, parse_mode: ParseMode) -> Result<Message>
+	where S: Into<String> {
+		let mut request = self.bot(&route.tenant).send_message(route.chat, msg);
+		request.payload_mut().parse_mode = Some(parse_mode);
+		request.payload_mut().message_thread_id = route.topic;
+		request.payload_mut().disable_notification = Some(silent);
+		self.call(route, request).await
+	}
+
+	/// Check whether mail matches suppression rule, returns reason and
+	/// whether it's counted
+	fn suppressed (&self) -> Option<(String, bool)> {
+		let mail = mail_parser::MessageParser::new().parse(&self.data)?;
+		let from = self.headers.as_ref().map_or("", |headers| headers.from.as_str());
+		let content = Content::new(from, mail.subject().unwrap_or_default(), &mail.body_text(0).unwrap_or_default());
+		self.router.suppresses(&content)
+	}
+
+	/// Check whether collected data looks like mail at all, returns reason if not
+	fn validate (&self) -> Result<(), String> {
+		let started = Instant::now();
+		let mail = match mail_parser::MessageParser::new().parse(&self.data) {
+			None => return Err("message can't be parsed".into()),
+			Some(mail) if mail.headers().is_empty() => return Err("message has no headers".into()),
+			Some(mail) => mail,
+		};
+		// MIME bombs are cheap to send and expensive to handle
+		let spent = started.elapsed();
+		if let Some(limit) = self.parse_time.filter(|limit| spent > *limit) {
+			return Err(format!("message took {}ms to parse, limit is {}s", spent.as_millis(), limit.as_secs()));
+		}
+		let depth = depth(&mail);
+		match self.max_depth {
+			Some(limit) if depth > limit => Err(format!("message parts are nested {} deep, limit is {}", depth, limit)),
+			_ => Ok(()),
+		}
+	}
+
+	/// How long mail is past delivery deadline, counting from time it was received
+	fn expired (&self) -> Option<Duration> {
+		let limit = self.expire_after?;
+		self.latency().filter(|age| *age > limit)
+	}
+
+	/// Store raw message in deadletter (if configured), note is added as a
+	/// header; returns where it went
+	async fn archive (&self, note: Option<&str>) -> Result<Option<String>> {
+		if let Some(storage) = &self.deadletter {
+			let stamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+			let name = format!("{}.{:09}.eml", stamp.as_secs(), stamp.subsec_nanos());
+			let mut data = vec![];
+			if let Some(note) = note {
+				data.extend_from_slice(format!("X-Smtp2tg-Note: {}\r\n", note).as_bytes());
+			}
+			data.extend_from_slice(&self.data);
+			Ok(Some(storage.put(&name, &data).await?))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Put files matching filter to `attachments_store` and link them
+	/// instead, nothing when store is not set or no file matches; `stored`
+	/// has links to files of this message that are stored already
+	async fn offload<F>(&self, outgoing: &OutgoingMessage, id: &str, filter: F, stored: &mut HashMap<String, String>) -> Result<Option<OutgoingMessage>>
+	where F: Fn(&Attachment) -> bool {
+		let Some(store) = &self.attachments_store else {
+			return Ok(None);
+		};
+		let (files, kept): (Vec<_>, Vec<_>) = outgoing.attachments.iter().cloned().partition(|file| filter(file));
+		if files.is_empty() {
+			return Ok(None);
+		}
+		let mut links = vec![];
+		for file in files {
+			let name = format!("{}-{}", id, file.name.chars()
+				.map(|c| if c.is_ascii_alphanumeric() || ".-_".contains(c) { c } else { '_' })
+				.collect::<String>());
+			let url = match stored.get(&name) {
+				Some(url) => url.clone(),
+				None => {
+					let url = store.put(&name, &file.data).await?;
+					stored.insert(name, url.clone());
+					url
+				},
+			};
+			links.push(Link {
+				text: file.name,
+				url,
+			});
+		}
+		let mut offloaded = outgoing.clone();
+		offloaded.attachments = kept;
+		Ok(Some(with_links(&offloaded, &links)))
+	}
+
+	/// Keep current message in spool, if it's enabled
+	fn spool(&self) -> Result<Option<PathBuf>> {
+		let (Some(spool), Some(headers)) = (&self.spool, &self.headers) else {
+			return Ok(None);
+		};
+		let priority = mail_parser::MessageParser::new().parse(&self.data)
+			.and_then(|mail| self.resolve(&mail, &headers.from, &headers.to).ok())
+			.map(|routing| routing.priority())
+			.unwrap_or_default();
+		Ok(Some(spool.put(&Envelope {
+			from: headers.from.clone(),
+			to: headers.to.clone(),
+			identity: self.identity.clone(),
+			priority,
+			received: headers.received.duration_since(SystemTime::UNIX_EPOCH).ok().map(|stamp| stamp.as_secs()),
+		}, &self.data)?))
+	}
+
+	/// Route by recipients upstream MTA had before rewriting envelope, taken
+	/// from `X-Original-To` or else `Delivered-To` headers; they are kept in
+	/// spool too, so retries go the same way
+	fn forwarded_recipients(&mut self) {
+		let Some(mail) = mail_parser::MessageParser::new().parse_headers(&self.data) else {
+			return;
+		};
+		let Some((name, original)) = ["X-Original-To", "Delivered-To"].into_iter().find_map(|name| {
+			let mut original: Vec<String> = vec![];
+			for value in mail.header_values(name).filter_map(|value| value.as_text()) {
+				let addr = value.trim().trim_start_matches('<').trim_end_matches('>').trim().to_lowercase();
+				if !addr.is_empty() && !original.contains(&addr) {
+					original.push(addr);
+				}
+			}
+			(!original.is_empty()).then_some((name, original))
+		}) else {
+			return;
+		};
+		if let Some(headers) = &mut self.headers {
+			info!("Routing mail to {} by {} header: {}", headers.to.join(", "), name, original.join(", "));
+			headers.to = original;
+		}
+	}
+
+	/// Add record to export, if it's enabled
+	fn export(&self, record: export::Record) {
+		if let Some(export) = &self.export {
+			if let Err(err) = export.write(record) {
+				error!("Failed to export delivery record: {:?}", err);
+			}
+		}
+	}
+
+	/// Compose message using formatting profile and add our footers
+	fn render(&self, mail: &mail_parser::Message, from: &str, (profile, fields): (&str, Option<&[Field]>)) -> Result<OutgoingMessage> {
+		let mut format = Cow::Borrowed(self.formats.get(profile).unwrap_or(&self.formats[""]));
+		// recipient can pick headers of it's own
+		if let Some(fields) = fields {
+			format.to_mut().fields = fields.to_vec();
+		}
+		let mut outgoing = compose::compose(mail, from, &format)?;
+		if let Some(name) = &self.silent_header {
+			outgoing.options.silent = mail.header(name.as_str()).and_then(|value| value.as_text())
+				.is_some_and(|value| matches!(value.trim().to_lowercase().as_str(), "yes" | "true" | "1" | "on"));
+		}
+		if let Some(previewer) = &self.previewer {
+			for file in outgoing.attachments.iter_mut()
+				.filter(|file| file.kind == Kind::Document && file.name.to_lowercase().ends_with(".pdf"))
+			{
+				match previewer.render(&file.data) {
+					Ok(image) => file.preview = Some(image),
+					Err(err) => outgoing.notes.push(markdown::escape(&format!("No preview for {}: {}", file.name, err))),
+				};
+			}
+		}
+		if let Some(signer) = &self.signer {
+			let footer = signer.footer(from, mail.subject().unwrap_or(""));
+			if let Some(last) = outgoing.text_chunks.last_mut() {
+				last.push('\n');
+				last.push_str(&footer);
+			}
+		}
+		if let Some(footer) = self.dmarc.as_ref().filter(|dmarc| dmarc.footer).and_then(|dmarc| dmarc.footer(mail)) {
+			if let Some(last) = outgoing.text_chunks.last_mut() {
+				last.push('\n');
+				last.push_str(&footer);
+			}
+		}
+		if let Some(check) = &self.spf_check {
+			if let Some(last) = outgoing.text_chunks.last_mut() {
+				let icon = match check.outcome {
+					spf::Outcome::Pass => "✅",
+					_ => "⚠️",
+				};
+				last.push('\n');
+				last.push_str(&markdown::escape(&format!("{} {}", icon, check)));
+			}
+		}
+		if let (Some(origin), Some(true)) = (&self.origin, self.geoip.as_ref().map(|geoip| geoip.footer)) {
+			if let Some(last) = outgoing.text_chunks.last_mut() {
+				last.push('\n');
+				last.push_str(&markdown::escape(&format!("⚠️ Suspicious origin: {}", origin)));
+			}
+		}
+		Ok(outgoing)
+	}
+
+	/// Find destination chats, trusted sender can pick one with header
+	fn resolve(&self, mail: &mail_parser::Message, from: &str, to: &[String]) -> Result<Routing> {
+		let content = Content::new(from, mail.subject().unwrap_or_default(), &mail.body_text(0).unwrap_or_default());
+		let mut routing = self.router.resolve(to, self.identity.as_deref(), &content)?;
+		let Some((name, value)) = self.router.chat_header.as_deref()
+			.and_then(|name| mail.header(name).and_then(|value| value.as_text()).map(|value| (name, value)))
+		else {
+			return Ok(routing);
+		};
+		if self.identity.is_none() && !self.router.trusts(from) {
+			warn!("Ignoring {} header from untrusted sender {}", name, from);
+			routing.notes.push(markdown::escape(&format!("{} header from untrusted sender {} is ignored", name, from)));
+			return Ok(routing);
+		}
+		let sender = self.identity.as_deref().unwrap_or(from);
+		match Routing::direct(value, format!("{} header from {}", name, sender)) {
+			Some(direct) => Ok(direct),
+			None => {
+				routing.notes.push(markdown::escape(&format!("{} header has bad chat \"{}\"", name, value)));
+				Ok(routing)
+			},
+		}
+	}
+
+	/// Attempt to deliver one message
+	async fn relay_mail (&self) -> Result<()> {
+		if let Some(headers) = &self.headers {
+			let mail = mail_parser::MessageParser::new().parse(&self.data)
+				.ok_or(anyhow!("Failed to parse mail"))?;
+
+			let mut routing = self.resolve(&mail, &headers.from, &headers.to)?;
+			// mail failing SPF only goes to default chat
+			if let (Some(check), Some(Action::Quarantine)) = (&self.spf_check, self.spf.as_ref().map(|spf| spf.action)) {
+				if let (spf::Outcome::Fail, Some(first)) = (check.outcome, routing.routes.first()) {
+					warn!("Message from {} quarantined: {}", headers.from, check);
+					let mut reasons = first.reasons.clone();
+					reasons.push(format!("quarantined, {}", check));
+					routing.routes = vec![Route {
+						chat: self.router.default_chat(),
+						topic: None,
+						reasons,
+						tenant: String::new(),
+						..first.clone()
+					}];
+				}
+			}
+			debug!("Mail from {} to {} goes to {}", headers.from, headers.to.join(", "),
+				routing.routes.iter().map(|route| format!("{} ({})", route.destination(), route.reasons.join(", "))).collect::<Vec<_>>().join(", "));
+			// place in line is taken before anything slow
+			let mut tickets = self.order.enqueue(routing.routes.iter().map(|route| route.chat));
+			for note in &routing.notes {
+				self.debug(note).await?;
+			}
+			if let Some((dmarc, chat)) = self.dmarc.as_ref().and_then(|dmarc| dmarc.reports.map(|chat| (dmarc, chat))) {
+				let route = Route {
+					chat,
+					topic: None,
+					reasons: vec!["DMARC reports".into()],
+					tenant: String::new(),
+					profile: String::new(),
+					priority: Priority::Low,
+					silent: Silent::Never,
+					emoji: None,
+					tag: None,
+					fields: None,
+					links: vec![],
+				};
+				for summary in dmarc.summaries(&mail) {
+					match summary {
+						Ok(summary) => { self.send(&route, summary, false, MarkdownV2).await?; },
+						Err(err) => { self.debug(markdown::escape(&format!("{}", err))).await?; },
+					};
+				}
+			}
+
+			let skew = self.skew(&mail, headers);
+			if let Some(skew) = &skew {
+				warn!("Message from {} was {}", headers.from, skew);
+			}
+			let verdict = match &self.dkim {
+				Some(dkim) => {
+					let verdict = dkim.verify(&self.data).await;
+					debug!("Message from {}: {}", headers.from, verdict);
+					Some(verdict.to_string())
+				},
+				None => None,
+			};
+
+			let id = journal::delivery_id(&self.data);
+			// links to files put to attachments store
+			let mut stored = HashMap::new();
+			// every profile in use is rendered once
+			let mut rendered: HashMap<(&str, Option<&[Field]>), OutgoingMessage> = HashMap::new();
+			for route in &routing.routes {
+				if let Entry::Vacant(entry) = rendered.entry(route.layout()) {
+					let mut outgoing = self.render(&mail, &headers.from, route.layout())?;
+					for note in &outgoing.notes {
+						self.debug(note).await?;
+					}
+					match self.offload(&outgoing, &id, |file| file.data.len() > UPLOAD_LIMIT, &mut stored).await {
+						Ok(Some(offloaded)) => outgoing = offloaded,
+						Ok(None) => {},
+						Err(err) => warn!("Failed to store files of {} too large for Telegram: {:?}", id, err),
+					};
+					if let Some(skew) = &skew {
+						with_skew(&mut outgoing, skew);
+					}
+					if let Some(verdict) = &verdict {
+						with_verdict(&mut outgoing, verdict);
+					}
+					entry.insert(outgoing);
+				}
+			}
+
+			let mut failure = None;
+			let mut rerouted = vec![];
+			for original in &routing.routes {
+				// batches keep order they are joined in, and waiting would leave
+				// nothing to join
+				if self.batcher.is_none() || rendered[&original.layout()].text_only().is_none() {
+					tickets.wait(original.chat).await;
+				}
+				if self.journal.contains(&id, original.chat, original.topic) {
+					info!("Message {} was already delivered to {}, skipping", id, original.chat);
+					continue;
+				}
+				// chats that keep refusing messages get them in default chat
+				let fallback;
+				let route = match self.bounces.disabled(original.chat) {
+					None => original,
+					Some(reason) => {
+						let default = self.router.tenant_chat(&original.tenant);
+						// default chat gets it anyway, or is the one disabled
+						let skip = routing.routes.iter().any(|route| route.chat == default && route.tenant == original.tenant);
+						let note = match skip {
+							true => format!("Message {} to {} skipped, chat is disabled: {}", id, original.chat, reason),
+							false => format!("Message {} to {} goes to default chat, chat is disabled: {}", id, original.chat, reason),
+						};
+						warn!("{}", note);
+						if let Err(err) = self.tell(&original.tenant, markdown::escape(&note)).await {
+							error!("Failed to contact Telegram:\n{:?}", err);
+						}
+						if skip {
+							continue;
+						}
+						rerouted.push(original.chat.0);
+						self.stats.rerouted();
+						let mut reasons = original.reasons.clone();
+						reasons.push(format!("chat {} is disabled", original.chat));
+						fallback = Route {
+							chat: default,
+							topic: None,
+							reasons,
+							..original.clone()
+						};
+						&fallback
+					},
+				};
+				let mut outgoing = &rendered[&route.layout()];
+				// chats over upload cap only get text
+				let capped;
+				if !outgoing.attachments.is_empty() && !self.uploads.allows(route.chat, outgoing.upload_size()) {
+					warn!("Chat {} reached upload cap with {} bytes sent, files of {} are not sent", route.chat, self.uploads.used(route.chat), id);
+					capped = match self.offload(outgoing, &id, |_| true, &mut stored).await {
+						Ok(Some(offloaded)) => offloaded,
+						Ok(None) => outgoing.without_files("monthly upload cap reached"),
+						Err(err) => {
+							warn!("Failed to store files of {}: {:?}", id, err);
+							outgoing.without_files("monthly upload cap reached")
+						},
+					};
+					outgoing = &capped;
+				}
+				let mut result = self.attempt(route, outgoing).await;
+				// some failures go away with another text or chat id
+				match result.as_ref().err().map(Failure::of) {
+					Some(Failure::Reformat) => {
+						warn!("Message {} to {} was refused, sending it as plain text: {:?}", id, route.chat, result);
+						result = self.attempt(route, &outgoing.escaped()).await;
+					},
+					Some(Failure::Migrate(chat)) => {
+						let note = format!("Chat {} was upgraded to supergroup {}, please update configuration", route.chat, chat);
+						warn!("{}", note);
+						if let Err(err) = self.tell(&route.tenant, markdown::escape(&note)).await {
+							error!("Failed to contact Telegram:\n{:?}", err);
+						}
+						result = self.attempt(&Route { chat, ..route.clone() }, outgoing).await;
+					},
+					_ => {},
+				};
+				match result {
+					Ok(()) => {
+						debug!("Message {} delivered to {}", id, route.destination());
+						self.journal.record(&id, original.chat, original.topic);
+						self.uploads.record(route.chat, outgoing.upload_size());
+						self.bounces.delivered(route.chat);
+					},
+					Err(err) if self.semantics.assume_sent(&err) => {
+						warn!("Message {} to {} is considered sent after error: {:?}", id, route.chat, err);
+						self.journal.record(&id, original.chat, original.topic);
+						self.uploads.record(route.chat, outgoing.upload_size());
+					},
+					// retrying won't help, other chats still get it
+					Err(err) if Failure::of(&err) == Failure::Drop => {
+						let mut note = format!("Message {} to {} dropped: {}", id, route.chat, err);
+						warn!("{}", note);
+						if let Some(period) = self.bounces.refused(route.chat, &err.to_string()) {
+							let disabled = format!("Chat {} keeps refusing messages, it's disabled for {}s and it's mail goes to default chat", route.chat, period.as_secs());
+							warn!("{}", disabled);
+							self.stats.disabled();
+							note = format!("{}\n{}", note, disabled);
+						}
+						if let Err(err) = self.tell(&route.tenant, markdown::escape(&note)).await {
+							error!("Failed to contact Telegram:\n{:?}", err);
+						}
+					},
+					Err(err) => {
+						failure = Some(err);
+						break;
+					},
+				};
+			}
+			drop(tickets);
+			self.export(export::Record {
+				id: id.clone(),
+				from: &headers.from,
+				to: &headers.to,
+				subject: mail.subject(),
+				chats: routing.routes.iter()
+					.filter(|route| self.journal.contains(&id, route.chat, route.topic))
+					.map(|route| route.chat.0)
+					.collect(),
+				rerouted,
+				status: if failure.is_some() { Status::Failed } else { Status::Delivered },
+				size: self.data.len(),
+				text_size: rendered.values().flat_map(|outgoing| &outgoing.text_chunks).map(|chunk| chunk.len()).sum(),
+				attachment_sizes: rendered.values().next().map(|outgoing| outgoing.attachments.iter()
+					.map(|attachment| attachment.data.len()).collect()).unwrap_or_default(),
+				..Default::default()
+			});
+			if let Some(err) = failure {
+				return Err(err);
+			}
+			if let (Some(latency), Some(limit)) = (self.latency(), self.latency_warn) {
+				if latency > limit {
+					let note = format!("Message {} from {} spent {:.1}s in gateway, over {}s", id, headers.from, latency.as_secs_f64(), limit.as_secs());
+					warn!("{}", note);
+					if let Err(err) = self.debug(markdown::escape(&note)).await {
+						error!("Failed to contact Telegram:\n{:?}", err);
+					}
+				}
+			}
+			let mut tenants: Vec<&str> = routing.routes.iter()
+				.map(|route| route.tenant.as_str())
+				.filter(|tenant| !tenant.is_empty())
+				.collect();
+			tenants.sort();
+			tenants.dedup();
+			self.stats.delivered(&tenants, self.data.len());
+		} else {
+			bail!("No headers.");
+		}
+		Ok(())
+	}
+
+	/// How far Date header is from time message was received, when it's
+	/// over `skew_warn`, like "sent 3 days ago"
+	fn skew (&self, mail: &mail_parser::Message, headers: &SomeHeaders) -> Option<String> {
+		let limit = self.skew_warn?;
+		let date = u64::try_from(mail.date()?.to_timestamp()).ok()?;
+		let received = headers.received.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+		match received.checked_sub(date) {
+			Some(age) if age > limit.as_secs() => Some(format!("sent {} ago", human(Duration::from_secs(age)))),
+			Some(_) => None,
+			None => Some(date - received).filter(|ahead| *ahead > limit.as_secs())
+				.map(|ahead| format!("dated {} ahead", human(Duration::from_secs(ahead)))),
+		}
+	}
+
+	/// Time current message spent in gateway so far
+	fn latency(&self) -> Option<Duration> {
+		self.headers.as_ref().and_then(|headers| headers.received.elapsed().ok())
+	}
+
+	/// Deliver message to route, with tag, emoji, latency and trace if they
+	/// are enabled
+	async fn attempt (&self, route: &Route, outgoing: &OutgoingMessage) -> Result<()> {
+		let tagged;
+		let outgoing = match &route.tag {
+			Some(tag) => {
+				tagged = with_tag(outgoing, tag);
+				&tagged
+			},
+			None => outgoing,
+		};
+		let branded;
+		let outgoing = match &route.emoji {
+			Some(emoji) => {
+				branded = with_emoji(outgoing, emoji);
+				&branded
+			},
+			None => outgoing,
+		};
+		let linked;
+		let outgoing = match route.links.is_empty() {
+			true => outgoing,
+			false => {
+				linked = with_links(outgoing, &route.links);
+				&linked
+			},
+		};
+		let timed;
+		let outgoing = match self.latency().filter(|_| self.latency_footer) {
+			Some(latency) => {
+				timed = with_latency(outgoing, latency);
+				&timed
+			},
+			None => outgoing,
+		};
+		let traced;
+		let outgoing = match self.trace {
+			Trace::Off => outgoing,
+			Trace::Log => {
+				info!("Routing to {}: {}", route.destination(), route.reasons.join(", "));
+				outgoing
+			},
+			Trace::Footer => {
+				traced = with_trace(outgoing, route);
+				&traced
+			},
+		};
+		let encoded;
+		let outgoing = match outgoing.options.markup {
+			Markup::MarkdownV2 => outgoing,
+			_ => {
+				encoded = outgoing.encoded();
+				&encoded
+			},
+		};
+		self.deliver(route, outgoing).await
+	}
+
+	/// Send composed message to specified user
+	async fn deliver (&self, route: &Route, outgoing: &OutgoingMessage) -> Result<()> {
+		let silent = outgoing.options.silent || route.silent.now();
+		let parse_mode = outgoing.options.parse_mode;
+		if let (Some(batcher), Some(text)) = (&self.batcher, outgoing.text_only()) {
+			return self.batched(batcher, route, text, silent, parse_mode).await;
+		}
+		if let Some(poll) = &outgoing.poll {
+			let mut request = self.bot(&route.tenant).send_poll(route.chat, &poll.question, poll.options.clone());
+			request.payload_mut().message_thread_id = route.topic;
+			request.payload_mut().disable_notification = Some(silent);
+			self.call(route, request).await?;
+		}
+		let mut chunks = outgoing.text_chunks.iter().peekable();
+		// files left out of groups, with reasons
+		let mut refused = vec![];
+		let photos: Vec<_> = outgoing.attachments.iter().filter(|file| file.kind == Kind::Photo).collect();
+		if let [file] = photos.as_slice() {
+			let photo = teloxide::types::InputFile::memory(file.data.clone()).file_name(file.name.clone());
+			let mut request = self.bot(&route.tenant).send_photo(route.chat, photo);
+			request.payload_mut().message_thread_id = route.topic;
+			request.payload_mut().disable_notification = Some(silent);
+			// footers could make text longer than composer expected
+			if let Some(text) = chunks.next_if(|text| text.len() <= compose::CAPTION_LIMIT) {
+				request = request.caption(text).parse_mode(outgoing.options.parse_mode);
+			}
+			self.call(route, request).await?;
+		} else {
+			// album takes 10 pictures at most, first one gets caption
+			for (index, album) in photos.chunks(10).enumerate() {
+				let caption = match index {
+					0 => chunks.next_if(|text| text.len() <= compose::CAPTION_LIMIT),
+					_ => None,
+				};
+				let caption = caption.map(|text| (text.as_str(), outgoing.options.parse_mode));
+				self.sendfiles(route, album, caption, silent, None, &mut refused).await?;
+			}
+		}
+		// playable media can't be grouped with documents, it goes separately
+		let (documents, media): (Vec<_>, Vec<_>) = outgoing.attachments.iter()
+			.filter(|file| file.kind != Kind::Photo)
+			.partition(|file| file.kind == Kind::Document);
+		for file in &documents {
+			if let Some(image) = &file.preview {
+				let photo = teloxide::types::InputFile::memory(image.clone()).file_name(format!("{}.png", file.name));
+				let mut request = self.bot(&route.tenant).send_photo(route.chat, photo);
+				request.payload_mut().message_thread_id = route.topic;
+				request.payload_mut().disable_notification = Some(silent);
+				self.call(route, request).await?;
+			}
+		}
+		if !documents.is_empty() {
+			// footers could make text longer than composer expected
+			let mut reply_to = None;
+			let caption = match &outgoing.options.caption {
+				_ if outgoing.options.text_first => {
+					for text in chunks.by_ref() {
+						let message = self.send(route, text, silent, parse_mode).await?;
+						reply_to.get_or_insert(message.id);
+					}
+					None
+				},
+				Some(caption) => {
+					for text in chunks.by_ref() {
+						self.send(route, text, silent, parse_mode).await?;
+					}
+					Some(caption)
+				},
+				None => match chunks.peek() {
+					Some(text) if text.len() > compose::CAPTION_LIMIT => {
+						for text in chunks.by_ref() {
+							self.send(route, text, silent, parse_mode).await?;
+						}
+						None
+					},
+					_ => chunks.next(),
+				},
+			};
+			let caption = caption.map(|text| (text.as_str(), outgoing.options.parse_mode));
+			self.sendfiles(route, &documents, caption, silent, reply_to, &mut refused).await?;
+		}
+		for text in chunks {
+			self.send(route, text, silent, parse_mode).await?;
+		}
+		for file in media {
+			let input = teloxide::types::InputFile::memory(file.data.clone()).file_name(file.name.clone());
+			match file.kind {
+				Kind::Audio => {
+					let mut request = self.bot(&route.tenant).send_audio(route.chat, input);
+					request.payload_mut().message_thread_id = route.topic;
+					request.payload_mut().disable_notification = Some(silent);
+					self.call(route, request).await?;
+				},
+				Kind::Voice => {
+					let mut request = self.bot(&route.tenant).send_voice(route.chat, input);
+					request.payload_mut().message_thread_id = route.topic;
+					request.payload_mut().disable_notification = Some(silent);
+					self.call(route, request).await?;
+				},
+				Kind::Video => {
+					let mut request = self.bot(&route.tenant).send_video(route.chat, input).supports_streaming(true);
+					request.payload_mut().message_thread_id = route.topic;
+					request.payload_mut().disable_notification = Some(silent);
+					self.call(route, request).await?;
+				},
+				Kind::Document | Kind::Photo => {},
+			};
+		}
+		if let Some((latitude, longitude)) = outgoing.location {
+			let mut request = self.bot(&route.tenant).send_location(route.chat, latitude, longitude);
+			request.payload_mut().message_thread_id = route.topic;
+			request.payload_mut().disable_notification = Some(silent);
+			self.call(route, request).await?;
+		}
+		if !refused.is_empty() {
+			let note = match refused.len() {
+				1 => format!("1 attachment could not be delivered: {}", refused[0]),
+				count => format!("{} attachments could not be delivered: {}", count, refused.join(", ")),
+			};
+			self.send(route, markdown::escape(&note), silent, MarkdownV2).await?;
+		}
+		Ok(())
+	}
+
+	/// Send short text merged with others to the same chat, when batch fails
+	/// every message is sent alone
+	async fn batched (&self, batcher: &Batcher, route: &Route, text: &str, silent: bool, parse_mode: ParseMode) -> Result<()> {
+		let key = (route.tenant.clone(), route.chat, route.topic, silent, parse_mode);
+		let merged = match batcher.join(&key, text) {
+			Joined::Lead => {
+				task::sleep(batcher.window).await;
+				let (batch, waiters) = batcher.take(&key);
+				if waiters.is_empty() {
+					self.send(route, text, silent, parse_mode).await?;
+					return Ok(());
+				}
+				let sent = match self.send(route, batch, silent, parse_mode).await {
+					Ok(_) => true,
+					Err(err) if self.semantics.assume_sent(&err) => {
+						warn!("Batch of {} messages to {} is considered sent after error: {:?}", waiters.len() + 1, route.chat, err);
+						true
+					},
+					Err(err) => {
+						warn!("Batch of {} messages to {} failed, sending them one by one: {:?}", waiters.len() + 1, route.chat, err);
+						false
+					},
+				};
+				for waiter in waiters {
+					// waiter gone means it's transaction is gone too
+					let _ = waiter.try_send(sent);
+				}
+				sent
+			},
+			Joined::Wait(receiver) => receiver.recv().await.unwrap_or(false),
+			Joined::Alone => false,
+		};
+		if !merged {
+			self.send(route, text, silent, parse_mode).await?;
+		}
+		Ok(())
+	}
+
+	/// Send files as media group, with `drop_refused` files Telegram refuses
+	/// are left out one at a time and listed in `refused` with reasons
+	async fn sendfiles(&self, route: &Route, files: &[&Attachment], caption: Option<(&str, ParseMode)>,
+		silent: bool, reply_to: Option<MessageId>, refused: &mut Vec<String>) -> Result<()>
+	{
+		let mut files = files.to_vec();
+		loop {
+			let err = match self.sendgroup(route, group(&files, caption), silent, reply_to).await {
+				Ok(_) => return Ok(()),
+				Err(err) if self.drop_refused && Failure::of(&err) == Failure::Refused => err,
+				Err(err) => return Err(err),
+			};
+			let at = match refused_file(&err, &files) {
+				Some(at) => at,
+				// Telegram didn't tell which one, group is tried without each
+				None => {
+					for at in 0..files.len() {
+						let mut rest = files.clone();
+						let file = rest.remove(at);
+						match self.sendgroup(route, group(&rest, caption), silent, reply_to).await {
+							Ok(_) => {
+								warn!("File {} to {} is left out: {}", file.name, route.chat, err);
+								refused.push(format!("{} ({})", file.name, err));
+								return Ok(());
+							},
+							Err(err) if Failure::of(&err) == Failure::Refused => {},
+							Err(err) => return Err(err),
+						};
+					}
+					return Err(err);
+				},
+			};
+			let file = files.remove(at);
+			warn!("File {} to {} is left out: {}", file.name, route.chat, err);
+			refused.push(format!("{} ({})", file.name, err));
+			if files.is_empty() {
+				if let Some((text, parse_mode)) = caption {
+					let mut request = self.bot(&route.tenant).send_message(route.chat, text);
+					request.payload_mut().parse_mode = Some(parse_mode);
+					request.payload_mut().message_thread_id = route.topic;
+					request.payload_mut().disable_notification = Some(silent);
+					request.payload_mut().reply_parameters = reply_to.map(|id| ReplyParameters::new(id).allow_sending_without_reply());
+					self.call(route, request).await?;
+				}
+				return Ok(());
+			}
+		}
+	}
+
+	/// Send media to specified user
+	async fn sendgroup<M>(&self, route: &Route, media: M, silent: bool, reply_to: Option<MessageId>) -> Result<Vec<Message>>
+	where M: IntoIterator<Item = InputMedia> {
+		let mut request = self.bot(&route.tenant).send_media_group(route.chat, media);
+		request.payload_mut().message_thread_id = route.topic;
+		request.payload_mut().disable_notification = Some(silent);
+		request.payload_mut().reply_parameters = reply_to.map(|id| ReplyParameters::new(id).allow_sending_without_reply());
+		self.call(route, request).await
+	}
+}
+
+impl server::Enforcer for TelegramTransport {
+	fn enforce(&mut self, policy: Arc<Policy>) {
+		self.policy = policy;
+	}
+
+	fn identify(&mut self, identity: String) {
+		self.identity = Some(identity);
+	}
+}
+
+impl server::Directory for server::Live<TelegramTransport> {
+	fn verify(&self, addr: &str) -> (u16, Vec<String>) {
+		self.read(|core| core.router.verify(addr))
+	}
+
+	fn expand(&self, addr: &str) -> (u16, Vec<String>) {
+		self.read(|core| core.router.expand(addr))
+	}
+}
+
+impl server::Screen for server::Live<TelegramTransport> {
+	fn screen(&self, remote: IpAddr) -> Option<Response> {
+		self.read(|core| core.access.as_ref().and_then(|access| access.screen(remote))
+			.or_else(|| core.geoip.as_ref().and_then(|geoip| geoip.screen(remote))))
+	}
+}
+
+impl updates::Gateway for server::Live<TelegramTransport> {
+	fn admin(&self) -> ChatId {
+		self.read(|core| core.router.default_chat())
+	}
+
+	fn status(&self) -> String {
+		self.read(|core| {
+			let mut status = vec![
+				format!("Up for {}", human(core.stats.uptime())),
+				format!("SMTP sessions in progress: {}", server::active()),
+			];
+			if let Some(spool) = &core.spool {
+				status.push(match spool.count() {
+					Ok(count) => format!("Spooled messages: {}", count),
+					Err(err) => format!("Spool can't be read: {}", err),
+				});
+			}
+			status.join("\n")
+		})
+	}
+
+	fn stats(&self) -> String {
+		self.read(|core| core.stats.report())
+	}
+}
+
+impl mailin::Handler for TelegramTransport {
+	/// Check credentials from `[auth]`, user becomes client identity
+	fn auth_login (&mut self, username: &str, password: &str) -> Response {
+		match &self.credentials {
+			Some(credentials) if credentials.verify(username, password) => {
+				self.identity = Some(username.to_owned());
+				AUTH_OK
+			},
+			_ => {
+				warn!("Authentication failed for {}", username);
+				INVALID_CREDENTIALS
+			},
+		}
+	}
+
+	/// Same as login auth, acting on behalf of someone else is not allowed
+	fn auth_plain (&mut self, authorization_id: &str, authentication_id: &str, password: &str) -> Response {
+		if !authorization_id.is_empty() && authorization_id != authentication_id {
+			warn!("{} tried to authenticate as {}", authentication_id, authorization_id);
+			return INVALID_CREDENTIALS;
+		}
+		self.auth_login(authentication_id, password)
+	}
+
+	/// New transaction, drop anything left from previous one. `mailin`
+	/// doesn't tell us about RSET, but MAIL always follows it
+	fn mail (&mut self, ip: IpAddr, domain: &str, from: &str) -> Response {
+		self.reset();
+		if self.policy.auth_required && self.identity.is_none() {
+			return self.reply(AUTHENTICATION_REQUIRED);
+		}
+		if !self.policy.allows_sender(from) {
+			return self.reply(Response::custom(550, format!("Sender {} not allowed here", from)));
+		}
+		self.forwarded = self.forwarders.iter().any(|net| net.contains(ip));
+		self.origin = self.geoip.as_ref().and_then(|geoip| geoip.suspicious(ip));
+		// authenticated and local clients are ours, SPF is for strangers
+		if self.identity.is_none() && !ip.to_canonical().is_loopback() {
+			self.spf_check = self.spf.as_ref().map(|spf| task::block_on(spf.check(ip, domain, from)));
+		}
+		if let Some(check) = &self.spf_check {
+			info!("{}", check);
+		}
+		OK
+	}
+
+	/// Verify whether address is deliverable
+	fn rcpt (&mut self, to: &str) -> Response {
+		if let (Some(check), Some(Action::Reject)) = (&self.spf_check, self.spf.as_ref().map(|spf| spf.action)) {
+			if check.outcome == spf::Outcome::Fail {
+				let reply = Response::custom(550, format!("{}, sender not allowed", check));
+				return self.reply_rcpt(to, reply);
+			}
+		}
+		let routed = self.identity.as_deref().is_some_and(|identity| self.router.routes_identity(identity));
+		if !routed && !self.router.accepts_with(to, self.policy.relay) {
+			return self.reply_rcpt(to, Response::custom(self.router.denial.code, self.router.denial.text.clone()));
+		}
+		match self.router.quota(to) {
+			Some((tenant, quota)) if self.stats.today(tenant) >= quota =>
+				self.reply_rcpt(to, Response::custom(452, format!("Daily quota for {} exceeded", tenant))),
+			_ => OK,
+		}
+	}
+
+	/// Save headers we need
+	fn data_start (&mut self, _domain: &str, from: &str, _is8bit: bool, to: &[String]) -> Response {
+		self.headers = Some(SomeHeaders{
+			from: from.to_string(),
+			to: to.iter()
+				.filter(|addr| !self.dropped.iter().any(|(dropped, _)| dropped == *addr))
+				.cloned()
+				.collect(),
+			received: SystemTime::now(),
+		});
+		OK
+	}
+
+	/// Save chunk(?) of data
+	fn data(&mut self, buf: &[u8]) -> Result<(), Error> {
+		if self.oversized {
+			return Ok(());
+		}
+		if self.policy.max_size.is_some_and(|max| self.data.len() + buf.len() > max) {
+			self.oversized = true;
+			self.data.clear();
+			return Ok(());
+		}
+		self.data.append(buf.to_vec().as_mut());
+		Ok(())
+	}
+
+	/// Attempt to send email, return permanent error if mail can't be parsed and
+	/// temporary error if sending fails
+	fn data_end(&mut self) -> Response {
+		if self.oversized {
+			if self.policy.never_reject {
+				task::block_on(self.swallow("exceeds \"max_size\""));
+			}
+			self.reset();
+			return match self.policy.never_reject {
+				true => OK,
+				false => Response::custom(552, "Message exceeds fixed maximum message size".into()),
+			};
+		}
+		let mut result = OK;
+		task::block_on(async {
+			if let Some(reply) = &self.refused {
+				self.swallow(&format!("refused with \"{}\"", reply)).await;
+			// there's no point in retrying mail we can't parse
+			} else if let Err(reason) = self.validate() {
+				result = Response::custom(554, format!("Transaction failed: {}", reason));
+				let (from, to) = match &self.headers {
+					Some(headers) => (headers.from.as_str(), headers.to.as_slice()),
+					None => ("", &[][..]),
+				};
+				self.export(export::Record {
+					id: journal::delivery_id(&self.data),
+					from,
+					to,
+					status: Status::Rejected,
+					size: self.data.len(),
+					..Default::default()
+				});
+				let stored = match self.archive(None).await {
+					Ok(Some(location)) => format!("stored as {}", location),
+					Ok(None) => "dropped".into(),
+					Err(err) => format!("failed to store: {:?}", err),
+				};
+				if let Err(err) = self.debug(markdown::escape(&format!("Rejected unparseable email ({}), {}", reason, stored))).await {
+					error!("Failed to contact Telegram:\n{:?}", err);
+				};
+			// nobody wants to read it
+			} else if let Some((reason, count)) = self.suppressed() {
+				let (from, to) = match &self.headers {
+					Some(headers) => (headers.from.as_str(), headers.to.as_slice()),
+					None => ("", &[][..]),
+				};
+				self.export(export::Record {
+					id: journal::delivery_id(&self.data),
+					from,
+					to,
+					status: Status::Suppressed,
+					size: self.data.len(),
+					..Default::default()
+				});
+				info!("Mail from {} to {} suppressed: {}", from, to.join(", "), reason);
+				if count {
+					self.stats.suppressed();
+				}
+			// relay mail
+			} else {
+				if self.forwarded {
+					self.forwarded_recipients();
+				}
+				for (to, reply) in &self.dropped {
+					if let Err(err) = self.debug(markdown::escape(&format!("Recipient {} was refused with \"{}\"", to, reply))).await {
+						error!("Failed to contact Telegram:\n{:?}", err);
+					};
+				}
+				if let Err(err) = self.relay_mail().await {
+					self.stats.failed();
+					match self.spool() {
+						// we'll retry it ourselves
+						Ok(Some(path)) => warn!("Sending email failed, spooled as {}: {:?}", path.display(), err),
+						spooled => {
+							if let Err(err) = spooled {
+								error!("Failed to spool email: {:?}", err);
+							}
+							result = INTERNAL_ERROR;
+							// sender won't retry, so mail is kept here
+							if self.policy.never_reject {
+								self.swallow(&format!("delivery failed: {}", err)).await;
+							// in case that fails - inform default recipient
+							} else if let Err(err) = self.debug(markdown::escape(&format!("Sending emails failed:\n{:?}", err))).await {
+								// in case that also fails - write some logs and bail
+								error!("Failed to contact Telegram:\n{:?}", err);
+							};
+						},
+					};
+				};
+			};
+		});
+		// clear - just in case
+		self.reset();
+		match self.policy.never_reject {
+			true => OK,
+			false => result,
+		}
+	}
+}
+
+/// Retry spooled mail forever, mail past `expire_after` goes to deadletter
+async fn drain(live: server::Live<TelegramTransport>, spool: Spool) {
+	loop {
+		task::sleep(spool.interval).await;
+		let core = live.read(TelegramTransport::clone);
+		let entries = match spool.entries() {
+			Ok(entries) => entries,
+			Err(err) => {
+				error!("Failed to read spool: {:?}", err);
+				continue;
+			},
+		};
+		for entry in entries {
+			let mut core = core.clone();
+			core.data = entry.data.clone();
+			core.headers = Some(SomeHeaders {
+				from: entry.envelope.from.clone(),
+				to: entry.envelope.to.clone(),
+				received: entry.envelope.received
+					.map_or_else(SystemTime::now, |stamp| SystemTime::UNIX_EPOCH + Duration::from_secs(stamp)),
+			});
+			core.identity = entry.envelope.identity.clone();
+			let done = match core.expired() {
+				Some(age) => {
+					core.export(export::Record {
+						id: journal::delivery_id(&core.data),
+						from: &entry.envelope.from,
+						to: &entry.envelope.to,
+						status: Status::Expired,
+						size: core.data.len(),
+						..Default::default()
+					});
+					let note = format!("expired in spool, {}s old", age.as_secs());
+					match core.archive(Some(&note)).await {
+						Ok(location) => warn!("Spooled mail from {} {}, {}", entry.envelope.from, note,
+							location.map_or("dropped".into(), |location| format!("stored as {}", location))),
+						Err(err) => error!("Failed to store expired mail from {}: {:?}", entry.envelope.from, err),
+					};
+					true
+				},
+				None => match core.relay_mail().instrument(tracing::info_span!("spool", from = %entry.envelope.from)).await {
+					Ok(()) => {
+						info!("Spooled mail from {} delivered", entry.envelope.from);
+						true
+					},
+					Err(err) => {
+						warn!("Spooled mail from {} is still not delivered: {:?}", entry.envelope.from, err);
+						false
+					},
+				},
+			};
+			if done {
+				if let Err(err) = spool.remove(&entry) {
+					error!("Failed to remove spooled mail: {:?}", err);
+				}
+			}
+		}
+	}
+}
+
+/// Print what would happen to a message with specified envelope
+fn route_test(settings: &config::Config, from: &str, to: &[String], subject: Option<&str>, identity: Option<&str>) -> Result<()> {
+	let router = Router::new(settings);
+	for addr in to {
+		if !router.accepts(addr) {
+			println!("Rejected at RCPT with {} {}: {}", router.denial.code, router.denial.text, addr);
+		}
+	}
+	let routing = router.resolve(to, identity, &Content::new(from, subject.unwrap_or_default(), ""))?;
+	println!("Routes:");
+	for route in &routing.routes {
+		match route.profile.as_str() {
+			"" => println!("\t{}: {}", route.destination(), route.reasons.join(", ")),
+			profile => println!("\t{} (profile {}): {}", route.destination(), profile, route.reasons.join(", ")),
+		}
+	}
+	for note in &routing.notes {
+		println!("Note: {}", note);
+	}
+
+	let mut raw = format!("From: {}\r\nTo: {}\r\n", from, to.join(", "));
+	if let Some(subject) = subject {
+		raw.push_str(&format!("Subject: {}\r\n", subject));
+	}
+	raw.push_str("\r\n");
+	let mail = mail_parser::MessageParser::new().parse(raw.as_bytes())
+		.ok_or(anyhow!("Failed to parse test mail"))?;
+	let formats = Format::profiles(settings);
+	let mut profiles: Vec<&str> = routing.routes.iter().map(|route| route.profile.as_str()).collect();
+	profiles.sort();
+	profiles.dedup();
+	println!("trace_routes: {:?}", Trace::new(settings));
+	for profile in profiles {
+		let format = &formats[profile];
+		let outgoing = compose::compose(&mail, from, format)?;
+		match profile {
+			"" => println!("Options:"),
+			profile => println!("Options (profile {}):", profile),
+		}
+		println!("\tparse_mode: {:?}", format.markup);
+		println!("\tansi_colors: {:?}", format.colors);
+		println!("\tbody_preference: {:?}", format.body_preference);
+		println!("\tbody_limit: {:?}", format.body_limit);
+		println!("\tattachments_only: {:?}", format.attachments_only);
+		println!("\tignore_attachments: {:?}", format.ignore_attachments);
+		println!("\tpolls: {:?}", format.polls);
+		println!("\tlocations: {:?}", format.locations);
+		println!("\tplay_audio: {:?}", format.play_audio);
+		println!("\tplay_video: {:?}", format.play_video);
+		println!("\tinline_images: {:?}", format.inline_images);
+		println!("\tchecksums: {:?}", format.checksums);
+		println!("\ttext_first: {:?}", format.text_first);
+		println!("\tattach_html: {:?}", format.attach_html);
+		println!("\textra_text_parts: {:?}", format.extra_text_parts);
+		println!("\tlong_caption: {:?}", format.long_caption);
+		println!("\tfields: {:?}", format.fields);
+		println!("\tattachments_allow: {:?}", format.attachments_allow);
+		println!("\tattachments_deny: {:?}", format.attachments_deny);
+		println!("Text:");
+		for chunk in &outgoing.text_chunks {
+			println!("{}", chunk);
+		}
+	}
+	Ok(())
+}
+
+/// Print what would be sent for a saved mail
+fn render_test(settings: config::Config, file: &str, to: &[String]) -> Result<()> {
+	let data = std::fs::read(file)
+		.map_err(|err| anyhow!("Can't read {}: {}", file, err))?;
+	let mail = mail_parser::MessageParser::new().parse(&data)
+		.ok_or(anyhow!("Failed to parse {}", file))?;
+	let from = mail.from().and_then(|from| from.first())
+		.and_then(|addr| addr.address()).unwrap_or("").to_owned();
+	// without explicit envelope mail is routed by it's headers
+	let to: Vec<String> = match to.is_empty() {
+		true => mail.to().into_iter().chain(mail.cc())
+			.flat_map(|addrs| addrs.iter())
+			.filter_map(|addr| addr.address().map(str::to_owned))
+			.collect(),
+		false => to.to_vec(),
+	};
+	if to.is_empty() {
+		bail!("{} has no recipients, use \"--route\"", file);
+	}
+	let core = TelegramTransport::new(settings);
+	println!("Envelope: {} -> {}", from, to.join(", "));
+	for addr in &to {
+		if !core.router.accepts(addr) {
+			println!("Rejected at RCPT with {} {}: {}", core.router.denial.code, core.router.denial.text, addr);
+		}
+	}
+	let routing = core.resolve(&mail, &from, &to)?;
+	for note in &routing.notes {
+		println!("Note: {}", note);
+	}
+	if let Some(dmarc) = &core.dmarc {
+		for summary in dmarc.summaries(&mail) {
+			match summary {
+				Ok(summary) => println!("DMARC report summary:\n{}", summary),
+				Err(err) => println!("Note: {}", err),
+			};
+		}
+	}
+	let mut rendered: HashMap<(&str, Option<&[Field]>), OutgoingMessage> = HashMap::new();
+	for route in &routing.routes {
+		if let Entry::Vacant(entry) = rendered.entry(route.layout()) {
+			let outgoing = core.render(&mail, &from, route.layout())?;
+			for note in &outgoing.notes {
+				println!("Note: {}", note);
+			}
+			entry.insert(outgoing);
+		}
+	}
+	for route in &routing.routes {
+		let outgoing = match &route.tag {
+			Some(tag) => with_tag(&rendered[&route.layout()], tag),
+			None => rendered[&route.layout()].clone(),
+		};
+		let outgoing = match &route.emoji {
+			Some(emoji) => with_emoji(&outgoing, emoji),
+			None => outgoing,
+		};
+		let outgoing = match route.links.is_empty() {
+			true => outgoing,
+			false => with_links(&outgoing, &route.links),
+		};
+		let outgoing = match core.trace {
+			Trace::Footer => with_trace(&outgoing, route),
+			_ => outgoing,
+		}.encoded();
+		match route.profile.as_str() {
+			"" => println!("\nTo {}: {}", route.destination(), route.reasons.join(", ")),
+			profile => println!("\nTo {} (profile {}): {}", route.destination(), profile, route.reasons.join(", ")),
+		}
+		println!("Parse mode: {:?}", outgoing.options.parse_mode);
+		if outgoing.options.silent || route.silent.now() {
+			println!("Silent: yes");
+		}
+		if outgoing.options.text_first {
+			println!("Text first: files reply to it");
+		}
+		if let Some(poll) = &outgoing.poll {
+			println!("Poll: {}", poll.question);
+			for option in &poll.options {
+				println!("\t{}", option);
+			}
+		}
+		if let Some(caption) = &outgoing.options.caption {
+			println!("Caption:\n{}", caption);
+		}
+		for (index, chunk) in outgoing.text_chunks.iter().enumerate() {
+			println!("Text {}/{}:\n{}", index + 1, outgoing.text_chunks.len(), chunk);
+		}
+		if !outgoing.attachments.is_empty() {
+			println!("Attachments:");
+		}
+		for file in &outgoing.attachments {
+			match &file.preview {
+				Some(preview) => println!("\t{} ({:?}, {} bytes, preview {} bytes)", file.name, file.kind, file.data.len(), preview.len()),
+				None => println!("\t{} ({:?}, {} bytes)", file.name, file.kind, file.data.len()),
+			}
+		}
+		if let Some((latitude, longitude)) = outgoing.location {
+			println!("Location: {}, {}", latitude, longitude);
+		}
+	}
+	Ok(())
+}
+
+/// Read configuration from file, with defaults applied
+pub fn settings(file: &str) -> Result<config::Config, config::ConfigError> {
+	config::Config::builder()
+		.set_default("listen_on", "0.0.0.0:1025")?
+		.set_default("hostname", "smtp.2.tg")?
+		.set_default("unknown", "relay")?
+		.add_source(config::File::with_name(file))
+		.build()
+}
+
+/// Read configuration from environment: TOML from `SMTP2TG_CONFIG`, then
+/// single settings over it, like `SMTP2TG__API_KEY` for "api_key" or
+/// `SMTP2TG__SPF__ACTION` for "spf.action"
+pub fn env_settings() -> Result<config::Config, config::ConfigError> {
+	let mut builder = config::Config::builder()
+		.set_default("listen_on", "0.0.0.0:1025")?
+		.set_default("hostname", "smtp.2.tg")?
+		.set_default("unknown", "relay")?;
+	if let Ok(toml) = std::env::var("SMTP2TG_CONFIG") {
+		builder = builder.add_source(config::File::from_str(&toml, config::FileFormat::Toml));
+	}
+	builder
+		.add_source(config::Environment::with_prefix("SMTP2TG")
+			.prefix_separator("__")
+			.separator("__")
+			.try_parsing(true))
+		.build()
+}
+
+/// Commented example configuration, for installations from a bare binary
+pub const EXAMPLE_CONFIG: &str = include_str!("../smtp2tg.toml.example");
+
+/// Run command line, `args` don't include program name
+pub async fn run<I>(args: I) -> Result<()>
+where I: IntoIterator<Item = String> {
+	let command = cli::parse(args)?;
+	// these don't need configuration
+	match &command {
+		cli::Command::HashPassword => {
+			let mut password = String::new();
+			std::io::stdin().read_line(&mut password)?;
+			println!("{}", auth::hash(password.trim_end_matches(['\r', '\n']))?);
+			return Ok(());
+		},
+		cli::Command::DumpExample { output: Some(path) } => {
+			std::fs::write(path, EXAMPLE_CONFIG)?;
+			return Ok(());
+		},
+		cli::Command::DumpExample { output: None } => {
+			print!("{}", EXAMPLE_CONFIG);
+			return Ok(());
+		},
+		cli::Command::DescribeJson => {
+			println!("{}", describe::json()?);
+			return Ok(());
+		},
+		_ => {},
+	};
+	let settings = match command {
+		cli::Command::Container => {
+			logging::json();
+			env_settings()
+				.expect("[environment] there was an error reading config from SMTP2TG_CONFIG and SMTP2TG__* variables")
+		},
+		_ => settings("smtp2tg.toml")
+			.expect("[smtp2tg.toml] there was an error reading config\n\
+				\tplease consult \"smtp2tg.toml.example\" (\"smtp2tg --dump-example-config\" prints it) for details"),
+	};
+	logging::init(&settings)?;
+
+	match command {
+		cli::Command::Serve => serve(settings, Some("smtp2tg.toml")).await,
+		cli::Command::Container => {
+			http::serve(HEALTH_ADDR, |request| match (request.method.as_str(), request.path.as_str()) {
+				("GET", "/healthz") if server::stopping() => http::Reply::new(503, "Shutting down"),
+				("GET", "/healthz") => http::Reply::new(200, "OK"),
+				_ => http::Reply::new(404, "Not found"),
+			})?;
+			serve(settings, None).await
+		},
+		cli::Command::RouteTest { from, to, subject, identity } =>
+			route_test(&settings, &from, &to, subject.as_deref(), identity.as_deref()),
+		cli::Command::Render { file, to } => render_test(settings, &file, &to),
+		cli::Command::StateExport { output } => state::export(&settings, output.as_deref()),
+		cli::Command::StateImport { input } => state::import(&settings, &input),
+		cli::Command::HashPassword | cli::Command::DumpExample { .. } | cli::Command::DescribeJson => unreachable!(),
+	}
+}
+
+/// Listen addresses, either single one or array
+fn addresses(settings: &config::Config, name: &str) -> Result<Vec<String>> {
+	let value = match settings.get::<config::Value>(name) {
+		Ok(value) => value,
+		Err(config::ConfigError::NotFound(_)) => return Ok(vec![]),
+		Err(err) => bail!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err),
+	};
+	let addresses = match value.clone().into_array() {
+		Ok(list) => list.into_iter().map(|addr| addr.into_string()).collect::<Result<Vec<_>, _>>(),
+		Err(_) => value.into_string().map(|addr| vec![addr]),
+	}.map_err(|_| anyhow!("[smtp2tg.toml] \"{}\" should be an address or an array of them", name))?;
+	Ok(addresses)
+}
+
+/// Policies of listeners, policy for address we don't listen on is a mistake
+fn policies(settings: &config::Config, listeners: &[server::Listener]) -> Result<server::Policies> {
+	let mut policies = policy::policies(settings);
+	let max_size = policy::max_size(settings);
+	let found = listeners.iter()
+		.map(|listener| {
+			let mut policy = policies.remove(&listener.addr).unwrap_or_default();
+			// listener's "0" overrides global limit and lifts it
+			policy.max_size = policy.max_size.or(max_size).filter(|size| *size > 0);
+			(listener.addr.clone(), Arc::new(policy))
+		})
+		.collect();
+	if let Some(addr) = policies.keys().next() {
+		bail!("[smtp2tg.toml] policy for \"{}\" doesn't match any listen address", addr);
+	}
+	Ok(found)
+}
+
+/// Finish sessions in progress on SIGTERM and exit, mail that doesn't make it
+/// in time is not acknowledged so senders retry it
+fn terminate() -> Result<()> {
+	let term = Arc::new(AtomicBool::new(false));
+	let flag = term.clone();
+	// signal handler can't do much more than that
+	unsafe { signal_hook_registry::register(libc::SIGTERM, move || flag.store(true, Ordering::Relaxed)) }?;
+	task::spawn(async move {
+		while !term.load(Ordering::Relaxed) {
+			task::sleep(Duration::from_millis(200)).await;
+		}
+		info!("Stopping, waiting for sessions in progress");
+		match task::spawn_blocking(|| server::drain(SHUTDOWN_GRACE)).await {
+			true => info!("All sessions finished, exiting"),
+			false => warn!("Sessions still running after {}s, exiting anyway", SHUTDOWN_GRACE.as_secs()),
+		};
+		std::process::exit(0);
+	});
+	Ok(())
+}
+
+/// Re-read configuration on SIGHUP, sessions started after that use it
+fn watch(live: server::Live<TelegramTransport>, file: String, listeners: Vec<server::Listener>) -> Result<()> {
+	let hangup = Arc::new(AtomicBool::new(false));
+	let flag = hangup.clone();
+	// signal handler can't do much more than that
+	unsafe { signal_hook_registry::register(libc::SIGHUP, move || flag.store(true, Ordering::Relaxed)) }?;
+	task::spawn(async move {
+		loop {
+			task::sleep(Duration::from_secs(1)).await;
+			if hangup.swap(false, Ordering::Relaxed) {
+				reload(&live, &file, &listeners);
+			}
+		}
+	});
+	Ok(())
+}
+
+/// Swap configuration, bad one is logged and old one is kept
+fn reload(live: &server::Live<TelegramTransport>, file: &str, listeners: &[server::Listener]) {
+	info!("Reloading {}", file);
+	let settings = match settings(file) {
+		Ok(settings) => settings,
+		Err(err) => {
+			error!("Failed to read {}, keeping old configuration: {}", file, err);
+			return;
+		},
+	};
+	let core = live.read(TelegramTransport::clone);
+	// bad settings panic, that shouldn't take server down
+	let reloaded = panic::catch_unwind(AssertUnwindSafe(|| -> Result<_> {
+		Ok((core.reload(settings.clone()), policies(&settings, listeners)?))
+	}));
+	match reloaded {
+		Ok(Ok((core, policies))) => {
+			live.replace(core, policies);
+			info!("Configuration reloaded from {}", file);
+		},
+		Ok(Err(err)) => error!("Failed to reload {}, keeping old configuration: {}", file, err),
+		Err(_) => error!("Failed to reload {}, keeping old configuration", file),
+	}
+}
+
+/// Start syslog, listeners and background tasks, then accept mail until
+/// server stops. Configuration is re-read from `reload` file on SIGHUP, when
+/// there's one. Logging should be started already
+pub async fn serve(settings: config::Config, reload: Option<&str>) -> Result<()> {
+	logging::syslog(&settings)?;
+
+	let mut listeners: Vec<server::Listener> = addresses(&settings, "listen_on")?.into_iter()
+		.map(|addr| server::Listener {
+			addr,
+			tls: false,
+		})
+		.collect();
+	listeners.extend(addresses(&settings, "listen_on_tls")?.into_iter()
+		.map(|addr| server::Listener {
+			addr,
+			tls: true,
+		}));
+	if listeners.is_empty() {
+		bail!("[smtp2tg.toml] \"listen_on\" and \"listen_on_tls\" are both empty");
+	}
+	let policies = policies(&settings, &listeners)?;
+	let (tls, starttls) = match (settings.get_string("tls.cert"), settings.get_string("tls.key")) {
+		(Ok(cert), Ok(key)) => {
+			let clients = match settings.get_string("tls.client_ca") {
+				Ok(ca) => Some(server::ClientAuth {
+					ca,
+					required: match settings.get_bool("tls.require_client_cert") {
+						Ok(required) => required,
+						Err(config::ConfigError::NotFound(_)) => true,
+						Err(err) => bail!("[smtp2tg.toml] can't get \"tls.require_client_cert\":\n {}", err),
+					},
+				}),
+				Err(config::ConfigError::NotFound(_)) => None,
+				Err(err) => bail!("[smtp2tg.toml] can't get \"tls.client_ca\":\n {}", err),
+			};
+			// client certificates are only asked for on TLS listener
+			(Some(server::tls_config(&cert, &key, clients)?), Some(server::tls_config(&cert, &key, None)?))
+		},
+		(Err(_), Err(_)) => (None, None),
+		_ => bail!("[smtp2tg.toml] \"tls\" table needs both \"cert\" and \"key\""),
+	};
+	let server_name = settings.get_string("hostname")?;
+	let vrfy = match settings.get_bool("vrfy") {
+		Ok(vrfy) => vrfy,
+		Err(config::ConfigError::NotFound(_)) => true,
+		Err(err) => bail!("[smtp2tg.toml] can't get \"vrfy\":\n {}", err),
+	};
+	let pregreet = match settings.get_int("pregreet") {
+		Ok(delay) if delay > 0 => Some(Duration::from_millis(delay as u64)),
+		Ok(_) | Err(config::ConfigError::NotFound(_)) => None,
+		Err(err) => bail!("[smtp2tg.toml] can't get \"pregreet\":\n {}", err),
+	};
+	let max_connections = match settings.get_int("max_connections") {
+		Ok(max) if max > 0 => Some(max as usize),
+		Ok(_) | Err(config::ConfigError::NotFound(_)) => None,
+		Err(err) => bail!("[smtp2tg.toml] can't get \"max_connections\":\n {}", err),
+	};
+	// TOML has octal integers, but "0660" is what people are used to
+	let socket_mode = match settings.get::<config::Value>("socket_mode") {
+		Ok(value) => Some(match value.kind {
+				config::ValueKind::String(mode) => i64::from_str_radix(&mode, 8).ok(),
+				_ => value.into_int().ok(),
+			}
+			.and_then(|mode| u32::try_from(mode).ok())
+			.filter(|mode| *mode <= 0o7777)
+			.ok_or(anyhow!("[smtp2tg.toml] \"socket_mode\" should be permissions like \"0660\""))?),
+		Err(config::ConfigError::NotFound(_)) => None,
+		Err(err) => bail!("[smtp2tg.toml] can't get \"socket_mode\":\n {}", err),
+	};
+	let updates = updates::Mode::new(&settings)?;
+	let leader = leader::Leader::new(&settings);
+	leader.start();
+	let core = TelegramTransport::new(settings);
+	let live = server::Live::new(core.clone(), policies);
+	updates::start(core.tg.clone(), updates, leader, Arc::new(live.clone())).await?;
+	terminate()?;
+	if let Some(file) = reload {
+		watch(live.clone(), file.to_owned(), listeners.clone())?;
+	}
+	if let Some(spool) = core.spool.clone() {
+		let live = live.clone();
+		thread::spawn(move || task::block_on(drain(live, spool)));
+	}
+	let mut server = server::Server::new(&server_name, live.clone(), tls);
+	if vrfy {
+		server = server.with_directory(Arc::new(live.clone()));
+	}
+	if let Some(config) = starttls {
+		server = server.with_starttls(config);
+	}
+	if let Some(delay) = pregreet {
+		server = server.with_pregreet(delay);
+	}
+	if let Some(max) = max_connections {
+		server = server.with_max_connections(max);
+	}
+	if let Some(mode) = socket_mode {
+		server = server.with_socket_mode(mode);
+	}
+	if core.access.is_some() || core.geoip.is_some() {
+		server = server.with_screen(Arc::new(live));
+	}
+	if core.credentials.is_some() {
+		server = server.with_auth();
+	}
+	server.serve(&listeners)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use mailin::Handler;
+	use std::{
+		cell::RefCell,
+		net::Ipv4Addr,
+		rc::Rc,
+	};
+
+	/// Recipients and data of finished transaction
+	type Captured = (Vec<String>, String);
+
+	/// Forwards everything to transport but captures transaction instead of
+	/// relaying it
+	struct Probe {
+		core: Rc<RefCell<TelegramTransport>>,
+		seen: Rc<RefCell<Vec<Captured>>>,
+	}
+
+	impl Handler for Probe {
+		fn mail(&mut self, ip: IpAddr, domain: &str, from: &str) -> Response {
+			self.core.borrow_mut().mail(ip, domain, from)
+		}
+
+		fn rcpt(&mut self, to: &str) -> Response {
+			self.core.borrow_mut().rcpt(to)
+		}
+
+		fn data_start(&mut self, domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+			self.core.borrow_mut().data_start(domain, from, is8bit, to)
+		}
+
+		fn data(&mut self, buf: &[u8]) -> Result<(), Error> {
+			self.core.borrow_mut().data(buf)
+		}
+
+		fn data_end(&mut self) -> Response {
+			let mut core = self.core.borrow_mut();
+			let to = core.headers.as_ref().map(|headers| headers.to.clone()).unwrap_or_default();
+			self.seen.borrow_mut().push((to, String::from_utf8_lossy(&core.data).into_owned()));
+			core.reset();
+			OK
+		}
+	}
+
+	fn transport() -> TelegramTransport {
+		let settings = config::Config::builder()
+			.set_default("unknown", "relay").unwrap()
+			.add_source(config::File::from_str("api_key = \"1:test\"\n[recipients]\n_ = 1\n", config::FileFormat::Toml))
+			.build().unwrap();
+		TelegramTransport::new(settings)
+	}
+
+	/// Feed lines to one session, returning transport and captured transactions
+	fn drive(lines: &[&str]) -> (Rc<RefCell<TelegramTransport>>, Vec<Captured>) {
+		let core = Rc::new(RefCell::new(transport()));
+		let seen = Rc::new(RefCell::new(vec![]));
+		let probe = Probe {
+			core: core.clone(),
+			seen: seen.clone(),
+		};
+		let mut session = mailin::SessionBuilder::new("test").build(IpAddr::V4(Ipv4Addr::LOCALHOST), probe);
+		for line in lines {
+			session.process(format!("{}\r\n", line).as_bytes());
+		}
+		let seen = seen.borrow().clone();
+		(core, seen)
+	}
+
+	#[async_std::test]
+	async fn transactions_dont_share_state() {
+		let (core, seen) = drive(&[
+			"EHLO client",
+			"MAIL FROM:<a@host>", "RCPT TO:<one@host>", "DATA", "Subject: one", "", "first", ".",
+			"MAIL FROM:<b@host>", "RCPT TO:<two@host>", "DATA", "Subject: two", "", "second", ".",
+		]);
+		assert_eq!(seen.len(), 2);
+		assert_eq!(seen[0].0, vec!["one@host".to_string()]);
+		assert!(seen[0].1.contains("first"));
+		assert_eq!(seen[1].0, vec!["two@host".to_string()]);
+		assert!(seen[1].1.contains("second"));
+		assert!(!seen[1].1.contains("first"));
+		assert!(core.borrow().data.is_empty());
+		assert!(core.borrow().headers.is_none());
+	}
+
+	#[async_std::test]
+	async fn rset_drops_recipients() {
+		let (_, seen) = drive(&[
+			"EHLO client",
+			"MAIL FROM:<a@host>", "RCPT TO:<one@host>", "RSET",
+			"MAIL FROM:<b@host>", "RCPT TO:<two@host>", "DATA", "", "body", ".",
+		]);
+		assert_eq!(seen.len(), 1);
+		assert_eq!(seen[0].0, vec!["two@host".to_string()]);
+	}
+
+	#[async_std::test]
+	async fn mail_resets_leftovers() {
+		let mut core = transport();
+		core.data_start("client", "a@host", false, &["one@host".into()]);
+		core.data(b"stale").unwrap();
+		core.mail(IpAddr::V4(Ipv4Addr::LOCALHOST), "client", "b@host");
+		assert!(core.data.is_empty());
+		assert!(core.headers.is_none());
+	}
+}
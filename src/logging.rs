@@ -0,0 +1,399 @@
+//! Runtime log. Events come through `tracing`, with spans per SMTP session
+//! written in front of them, and are filtered by `log_level`. Everything goes
+//! to stderr, or to stdout as JSON lines in container mode, and also to syslog
+//! when `syslog` is set to "udp://host:port", "tcp://host:port" or
+//! "unix:///dev/log".
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+
+use tracing::{
+	field::{
+		Field,
+		Visit,
+	},
+	span,
+	Event,
+	Level,
+	Metadata,
+	Subscriber,
+};
+
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	fmt::{
+		self,
+		Write as _,
+	},
+	fs,
+	io::Write,
+	net::{
+		TcpStream,
+		UdpSocket,
+	},
+	os::unix::net::UnixDatagram,
+	sync::{
+		atomic::{
+			AtomicBool,
+			AtomicU64,
+			Ordering,
+		},
+		Mutex,
+		OnceLock,
+	},
+	time::SystemTime,
+};
+
+use crate::stats;
+
+/// Log an error
+macro_rules! error {
+	($($arg:tt)*) => { ::tracing::error!($($arg)*) };
+}
+
+/// Log a warning
+macro_rules! warn {
+	($($arg:tt)*) => { ::tracing::warn!($($arg)*) };
+}
+
+/// Log an informational message
+macro_rules! info {
+	($($arg:tt)*) => { ::tracing::info!($($arg)*) };
+}
+
+/// Log details only needed to diagnose problems
+macro_rules! debug {
+	($($arg:tt)*) => { ::tracing::debug!($($arg)*) };
+}
+
+/// Syslog severities we use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+	Error = 3,
+	Warning = 4,
+	Info = 6,
+	Debug = 7,
+}
+
+impl Severity {
+	fn name(self) -> &'static str {
+		match self {
+			Severity::Error => "error",
+			Severity::Warning => "warning",
+			Severity::Info => "info",
+			Severity::Debug => "debug",
+		}
+	}
+}
+
+impl From<&Level> for Severity {
+	fn from(level: &Level) -> Severity {
+		match *level {
+			Level::ERROR => Severity::Error,
+			Level::WARN => Severity::Warning,
+			Level::INFO => Severity::Info,
+			_ => Severity::Debug,
+		}
+	}
+}
+
+/// Syslog facilities by name
+const FACILITIES: &[(&str, u8)] = &[
+	("user", 1),
+	("mail", 2),
+	("daemon", 3),
+	("local0", 16),
+	("local1", 17),
+	("local2", 18),
+	("local3", 19),
+	("local4", 20),
+	("local5", 21),
+	("local6", 22),
+	("local7", 23),
+];
+
+enum Transport {
+	Udp(UdpSocket),
+	/// Connection is reopened when it breaks
+	Tcp(String, Option<TcpStream>),
+	Unix(UnixDatagram, String),
+}
+
+/// `Syslog` sends lines to syslog server
+struct Syslog {
+	facility: u8,
+	host: String,
+	transport: Mutex<Transport>,
+}
+
+static SYSLOG: OnceLock<Syslog> = OnceLock::new();
+
+/// Lines go to stdout as JSON objects instead of stderr
+static JSON: AtomicBool = AtomicBool::new(false);
+
+impl Syslog {
+	fn new(target: &str, facility: &str) -> Result<Syslog> {
+		let facility = FACILITIES.iter()
+			.find(|(name, _)| *name == facility)
+			.map(|(_, code)| *code)
+			.ok_or(anyhow!("[smtp2tg.toml] unknown \"syslog_facility\" \"{}\"", facility))?;
+		let transport = match target.split_once("://") {
+			Some(("udp", addr)) => {
+				let socket = UdpSocket::bind("0.0.0.0:0")?;
+				socket.connect(addr)?;
+				Transport::Udp(socket)
+			},
+			Some(("tcp", addr)) => Transport::Tcp(addr.to_owned(), None),
+			Some(("unix", path)) => Transport::Unix(UnixDatagram::unbound()?, path.to_owned()),
+			_ => bail!("[smtp2tg.toml] \"syslog\" should look like \"udp://host:514\", \"tcp://host:514\" or \"unix:///dev/log\""),
+		};
+		let host = fs::read_to_string("/etc/hostname").unwrap_or_default();
+		Ok(Syslog {
+			facility,
+			host: host.trim().to_owned(),
+			transport: Mutex::new(transport),
+		})
+	}
+
+	fn send(&self, severity: Severity, text: &str) -> Result<()> {
+		let priority = self.facility as u32 * 8 + severity as u32;
+		let mut transport = self.transport.lock().unwrap();
+		match &mut *transport {
+			// local daemon stamps messages itself
+			Transport::Unix(socket, path) => {
+				socket.send_to(format!("<{}>smtp2tg[{}]: {}", priority, std::process::id(), text).as_bytes(), path.as_str())?;
+			},
+			Transport::Udp(socket) => {
+				socket.send(self.line(priority, text).as_bytes())?;
+			},
+			Transport::Tcp(addr, stream) => {
+				let mut line = self.line(priority, text);
+				line.push('\n');
+				if stream.is_none() {
+					*stream = Some(TcpStream::connect(addr.as_str())?);
+				}
+				if let Some(connection) = stream {
+					if let Err(err) = connection.write_all(line.as_bytes()) {
+						*stream = None;
+						return Err(err.into());
+					}
+				}
+			},
+		};
+		Ok(())
+	}
+
+	/// RFC 5424 message
+	fn line(&self, priority: u32, text: &str) -> String {
+		format!("<{}>1 {} {} smtp2tg {} - - {}", priority, now(),
+			if self.host.is_empty() { "-" } else { &self.host }, std::process::id(), text)
+	}
+}
+
+/// Current time like "2024-01-31T23:59:59Z"
+fn now() -> String {
+	let stamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+		.map_or(0, |stamp| stamp.as_secs());
+	let (year, month, day) = stats::civil(stamp);
+	let time = stamp % 86400;
+	format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// `Fields` are values recorded for event or span, message is kept apart
+#[derive(Default)]
+struct Fields {
+	message: String,
+	rest: Vec<String>,
+}
+
+impl Visit for Fields {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		match field.name() {
+			"message" => { let _ = write!(self.message, "{:?}", value); },
+			name => self.rest.push(format!("{}={:?}", name, value)),
+		};
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		match field.name() {
+			"message" => self.message.push_str(value),
+			name => self.rest.push(format!("{}={}", name, value)),
+		};
+	}
+}
+
+/// `Span` is what we write about open span
+struct Span {
+	name: &'static str,
+	fields: Vec<String>,
+	/// Handles still referring to span
+	refs: usize,
+}
+
+impl fmt::Display for Span {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.fields.is_empty() {
+			true => write!(f, "{}", self.name),
+			false => write!(f, "{}{{{}}}", self.name, self.fields.join(" ")),
+		}
+	}
+}
+
+thread_local! {
+	/// Spans entered on this thread, innermost last
+	static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(vec![]) };
+}
+
+/// `Logger` writes events along with spans they happened in
+struct Logger {
+	level: Level,
+	next: AtomicU64,
+	spans: Mutex<HashMap<u64, Span>>,
+}
+
+impl Subscriber for Logger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		let ours = metadata.target().starts_with("smtp2tg");
+		match metadata.is_span() {
+			true => ours,
+			// other crates are only heard when something goes wrong
+			false => *metadata.level() <= self.level && (ours || *metadata.level() <= Level::WARN),
+		}
+	}
+
+	fn new_span(&self, attributes: &span::Attributes) -> span::Id {
+		let mut fields = Fields::default();
+		attributes.record(&mut fields);
+		let id = self.next.fetch_add(1, Ordering::Relaxed);
+		self.spans.lock().unwrap().insert(id, Span {
+			name: attributes.metadata().name(),
+			fields: fields.rest,
+			refs: 1,
+		});
+		span::Id::from_u64(id)
+	}
+
+	fn record(&self, span: &span::Id, values: &span::Record) {
+		let mut fields = Fields::default();
+		values.record(&mut fields);
+		if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+			span.fields.append(&mut fields.rest);
+		}
+	}
+
+	fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+	fn event(&self, event: &Event) {
+		let mut fields = Fields::default();
+		event.record(&mut fields);
+		let mut text = String::new();
+		{
+			let spans = self.spans.lock().unwrap();
+			ENTERED.with(|entered| for span in entered.borrow().iter().filter_map(|id| spans.get(id)) {
+				let _ = write!(text, "{}: ", span);
+			});
+		}
+		text.push_str(&fields.message);
+		for field in fields.rest {
+			text.push(' ');
+			text.push_str(&field);
+		}
+		write(event.metadata().level().into(), text);
+	}
+
+	fn enter(&self, span: &span::Id) {
+		ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+	}
+
+	fn exit(&self, span: &span::Id) {
+		ENTERED.with(|entered| {
+			let mut entered = entered.borrow_mut();
+			if let Some(index) = entered.iter().rposition(|id| *id == span.into_u64()) {
+				entered.remove(index);
+			}
+		});
+	}
+
+	fn clone_span(&self, span: &span::Id) -> span::Id {
+		if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+			span.refs += 1;
+		}
+		span.clone()
+	}
+
+	fn try_close(&self, span: span::Id) -> bool {
+		let mut spans = self.spans.lock().unwrap();
+		let Some(entry) = spans.get_mut(&span.into_u64()) else {
+			return false;
+		};
+		entry.refs -= 1;
+		if entry.refs > 0 {
+			return false;
+		}
+		spans.remove(&span.into_u64());
+		true
+	}
+}
+
+/// Read `log_level` and start logging. Daemon embedding us can set up it's
+/// own subscriber first, then this only warns about it
+pub fn init(settings: &config::Config) -> Result<()> {
+	let level = match settings.get_string("log_level") {
+		Ok(level) => level.parse().map_err(|_| anyhow!("[smtp2tg.toml] \"log_level\" should be \"error\", \"warn\", \"info\", \"debug\" or \"trace\""))?,
+		Err(config::ConfigError::NotFound(_)) => Level::INFO,
+		Err(err) => bail!("[smtp2tg.toml] can't get \"log_level\":\n {}", err),
+	};
+	let logger = Logger {
+		level,
+		next: AtomicU64::new(1),
+		spans: Mutex::default(),
+	};
+	if tracing::subscriber::set_global_default(logger).is_err() {
+		warn!("Logging is already set up, \"log_level\" is ignored");
+	}
+	Ok(())
+}
+
+/// Read `syslog` and `syslog_facility`, logging only goes to stderr without
+/// them
+pub fn syslog(settings: &config::Config) -> Result<()> {
+	let target = match settings.get_string("syslog") {
+		Ok(target) => target,
+		Err(config::ConfigError::NotFound(_)) => return Ok(()),
+		Err(err) => bail!("[smtp2tg.toml] can't get \"syslog\":\n {}", err),
+	};
+	let facility = match settings.get_string("syslog_facility") {
+		Ok(facility) => facility,
+		Err(config::ConfigError::NotFound(_)) => "mail".into(),
+		Err(err) => bail!("[smtp2tg.toml] can't get \"syslog_facility\":\n {}", err),
+	};
+	let _ = SYSLOG.set(Syslog::new(&target, &facility)?);
+	Ok(())
+}
+
+/// Write lines to stdout as JSON, for container log collectors
+pub fn json() {
+	JSON.store(true, Ordering::Relaxed);
+}
+
+/// Write a line to stderr or stdout, and to syslog
+fn write(severity: Severity, text: String) {
+	match JSON.load(Ordering::Relaxed) {
+		true => println!("{}", serde_json::json!({
+			"time": now(),
+			"level": severity.name(),
+			"message": text,
+		})),
+		false => eprintln!("{}", text),
+	};
+	if let Some(syslog) = SYSLOG.get() {
+		// syslog wants single line messages
+		if let Err(err) = syslog.send(severity, &text.replace('\n', " ")) {
+			eprintln!("Failed to write to syslog: {}", err);
+		}
+	}
+}
@@ -0,0 +1,114 @@
+//! Delivery order per chat. Sessions deliver concurrently and wait out
+//! retries, so mail to the same chat could overtake one another. Every
+//! transaction takes it's place in queues of all it's chats at once, as soon
+//! as routes are known, and delivers to a chat only when transactions before
+//! it are done with it.
+
+use async_std::channel::{
+	self,
+	Receiver,
+	Sender,
+};
+use teloxide::types::ChatId;
+
+use std::{
+	collections::{
+		HashMap,
+		VecDeque,
+	},
+	sync::{
+		Arc,
+		Mutex,
+	},
+};
+
+/// Chats are only in map while some transaction has their turn, others wait
+/// in line
+type Queues = HashMap<ChatId, VecDeque<Sender<()>>>;
+
+/// `Order` holds queues of all chats
+#[derive(Clone, Default)]
+pub struct Order {
+	queues: Arc<Mutex<Queues>>,
+}
+
+impl Order {
+	/// Take places in queues of chats
+	pub fn enqueue(&self, chats: impl IntoIterator<Item = ChatId>) -> Tickets {
+		let mut queues = self.queues.lock().unwrap();
+		let mut places = HashMap::new();
+		for chat in chats {
+			if places.contains_key(&chat) {
+				continue;
+			}
+			let place = match queues.get_mut(&chat) {
+				Some(waiting) => {
+					let (sender, receiver) = channel::bounded(1);
+					waiting.push_back(sender);
+					Place::Waiting(receiver)
+				},
+				None => {
+					queues.insert(chat, VecDeque::new());
+					Place::Turn
+				},
+			};
+			places.insert(chat, place);
+		}
+		Tickets {
+			order: self.clone(),
+			places,
+		}
+	}
+}
+
+/// Pass turn to next transaction in line, ones that are gone are skipped
+fn pass(queues: &mut Queues, chat: ChatId) {
+	let Some(waiting) = queues.get_mut(&chat) else {
+		return;
+	};
+	while let Some(next) = waiting.pop_front() {
+		if next.try_send(()).is_ok() {
+			return;
+		}
+	}
+	queues.remove(&chat);
+}
+
+/// `Place` of transaction in a chat queue
+enum Place {
+	Turn,
+	/// Gets a message when transaction before is done
+	Waiting(Receiver<()>),
+}
+
+/// `Tickets` are places of a transaction, they are given up when dropped
+pub struct Tickets {
+	order: Order,
+	places: HashMap<ChatId, Place>,
+}
+
+impl Tickets {
+	/// Wait until transactions before are done with chat
+	pub async fn wait(&mut self, chat: ChatId) {
+		if let Some(Place::Waiting(receiver)) = self.places.get(&chat) {
+			// sender is kept in queue until it's our turn
+			let _ = receiver.recv().await;
+			self.places.insert(chat, Place::Turn);
+		}
+	}
+}
+
+impl Drop for Tickets {
+	fn drop(&mut self) {
+		let mut queues = self.order.queues.lock().unwrap();
+		for (chat, place) in self.places.drain() {
+			match place {
+				Place::Turn => pass(&mut queues, chat),
+				// turn could have come while we were not waiting
+				Place::Waiting(receiver) => if receiver.try_recv().is_ok() {
+					pass(&mut queues, chat);
+				},
+			};
+		}
+	}
+}
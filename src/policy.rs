@@ -0,0 +1,112 @@
+//! Per-listener policies, so loopback listener can stay permissive while the
+//! public one is locked down. Policies are `[policies."<listen address>"]`
+//! tables.
+
+use std::collections::HashMap;
+
+use crate::uploads;
+
+/// `Policy` is what clients of a listener may do, default allows everything
+/// global settings allow
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Policy {
+	/// Reject mail until client authenticates
+	pub auth_required: bool,
+	/// Accept mail for unknown recipients, global `unknown` setting applies
+	/// when unset
+	pub relay: Option<bool>,
+	/// Largest message in bytes, global `max_size` applies when unset, zero
+	/// lifts the limit
+	pub max_size: Option<usize>,
+	/// Envelope senders allowed, either addresses or "@domain", empty list
+	/// allows everyone
+	pub senders: Vec<String>,
+	/// Always answer 250 and handle failures ourselves, for devices giving up
+	/// after any error
+	pub never_reject: bool,
+	/// Offer SMTP AUTH before TLS is started, for devices without TLS
+	pub plaintext_auth: bool,
+}
+
+impl Policy {
+	fn new(addr: &str, table: HashMap<String, config::Value>) -> Policy {
+		let mut policy = Policy::default();
+		for (key, value) in table {
+			let name = format!("policies.\"{}\".{}", addr, key);
+			match key.as_str() {
+				"auth_required" => policy.auth_required = value.into_bool().unwrap_or_else(|err| {
+					error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+					panic!("bad setting");
+				}),
+				"relay" => policy.relay = Some(value.into_bool().unwrap_or_else(|err| {
+					error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+					panic!("bad setting");
+				})),
+				"never_reject" => policy.never_reject = value.into_bool().unwrap_or_else(|err| {
+					error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+					panic!("bad setting");
+				}),
+				"plaintext_auth" => policy.plaintext_auth = value.into_bool().unwrap_or_else(|err| {
+					error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+					panic!("bad setting");
+				}),
+				"max_size" => policy.max_size = Some(uploads::size(value, &name) as usize),
+				"senders" => policy.senders = value.into_array().unwrap_or_else(|err| {
+					error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+					panic!("bad setting");
+				}).into_iter().map(|sender| sender.into_string()
+					.unwrap_or_else(|_| {
+						error!("[smtp2tg.toml] \"{}\" should list strings.", name);
+						panic!("bad setting");
+					}).to_lowercase())
+					.collect(),
+				_ => {
+					error!("[smtp2tg.toml] unknown key \"{}\".", name);
+					panic!("bad setting");
+				},
+			};
+		}
+		policy
+	}
+
+	/// Check whether envelope sender may use this listener
+	pub fn allows_sender(&self, from: &str) -> bool {
+		let from = from.to_lowercase();
+		self.senders.is_empty() || self.senders.iter().any(|sender| match sender.starts_with('@') {
+			true => from.ends_with(sender.as_str()),
+			false => from == *sender,
+		})
+	}
+}
+
+/// Read global `max_size`, default keeps single mail from eating all memory,
+/// "0" lifts the limit
+pub fn max_size(settings: &config::Config) -> Option<usize> {
+	match settings.get::<config::Value>("max_size") {
+		Ok(value) => Some(uploads::size(value, "max_size") as usize).filter(|max| *max > 0),
+		Err(config::ConfigError::NotFound(_)) => Some(100 << 20),
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"max_size\":\n {}", err);
+			panic!("bad setting");
+		},
+	}
+}
+
+/// Read `[policies]`, keyed by listen address
+pub fn policies(settings: &config::Config) -> HashMap<String, Policy> {
+	match settings.get_table("policies") {
+		Ok(policies) => policies.into_iter().map(|(addr, value)| {
+			let table = value.into_table().unwrap_or_else(|_| {
+				error!("[smtp2tg.toml] \"policies.\"{}\"\" should be a table.", addr);
+				panic!("bad setting");
+			});
+			let policy = Policy::new(&addr, table);
+			(addr, policy)
+		}).collect(),
+		Err(config::ConfigError::NotFound(_)) => HashMap::new(),
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"policies\":\n {}", err);
+			panic!("bad setting");
+		},
+	}
+}
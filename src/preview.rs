@@ -0,0 +1,100 @@
+//! First page previews for PDF attachments. Rendering is done by external
+//! tool that reads PDF on stdin and writes image to stdout.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+
+use std::{
+	io::{
+		Read,
+		Write,
+	},
+	process::{
+		Command,
+		Stdio,
+	},
+	thread,
+	time::{
+		Duration,
+		Instant,
+	},
+};
+
+/// How long renderer may run
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Largest image Telegram accepts as photo
+const PHOTO_LIMIT: usize = 10 * 1024 * 1024;
+
+/// `Previewer` runs configured renderer
+#[derive(Clone, Debug)]
+pub struct Previewer {
+	command: Vec<String>,
+}
+
+impl Previewer {
+	/// Read `pdf_preview` command, previews are off when it's unset
+	pub fn new(settings: &config::Config) -> Option<Previewer> {
+		match settings.get_array("pdf_preview") {
+			Ok(command) => {
+				let command: Vec<String> = command.into_iter().map(|arg| arg.into_string()
+					.expect("[smtp2tg.toml] \"pdf_preview\" should list strings.\n"))
+					.collect();
+				if command.is_empty() {
+					error!("[smtp2tg.toml] \"pdf_preview\" should have at least a program name.");
+					panic!("bad setting");
+				}
+				Some(Previewer { command })
+			},
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"pdf_preview\":\n {}", err);
+				panic!("bad setting");
+			},
+		}
+	}
+
+	/// Render first page of PDF into image
+	pub fn render(&self, pdf: &[u8]) -> Result<Vec<u8>> {
+		let mut child = Command::new(&self.command[0])
+			.args(&self.command[1..])
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()
+			.map_err(|err| anyhow!("Can't run {}: {}", self.command[0], err))?;
+		// feed and drain in threads so neither pipe can block us
+		let mut stdin = child.stdin.take().ok_or(anyhow!("No stdin for renderer"))?;
+		let pdf = pdf.to_vec();
+		let writer = thread::spawn(move || stdin.write_all(&pdf));
+		let mut stdout = child.stdout.take().ok_or(anyhow!("No stdout for renderer"))?;
+		let reader = thread::spawn(move || {
+			let mut image = vec![];
+			stdout.read_to_end(&mut image).map(|_| image)
+		});
+		let started = Instant::now();
+		let status = loop {
+			if let Some(status) = child.try_wait()? {
+				break status;
+			}
+			if started.elapsed() > TIMEOUT {
+				child.kill()?;
+				child.wait()?;
+				bail!("Renderer timed out");
+			}
+			thread::sleep(Duration::from_millis(50));
+		};
+		// renderer may stop reading after first page, that's fine
+		let _ = writer.join();
+		let image = reader.join().map_err(|_| anyhow!("Reader thread panicked"))??;
+		if !status.success() {
+			bail!("Renderer failed with {}", status);
+		}
+		if image.is_empty() || image.len() > PHOTO_LIMIT {
+			bail!("Renderer produced {} bytes", image.len());
+		}
+		Ok(image)
+	}
+}
@@ -0,0 +1,62 @@
+//! Retries of Telegram requests that failed for a moment, so network blips
+//! don't bounce mail. Delay is doubled after every attempt, except for flood
+//! control where Telegram tells how long to wait.
+
+use anyhow::{
+	bail,
+	Result,
+};
+
+use std::time::Duration;
+
+/// `Retry` is how hard we try before giving up on request
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+	/// Attempts of every request, 1 means no retries
+	pub attempts: u32,
+	/// Delay before first retry
+	delay: Duration,
+	/// Longest flood control wait per request
+	pub max_wait: Duration,
+}
+
+impl Default for Retry {
+	fn default() -> Retry {
+		Retry {
+			attempts: 3,
+			delay: Duration::from_secs(1),
+			max_wait: Duration::from_secs(300),
+		}
+	}
+}
+
+impl Retry {
+	/// Read `[retry]` table
+	pub fn new(settings: &config::Config) -> Result<Retry> {
+		let mut retry = Retry::default();
+		match settings.get_int("retry.attempts") {
+			Ok(attempts) if attempts >= 1 => retry.attempts = attempts as u32,
+			Ok(_) => bail!("[smtp2tg.toml] \"retry.attempts\" should be at least 1"),
+			Err(config::ConfigError::NotFound(_)) => {},
+			Err(err) => bail!("[smtp2tg.toml] can't get \"retry.attempts\":\n {}", err),
+		};
+		match settings.get_int("retry.delay_ms") {
+			Ok(ms) if ms >= 0 => retry.delay = Duration::from_millis(ms as u64),
+			Ok(_) => bail!("[smtp2tg.toml] \"retry.delay_ms\" can't be negative"),
+			Err(config::ConfigError::NotFound(_)) => {},
+			Err(err) => bail!("[smtp2tg.toml] can't get \"retry.delay_ms\":\n {}", err),
+		};
+		match settings.get_int("retry.max_wait") {
+			Ok(seconds) if seconds >= 0 => retry.max_wait = Duration::from_secs(seconds as u64),
+			Ok(_) => bail!("[smtp2tg.toml] \"retry.max_wait\" can't be negative"),
+			Err(config::ConfigError::NotFound(_)) => {},
+			Err(err) => bail!("[smtp2tg.toml] can't get \"retry.max_wait\":\n {}", err),
+		};
+		Ok(retry)
+	}
+
+	/// Delay after failed attempt, counting from 1
+	pub fn delay(&self, attempt: u32) -> Duration {
+		self.delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+	}
+}
@@ -0,0 +1,1243 @@
+//! Recipient resolution: decides which chats should get the mail and keeps
+//! track of why each of them was selected.
+
+use anyhow::{
+	bail,
+	Result,
+};
+use regex::{
+	Regex,
+	RegexBuilder,
+};
+use serde::{
+	Deserialize,
+	Serialize,
+};
+use teloxide::{
+	types::{
+		ChatId,
+		MessageId,
+		ThreadId,
+	},
+	utils::markdown,
+};
+
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+};
+
+use crate::{
+	compose::Field,
+	server::Directory,
+};
+
+/// `Priority` sets which deliveries go first when there's a backlog
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+	High,
+	#[default]
+	Normal,
+	Low,
+}
+
+/// `Silent` sets when messages are sent without notification
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Silent {
+	#[default]
+	Never,
+	Always,
+	/// Minutes since midnight UTC, window can pass midnight
+	Between(u16, u16),
+}
+
+impl Silent {
+	/// Read either boolean or window like "22:00-08:00"
+	fn new(value: config::Value, name: &str, addr: &str) -> Silent {
+		if let Ok(silent) = value.clone().into_bool() {
+			return match silent {
+				true => Silent::Always,
+				false => Silent::Never,
+			};
+		}
+		let minutes = |time: &str| time.trim().split_once(':')
+			.and_then(|(hour, minute)| Some((hour.parse::<u16>().ok()?, minute.parse::<u16>().ok()?)))
+			.filter(|&(hour, minute)| hour < 24 && minute < 60)
+			.map(|(hour, minute)| hour * 60 + minute);
+		value.into_string().ok()
+			.and_then(|window| {
+				let (start, end) = window.split_once('-')?;
+				Some(Silent::Between(minutes(start)?, minutes(end)?))
+			})
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] \"{}.{}.silent\" should be a boolean or UTC time window like \"22:00-08:00\".\n", name, addr))
+	}
+
+	/// Whether message sent now should be silent
+	pub fn now(&self) -> bool {
+		match *self {
+			Silent::Never => false,
+			Silent::Always => true,
+			Silent::Between(start, end) => {
+				let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+					.map(|time| (time.as_secs() / 60 % 1440) as u16)
+					.unwrap_or_default();
+				match start <= end {
+					true => (start..end).contains(&now),
+					false => now >= start || now < end,
+				}
+			},
+		}
+	}
+}
+
+/// `Route` is a destination chat with reasons it was selected
+#[derive(Clone, Debug)]
+pub struct Route {
+	pub chat: ChatId,
+	/// Forum topic in supergroup
+	pub topic: Option<ThreadId>,
+	pub reasons: Vec<String>,
+	/// Tenant this route belongs to, empty for global namespace
+	pub tenant: String,
+	/// Formatting profile selected by address extension, empty for default
+	pub profile: String,
+	pub priority: Priority,
+	/// When messages go without notification
+	pub silent: Silent,
+	/// Put in front of every message
+	pub emoji: Option<Emoji>,
+	/// Subaddress tag, like "db01" in `alerts+db01@host`
+	pub tag: Option<String>,
+	/// Headers shown instead of ones set by profile
+	pub fields: Option<Vec<Field>>,
+	/// Put under every message
+	pub links: Vec<Link>,
+}
+
+impl Route {
+	/// Profile and headers message for this route is rendered with
+	pub fn layout(&self) -> (&str, Option<&[Field]>) {
+		(&self.profile, self.fields.as_deref())
+	}
+
+	/// Chat with topic, for humans
+	pub fn destination(&self) -> String {
+		match self.topic {
+			Some(topic) => format!("{} topic {}", self.chat, topic),
+			None => self.chat.to_string(),
+		}
+	}
+}
+
+/// `Emoji` brands messages, custom emoji can only be sent by bots with paid
+/// username and others see `text` instead
+#[derive(Clone, Debug, PartialEq)]
+pub struct Emoji {
+	pub text: String,
+	/// Custom emoji id
+	pub id: Option<String>,
+}
+
+/// `Link` is static context for responders, like runbook or dashboard
+#[derive(Clone, Debug, PartialEq)]
+pub struct Link {
+	pub text: String,
+	pub url: String,
+}
+
+impl Link {
+	/// Read `{ text = "<text>", url = "<url>" }`
+	fn new(value: config::Value, name: &str, addr: &str) -> Link {
+		let mut table = value.into_table()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}.{}.links\" should list tables with \"text\" and \"url\".\n", name, addr));
+		let mut field = |field: &str| table.remove(field).and_then(|value| value.into_string().ok())
+			.filter(|value| !value.is_empty())
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] \"{}.{}.links\" entries should have \"{}\" string.\n", name, addr, field));
+		let text = field("text");
+		let url = field("url");
+		if !url.starts_with("https://") && !url.starts_with("http://") {
+			panic!("[smtp2tg.toml] \"{}.{}.links\" has \"{}\", only http and https URLs are allowed.\n", name, addr, url);
+		}
+		Link {
+			text,
+			url,
+		}
+	}
+}
+
+/// `Routing` is a result of resolving envelope recipients
+#[derive(Clone, Debug, Default)]
+pub struct Routing {
+	pub routes: Vec<Route>,
+	/// Things worth reporting to debug chat
+	pub notes: Vec<String>,
+}
+
+impl Routing {
+	/// Single destination picked by sender, like `-100123` or `-100123/42`
+	/// for a topic
+	pub fn direct(value: &str, reason: String) -> Option<Routing> {
+		let (chat, topic) = match value.trim().split_once('/') {
+			Some((chat, topic)) => (chat, Some(topic.trim().parse().ok()?)),
+			None => (value.trim(), None),
+		};
+		let mut routing = Routing::default();
+		routing.add(&Recipient {
+			chat: ChatId(chat.trim().parse().ok()?),
+			topic: topic.map(|topic| ThreadId(MessageId(topic))),
+			profile: "".into(),
+			priority: Priority::Normal,
+			silent: Silent::Never,
+			emoji: None,
+			fields: None,
+			links: vec![],
+		}, reason, "", "", None);
+		Some(routing)
+	}
+
+	/// Add destination, merging reasons for chats (or topics) already
+	/// present, first profile, emoji, tag and links and highest priority win,
+	/// notifications are kept unless every recipient asks for silence
+	fn add(&mut self, recipient: &Recipient, reason: String, tenant: &str, profile: &str, tag: Option<&str>) {
+		match self.routes.iter_mut().find(|route| route.chat == recipient.chat && route.topic == recipient.topic) {
+			Some(route) => {
+				route.reasons.push(reason);
+				route.priority = route.priority.min(recipient.priority);
+				if route.silent != recipient.silent {
+					route.silent = match (route.silent, recipient.silent) {
+						(Silent::Always, other) | (other, Silent::Always) => other,
+						// windows don't merge, notifying is safer
+						_ => Silent::Never,
+					};
+				}
+			},
+			None => self.routes.push(Route {
+				chat: recipient.chat,
+				topic: recipient.topic,
+				reasons: vec![reason],
+				tenant: tenant.to_owned(),
+				profile: profile.to_owned(),
+				priority: recipient.priority,
+				silent: recipient.silent,
+				emoji: recipient.emoji.clone(),
+				tag: tag.map(str::to_owned),
+				fields: recipient.fields.clone(),
+				links: recipient.links.clone(),
+			}),
+		}
+	}
+
+	/// Priority of the most urgent route
+	pub fn priority(&self) -> Priority {
+		self.routes.iter().map(|route| route.priority).min().unwrap_or_default()
+	}
+}
+
+/// `Trace` sets where route decisions are reported
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trace {
+	Off,
+	Log,
+	Footer,
+}
+
+impl Trace {
+	pub fn new(settings: &config::Config) -> Trace {
+		match settings.get_string("trace_routes") {
+			Err(config::ConfigError::NotFound(_)) => Trace::Off,
+			Ok(value) => match value.as_str() {
+				"off" => Trace::Off,
+				"log" => Trace::Log,
+				"footer" => Trace::Footer,
+				_ => {
+					error!("[smtp2tg.toml] \"trace_routes\" should be either \"off\", \"log\" or \"footer\".");
+					panic!("bad setting");
+				},
+			},
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"trace_routes\":\n {}", err);
+				panic!("bad setting");
+			},
+		}
+	}
+}
+
+/// `Recipient` is where mail for an address goes
+#[derive(Clone, Debug)]
+struct Recipient {
+	chat: ChatId,
+	/// Forum topic in supergroup
+	topic: Option<ThreadId>,
+	/// Formatting profile, empty for default
+	profile: String,
+	priority: Priority,
+	silent: Silent,
+	emoji: Option<Emoji>,
+	/// Headers shown instead of ones set by profile
+	fields: Option<Vec<Field>>,
+	links: Vec<Link>,
+}
+
+impl Recipient {
+	/// Read either chat id or `{ chat = <id>, topic = <id>, profile = "<name>",
+	/// priority = "<high|normal|low>", silent = <bool|"HH:MM-HH:MM">,
+	/// emoji = "<emoji>", emoji_id = "<id>",
+	/// fields = ["<date|from|subject>", ...], links = [{ text = "<text>",
+	/// url = "<url>" }, ...] }`
+	fn new(value: config::Value, name: &str, addr: &str) -> Recipient {
+		if let Ok(chat) = value.clone().into_int() {
+			return Recipient {
+				chat: ChatId(chat),
+				topic: None,
+				profile: "".into(),
+				priority: Priority::Normal,
+				silent: Silent::Never,
+				emoji: None,
+				fields: None,
+				links: vec![],
+			};
+		}
+		let mut table = value.into_table()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}\" table values should be integers, tables or arrays of them.\n", name));
+		let chat = table.remove("chat").and_then(|chat| chat.into_int().ok())
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] \"{}.{}.chat\" should be an integer.\n", name, addr));
+		let topic = table.remove("topic").map(|topic| topic.into_int().ok()
+			.and_then(|topic| i32::try_from(topic).ok())
+			.map(|topic| ThreadId(MessageId(topic)))
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] \"{}.{}.topic\" should be a topic id.\n", name, addr)));
+		let profile = table.remove("profile").map(|profile| profile.into_string()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}.{}.profile\" should be a string.\n", name, addr)))
+			.unwrap_or_default();
+		let priority = match table.remove("priority").map(|priority| priority.into_string()) {
+			None => Priority::Normal,
+			Some(Ok(priority)) if priority == "high" => Priority::High,
+			Some(Ok(priority)) if priority == "normal" => Priority::Normal,
+			Some(Ok(priority)) if priority == "low" => Priority::Low,
+			Some(_) => panic!("[smtp2tg.toml] \"{}.{}.priority\" should be either \"high\", \"normal\" or \"low\".\n", name, addr),
+		};
+		let silent = table.remove("silent").map(|silent| Silent::new(silent, name, addr)).unwrap_or_default();
+		let id = table.remove("emoji_id").map(|id| id.into_string().ok()
+			.filter(|id| !id.is_empty() && id.bytes().all(|c| c.is_ascii_digit()))
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] \"{}.{}.emoji_id\" should be a custom emoji id, digits only.\n", name, addr)));
+		let emoji = match table.remove("emoji").map(|text| text.into_string()) {
+			Some(Ok(text)) if !text.is_empty() => Some(Emoji {
+				text,
+				id,
+			}),
+			Some(_) => panic!("[smtp2tg.toml] \"{}.{}.emoji\" should be a non-empty string.\n", name, addr),
+			None if id.is_some() => panic!("[smtp2tg.toml] \"{}.{}.emoji_id\" needs \"emoji\" to show where custom emoji are not available.\n", name, addr),
+			None => None,
+		};
+		let fields = table.remove("fields").map(|fields| fields.into_array().ok()
+			.and_then(Field::list)
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] \"{}.{}.fields\" should only list \"date\", \"from\" and \"subject\".\n", name, addr)));
+		let links = table.remove("links").map(|links| links.into_array()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}.{}.links\" should be an array.\n", name, addr))
+			.into_iter().map(|link| Link::new(link, name, addr))
+			.collect())
+			.unwrap_or_default();
+		Recipient {
+			chat: ChatId(chat),
+			topic,
+			profile,
+			priority,
+			silent,
+			emoji,
+			fields,
+			links,
+		}
+	}
+}
+
+/// Chats of `[groups]` by name
+type Groups = HashMap<String, Vec<Recipient>>;
+
+/// Read `[groups]`, lists of chats addresses can refer to by name. Members
+/// are chats or names of other groups and of global recipients addresses
+fn groups(settings: &config::Config) -> Groups {
+	let table = |key: &str| match settings.get_table(key) {
+		Ok(table) => table,
+		Err(config::ConfigError::NotFound(_)) => config::Map::new(),
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"{}\":\n {}", key, err);
+			panic!("bad setting");
+		},
+	};
+	let groups = table("groups");
+	let addrs = table("recipients");
+	groups.keys()
+		.map(|group| (group.clone(), expand(("groups", group), &groups, &addrs, &mut vec![])))
+		.collect()
+}
+
+/// Chats group or address stands for, names are followed into other groups
+/// and addresses; `path` is what was followed so far, to catch loops
+fn expand((table, key): (&str, &str), groups: &config::Map<String, config::Value>, addrs: &config::Map<String, config::Value>, path: &mut Vec<String>) -> Vec<Recipient> {
+	let here = format!("{}.{}", table, key);
+	if let Some(start) = path.iter().position(|seen| *seen == here) {
+		panic!("[smtp2tg.toml] \"{}\" refers to itself: {} -> {}.\n", here, path[start..].join(" -> "), here);
+	}
+	let value = match table {
+		"groups" => groups[key].clone(),
+		_ => addrs[key].clone(),
+	};
+	let values = match value.clone().into_array() {
+		Ok(values) => values,
+		Err(_) if table == "groups" => panic!("[smtp2tg.toml] \"{}\" should be an array.\n", here),
+		Err(_) => vec![value],
+	};
+	path.push(here.clone());
+	let mut chats = vec![];
+	for value in values {
+		match &value.kind {
+			config::ValueKind::String(name) if groups.contains_key(name) => chats.extend(expand(("groups", name), groups, addrs, path)),
+			// addresses only name groups, like in recipients
+			config::ValueKind::String(name) if table == "groups" && addrs.contains_key(name) => chats.extend(expand(("recipients", name), groups, addrs, path)),
+			config::ValueKind::String(name) => panic!("[smtp2tg.toml] \"{}\" refers to unknown {} \"{}\".\n", here,
+				if table == "groups" { "group or address" } else { "group" }, name),
+			_ => chats.push(Recipient::new(value, table, key)),
+		};
+	}
+	path.pop();
+	if chats.is_empty() {
+		panic!("[smtp2tg.toml] \"{}\" should list at least one chat.\n", here);
+	}
+	chats
+}
+
+/// Read recipients table, it should have default recipient. Address can go
+/// to several chats when it's value is an array, strings are group names
+/// standing for all their chats; default is a single chat
+fn recipients(table: config::Map<String, config::Value>, name: &str, groups: &Groups) -> HashMap<String, Vec<Recipient>> {
+	let recipients: HashMap<String, Vec<Recipient>> = table
+		.into_iter().map(|(addr, value)| {
+			let values = match value.clone().into_array() {
+				Ok(values) => values,
+				Err(_) => vec![value],
+			};
+			let mut recipients = vec![];
+			for value in values {
+				match &value.kind {
+					config::ValueKind::String(group) => recipients.extend(groups.get(group)
+						.unwrap_or_else(|| panic!("[smtp2tg.toml] \"{}.{}\" refers to unknown group \"{}\".\n", name, addr, group))
+						.iter().cloned()),
+					_ => recipients.push(Recipient::new(value, name, &addr)),
+				};
+			}
+			if recipients.is_empty() {
+				panic!("[smtp2tg.toml] \"{}.{}\" should list at least one chat.\n", name, addr);
+			}
+			if addr == "_" && recipients.len() > 1 {
+				panic!("[smtp2tg.toml] \"{}._\" should be a single chat, not {} of them.\n", name, recipients.len());
+			}
+			(addr, recipients)
+		}).collect();
+	if !recipients.contains_key("_") {
+		error!("[smtp2tg.toml] \"{}\" table misses \"default_recipient\".", name);
+		panic!("no default recipient");
+	}
+	recipients
+}
+
+/// `Content` is what classifier looks at, lowercased
+#[derive(Clone, Debug, Default)]
+pub struct Content {
+	/// Envelope sender
+	from: String,
+	subject: String,
+	body: String,
+}
+
+impl Content {
+	pub fn new(from: &str, subject: &str, body: &str) -> Content {
+		Content {
+			from: from.to_lowercase(),
+			subject: subject.to_lowercase(),
+			body: body.to_lowercase(),
+		}
+	}
+}
+
+/// `Rule` sends mail for default recipient elsewhere when keyword is found
+#[derive(Clone, Debug)]
+struct Rule {
+	recipient: Recipient,
+	subject: Vec<String>,
+	body: Vec<String>,
+}
+
+/// Read lowercased keyword list from rule
+fn keywords(table: &mut config::Map<String, config::Value>, name: &str, field: &str) -> Vec<String> {
+	table.remove(field).map(|list| list.into_array()
+		.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}.{}\" should be an array.\n", name, field))
+		.into_iter().map(|keyword| keyword.into_string()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}.{}\" should list strings.\n", name, field))
+			.to_lowercase())
+		.collect())
+		.unwrap_or_default()
+}
+
+impl Rule {
+	/// Read one `[[classify]]` block, it's a recipient with keyword lists
+	fn new(value: config::Value, index: usize) -> Rule {
+		let name = format!("classify.{}", index);
+		let mut table = value.into_table()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"classify\" should be an array of tables.\n"));
+		let subject = keywords(&mut table, &name, "subject");
+		let body = keywords(&mut table, &name, "body");
+		if subject.is_empty() && body.is_empty() {
+			error!("[smtp2tg.toml] \"{}\" needs \"subject\" or \"body\" keywords.", name);
+			panic!("bad setting");
+		}
+		Rule {
+			recipient: Recipient::new(config::Value::from(table), "classify", &index.to_string()),
+			subject,
+			body,
+		}
+	}
+
+	/// Keyword found, as reason
+	fn matches(&self, content: &Content) -> Option<String> {
+		self.subject.iter().find(|keyword| content.subject.contains(keyword.as_str()))
+			.map(|keyword| format!("keyword \"{}\" in subject", keyword))
+			.or_else(|| self.body.iter().find(|keyword| content.body.contains(keyword.as_str()))
+				.map(|keyword| format!("keyword \"{}\" in body", keyword)))
+	}
+}
+
+/// `Pattern` sends mail for matching address to it's own chat, whatever
+/// recipients table says. Every regex it has should match
+#[derive(Clone, Debug)]
+struct Pattern {
+	recipient: Recipient,
+	from: Option<Regex>,
+	to: Option<Regex>,
+	subject: Option<Regex>,
+}
+
+impl Pattern {
+	/// Read one `[[routes]]` block, it's a recipient with regexes
+	fn new(value: config::Value, index: usize) -> Pattern {
+		let name = format!("routes.{}", index);
+		let mut table = value.into_table()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"routes\" should be an array of tables.\n"));
+		let mut regex = |field: &str| table.remove(field).map(|pattern| {
+			let pattern = pattern.into_string()
+				.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}.{}\" should be a string.\n", name, field));
+			RegexBuilder::new(&pattern).case_insensitive(true).build().unwrap_or_else(|err| {
+				error!("[smtp2tg.toml] \"{}.{}\" is not a valid regex:\n {}", name, field, err);
+				panic!("bad setting");
+			})
+		});
+		let from = regex("from");
+		let to = regex("to");
+		let subject = regex("subject");
+		if from.is_none() && to.is_none() && subject.is_none() {
+			error!("[smtp2tg.toml] \"{}\" needs \"from\", \"to\" or \"subject\".", name);
+			panic!("bad setting");
+		}
+		Pattern {
+			recipient: Recipient::new(config::Value::from(table), "routes", &index.to_string()),
+			from,
+			to,
+			subject,
+		}
+	}
+
+	/// Whether mail for address matches
+	fn matches(&self, to: &str, content: &Content) -> bool {
+		[(&self.from, content.from.as_str()), (&self.to, to), (&self.subject, content.subject.as_str())].into_iter()
+			.all(|(regex, text)| match regex {
+				Some(regex) => regex.is_match(text),
+				None => true,
+			})
+	}
+}
+
+/// `Suppression` drops mail nobody needs to read, like cron runs without
+/// output. Every condition it has should hold
+#[derive(Clone, Debug)]
+struct Suppression {
+	/// Body has no text
+	empty: bool,
+	/// Any of these in subject
+	subject: Vec<String>,
+	/// Any of these in body
+	body: Vec<String>,
+	/// Count dropped mail in daily stats
+	count: bool,
+}
+
+impl Suppression {
+	/// Read one `[[suppress]]` block
+	fn new(value: config::Value, index: usize) -> Suppression {
+		let name = format!("suppress.{}", index);
+		let mut table = value.into_table()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"suppress\" should be an array of tables.\n"));
+		let mut flag = |field: &str| table.remove(field).map(|flag| flag.into_bool()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"{}.{}\" should be a boolean.\n", name, field)))
+			.unwrap_or_default();
+		let empty = flag("empty");
+		let count = flag("count");
+		let subject = keywords(&mut table, &name, "subject");
+		let body = keywords(&mut table, &name, "body");
+		if let Some(key) = table.keys().next() {
+			panic!("[smtp2tg.toml] unknown key \"{}.{}\".\n", name, key);
+		}
+		if !empty && subject.is_empty() && body.is_empty() {
+			error!("[smtp2tg.toml] \"{}\" needs \"empty\", \"subject\" or \"body\".", name);
+			panic!("bad setting");
+		}
+		Suppression {
+			empty,
+			subject,
+			body,
+			count,
+		}
+	}
+
+	/// Conditions met, as reason
+	fn matches(&self, content: &Content) -> Option<String> {
+		let mut reasons = vec![];
+		if self.empty {
+			match content.body.trim().is_empty() {
+				true => reasons.push("empty body".to_string()),
+				false => return None,
+			}
+		}
+		for (keywords, text, field) in [(&self.subject, &content.subject, "subject"), (&self.body, &content.body, "body")] {
+			if keywords.is_empty() {
+				continue;
+			}
+			match keywords.iter().find(|keyword| text.contains(keyword.as_str())) {
+				Some(keyword) => reasons.push(format!("keyword \"{}\" in {}", keyword, field)),
+				None => return None,
+			}
+		}
+		Some(reasons.join(", "))
+	}
+}
+
+/// Read policy for unknown addresses
+fn relay(value: Result<String, config::ConfigError>, name: &str) -> bool {
+	match value {
+		Ok(value) => {
+			match value.as_str() {
+				"relay" => true,
+				"deny" => false,
+				_ => {
+					error!("[smtp2tg.toml] \"{}\" should be either \"relay\" or \"deny\".", name);
+					panic!("bad setting");
+				},
+			}
+		},
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err);
+			panic!("bad setting");
+		},
+	}
+}
+
+/// `Denial` is a reply for recipients we don't accept
+#[derive(Clone, Debug)]
+pub struct Denial {
+	pub code: u16,
+	pub text: String,
+}
+
+impl Denial {
+	fn new(settings: &config::Config) -> Denial {
+		let code = match settings.get_int("deny_code") {
+			Ok(code) if (400..600).contains(&code) => code as u16,
+			Err(config::ConfigError::NotFound(_)) => 550,
+			_ => {
+				error!("[smtp2tg.toml] \"deny_code\" should be 4xx or 5xx SMTP code.");
+				panic!("bad setting");
+			},
+		};
+		let text = match settings.get_string("deny_text") {
+			Ok(text) => text,
+			Err(config::ConfigError::NotFound(_)) => "Mailbox unavailable".into(),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"deny_text\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		Denial {
+			code,
+			text,
+		}
+	}
+}
+
+/// Lowercase domain of address
+fn domain(addr: &str) -> String {
+	addr.rsplit_once('@').map(|(_, domain)| domain.to_lowercase()).unwrap_or_default()
+}
+
+/// `Namespace` is a set of recipients with own default chat and policy
+#[derive(Clone, Debug)]
+struct Namespace {
+	/// Tenant name, empty for global namespace
+	name: String,
+	/// Domains belonging to tenant
+	domains: Vec<String>,
+	recipients: HashMap<String, Vec<Recipient>>,
+	/// Whether unknown addresses go to default chat or get rejected
+	relay: bool,
+	/// Separate bot for this tenant
+	api_key: Option<String>,
+	/// Messages per day
+	quota: Option<u64>,
+}
+
+impl Namespace {
+	/// Read one `[tenants.<name>]` block
+	fn tenant(name: String, value: config::Value, groups: &Groups) -> Namespace {
+		let mut table = value.into_table()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"tenants.{}\" should be a table.\n", name));
+		let domains = table.remove("domains")
+			.and_then(|domains| domains.into_array().ok())
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] \"tenants.{}.domains\" should be an array.\n", name))
+			.into_iter().map(|domain| domain.into_string()
+				.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"tenants.{}.domains\" should list strings.\n", name))
+				.to_lowercase())
+			.collect();
+		let recipients = recipients(table.remove("recipients")
+			.and_then(|recipients| recipients.into_table().ok())
+			.unwrap_or_else(|| panic!("[smtp2tg.toml] missing table \"tenants.{}.recipients\".\n", name)),
+			&format!("tenants.{}.recipients", name), groups);
+		let relay = relay(match table.remove("unknown") {
+			Some(value) => value.into_string(),
+			None => Ok("relay".into()),
+		}, &format!("tenants.{}.unknown", name));
+		let api_key = table.remove("api_key").map(|key| key.into_string()
+			.unwrap_or_else(|_| panic!("[smtp2tg.toml] \"tenants.{}.api_key\" should be a string.\n", name)));
+		let quota = table.remove("quota").map(|quota| match quota.into_uint() {
+			Ok(quota) => quota,
+			Err(_) => panic!("[smtp2tg.toml] \"tenants.{}.quota\" should be a positive integer.\n", name),
+		});
+		Namespace {
+			name,
+			domains,
+			recipients,
+			relay,
+			api_key,
+			quota,
+		}
+	}
+
+	fn default(&self) -> &Recipient {
+		&self.recipients["_"][0]
+	}
+
+	/// Describe where decision was made, for tracing
+	fn origin(&self) -> String {
+		if self.name.is_empty() {
+			"".into()
+		} else {
+			format!("tenant {}: ", self.name)
+		}
+	}
+}
+
+/// `Router` holds routing tables
+#[derive(Clone)]
+pub struct Router {
+	global: Namespace,
+	tenants: Vec<Namespace>,
+	/// Where mail of authenticated clients goes, regardless of envelope
+	identities: HashMap<String, Recipient>,
+	/// Classifier for mail that would go to global default, first match wins
+	rules: Vec<Rule>,
+	/// Routes by regexes, checked before recipients tables, first match wins
+	patterns: Vec<Pattern>,
+	/// Mail that isn't delivered at all
+	suppressions: Vec<Suppression>,
+	/// Reply for recipients we don't accept
+	pub denial: Denial,
+	/// Domains rejected at RCPT whatever the policy is
+	reject_domains: Vec<String>,
+	/// Separates formatting profile from local part, like in `alerts.short@host`
+	separator: Option<String>,
+	/// Separates tag from local part, like in `alerts+db01@host`
+	tag_separator: Option<String>,
+	/// Known formatting profiles
+	profiles: Vec<String>,
+	/// Header trusted senders set to pick chat themselves
+	pub chat_header: Option<String>,
+	/// Envelope senders allowed to use `chat_header` without authentication,
+	/// either addresses or "@domain"
+	trusted_senders: Vec<String>,
+}
+
+impl Router {
+	/// Read routing tables from configuration
+	pub fn new(settings: &config::Config) -> Router {
+		let groups = groups(settings);
+		let global = Namespace {
+			name: "".into(),
+			domains: vec![],
+			recipients: recipients(settings.get_table("recipients")
+				.expect("[smtp2tg.toml] missing table \"recipients\".\n"), "recipients", &groups),
+			relay: relay(settings.get_string("unknown"), "unknown"),
+			api_key: None,
+			quota: None,
+		};
+		let tenants = match settings.get_table("tenants") {
+			Ok(tenants) => tenants.into_iter().map(|(name, value)| Namespace::tenant(name, value, &groups)).collect(),
+			Err(config::ConfigError::NotFound(_)) => vec![],
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"tenants\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let identities = match settings.get_table("identities") {
+			Ok(identities) => identities.into_iter().map(|(identity, value)| {
+				let recipient = Recipient::new(value, "identities", &identity);
+				(identity, recipient)
+			}).collect(),
+			Err(config::ConfigError::NotFound(_)) => HashMap::new(),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"identities\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let rules: Vec<Rule> = match settings.get_array("classify") {
+			Ok(rules) => rules.into_iter().enumerate().map(|(index, value)| Rule::new(value, index)).collect(),
+			Err(config::ConfigError::NotFound(_)) => vec![],
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"classify\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let patterns: Vec<Pattern> = match settings.get_array("routes") {
+			Ok(patterns) => patterns.into_iter().enumerate().map(|(index, value)| Pattern::new(value, index)).collect(),
+			Err(config::ConfigError::NotFound(_)) => vec![],
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"routes\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let suppressions = match settings.get_array("suppress") {
+			Ok(rules) => rules.into_iter().enumerate().map(|(index, value)| Suppression::new(value, index)).collect(),
+			Err(config::ConfigError::NotFound(_)) => vec![],
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"suppress\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let reject_domains = match settings.get_array("reject_domains") {
+			Ok(domains) => domains.into_iter().map(|domain| domain.into_string()
+				.expect("[smtp2tg.toml] \"reject_domains\" should list strings.\n")
+				.to_lowercase())
+				.collect(),
+			Err(config::ConfigError::NotFound(_)) => vec![],
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"reject_domains\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let separator = match settings.get_string("extension_separator") {
+			Ok(separator) if !separator.is_empty() => Some(separator),
+			Ok(_) | Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"extension_separator\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let tag_separator = match settings.get_string("tag_separator") {
+			Ok(separator) if separator.is_empty() => None,
+			Ok(separator) => Some(separator),
+			Err(config::ConfigError::NotFound(_)) => Some("+".into()),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"tag_separator\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let chat_header = match settings.get_string("chat_header") {
+			Ok(name) if name.is_empty() => None,
+			Ok(name) => Some(name),
+			Err(config::ConfigError::NotFound(_)) => Some("X-SMTP2TG-Chat".into()),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"chat_header\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let trusted_senders = match settings.get_array("trusted_senders") {
+			Ok(senders) => senders.into_iter().map(|sender| sender.into_string()
+				.expect("[smtp2tg.toml] \"trusted_senders\" should list strings.\n")
+				.to_lowercase())
+				.collect(),
+			Err(config::ConfigError::NotFound(_)) => vec![],
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"trusted_senders\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let profiles: Vec<String> = settings.get_table("profiles")
+			.map(|profiles| profiles.into_keys().collect())
+			.unwrap_or_default();
+		for namespace in tenants.iter().chain([&global]) {
+			let rules = rules.iter().map(|rule| ("classify", &rule.recipient));
+			let patterns = patterns.iter().map(|pattern| ("routes", &pattern.recipient));
+			for (addr, recipient) in namespace.recipients.iter()
+				.flat_map(|(addr, recipients)| recipients.iter().map(move |recipient| (addr.as_str(), recipient)))
+				.chain(identities.iter().map(|(identity, recipient)| (identity.as_str(), recipient)))
+				.chain(rules)
+				.chain(patterns)
+			{
+				if !recipient.profile.is_empty() && !profiles.contains(&recipient.profile) {
+					error!("[smtp2tg.toml] recipient \"{}\" uses unknown profile \"{}\".", addr, recipient.profile);
+					panic!("bad setting");
+				}
+			}
+		}
+		Router {
+			global,
+			tenants,
+			identities,
+			rules,
+			patterns,
+			suppressions,
+			denial: Denial::new(settings),
+			reject_domains,
+			separator,
+			tag_separator,
+			profiles,
+			chat_header,
+			trusted_senders,
+		}
+	}
+
+	/// Chat that gets debug messages and mail without recipients
+	pub fn default_chat(&self) -> ChatId {
+		self.global.default().chat
+	}
+
+	/// Default chat of a tenant, global one for empty or unknown name
+	pub fn tenant_chat(&self, tenant: &str) -> ChatId {
+		self.tenants.iter()
+			.find(|namespace| namespace.name == tenant)
+			.unwrap_or(&self.global)
+			.default().chat
+	}
+
+	/// Select namespace by recipient domain
+	fn namespace(&self, to: &str) -> &Namespace {
+		let domain = domain(to);
+		self.tenants.iter()
+			.find(|tenant| tenant.domains.contains(&domain))
+			.unwrap_or(&self.global)
+	}
+
+	/// Tenants having their own bots, as (name, api key)
+	pub fn api_keys(&self) -> Vec<(String, String)> {
+		self.tenants.iter()
+			.filter_map(|tenant| tenant.api_key.clone().map(|key| (tenant.name.clone(), key)))
+			.collect()
+	}
+
+	/// Daily quota applying to this address, as (tenant, messages)
+	pub fn quota(&self, to: &str) -> Option<(&str, u64)> {
+		let namespace = self.namespace(to);
+		namespace.quota.map(|quota| (namespace.name.as_str(), quota))
+	}
+
+	/// Check whether we accept mail for this address
+	pub fn accepts(&self, to: &str) -> bool {
+		self.accepts_with(to, None)
+	}
+
+	/// Check whether envelope sender may pick chat with `chat_header`
+	pub fn trusts(&self, from: &str) -> bool {
+		let from = from.to_lowercase();
+		self.trusted_senders.iter().any(|sender| match sender.starts_with('@') {
+			true => from.ends_with(sender.as_str()),
+			false => from == *sender,
+		})
+	}
+
+	/// Check whether authenticated client has own route
+	pub fn routes_identity(&self, identity: &str) -> bool {
+		self.identities.contains_key(identity)
+	}
+
+	/// Same as `accepts`, with relaying of unknown addresses overridden
+	pub fn accepts_with(&self, to: &str, relay: Option<bool>) -> bool {
+		if self.reject_domains.contains(&domain(to)) {
+			return false;
+		}
+		// only "to" is known at RCPT, other conditions are checked later
+		if self.patterns.iter().any(|pattern| pattern.to.as_ref().is_some_and(|regex| regex.is_match(&to.to_lowercase()))) {
+			return true;
+		}
+		let (to, _, _) = self.extension(to);
+		let namespace = self.namespace(&to);
+		relay.unwrap_or(namespace.relay) || namespace.recipients.contains_key(to.as_ref())
+	}
+
+	/// Split formatting profile and then tag off the address, profile only
+	/// when address itself is unknown and profile exists, tag only when
+	/// address without it is known
+	fn extension<'a>(&'a self, to: &'a str) -> (Cow<'a, str>, &'a str, Option<&'a str>) {
+		if self.namespace(to).recipients.contains_key(to) {
+			return (to.into(), "", None);
+		}
+		let mut addr: Cow<'a, str> = to.into();
+		let mut profile = "";
+		if let (Some(separator), Some((local, domain))) = (&self.separator, to.rsplit_once('@')) {
+			if let Some((base, found)) = local.rsplit_once(separator.as_str()) {
+				if self.profiles.iter().any(|known| known == found) {
+					addr = format!("{}@{}", base, domain).into();
+					profile = found;
+				}
+			}
+		}
+		if let (Some(separator), Some((local, domain))) = (&self.tag_separator, to.rsplit_once('@')) {
+			// tag comes before profile, if there's one
+			let local = match profile.is_empty() {
+				true => local,
+				false => &local[..local.len() - profile.len() - self.separator.as_ref().map_or(0, String::len)],
+			};
+			if let Some((base, tag)) = local.split_once(separator.as_str()) {
+				let base = format!("{}@{}", base, domain);
+				if !tag.is_empty() && self.namespace(&base).recipients.contains_key(&base) {
+					return (base.into(), profile, Some(tag));
+				}
+			}
+		}
+		(addr, profile, None)
+	}
+
+	/// Chats of known recipient, "_" is not an address
+	fn lookup(&self, to: &str) -> (&Namespace, Option<Vec<ChatId>>) {
+		let (to, _, _) = self.extension(to);
+		let namespace = self.namespace(&to);
+		let chats = match to.as_ref() {
+			"_" => None,
+			to => namespace.recipients.get(to).map(|recipients| recipients.iter().map(|recipient| recipient.chat).collect()),
+		};
+		(namespace, chats)
+	}
+
+	/// Check whether mail should be dropped, returns reason and whether it
+	/// should be counted
+	pub fn suppresses(&self, content: &Content) -> Option<(String, bool)> {
+		self.suppressions.iter()
+			.find_map(|suppression| suppression.matches(content).map(|reason| (reason, suppression.count)))
+	}
+
+	/// Find destination chats for envelope recipients.
+	/// Mail of authenticated client with own route only goes there. Otherwise
+	/// addresses matching `[[routes]]` go where first matching rule says, all
+	/// known addresses are added to recipient list, for anyone else
+	/// default of their namespace is added, global default can be replaced by
+	/// classifier. Also if list is empty global default is added
+	pub fn resolve(&self, to: &[String], identity: Option<&str>, content: &Content) -> Result<Routing> {
+		let mut routing = Routing::default();
+		if to.is_empty() {
+			bail!("No recipient addresses.");
+		}
+		if let Some((identity, recipient)) = identity.and_then(|identity| self.identities.get_key_value(identity)) {
+			routing.add(recipient, format!("identity {}", identity), "", &recipient.profile, None);
+			return Ok(routing);
+		}
+		for item in to {
+			let found = self.patterns.iter().enumerate()
+				.find(|(_, pattern)| pattern.matches(&item.to_lowercase(), content));
+			if let Some((index, pattern)) = found {
+				let recipient = &pattern.recipient;
+				routing.add(recipient, format!("route {} for {}", index, item), "", &recipient.profile, None);
+				continue;
+			}
+			let (addr, profile, tag) = self.extension(item);
+			let namespace = self.namespace(&addr);
+			let (recipients, reason) = match namespace.recipients.get(addr.as_ref()) {
+				Some(recipients) => (recipients.as_slice(), format!("{}recipient {}", namespace.origin(), item)),
+				None => {
+					routing.notes.push(format!("Recipient [{}] not found\\.", markdown::escape(item)));
+					let classified = match namespace.name.is_empty() {
+						true => self.rules.iter().find_map(|rule| rule.matches(content).map(|reason| (rule, reason))),
+						false => None,
+					};
+					match classified {
+						Some((rule, reason)) => (std::slice::from_ref(&rule.recipient), format!("{} for unknown {}", reason, item)),
+						None => (std::slice::from_ref(namespace.default()), format!("{}default for unknown {}", namespace.origin(), item)),
+					}
+				}
+			};
+			for recipient in recipients {
+				// address extension overrides recipient's own profile
+				let profile = if profile.is_empty() { &recipient.profile } else { profile };
+				routing.add(recipient, reason.clone(), &namespace.name, profile, tag);
+			}
+		};
+		if routing.routes.is_empty() {
+			routing.notes.push("No recipient or envelope address\\.".into());
+			let recipient = self.global.default();
+			routing.add(recipient, "default, no recipients".into(), "", &recipient.profile, None);
+		};
+		// urgent chats don't wait for bulky deliveries
+		routing.routes.sort_by_key(|route| route.priority);
+		Ok(routing)
+	}
+}
+
+impl Directory for Router {
+	fn verify(&self, addr: &str) -> (u16, Vec<String>) {
+		match self.lookup(addr) {
+			_ if !self.accepts(addr) => (550, vec![format!("<{}> not found", addr)]),
+			(_, Some(_)) => (250, vec![format!("<{}>", addr)]),
+			(namespace, None) if namespace.relay => (252, vec!["Cannot VRFY user, but will accept message".into()]),
+			_ => (550, vec![format!("<{}> not found", addr)]),
+		}
+	}
+
+	fn expand(&self, addr: &str) -> (u16, Vec<String>) {
+		match self.lookup(addr) {
+			_ if !self.accepts(addr) => (550, vec![format!("<{}> not found", addr)]),
+			(namespace, Some(chats)) => (250, chats.iter().map(|chat| format!("<{}> {}chat {}", addr, namespace.origin(), chat)).collect()),
+			(namespace, None) if namespace.relay => (250, vec![format!("<{}> {}default chat {}", addr, namespace.origin(), namespace.default().chat)]),
+			_ => (550, vec![format!("<{}> not found", addr)]),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Settings from TOML text, like in smtp2tg.toml
+	fn settings(toml: &str) -> config::Config {
+		config::Config::builder()
+			.set_default("unknown", "relay").unwrap()
+			.add_source(config::File::from_str(toml, config::FileFormat::Toml))
+			.build().unwrap()
+	}
+
+	fn router() -> Router {
+		Router::new(&settings(r#"
+			extension_separator = "."
+			[recipients]
+			_ = 1
+			"alerts@host" = 2
+			"oncall@host" = ["infra", 5]
+			"otp@host" = { chat = 6, priority = "high" }
+			[groups]
+			infra = [3, "ops", "alerts@host"]
+			ops = [{ chat = 4, topic = 7 }]
+			[profiles.short]
+			markup = "plain"
+			[tenants.acme]
+			domains = ["acme.com"]
+			unknown = "deny"
+			[tenants.acme.recipients]
+			_ = -500
+			"ops@acme.com" = -501
+		"#))
+	}
+
+	/// Destinations of routing, as (chat, topic)
+	fn chats(routing: &Routing) -> Vec<(i64, Option<i32>)> {
+		routing.routes.iter().map(|route| (route.chat.0, route.topic.map(|topic| topic.0.0))).collect()
+	}
+
+	#[test]
+	fn extension_finds_profile_and_tag() {
+		let router = router();
+		for (to, addr, profile, tag) in [
+			("alerts@host", "alerts@host", "", None),
+			("alerts.short@host", "alerts@host", "short", None),
+			("alerts.long@host", "alerts.long@host", "", None),
+			("alerts+db01@host", "alerts@host", "", Some("db01")),
+			("alerts+db01.short@host", "alerts@host", "short", Some("db01")),
+			("nobody+db01@host", "nobody+db01@host", "", None),
+		] {
+			let (found, found_profile, found_tag) = router.extension(to);
+			assert_eq!((found.as_ref(), found_profile, found_tag), (addr, profile, tag), "{}", to);
+		}
+	}
+
+	#[test]
+	fn resolve_routes() {
+		let router = router();
+		let content = Content::default();
+		let resolve = |to: &[&str]| router.resolve(&to.iter().map(|to| to.to_string()).collect::<Vec<_>>(), None, &content).unwrap();
+		for (to, expected) in [
+			(&["alerts@host"][..], vec![(2, None)]),
+			(&["alerts.short@host"], vec![(2, None)]),
+			(&["unknown@host"], vec![(1, None)]),
+			(&["ops@acme.com", "other@acme.com"], vec![(-501, None), (-500, None)]),
+			(&["oncall@host"], vec![(3, None), (4, Some(7)), (2, None), (5, None)]),
+			// chats met twice are sent to once
+			(&["alerts@host", "oncall@host"], vec![(2, None), (3, None), (4, Some(7)), (5, None)]),
+			// urgent ones go first
+			(&["alerts@host", "otp@host"], vec![(6, None), (2, None)]),
+		] {
+			assert_eq!(chats(&resolve(to)), expected, "{:?}", to);
+		}
+		let routing = resolve(&["alerts.short@host", "ops@acme.com"]);
+		assert_eq!(routing.routes[0].profile, "short");
+		assert_eq!(routing.routes[1].tenant, "acme");
+		assert!(router.resolve(&[], None, &content).is_err());
+	}
+
+	#[test]
+	fn tenants_have_own_default_chat() {
+		let router = router();
+		for (tenant, expected) in [("", 1), ("acme", -500), ("missing", 1)] {
+			assert_eq!(router.tenant_chat(tenant), ChatId(expected), "{:?}", tenant);
+		}
+	}
+
+	#[test]
+	fn expn_lists_chats() {
+		let router = router();
+		assert_eq!(router.expand("alerts@host"), (250, vec!["<alerts@host> chat 2".to_string()]));
+		assert_eq!(router.expand("unknown@host"), (250, vec!["<unknown@host> default chat 1".to_string()]));
+		assert_eq!(router.expand("ops@acme.com"), (250, vec!["<ops@acme.com> tenant acme: chat -501".to_string()]));
+		assert_eq!(router.expand("other@acme.com").0, 550);
+	}
+
+	#[test]
+	fn groups_expand_names() {
+		let groups = groups(&settings(r#"
+			[recipients]
+			_ = 1
+			alice = [5, "team"]
+			[groups]
+			infra = ["alice", "ops-room", 7]
+			ops-room = [8]
+			team = [9]
+		"#));
+		let chats = |group: &str| groups[group].iter().map(|recipient| recipient.chat.0).collect::<Vec<_>>();
+		assert_eq!(chats("infra"), vec![5, 9, 8, 7]);
+		assert_eq!(chats("ops-room"), vec![8]);
+	}
+
+	#[test]
+	#[should_panic(expected = "refers to itself")]
+	fn groups_catch_loops() {
+		groups(&settings(r#"
+			[recipients]
+			_ = 1
+			alice = "team"
+			[groups]
+			team = ["infra"]
+			infra = ["alice"]
+		"#));
+	}
+
+	#[test]
+	#[should_panic(expected = "unknown group or address \"bob\"")]
+	fn groups_catch_unknown_names() {
+		groups(&settings(r#"
+			[recipients]
+			_ = 1
+			[groups]
+			team = [1, "bob"]
+		"#));
+	}
+}
@@ -0,0 +1,147 @@
+//! Pauses Telegram asks for in flood control replies. Pause applies to every
+//! request to the chat, not only the refused one, so concurrent sessions
+//! don't keep hitting it. When another chat of the same bot gets paused while
+//! first pause still runs, limit is bot-wide and all requests of the bot wait.
+//! Requests also take turns by route priority: while bot has higher priority
+//! requests in flight or waiting for their turn, lower ones are held back.
+//! Turn only covers request itself, not pauses and retry delays around it.
+
+use async_std::{
+	channel::{
+		self,
+		Sender,
+	},
+	task,
+};
+use teloxide::types::ChatId;
+
+use std::{
+	collections::HashMap,
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::{
+		Duration,
+		Instant,
+	},
+};
+
+use crate::routing::Priority;
+
+/// `Pauses` of one bot
+#[derive(Debug, Default)]
+struct Pauses {
+	bot: Option<Instant>,
+	chats: HashMap<ChatId, Instant>,
+	/// Requests waiting for turn or in flight, by priority
+	active: [usize; 3],
+	/// Held back requests, woken when some turn ends
+	waiting: Vec<Sender<()>>,
+}
+
+/// `Schedule` holds pauses of all bots, keyed by tenant
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+	bots: Arc<Mutex<HashMap<String, Pauses>>>,
+}
+
+impl Schedule {
+	/// Wait until bot has no requests of higher priority, lower ones wait
+	/// while returned `Turn` is kept
+	pub async fn turn(&self, tenant: &str, priority: Priority) -> Turn {
+		self.bots.lock().unwrap().entry(tenant.to_owned()).or_default().active[priority as usize] += 1;
+		let turn = Turn {
+			bots: self.bots.clone(),
+			tenant: tenant.to_owned(),
+			priority,
+		};
+		loop {
+			let (sender, receiver) = channel::bounded(1);
+			{
+				let mut bots = self.bots.lock().unwrap();
+				let pauses = bots.entry(tenant.to_owned()).or_default();
+				if pauses.active[..priority as usize].iter().all(|count| *count == 0) {
+					return turn;
+				}
+				pauses.waiting.push(sender);
+			}
+			let _ = receiver.recv().await;
+		}
+	}
+
+	/// Wait until requests to chat are allowed again
+	pub async fn ready(&self, tenant: &str, chat: ChatId) {
+		loop {
+			let now = Instant::now();
+			let until = self.bots.lock().unwrap().get(tenant)
+				.and_then(|pauses| pauses.bot.max(pauses.chats.get(&chat).copied()))
+				.filter(|until| *until > now);
+			match until {
+				Some(until) => task::sleep(until - now).await,
+				None => return,
+			}
+		}
+	}
+
+	/// Pause chat for `delay`
+	pub fn pause(&self, tenant: &str, chat: ChatId, delay: Duration) {
+		let now = Instant::now();
+		let until = now + delay;
+		let mut bots = self.bots.lock().unwrap();
+		let pauses = bots.entry(tenant.to_owned()).or_default();
+		pauses.chats.retain(|_, paused| *paused > now);
+		if pauses.chats.keys().any(|other| *other != chat) {
+			warn!("Telegram limits whole bot, pausing it for {:?}", delay);
+			pauses.bot = pauses.bot.filter(|paused| *paused > until).or(Some(until));
+		}
+		let paused = pauses.chats.entry(chat).or_insert(until);
+		*paused = (*paused).max(until);
+	}
+}
+
+/// `Turn` of request, see `Schedule::turn`
+#[derive(Debug)]
+pub struct Turn {
+	bots: Arc<Mutex<HashMap<String, Pauses>>>,
+	tenant: String,
+	priority: Priority,
+}
+
+impl Drop for Turn {
+	fn drop(&mut self) {
+		if let Some(pauses) = self.bots.lock().unwrap().get_mut(&self.tenant) {
+			pauses.active[self.priority as usize] -= 1;
+			// they check again whether it's their turn now
+			for waiting in pauses.waiting.drain(..) {
+				let _ = waiting.try_send(());
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[async_std::test]
+	async fn lower_priority_waits_for_higher() {
+		let schedule = Schedule::default();
+		let high = schedule.turn("", Priority::High).await;
+		let low = task::spawn({
+			let schedule = schedule.clone();
+			async move {
+				let _turn = schedule.turn("", Priority::Low).await;
+				Instant::now()
+			}
+		});
+		// other bots and same priority don't wait
+		drop(schedule.turn("tenant", Priority::Low).await);
+		drop(schedule.turn("", Priority::High).await);
+		task::sleep(Duration::from_millis(100)).await;
+		let released = Instant::now();
+		drop(high);
+		assert!(low.await >= released);
+		drop(schedule.turn("", Priority::Low).await);
+	}
+}
@@ -0,0 +1,752 @@
+//! SMTP listener. We drive `mailin` state machine ourselves so connections can
+//! be wrapped (e.g. in TLS) before first byte of SMTP is spoken.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use base64::{
+	engine::general_purpose::STANDARD,
+	Engine,
+};
+use mailin::{
+	Action,
+	Handler,
+	Response,
+	SessionBuilder,
+};
+use rustls::{
+	server::WebPkiClientVerifier,
+	RootCertStore,
+	ServerConfig,
+	ServerConnection,
+	StreamOwned,
+};
+
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	fs::File,
+	io::{
+		BufRead,
+		BufReader,
+		ErrorKind,
+		Read,
+		Write,
+	},
+	net::{
+		IpAddr,
+		TcpStream,
+	},
+	rc::Rc,
+	sync::{
+		atomic::{
+			AtomicBool,
+			AtomicUsize,
+			Ordering,
+		},
+		Arc,
+		RwLock,
+	},
+	thread,
+	time::{
+		Duration,
+		Instant,
+	},
+};
+
+use crate::{
+	policy::Policy,
+	socket::{
+		Socket,
+		Stream,
+	},
+	x509,
+};
+
+const FIVE_MINUTES: Duration = Duration::new(5 * 60, 0);
+
+/// Longest command line RFC 5321 allows, CRLF included
+const COMMAND_LIMIT: usize = 512;
+
+/// Longest text line RFC 5321 allows, CRLF included
+const TEXT_LIMIT: usize = 1000;
+
+/// Sessions in progress, shutdown waits for them
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// Set on shutdown, new connections are turned away
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// `Active` counts session while it lives
+struct Active;
+
+impl Active {
+	fn new() -> Active {
+		ACTIVE.fetch_add(1, Ordering::SeqCst);
+		Active
+	}
+}
+
+impl Drop for Active {
+	fn drop(&mut self) {
+		ACTIVE.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+/// Whether server is shutting down
+pub fn stopping() -> bool {
+	STOPPING.load(Ordering::SeqCst)
+}
+
+/// Number of sessions in progress
+pub fn active() -> usize {
+	ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Stop taking connections and wait up to `grace` for sessions in progress,
+/// returns whether they all finished
+pub fn drain(grace: Duration) -> bool {
+	STOPPING.store(true, Ordering::SeqCst);
+	let deadline = Instant::now() + grace;
+	while ACTIVE.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+		thread::sleep(Duration::from_millis(100));
+	}
+	ACTIVE.load(Ordering::SeqCst) == 0
+}
+
+/// `Directory` answers VRFY and EXPN, replies are code and lines of text
+pub trait Directory: Send + Sync {
+	fn verify(&self, addr: &str) -> (u16, Vec<String>);
+	fn expand(&self, addr: &str) -> (u16, Vec<String>);
+}
+
+/// `Screen` decides whether to talk to connecting client at all
+pub trait Screen: Send + Sync {
+	/// Rejection to send instead of greeting
+	fn screen(&self, remote: IpAddr) -> Option<Response>;
+}
+
+/// `Enforcer` is handler that follows listener policy
+pub trait Enforcer {
+	fn enforce(&mut self, policy: Arc<Policy>);
+	/// Client proved it's identity, e.g. with certificate
+	fn identify(&mut self, identity: String);
+}
+
+/// `Listener` is one address we accept connections on
+#[derive(Clone, Debug)]
+pub struct Listener {
+	pub addr: String,
+	/// Speak TLS from the very first byte (SMTPS)
+	pub tls: bool,
+}
+
+/// Listener policies by address
+pub type Policies = HashMap<String, Arc<Policy>>;
+
+/// `Live` is handler and listener policies new connections start with, they
+/// are replaced together on reload while sessions in progress keep old ones
+pub struct Live<H> {
+	current: Arc<RwLock<(H, Policies)>>,
+}
+
+impl<H> Clone for Live<H> {
+	fn clone(&self) -> Live<H> {
+		Live {
+			current: self.current.clone(),
+		}
+	}
+}
+
+impl<H> Live<H>
+where H: Enforcer + Clone {
+	pub fn new(handler: H, policies: Policies) -> Live<H> {
+		Live {
+			current: Arc::new(RwLock::new((handler, policies))),
+		}
+	}
+
+	/// Look at current handler
+	pub fn read<T, F>(&self, reader: F) -> T
+	where F: FnOnce(&H) -> T {
+		reader(&self.current.read().unwrap().0)
+	}
+
+	/// Swap handler and policies
+	pub fn replace(&self, handler: H, policies: Policies) {
+		*self.current.write().unwrap() = (handler, policies);
+	}
+
+	/// Handler for new session on listener, following it's policy
+	fn session(&self, addr: &str) -> (H, Arc<Policy>) {
+		let current = self.current.read().unwrap();
+		let mut handler = current.0.clone();
+		let policy = current.1.get(addr).cloned().unwrap_or_default();
+		handler.enforce(policy.clone());
+		(handler, policy)
+	}
+}
+
+/// `ClientAuth` is how TLS clients are checked
+#[derive(Clone, Debug)]
+pub struct ClientAuth {
+	/// CA bundle client certificates are verified against
+	pub ca: String,
+	/// Refuse clients without certificate
+	pub required: bool,
+}
+
+/// Load certificates from PEM file
+fn certs(path: &str) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
+	rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+		.collect::<Result<Vec<_>, _>>()
+		.map_err(|err| anyhow!("Can't parse certificates from {}: {}", path, err))
+}
+
+/// Load certificate chain and key for TLS listeners
+pub fn tls_config(cert: &str, key: &str, clients: Option<ClientAuth>) -> Result<Arc<ServerConfig>> {
+	let certs = certs(cert)?;
+	let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+		.ok_or(anyhow!("No private key found in {}", key))?;
+	let builder = ServerConfig::builder();
+	let config = match clients {
+		Some(clients) => {
+			let mut roots = RootCertStore::empty();
+			for cert in self::certs(&clients.ca)? {
+				roots.add(cert)?;
+			}
+			let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+			let verifier = match clients.required {
+				true => verifier.build()?,
+				false => verifier.allow_unauthenticated().build()?,
+			};
+			builder.with_client_cert_verifier(verifier)
+		},
+		None => builder.with_no_client_auth(),
+	}.with_single_cert(certs, key)?;
+	Ok(Arc::new(config))
+}
+
+/// `Server` accepts connections and runs SMTP sessions
+pub struct Server<H>
+where H: Handler + Enforcer + Clone + Send + Sync + 'static {
+	/// Offer SMTP AUTH, handler checks credentials
+	auth: bool,
+	builder: SessionBuilder,
+	directory: Option<Arc<dyn Directory>>,
+	live: Live<H>,
+	/// Sessions over this get 421
+	max_connections: Option<usize>,
+	pregreet: Option<Duration>,
+	screen: Option<Arc<dyn Screen>>,
+	/// Permissions of Unix sockets
+	socket_mode: Option<u32>,
+	/// Offered to plain text clients with STARTTLS
+	starttls: Option<Arc<ServerConfig>>,
+	tls: Option<Arc<ServerConfig>>,
+}
+
+impl<H> Server<H>
+where H: Handler + Enforcer + Clone + Send + Sync + 'static {
+	pub fn new(name: &str, live: Live<H>, tls: Option<Arc<ServerConfig>>) -> Server<H> {
+		Server {
+			auth: false,
+			builder: SessionBuilder::new(name),
+			directory: None,
+			live,
+			max_connections: None,
+			pregreet: None,
+			screen: None,
+			socket_mode: None,
+			starttls: None,
+			tls,
+		}
+	}
+
+	/// Offer SMTP AUTH PLAIN and LOGIN
+	pub fn with_auth(mut self) -> Server<H> {
+		self.auth = true;
+		self
+	}
+
+	/// Answer VRFY and EXPN using specified directory
+	pub fn with_directory(mut self, directory: Arc<dyn Directory>) -> Server<H> {
+		self.directory = Some(directory);
+		self
+	}
+
+	/// Refuse connections while this many sessions are in progress
+	pub fn with_max_connections(mut self, max: usize) -> Server<H> {
+		self.max_connections = Some(max);
+		self
+	}
+
+	/// Wait before greeting plain text clients, dropping those who speak first
+	pub fn with_pregreet(mut self, delay: Duration) -> Server<H> {
+		self.pregreet = Some(delay);
+		self
+	}
+
+	/// Check every client before greeting it
+	pub fn with_screen(mut self, screen: Arc<dyn Screen>) -> Server<H> {
+		self.screen = Some(screen);
+		self
+	}
+
+	/// Set permissions of Unix sockets, like 0o660
+	pub fn with_socket_mode(mut self, mode: u32) -> Server<H> {
+		self.socket_mode = Some(mode);
+		self
+	}
+
+	/// Let plain text clients switch to TLS
+	pub fn with_starttls(mut self, config: Arc<ServerConfig>) -> Server<H> {
+		self.starttls = Some(config);
+		self
+	}
+
+	/// Bind all listeners and serve them forever
+	pub fn serve(self, listeners: &[Listener]) -> Result<()> {
+		let mut threads = vec![];
+		for listener in listeners {
+			if listener.tls && self.tls.is_none() {
+				bail!("Listener {} needs TLS but no certificate is configured", listener.addr);
+			}
+			let socket = Socket::bind(&listener.addr, self.socket_mode)
+				.map_err(|err| anyhow!("Can't listen on {}: {}", listener.addr, err))?;
+			let (tls, starttls) = match listener.tls {
+				true => (self.tls.clone(), None),
+				false => (None, self.starttls.clone()),
+			};
+			let mut builder = self.builder.clone();
+			if starttls.is_some() {
+				builder.enable_start_tls();
+			}
+			let session = Settings {
+				auth: self.auth,
+				builder,
+				directory: self.directory.clone(),
+				max_connections: self.max_connections,
+				policy: Arc::default(),
+				pregreet: self.pregreet,
+				screen: self.screen.clone(),
+				starttls,
+				tls,
+			};
+			let addr = listener.addr.clone();
+			let live = self.live.clone();
+			threads.push(thread::spawn(move || accept(socket, &addr, session, live)));
+		}
+		for thread in threads {
+			thread.join().map_err(|_| anyhow!("Listener thread panicked"))?;
+		}
+		Ok(())
+	}
+}
+
+/// `Settings` is everything session needs besides handler
+#[derive(Clone)]
+struct Settings {
+	auth: bool,
+	builder: SessionBuilder,
+	directory: Option<Arc<dyn Directory>>,
+	max_connections: Option<usize>,
+	/// Listener policy session was started with
+	policy: Arc<Policy>,
+	pregreet: Option<Duration>,
+	screen: Option<Arc<dyn Screen>>,
+	starttls: Option<Arc<ServerConfig>>,
+	tls: Option<Arc<ServerConfig>>,
+}
+
+/// Accept connections forever, each one gets it's own thread
+fn accept<H>(socket: Socket, addr: &str, settings: Settings, live: Live<H>)
+where H: Handler + Enforcer + Clone + Send + Sync + 'static {
+	loop {
+		match socket.accept() {
+			// TLS clients can't be told anything before handshake
+			Ok(mut stream) if stopping() => if settings.tls.is_none() {
+				let _ = write_response(&mut stream, &Response::custom(421, "Service shutting down".into()));
+			},
+			Ok(mut stream) if settings.max_connections.is_some_and(|max| active() >= max) => {
+				warn!("Refused connection from {}, too many sessions", stream.peer());
+				if settings.tls.is_none() {
+					let _ = write_response(&mut stream, &Response::custom(421, "Too many connections, try again later".into()));
+				}
+			},
+			Ok(stream) => {
+				let active = Active::new();
+				let (handler, policy) = live.session(addr);
+				let settings = Settings {
+					policy,
+					..settings.clone()
+				};
+				let peer = stream.peer();
+				thread::spawn(move || {
+					let _active = active;
+					let _session = tracing::info_span!("session", peer = %peer).entered();
+					if let Err(err) = connection(stream, &settings, handler) {
+						error!("SMTP session failed: {:?}", err);
+					}
+				});
+			},
+			Err(err) => error!("Connection failed: {}", err),
+		}
+	}
+}
+
+/// Run one SMTP session, wrapping it in TLS first if needed
+fn connection<H>(mut stream: Stream, settings: &Settings, handler: H) -> Result<()>
+where H: Handler + Enforcer {
+	let remote = stream.remote()?;
+	stream.set_timeout(Some(FIVE_MINUTES))?;
+	// local clients are not screened
+	let screen = settings.screen.as_ref().filter(|_| matches!(stream, Stream::Tcp(_)));
+	if let Some(rejection) = screen.and_then(|screen| screen.screen(remote)) {
+		info!("Rejected {} before greeting", remote);
+		// there's no way to tell anything to TLS client before handshake
+		if settings.tls.is_none() {
+			write_response(&mut stream, &rejection)?;
+		}
+		return Ok(());
+	}
+	let handler = Rc::new(RefCell::new(handler));
+	match &settings.tls {
+		Some(config) => {
+			let mut conn = ServerConnection::new(config.clone())?;
+			// finish handshake first to know who's there before greeting
+			while conn.is_handshaking() {
+				conn.complete_io(&mut stream)?;
+			}
+			if let Some(identity) = conn.peer_certificates()
+				.and_then(|certs| certs.first())
+				.and_then(|cert| x509::identity(cert))
+			{
+				info!("Client {} identified as {}", remote, identity);
+				handler.borrow_mut().identify(identity);
+			}
+			let mut session = settings.builder.build(remote, Shared(handler.clone()));
+			let mut stream = BufReader::new(StreamOwned::new(conn, stream));
+			write_response(stream.get_mut(), &session.greeting())?;
+			if run(&mut session, &handler, &mut stream, settings, true)? {
+				bail!("STARTTLS over TLS");
+			}
+			Ok(())
+		},
+		None => {
+			if let (Some(delay), Stream::Tcp(tcp)) = (settings.pregreet, &stream) {
+				if spoke_early(tcp, delay)? {
+					info!("Rejected {}: spoke before greeting", remote);
+					write_response(&mut stream, &Response::custom(554, "Protocol violation: talking before greeting".into()))?;
+					return Ok(());
+				}
+			}
+			let mut session = settings.builder.build(remote, Shared(handler.clone()));
+			let mut stream = BufReader::new(stream);
+			write_response(stream.get_mut(), &session.greeting())?;
+			if !run(&mut session, &handler, &mut stream, settings, false)? {
+				return Ok(());
+			}
+			let config = settings.starttls.clone().ok_or(anyhow!("STARTTLS is not supported"))?;
+			// anything client sent after STARTTLS is dropped with the buffer
+			let conn = ServerConnection::new(config)?;
+			let mut stream = BufReader::new(StreamOwned::new(conn, stream.into_inner()));
+			session.tls_active();
+			if run(&mut session, &handler, &mut stream, settings, true)? {
+				bail!("STARTTLS over TLS");
+			}
+			Ok(())
+		},
+	}
+}
+
+/// Wait for specified time checking whether client sends anything before
+/// greeting, legitimate clients never do
+fn spoke_early(stream: &TcpStream, delay: Duration) -> Result<bool> {
+	stream.set_read_timeout(Some(delay))?;
+	let mut byte = [0; 1];
+	let spoke = match stream.peek(&mut byte) {
+		Ok(0) => bail!("Client disconnected before greeting"),
+		Ok(_) => true,
+		Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => false,
+		Err(err) => return Err(err.into()),
+	};
+	stream.set_read_timeout(Some(FIVE_MINUTES))?;
+	Ok(spoke)
+}
+
+/// Answer VRFY/EXPN ourselves as `mailin` doesn't pass address to handler
+fn lookup<W>(stream: &mut W, line: &[u8], directory: &dyn Directory) -> Result<bool>
+where W: Write {
+	let line = String::from_utf8_lossy(line);
+	let line = line.trim_end();
+	let (verb, arg) = line.split_once(' ').unwrap_or((line, ""));
+	let expand = match verb.to_ascii_uppercase().as_str() {
+		"VRFY" => false,
+		"EXPN" => true,
+		_ => return Ok(false),
+	};
+	let arg = arg.trim();
+	let addr = match (arg.find('<'), arg.rfind('>')) {
+		(Some(start), Some(end)) if start < end => &arg[start + 1..end],
+		_ => arg,
+	};
+	let (code, lines) = if addr.is_empty() {
+		(501, vec!["Syntax: VRFY <address>".into()])
+	} else if expand {
+		directory.expand(addr)
+	} else {
+		directory.verify(addr)
+	};
+	for (index, text) in lines.iter().enumerate() {
+		let sep = if index + 1 < lines.len() { '-' } else { ' ' };
+		write!(stream, "{}{}{}\r\n", code, sep, text)?;
+	}
+	stream.flush()?;
+	Ok(true)
+}
+
+/// `Shared` passes session calls to handler we also use ourselves
+struct Shared<H>(Rc<RefCell<H>>);
+
+impl<H> Handler for Shared<H>
+where H: Handler {
+	fn helo(&mut self, ip: IpAddr, domain: &str) -> Response {
+		self.0.borrow_mut().helo(ip, domain)
+	}
+
+	fn mail(&mut self, ip: IpAddr, domain: &str, from: &str) -> Response {
+		self.0.borrow_mut().mail(ip, domain, from)
+	}
+
+	fn rcpt(&mut self, to: &str) -> Response {
+		self.0.borrow_mut().rcpt(to)
+	}
+
+	fn data_start(&mut self, domain: &str, from: &str, is8bit: bool, to: &[String]) -> Response {
+		self.0.borrow_mut().data_start(domain, from, is8bit, to)
+	}
+
+	fn data(&mut self, buf: &[u8]) -> std::io::Result<()> {
+		self.0.borrow_mut().data(buf)
+	}
+
+	fn data_end(&mut self) -> Response {
+		self.0.borrow_mut().data_end()
+	}
+}
+
+/// Read one line of SASL exchange, None when client cancels it
+fn challenge<S>(stream: &mut BufReader<S>, prompt: &str) -> Result<Option<String>>
+where S: Read + Write {
+	write!(stream.get_mut(), "334 {}\r\n", prompt)?;
+	stream.get_mut().flush()?;
+	let mut line = vec![];
+	if !read_line(stream, &mut line, COMMAND_LIMIT)? {
+		bail!("SASL line too long");
+	}
+	let line = String::from_utf8_lossy(&line);
+	Ok(match line.trim() {
+		"*" => None,
+		line => Some(line.to_owned()),
+	})
+}
+
+fn decode(text: &str) -> Option<String> {
+	String::from_utf8(STANDARD.decode(text).ok()?).ok()
+}
+
+/// Run AUTH PLAIN or LOGIN exchange ourselves, `mailin` demands
+/// authentication from everyone once it's enabled
+fn authenticate<H, S>(stream: &mut BufReader<S>, arg: &str, handler: &RefCell<H>) -> Result<Response>
+where H: Handler, S: Read + Write {
+	let cancelled = Response::custom(501, "Authentication cancelled".into());
+	let malformed = Response::custom(501, "Malformed authentication data".into());
+	let mut words = arg.split_whitespace();
+	let mechanism = words.next().unwrap_or_default().to_ascii_uppercase();
+	let initial = words.next().map(str::to_owned);
+	match mechanism.as_str() {
+		"PLAIN" => {
+			let Some(data) = initial.map_or_else(|| challenge(stream, ""), |data| Ok(Some(data)))? else {
+				return Ok(cancelled);
+			};
+			let data = decode(&data).unwrap_or_default();
+			Ok(match data.split('\0').collect::<Vec<_>>()[..] {
+				[authorization, user, password] => handler.borrow_mut().auth_plain(authorization, user, password),
+				_ => malformed,
+			})
+		},
+		"LOGIN" => {
+			let Some(user) = initial.map_or_else(|| challenge(stream, "VXNlcm5hbWU6"), |user| Ok(Some(user)))? else {
+				return Ok(cancelled);
+			};
+			let Some(password) = challenge(stream, "UGFzc3dvcmQ6")? else {
+				return Ok(cancelled);
+			};
+			Ok(match (decode(&user), decode(&password)) {
+				(Some(user), Some(password)) => handler.borrow_mut().auth_login(&user, &password),
+				_ => malformed,
+			})
+		},
+		_ => Ok(Response::custom(504, "Unrecognized authentication type".into())),
+	}
+}
+
+/// Add extensions `mailin` doesn't know about to EHLO reply
+fn with_extensions(reply: &[u8], extensions: &[String]) -> Vec<u8> {
+	let reply = String::from_utf8_lossy(reply);
+	let mut lines: Vec<String> = reply.lines()
+		.map(|line| match line.strip_prefix("250 ") {
+			Some(rest) => format!("250-{}", rest),
+			None => line.to_owned(),
+		})
+		.collect();
+	lines.extend(extensions.iter().map(|extension| format!("250-{}", extension)));
+	if let Some(last) = lines.last_mut() {
+		last.replace_range(..4, "250 ");
+	}
+	format!("{}\r\n", lines.join("\r\n")).into_bytes()
+}
+
+/// Take SIZE parameter off MAIL command as `mailin` doesn't accept it,
+/// returns command without it and declared size
+fn declared_size(line: &str) -> (String, Option<usize>) {
+	let mut size = None;
+	let words: Vec<&str> = line.split_whitespace()
+		.filter(|word| match word.get(..5) {
+			Some(key) if key.eq_ignore_ascii_case("SIZE=") => {
+				size = word[5..].parse().ok();
+				false
+			},
+			_ => true,
+		})
+		.collect();
+	(format!("{}\r\n", words.join(" ")), size)
+}
+
+/// Read line up to `limit` bytes long, returns false when it's longer; rest
+/// of such line is skipped without keeping it
+fn read_line<R>(stream: &mut R, line: &mut Vec<u8>, limit: usize) -> Result<bool>
+where R: BufRead {
+	if stream.by_ref().take(limit as u64).read_until(b'\n', line)? == 0 {
+		bail!("Unexpected EOF");
+	}
+	if line.ends_with(b"\n") || line.len() < limit {
+		return Ok(true);
+	}
+	loop {
+		let buf = stream.fill_buf()?;
+		if buf.is_empty() {
+			bail!("Unexpected EOF");
+		}
+		match buf.iter().position(|byte| *byte == b'\n') {
+			Some(end) => {
+				stream.consume(end + 1);
+				return Ok(false);
+			},
+			None => {
+				let len = buf.len();
+				stream.consume(len);
+			},
+		}
+	}
+}
+
+/// Feed client lines to the state machine until session ends, returns true
+/// when client asked to switch to TLS
+fn run<H, S>(session: &mut mailin::Session<Shared<H>>, handler: &RefCell<H>, stream: &mut BufReader<S>, settings: &Settings, secure: bool) -> Result<bool>
+where H: Handler, S: Read + Write {
+	let mut line = Vec::with_capacity(80);
+	let mut authenticated = false;
+	// between 354 and lone dot lines are message body, not commands
+	let mut data = false;
+	loop {
+		line.clear();
+		let limit = match data {
+			true => TEXT_LIMIT,
+			false => COMMAND_LIMIT,
+		};
+		if !read_line(stream, &mut line, limit)? {
+			write_response(stream.get_mut(), &Response::custom(500, "Line too long".into()))?;
+			// client is still sending body, there's no way to resync with it
+			if data {
+				warn!("Closing session, body line over {} bytes", TEXT_LIMIT);
+				return Ok(false);
+			}
+			continue;
+		}
+		if let (false, Some(directory)) = (data, &settings.directory) {
+			if lookup(stream.get_mut(), &line, directory.as_ref())? {
+				continue;
+			}
+		}
+		let text = String::from_utf8_lossy(&line).into_owned();
+		let (verb, arg) = text.trim_end().split_once(' ').unwrap_or((text.trim_end(), ""));
+		let verb = verb.to_ascii_uppercase();
+		// SIZE only comes with MAIL FROM, body lines are not touched
+		if !data && verb == "MAIL" && arg.trim_start().get(..5).is_some_and(|from| from.eq_ignore_ascii_case("FROM:")) {
+			let (command, size) = declared_size(&text);
+			if let (Some(size), Some(max)) = (size, settings.policy.max_size) {
+				if size > max && !settings.policy.never_reject {
+					write_response(stream.get_mut(), &Response::custom(552, "Message size exceeds fixed maximum message size".into()))?;
+					continue;
+				}
+			}
+			line = command.into_bytes();
+		}
+		if !data && settings.auth && verb == "AUTH" {
+			let res = if authenticated {
+				Response::custom(503, "Already authenticated".into())
+			} else if !secure && !settings.policy.plaintext_auth {
+				Response::custom(538, "Encryption required for requested authentication mechanism".into())
+			} else {
+				authenticate(stream, arg, handler)?
+			};
+			authenticated |= res.code == 235;
+			write_response(stream.get_mut(), &res)?;
+			continue;
+		}
+		let res = session.process(&line);
+		data = match data {
+			true => !matches!(line.as_slice(), b".\r\n" | b".\n"),
+			false => res.code == 354,
+		};
+		let mut extensions = vec![];
+		if verb == "EHLO" && res.code == 250 {
+			if settings.auth && (secure || settings.policy.plaintext_auth) {
+				extensions.push("AUTH PLAIN LOGIN".to_string());
+			}
+			if let Some(max) = settings.policy.max_size {
+				extensions.push(format!("SIZE {}", max));
+			}
+		}
+		match res.action {
+			Action::Reply if !extensions.is_empty() => {
+				stream.get_mut().write_all(&with_extensions(&res.buffer()?, &extensions))?;
+				stream.get_mut().flush()?;
+			},
+			Action::Reply => write_response(stream.get_mut(), &res)?,
+			Action::Close => {
+				write_response(stream.get_mut(), &res)?;
+				return Ok(false);
+			},
+			Action::UpgradeTls => {
+				write_response(stream.get_mut(), &res)?;
+				return Ok(true);
+			},
+			Action::NoReply => (),
+		}
+	}
+}
+
+/// Send response to client
+fn write_response<W>(stream: &mut W, res: &Response) -> Result<()>
+where W: Write {
+	// single write, some clients start TLS handshake right after reply code
+	let mut buf = vec![];
+	res.write_to(&mut buf)?;
+	stream.write_all(&buf)?;
+	stream.flush()?;
+	Ok(())
+}
@@ -0,0 +1,130 @@
+//! Listening sockets, either TCP or Unix domain ones for local MTAs. Unix
+//! socket addresses look like "unix:/run/smtp2tg.sock".
+
+use anyhow::{
+	anyhow,
+	Result,
+};
+
+use std::{
+	fs,
+	io::{
+		self,
+		ErrorKind,
+		Read,
+		Write,
+	},
+	net::{
+		IpAddr,
+		Ipv4Addr,
+		TcpListener,
+		TcpStream,
+	},
+	os::unix::{
+		fs::{
+			FileTypeExt,
+			PermissionsExt,
+		},
+		net::{
+			UnixListener,
+			UnixStream,
+		},
+	},
+	time::Duration,
+};
+
+/// `Socket` accepts connections
+pub enum Socket {
+	Tcp(TcpListener),
+	Unix(UnixListener),
+}
+
+impl Socket {
+	/// Bind address, stale Unix socket is replaced and gets `mode` permissions
+	pub fn bind(addr: &str, mode: Option<u32>) -> Result<Socket> {
+		let Some(path) = addr.strip_prefix("unix:") else {
+			return Ok(Socket::Tcp(TcpListener::bind(addr)?));
+		};
+		match fs::symlink_metadata(path) {
+			Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+			Ok(_) => return Err(anyhow!("{} exists and is not a socket", path)),
+			Err(err) if err.kind() == ErrorKind::NotFound => {},
+			Err(err) => return Err(err.into()),
+		};
+		let socket = UnixListener::bind(path)?;
+		if let Some(mode) = mode {
+			fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+		}
+		Ok(Socket::Unix(socket))
+	}
+
+	/// Wait for next client
+	pub fn accept(&self) -> io::Result<Stream> {
+		match self {
+			Socket::Tcp(socket) => socket.accept().map(|(stream, _)| Stream::Tcp(stream)),
+			Socket::Unix(socket) => socket.accept().map(|(stream, _)| Stream::Unix(stream)),
+		}
+	}
+}
+
+/// `Stream` is connected client
+pub enum Stream {
+	Tcp(TcpStream),
+	Unix(UnixStream),
+}
+
+impl Stream {
+	/// Client address, local clients look like loopback ones
+	pub fn remote(&self) -> io::Result<IpAddr> {
+		match self {
+			Stream::Tcp(stream) => Ok(stream.peer_addr()?.ip()),
+			Stream::Unix(_) => Ok(Ipv4Addr::LOCALHOST.into()),
+		}
+	}
+
+	/// Client address, for humans
+	pub fn peer(&self) -> String {
+		match self {
+			Stream::Tcp(stream) => stream.peer_addr().map_or("unknown".into(), |peer| peer.to_string()),
+			Stream::Unix(_) => "local".into(),
+		}
+	}
+
+	pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+		match self {
+			Stream::Tcp(stream) => {
+				stream.set_read_timeout(timeout)?;
+				stream.set_write_timeout(timeout)
+			},
+			Stream::Unix(stream) => {
+				stream.set_read_timeout(timeout)?;
+				stream.set_write_timeout(timeout)
+			},
+		}
+	}
+}
+
+impl Read for Stream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		match self {
+			Stream::Tcp(stream) => stream.read(buf),
+			Stream::Unix(stream) => stream.read(buf),
+		}
+	}
+}
+
+impl Write for Stream {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self {
+			Stream::Tcp(stream) => stream.write(buf),
+			Stream::Unix(stream) => stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		match self {
+			Stream::Tcp(stream) => stream.flush(),
+			Stream::Unix(stream) => stream.flush(),
+		}
+	}
+}
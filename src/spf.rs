@@ -0,0 +1,324 @@
+//! SPF (RFC 7208): whether connecting client may send mail for MAIL FROM
+//! domain. Failing mail can be just annotated, quarantined to default chat or
+//! rejected at RCPT. Macros with transformers and "ptr" mechanism are not
+//! supported, such records are errors.
+
+use anyhow::{
+	bail,
+	Result,
+};
+
+use std::{
+	fmt,
+	future::Future,
+	net::IpAddr,
+	pin::Pin,
+};
+
+use crate::{
+	access::Net,
+	dns::Resolver,
+};
+
+/// DNS lookups one check can take
+const LOOKUP_LIMIT: u32 = 10;
+
+/// `Outcome` is SPF result
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+	Pass,
+	Fail,
+	SoftFail,
+	Neutral,
+	/// Domain has no SPF record
+	None,
+	TempError,
+	PermError,
+}
+
+impl fmt::Display for Outcome {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Outcome::Pass => "pass",
+			Outcome::Fail => "fail",
+			Outcome::SoftFail => "softfail",
+			Outcome::Neutral => "neutral",
+			Outcome::None => "none",
+			Outcome::TempError => "temperror",
+			Outcome::PermError => "permerror",
+		})
+	}
+}
+
+/// `Check` is SPF result for a transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+	pub outcome: Outcome,
+	pub domain: String,
+	pub ip: IpAddr,
+}
+
+impl fmt::Display for Check {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "SPF {} for {} from {}", self.outcome, self.domain, self.ip)
+	}
+}
+
+/// `Action` sets what happens to mail failing SPF
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+	/// Just show result in message
+	Annotate,
+	/// Deliver to default chat only
+	Quarantine,
+	/// Refuse recipients
+	Reject,
+}
+
+/// `Spf` holds resolver and policy
+#[derive(Clone, Debug)]
+pub struct Spf {
+	resolver: Resolver,
+	pub action: Action,
+}
+
+impl Spf {
+	/// Read `[spf]` table, it's optional
+	pub fn new(settings: &config::Config) -> Result<Option<Spf>> {
+		if let Err(config::ConfigError::NotFound(_)) = settings.get_table("spf") {
+			return Ok(None);
+		}
+		let action = match settings.get_string("spf.action") {
+			Ok(action) => match action.as_str() {
+				"annotate" => Action::Annotate,
+				"quarantine" => Action::Quarantine,
+				"reject" => Action::Reject,
+				_ => bail!("[smtp2tg.toml] \"spf.action\" should be either \"annotate\", \"quarantine\" or \"reject\""),
+			},
+			Err(config::ConfigError::NotFound(_)) => Action::Annotate,
+			Err(err) => bail!("[smtp2tg.toml] can't get \"spf.action\":\n {}", err),
+		};
+		Ok(Some(Spf {
+			resolver: Resolver::new(settings, "spf.resolver")?,
+			action,
+		}))
+	}
+
+	/// Check client against envelope sender, HELO domain stands for empty
+	/// sender of bounces
+	pub async fn check(&self, ip: IpAddr, helo: &str, from: &str) -> Check {
+		let sender = match from.is_empty() {
+			true => format!("postmaster@{}", helo),
+			false => from.to_owned(),
+		};
+		let domain = sender.rsplit_once('@').map_or(helo, |(_, domain)| domain).to_lowercase();
+		let mut eval = Eval {
+			resolver: &self.resolver,
+			ip: ip.to_canonical(),
+			sender,
+			helo: helo.to_owned(),
+			lookups: 0,
+		};
+		let outcome = eval.check_host(domain.clone()).await;
+		Check {
+			outcome,
+			domain,
+			ip: eval.ip,
+		}
+	}
+}
+
+/// `Eval` is state of a single check
+struct Eval<'a> {
+	resolver: &'a Resolver,
+	ip: IpAddr,
+	sender: String,
+	helo: String,
+	/// DNS lookups taken by mechanisms so far
+	lookups: u32,
+}
+
+impl<'a> Eval<'a> {
+	/// Evaluate policy of domain, includes and redirects recurse
+	fn check_host(&mut self, domain: String) -> Pin<Box<dyn Future<Output = Outcome> + '_>> {
+		Box::pin(async move {
+			let records = match self.resolver.txt(&domain).await {
+				Ok(records) => records,
+				Err(err) => {
+					debug!("SPF lookup failed: {}", err);
+					return Outcome::TempError;
+				},
+			};
+			let records: Vec<&String> = records.iter()
+				.filter(|record| record.to_lowercase() == "v=spf1" || record.to_lowercase().starts_with("v=spf1 "))
+				.collect();
+			let record = match records.as_slice() {
+				[] => return Outcome::None,
+				[record] => record.to_owned(),
+				_ => return Outcome::PermError,
+			};
+			let mut redirect = None;
+			for term in record.split_whitespace().skip(1) {
+				match term.split_once('=') {
+					Some((name, value)) if name.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) => {
+						if name.eq_ignore_ascii_case("redirect") {
+							redirect = Some(value.to_owned());
+						}
+						continue;
+					},
+					_ => {},
+				};
+				let (outcome, mechanism) = match term.chars().next() {
+					Some('+') => (Outcome::Pass, &term[1..]),
+					Some('-') => (Outcome::Fail, &term[1..]),
+					Some('~') => (Outcome::SoftFail, &term[1..]),
+					Some('?') => (Outcome::Neutral, &term[1..]),
+					_ => (Outcome::Pass, term),
+				};
+				match self.mechanism(&domain, mechanism).await {
+					Ok(true) => return outcome,
+					Ok(false) => {},
+					Err(outcome) => return outcome,
+				};
+			}
+			match redirect {
+				Some(target) => {
+					let Some(target) = self.expand(&target, &domain) else {
+						return Outcome::PermError;
+					};
+					if self.count() {
+						return Outcome::PermError;
+					}
+					match self.check_host(target).await {
+						Outcome::None => Outcome::PermError,
+						outcome => outcome,
+					}
+				},
+				None => Outcome::Neutral,
+			}
+		})
+	}
+
+	/// Count DNS lookup, returns whether there were too many
+	fn count(&mut self) -> bool {
+		self.lookups += 1;
+		self.lookups > LOOKUP_LIMIT
+	}
+
+	/// Whether mechanism matches client, errors end the check
+	async fn mechanism(&mut self, domain: &str, mechanism: &str) -> Result<bool, Outcome> {
+		let (name, arg) = match mechanism.find([':', '/']) {
+			Some(at) => (&mechanism[..at], &mechanism[at..]),
+			None => (mechanism, ""),
+		};
+		let spec = arg.strip_prefix(':');
+		// "a" and "mx" can have prefix lengths for both families, like "/24//64"
+		let rest = spec.unwrap_or(arg);
+		let (target, cidr) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+		let target = match target.is_empty() {
+			true => domain.to_owned(),
+			false => self.expand(target, domain).ok_or(Outcome::PermError)?,
+		};
+		let (v4, v6) = cidr.split_once("//").unwrap_or((cidr, ""));
+		let v4 = match v4.strip_prefix('/') {
+			Some(v4) => v4.parse().map_err(|_| Outcome::PermError)?,
+			None => 32,
+		};
+		let v6 = match v6.is_empty() {
+			true => 128,
+			false => v6.parse().map_err(|_| Outcome::PermError)?,
+		};
+		let client = self.ip;
+		let within = |ip: IpAddr| Net {
+			addr: ip,
+			prefix: if ip.is_ipv4() { v4 } else { v6 },
+		}.contains(client);
+		match name.to_lowercase().as_str() {
+			"all" => Ok(true),
+			"include" => {
+				if self.count() {
+					return Err(Outcome::PermError);
+				}
+				match self.check_host(target).await {
+					Outcome::Pass => Ok(true),
+					Outcome::Fail | Outcome::SoftFail | Outcome::Neutral => Ok(false),
+					Outcome::TempError => Err(Outcome::TempError),
+					Outcome::None | Outcome::PermError => Err(Outcome::PermError),
+				}
+			},
+			"a" => {
+				if self.count() {
+					return Err(Outcome::PermError);
+				}
+				let ips = self.resolver.ips(&target).await.map_err(|_| Outcome::TempError)?;
+				Ok(ips.into_iter().any(within))
+			},
+			"mx" => {
+				if self.count() {
+					return Err(Outcome::PermError);
+				}
+				let hosts = self.resolver.mx(&target).await.map_err(|_| Outcome::TempError)?;
+				for host in hosts.iter().take(LOOKUP_LIMIT as usize) {
+					let ips = self.resolver.ips(host).await.map_err(|_| Outcome::TempError)?;
+					if ips.into_iter().any(within) {
+						return Ok(true);
+					}
+				}
+				Ok(false)
+			},
+			"ip4" | "ip6" => {
+				let net = spec.and_then(Net::parse).ok_or(Outcome::PermError)?;
+				Ok(net.contains(self.ip))
+			},
+			"exists" => {
+				if self.count() {
+					return Err(Outcome::PermError);
+				}
+				let ips = self.resolver.ips(&target).await.map_err(|_| Outcome::TempError)?;
+				Ok(ips.iter().any(IpAddr::is_ipv4))
+			},
+			_ => Err(Outcome::PermError),
+		}
+	}
+
+	/// Expand macros in domain spec, ones with transformers are not supported
+	fn expand(&self, spec: &str, domain: &str) -> Option<String> {
+		let mut expanded = String::new();
+		let mut chars = spec.chars();
+		while let Some(c) = chars.next() {
+			if c != '%' {
+				expanded.push(c);
+				continue;
+			}
+			match chars.next()? {
+				'%' => expanded.push('%'),
+				'_' => expanded.push(' '),
+				'-' => expanded.push_str("%20"),
+				'{' => {
+					let letter = chars.next()?.to_ascii_lowercase();
+					if chars.next()? != '}' {
+						return None;
+					}
+					let (local, sender_domain) = self.sender.rsplit_once('@').unwrap_or(("postmaster", &self.sender));
+					match letter {
+						's' => expanded.push_str(&self.sender),
+						'l' => expanded.push_str(local),
+						'o' => expanded.push_str(sender_domain),
+						'd' => expanded.push_str(domain),
+						'h' => expanded.push_str(&self.helo),
+						'i' => expanded.push_str(&match self.ip {
+							IpAddr::V4(ip) => ip.to_string(),
+							IpAddr::V6(ip) => ip.octets().iter()
+								.map(|byte| format!("{:x}.{:x}", byte >> 4, byte & 0x0f))
+								.collect::<Vec<_>>().join("."),
+						}),
+						'v' => expanded.push_str(if self.ip.is_ipv4() { "in-addr" } else { "ip6" }),
+						_ => return None,
+					};
+				},
+				_ => return None,
+			};
+		}
+		Some(expanded)
+	}
+}
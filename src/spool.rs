@@ -0,0 +1,181 @@
+//! Spool for mail we failed to deliver. Instead of asking sender to retry
+//! (which many of them never do) mail is accepted and kept on disk, then
+//! retried in background until it's delivered or expires. Each message is a
+//! raw `.eml` file with envelope in `.json` file next to it.
+
+use anyhow::Result;
+use serde::{
+	Deserialize,
+	Serialize,
+};
+
+use std::{
+	fs,
+	path::PathBuf,
+	time::{
+		Duration,
+		SystemTime,
+	},
+};
+
+use crate::routing::Priority;
+
+/// `Envelope` is what we know about message besides it's data
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Envelope {
+	pub from: String,
+	pub to: Vec<String>,
+	/// Authenticated client
+	#[serde(default)]
+	pub identity: Option<String>,
+	/// Priority of the most urgent route
+	#[serde(default)]
+	pub priority: Priority,
+	/// Unix time message was received
+	#[serde(default)]
+	pub received: Option<u64>,
+}
+
+/// `Entry` is one spooled message
+#[derive(Debug)]
+pub struct Entry {
+	pub envelope: Envelope,
+	pub data: Vec<u8>,
+	/// File name without extension
+	name: String,
+}
+
+/// `Spool` is a directory with undelivered mail
+#[derive(Clone, Debug)]
+pub struct Spool {
+	dir: PathBuf,
+	/// How often spooled mail is retried
+	pub interval: Duration,
+}
+
+impl Spool {
+	/// Read `spool` and `spool_interval`, there's no spool without directory
+	pub fn new(settings: &config::Config) -> Option<Spool> {
+		let dir = match settings.get_string("spool") {
+			Ok(dir) => PathBuf::from(dir),
+			Err(config::ConfigError::NotFound(_)) => return None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"spool\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let interval = match settings.get_string("spool_interval") {
+			Ok(value) => crate::parse_duration(&value).filter(|interval| !interval.is_zero()).unwrap_or_else(|| {
+				error!("[smtp2tg.toml] \"spool_interval\" should be a duration like \"30s\" or \"5m\".");
+				panic!("bad setting");
+			}),
+			Err(config::ConfigError::NotFound(_)) => Duration::from_secs(60),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"spool_interval\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		Some(Spool {
+			dir,
+			interval,
+		})
+	}
+
+	/// Store message, envelope is written last so half written message is
+	/// never picked up
+	pub fn put(&self, envelope: &Envelope, data: &[u8]) -> Result<PathBuf> {
+		let stamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+		let name = format!("{}.{:09}", stamp.as_secs(), stamp.subsec_nanos());
+		fs::write(self.file(&name, "eml"), data)?;
+		fs::write(self.file(&name, "json"), serde_json::to_vec(envelope)?)?;
+		Ok(self.file(&name, "eml"))
+	}
+
+	/// Path of message or envelope file
+	fn file(&self, name: &str, extension: &str) -> PathBuf {
+		self.dir.join(format!("{}.{}", name, extension))
+	}
+
+	/// Spooled messages, most urgent first, then oldest first
+	pub fn entries(&self) -> Result<Vec<Entry>> {
+		let mut names: Vec<String> = fs::read_dir(&self.dir)?
+			.filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+			.filter_map(|name| name.strip_suffix(".json").map(str::to_owned))
+			.collect();
+		names.sort();
+		let mut entries = vec![];
+		for name in names {
+			let read = || -> Result<Entry> {
+				Ok(Entry {
+					envelope: serde_json::from_slice(&fs::read(self.file(&name, "json"))?)?,
+					data: fs::read(self.file(&name, "eml"))?,
+					name: name.clone(),
+				})
+			};
+			match read() {
+				Ok(entry) => entries.push(entry),
+				Err(err) => error!("Failed to read spooled message {}: {:?}", name, err),
+			}
+		}
+		entries.sort_by_key(|entry| entry.envelope.priority);
+		Ok(entries)
+	}
+
+	/// Number of spooled messages
+	pub fn count(&self) -> Result<usize> {
+		Ok(fs::read_dir(&self.dir)?
+			.filter_map(|entry| entry.ok())
+			.filter(|entry| entry.file_name().to_string_lossy().ends_with(".json"))
+			.count())
+	}
+
+	/// Forget delivered message
+	pub fn remove(&self, entry: &Entry) -> Result<()> {
+		fs::remove_file(self.file(&entry.name, "json"))?;
+		fs::remove_file(self.file(&entry.name, "eml"))?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn envelope(from: &str, priority: Priority) -> Envelope {
+		Envelope {
+			from: from.into(),
+			to: vec!["alerts@host".into()],
+			identity: None,
+			priority,
+			received: Some(1),
+		}
+	}
+
+	#[test]
+	fn spooled_mail_comes_back_by_priority() {
+		let dir = std::env::temp_dir().join(format!("smtp2tg-spool-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let spool = Spool {
+			dir: dir.clone(),
+			interval: Duration::from_secs(60),
+		};
+		spool.put(&envelope("first@host", Priority::Low), b"first").unwrap();
+		spool.put(&envelope("second@host", Priority::Normal), b"second").unwrap();
+		spool.put(&envelope("third@host", Priority::High), b"third").unwrap();
+		spool.put(&envelope("fourth@host", Priority::Normal), b"fourth").unwrap();
+		// message without envelope is not picked up yet
+		fs::write(dir.join("0.000000000.eml"), b"half written").unwrap();
+		assert_eq!(spool.count().unwrap(), 4);
+		let entries = spool.entries().unwrap();
+		let found: Vec<(&str, &[u8])> = entries.iter().map(|entry| (entry.envelope.from.as_str(), entry.data.as_slice())).collect();
+		assert_eq!(found, vec![
+			("third@host", &b"third"[..]),
+			("second@host", b"second"),
+			("fourth@host", b"fourth"),
+			("first@host", b"first"),
+		]);
+		spool.remove(&entries[0]).unwrap();
+		assert_eq!(spool.count().unwrap(), 3);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,193 @@
+//! Persistent runtime state. It's kept in a JSON file so it survives restarts
+//! and can be exported and imported when moving to another host.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use serde::{
+	Deserialize,
+	Serialize,
+};
+
+use std::{
+	fs,
+	io::ErrorKind,
+	path::{
+		Path,
+		PathBuf,
+	},
+	sync::{
+		Arc,
+		Mutex,
+	},
+};
+
+/// Format of state file, bumped on incompatible changes
+const VERSION: u32 = 1;
+
+/// `Sent` is one journal entry
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Sent {
+	/// Delivery id
+	pub id: String,
+	pub chat: i64,
+	/// Forum topic
+	#[serde(default)]
+	pub topic: Option<i32>,
+	/// Unix time of delivery
+	pub stamp: u64,
+}
+
+/// `Upload` is volume sent to a chat during a month
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Upload {
+	pub chat: i64,
+	/// Year and month, like 202610
+	pub month: u32,
+	pub bytes: u64,
+}
+
+/// `Disabled` is a chat we stopped delivering to as it kept refusing messages
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Disabled {
+	pub chat: i64,
+	/// Last refusal
+	pub reason: String,
+	/// Unix time delivery is tried again
+	pub until: u64,
+}
+
+/// `Snapshot` is everything we persist
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+	pub version: u32,
+	/// Completed deliveries, for skipping chats on retry
+	#[serde(default)]
+	pub sent: Vec<Sent>,
+	/// Upload volume per chat, for monthly caps
+	#[serde(default)]
+	pub uploads: Vec<Upload>,
+	/// Chats refusing messages
+	#[serde(default)]
+	pub disabled: Vec<Disabled>,
+}
+
+impl Default for Snapshot {
+	fn default() -> Snapshot {
+		Snapshot {
+			version: VERSION,
+			sent: vec![],
+			uploads: vec![],
+			disabled: vec![],
+		}
+	}
+}
+
+impl Snapshot {
+	/// Parse and check snapshot
+	pub fn parse(data: &[u8]) -> Result<Snapshot> {
+		let snapshot: Snapshot = serde_json::from_slice(data)?;
+		if snapshot.version != VERSION {
+			bail!("Unsupported state version {}, expected {}", snapshot.version, VERSION);
+		}
+		Ok(snapshot)
+	}
+
+	/// Read state file, missing file is an empty state
+	pub fn load(path: &Path) -> Result<Snapshot> {
+		match fs::read(path) {
+			Ok(data) => Snapshot::parse(&data)
+				.map_err(|err| anyhow!("Can't read state from {}: {}", path.display(), err)),
+			Err(err) if err.kind() == ErrorKind::NotFound => Ok(Snapshot::default()),
+			Err(err) => Err(err.into()),
+		}
+	}
+
+	/// Write state file, replacing it only when whole state is written
+	pub fn save(&self, path: &Path) -> Result<()> {
+		let mut temp = path.as_os_str().to_owned();
+		temp.push(".tmp");
+		fs::write(&temp, serde_json::to_vec_pretty(self)?)?;
+		fs::rename(&temp, path)?;
+		Ok(())
+	}
+}
+
+/// Read `state_file` path, state is kept in memory only when it's unset
+pub fn path(settings: &config::Config) -> Option<PathBuf> {
+	match settings.get_string("state_file") {
+		Ok(path) => Some(PathBuf::from(path)),
+		Err(config::ConfigError::NotFound(_)) => None,
+		Err(err) => {
+			error!("[smtp2tg.toml] can't get \"state_file\":\n {}", err);
+			panic!("bad setting");
+		},
+	}
+}
+
+/// `Store` is snapshot shared by everything that keeps state, it's saved on
+/// every change when state file is configured
+#[derive(Clone, Default)]
+pub struct Store {
+	snapshot: Arc<Mutex<Snapshot>>,
+	file: Option<PathBuf>,
+}
+
+impl Store {
+	/// Create store, restoring it from state file if there's one
+	pub fn new(settings: &config::Config) -> Store {
+		let file = path(settings);
+		let snapshot = match &file {
+			Some(path) => Snapshot::load(path).unwrap_or_else(|err| {
+				error!("{}", err);
+				panic!("bad state");
+			}),
+			None => Snapshot::default(),
+		};
+		Store {
+			snapshot: Arc::new(Mutex::new(snapshot)),
+			file,
+		}
+	}
+
+	/// Look at current state
+	pub fn read<T, F>(&self, reader: F) -> T
+	where F: FnOnce(&Snapshot) -> T {
+		reader(&self.snapshot.lock().unwrap())
+	}
+
+	/// Change state and save it
+	pub fn update<F>(&self, writer: F)
+	where F: FnOnce(&mut Snapshot) {
+		let mut snapshot = self.snapshot.lock().unwrap();
+		writer(&mut snapshot);
+		if let Some(path) = &self.file {
+			if let Err(err) = snapshot.save(path) {
+				error!("Failed to save state to {}: {:?}", path.display(), err);
+			}
+		}
+	}
+}
+
+/// Print state file as JSON, to file if specified
+pub fn export(settings: &config::Config, output: Option<&str>) -> Result<()> {
+	let path = path(settings).ok_or(anyhow!("[smtp2tg.toml] \"state_file\" is not set"))?;
+	let data = serde_json::to_string_pretty(&Snapshot::load(&path)?)?;
+	match output {
+		Some(output) => fs::write(output, data)?,
+		None => println!("{}", data),
+	};
+	Ok(())
+}
+
+/// Replace state file with checked copy of exported one
+pub fn import(settings: &config::Config, input: &str) -> Result<()> {
+	let path = path(settings).ok_or(anyhow!("[smtp2tg.toml] \"state_file\" is not set"))?;
+	let snapshot = Snapshot::parse(&fs::read(input)?)
+		.map_err(|err| anyhow!("Can't import {}: {}", input, err))?;
+	snapshot.save(&path)?;
+	eprintln!("Imported {} journal entries into {}", snapshot.sent.len(), path.display());
+	Ok(())
+}
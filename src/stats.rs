@@ -0,0 +1,157 @@
+//! Delivery counters shared between all SMTP sessions.
+
+use std::{
+	collections::BTreeMap,
+	sync::{
+		Arc,
+		Mutex,
+	},
+	time::{
+		Duration,
+		Instant,
+		SystemTime,
+	},
+};
+
+/// Unix time as UTC (year, month, day)
+pub fn civil(stamp: u64) -> (i64, u32, u32) {
+	// see http://howardhinnant.github.io/date_algorithms.html
+	let days = (stamp / 86400) as i64 + 719468;
+	let era = days.div_euclid(146097);
+	let doe = days - era * 146097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + i64::from(month <= 2);
+	(year, month as u32, day as u32)
+}
+
+/// `Usage` is consumption of a single tenant
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+	pub messages: u64,
+	pub bytes: u64,
+	/// Messages since UTC midnight
+	pub today: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+	/// Days since epoch, for resetting daily counters
+	day: u64,
+	delivered: u64,
+	failed: u64,
+	/// Mail dropped by suppression rules
+	suppressed: u64,
+	/// Messages for disabled chats sent to default chat
+	rerouted: u64,
+	/// Chats disabled for refusing messages
+	disabled: u64,
+	tenants: BTreeMap<String, Usage>,
+}
+
+impl Counters {
+	/// Reset daily counters when day changes, reporting previous day
+	fn roll(&mut self) {
+		let day = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+			.map(|stamp| stamp.as_secs() / 86400).unwrap_or(0);
+		if day != self.day {
+			if self.day != 0 {
+				info!("{}", self.report());
+			}
+			self.day = day;
+			for usage in self.tenants.values_mut() {
+				usage.today = 0;
+			}
+		}
+	}
+
+	fn report(&self) -> String {
+		let mut report = vec![format!("Delivered: {}, failed: {}, suppressed: {}, rerouted: {}, chats disabled: {}",
+			self.delivered, self.failed, self.suppressed, self.rerouted, self.disabled)];
+		for (name, usage) in &self.tenants {
+			report.push(format!("Tenant {}: {} messages ({} today), {} bytes", name, usage.messages, usage.today, usage.bytes));
+		}
+		report.join("\n")
+	}
+}
+
+/// `Stats` is a cheap to clone handle to counters
+#[derive(Clone)]
+pub struct Stats {
+	counters: Arc<Mutex<Counters>>,
+	started: Instant,
+}
+
+impl Default for Stats {
+	fn default() -> Stats {
+		Stats {
+			counters: Arc::default(),
+			started: Instant::now(),
+		}
+	}
+}
+
+impl Stats {
+	/// Count delivered message, charging every tenant involved
+	pub fn delivered(&self, tenants: &[&str], bytes: usize) {
+		let mut counters = self.counters.lock().unwrap();
+		counters.roll();
+		counters.delivered += 1;
+		for tenant in tenants {
+			let usage = counters.tenants.entry(tenant.to_string()).or_default();
+			usage.messages += 1;
+			usage.bytes += bytes as u64;
+			usage.today += 1;
+		}
+	}
+
+	/// Count message we failed to deliver
+	pub fn failed(&self) {
+		let mut counters = self.counters.lock().unwrap();
+		counters.roll();
+		counters.failed += 1;
+	}
+
+	/// Count message dropped as not worth reading
+	pub fn suppressed(&self) {
+		let mut counters = self.counters.lock().unwrap();
+		counters.roll();
+		counters.suppressed += 1;
+	}
+
+	/// Count message sent to default chat instead of disabled one
+	pub fn rerouted(&self) {
+		let mut counters = self.counters.lock().unwrap();
+		counters.roll();
+		counters.rerouted += 1;
+	}
+
+	/// Count chat disabled for refusing messages
+	pub fn disabled(&self) {
+		let mut counters = self.counters.lock().unwrap();
+		counters.roll();
+		counters.disabled += 1;
+	}
+
+	/// Counters as they go to daily report
+	pub fn report(&self) -> String {
+		let mut counters = self.counters.lock().unwrap();
+		counters.roll();
+		counters.report()
+	}
+
+	/// Time since counting started
+	pub fn uptime(&self) -> Duration {
+		self.started.elapsed()
+	}
+
+	/// Messages tenant sent since UTC midnight
+	pub fn today(&self, tenant: &str) -> u64 {
+		let mut counters = self.counters.lock().unwrap();
+		counters.roll();
+		counters.tenants.get(tenant).map_or(0, |usage| usage.today)
+	}
+}
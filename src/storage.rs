@@ -0,0 +1,227 @@
+//! Where mail and files that don't go to Telegram are kept: local directory,
+//! S3-compatible bucket or WebDAV collection. Remote stores give back links
+//! to what was stored, so deployments without disks can still point at it.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use ring::{
+	digest,
+	hmac,
+};
+use url::Url;
+
+use std::{
+	path::PathBuf,
+	time::{
+		Duration,
+		SystemTime,
+	},
+};
+
+/// Longest time presigned S3 links can be valid for
+const LINK_EXPIRY: Duration = Duration::from_secs(7 * 86400);
+
+/// `Storage` is a place to keep objects in
+#[derive(Clone, Debug)]
+pub enum Storage {
+	Dir(PathBuf),
+	S3(Bucket),
+	WebDav(Collection),
+}
+
+/// `Bucket` is S3-compatible bucket, objects go under prefix
+#[derive(Clone, Debug)]
+pub struct Bucket {
+	endpoint: Url,
+	region: String,
+	access_key: String,
+	secret_key: String,
+	name: String,
+	prefix: String,
+	/// Links are made with this base instead of being presigned
+	public_url: Option<String>,
+	client: reqwest::Client,
+}
+
+/// `Collection` is WebDAV directory
+#[derive(Clone, Debug)]
+pub struct Collection {
+	url: Url,
+	username: Option<String>,
+	password: Option<String>,
+	/// Links are made with this base instead of collection URL
+	public_url: Option<String>,
+	client: reqwest::Client,
+}
+
+impl Storage {
+	/// Read location from setting `name`: directory, "s3://bucket/prefix" or
+	/// "dav://" and "davs://" URL, credentials are in `[storage]`
+	pub fn new(settings: &config::Config, name: &str) -> Result<Option<Storage>> {
+		let Some(location) = optional(settings, name)? else {
+			return Ok(None);
+		};
+		let public_url = optional(settings, "storage.public_url")?
+			.map(|url| url.trim_end_matches('/').to_owned());
+		let storage = if let Some(path) = location.strip_prefix("s3://") {
+			let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+			if bucket.is_empty() {
+				bail!("[smtp2tg.toml] \"{}\" should be like \"s3://bucket/prefix\"", name);
+			}
+			let endpoint = required(settings, "storage.endpoint", name)?;
+			Storage::S3(Bucket {
+				endpoint: Url::parse(&endpoint)
+					.map_err(|err| anyhow!("[smtp2tg.toml] \"storage.endpoint\" should be an URL:\n {}", err))?,
+				region: optional(settings, "storage.region")?.unwrap_or_else(|| "us-east-1".into()),
+				access_key: required(settings, "storage.access_key", name)?,
+				secret_key: required(settings, "storage.secret_key", name)?,
+				name: bucket.to_owned(),
+				prefix: prefix.trim_matches('/').to_owned(),
+				public_url,
+				client: reqwest::Client::new(),
+			})
+		} else if location.starts_with("dav://") || location.starts_with("davs://") {
+			let mut url = Url::parse(&location.replacen("dav", "http", 1))
+				.map_err(|err| anyhow!("[smtp2tg.toml] \"{}\" should be an URL:\n {}", name, err))?;
+			if !url.path().ends_with('/') {
+				url.set_path(&format!("{}/", url.path()));
+			}
+			Storage::WebDav(Collection {
+				url,
+				username: optional(settings, "storage.username")?,
+				password: optional(settings, "storage.password")?,
+				public_url,
+				client: reqwest::Client::new(),
+			})
+		} else {
+			Storage::Dir(PathBuf::from(location))
+		};
+		Ok(Some(storage))
+	}
+
+	/// Whether stored objects get links that work in Telegram
+	pub fn remote(&self) -> bool {
+		!matches!(self, Storage::Dir(_))
+	}
+
+	/// Keep object under name, returns path or link to it
+	pub async fn put(&self, name: &str, data: &[u8]) -> Result<String> {
+		match self {
+			Storage::Dir(dir) => {
+				let path = dir.join(name);
+				std::fs::write(&path, data)?;
+				Ok(path.display().to_string())
+			},
+			Storage::S3(bucket) => bucket.put(name, data).await,
+			Storage::WebDav(collection) => collection.put(name, data).await,
+		}
+	}
+}
+
+impl Bucket {
+	async fn put(&self, name: &str, data: &[u8]) -> Result<String> {
+		let key = match self.prefix.is_empty() {
+			true => name.to_owned(),
+			false => format!("{}/{}", self.prefix, name),
+		};
+		// path style works with every S3-compatible service
+		let path = format!("/{}/{}", self.name, encode(&key, true));
+		let host = &self.endpoint[url::Position::BeforeHost..url::Position::AfterPort];
+		let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+		let (day, stamp) = timestamp(now);
+		let hash = hex(digest::digest(&digest::SHA256, data).as_ref());
+		let canonical = format!("PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+			path, host, hash, stamp, hash);
+		let scope = format!("{}/{}/s3/aws4_request", day, self.region);
+		let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+			self.access_key, scope, self.sign(&day, &stamp, &scope, &canonical));
+		let base = self.endpoint.as_str().trim_end_matches('/');
+		let response = self.client.put(format!("{}{}", base, path))
+			.header("x-amz-content-sha256", &hash)
+			.header("x-amz-date", &stamp)
+			.header("authorization", authorization)
+			.body(data.to_vec())
+			.send().await?;
+		if !response.status().is_success() {
+			bail!("S3 refused to store {}: {}", key, response.status());
+		}
+		if let Some(public) = &self.public_url {
+			return Ok(format!("{}/{}", public, encode(&key, true)));
+		}
+		let credential = format!("{}/{}", self.access_key, scope);
+		let query = format!("X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+			encode(&credential, false), stamp, LINK_EXPIRY.as_secs());
+		let canonical = format!("GET\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD", path, query, host);
+		Ok(format!("{}{}?{}&X-Amz-Signature={}", base, path, query, self.sign(&day, &stamp, &scope, &canonical)))
+	}
+
+	/// AWS Signature Version 4 of canonical request
+	fn sign(&self, day: &str, stamp: &str, scope: &str, canonical: &str) -> String {
+		let text = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", stamp, scope,
+			hex(digest::digest(&digest::SHA256, canonical.as_bytes()).as_ref()));
+		let mut key = format!("AWS4{}", self.secret_key).into_bytes();
+		for part in [day, self.region.as_str(), "s3", "aws4_request", &text] {
+			key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes()).as_ref().to_vec();
+		}
+		hex(&key)
+	}
+}
+
+impl Collection {
+	async fn put(&self, name: &str, data: &[u8]) -> Result<String> {
+		let name = encode(name, false);
+		let url = self.url.join(&name)?;
+		let mut request = self.client.put(url.clone()).body(data.to_vec());
+		if let Some(username) = &self.username {
+			request = request.basic_auth(username, self.password.as_ref());
+		}
+		let response = request.send().await?;
+		if !response.status().is_success() {
+			bail!("WebDAV server refused to store {}: {}", name, response.status());
+		}
+		Ok(match &self.public_url {
+			Some(public) => format!("{}/{}", public, name),
+			None => url.to_string(),
+		})
+	}
+}
+
+/// Read optional string setting
+fn optional(settings: &config::Config, name: &str) -> Result<Option<String>> {
+	match settings.get_string(name) {
+		Ok(value) => Ok(Some(value)),
+		Err(config::ConfigError::NotFound(_)) => Ok(None),
+		Err(err) => bail!("[smtp2tg.toml] can't get \"{}\":\n {}", name, err),
+	}
+}
+
+/// Read string setting location in `by` can't do without
+fn required(settings: &config::Config, name: &str, by: &str) -> Result<String> {
+	optional(settings, name)?
+		.ok_or_else(|| anyhow!("[smtp2tg.toml] \"{}\" is needed for \"{}\"", name, by))
+}
+
+/// Percent-encode everything but unreserved characters, and slashes when
+/// they separate path
+fn encode(text: &str, path: bool) -> String {
+	text.bytes().map(|byte| match byte {
+		b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+		b'/' if path => "/".into(),
+		_ => format!("%{:02X}", byte),
+	}).collect()
+}
+
+/// Date and time in S3 signatures, like ("20240131", "20240131T235959Z")
+fn timestamp(now: u64) -> (String, String) {
+	let time = mail_parser::DateTime::from_timestamp(now as i64);
+	let day = format!("{:04}{:02}{:02}", time.year, time.month, time.day);
+	let stamp = format!("{}T{:02}{:02}{:02}Z", day, time.hour, time.minute, time.second);
+	(day, stamp)
+}
+
+fn hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
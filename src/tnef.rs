@@ -0,0 +1,288 @@
+//! Outlook TNEF (winmail.dat) decoding: just enough to get embedded files and
+//! RTF body out.
+
+use anyhow::{
+	bail,
+	Result,
+};
+
+/// TNEF stream starts with this
+const SIGNATURE: u32 = 0x223e9f78;
+/// Attribute levels
+const LEVEL_MESSAGE: u8 = 1;
+const LEVEL_ATTACHMENT: u8 = 2;
+/// Attributes we care about, lower 16 bits of attribute id
+const ATT_ATTACH_DATA: u16 = 0x800f;
+const ATT_ATTACH_TITLE: u16 = 0x8010;
+const ATT_ATTACH_REND_DATA: u16 = 0x9002;
+const ATT_MSG_PROPS: u16 = 0x9003;
+const ATT_ATTACHMENT: u16 = 0x9005;
+/// MAPI properties we care about
+const PR_RTF_COMPRESSED: u16 = 0x1009;
+const PR_ATTACH_LONG_FILENAME: u16 = 0x3707;
+/// Compressed RTF magic values
+const LZFU: u32 = 0x75465a4c;
+const MELA: u32 = 0x414c454d;
+/// Compressed RTF dictionary is prefilled with this
+const RTF_PREBUF: &[u8] = b"{\\rtf1\\ansi\\mac\\deff0\\deftab720{\\fonttbl;}{\\f0\\fnil \\froman \\fswiss \\fmodern \\fscript \\fdecor MS Sans SerifSymbolArialTimes New RomanCourier{\\colortbl\\red0\\green0\\blue0\r\n\\par \\pard\\plain\\f0\\fs20\\b\\i\\u\\tab\\tx";
+
+/// `File` is an attachment found inside TNEF
+#[derive(Clone, Debug, Default)]
+pub struct File {
+	pub name: String,
+	pub data: Vec<u8>,
+}
+
+/// `Decoded` is everything we could get out of TNEF
+#[derive(Clone, Debug, Default)]
+pub struct Decoded {
+	pub files: Vec<File>,
+	/// Message body in RTF
+	pub rtf: Option<Vec<u8>>,
+}
+
+/// Little endian reader over byte slice
+struct Reader<'a> {
+	data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+		if self.data.len() < count {
+			bail!("Truncated TNEF data");
+		}
+		let (head, tail) = self.data.split_at(count);
+		self.data = tail;
+		Ok(head)
+	}
+
+	fn u8(&mut self) -> Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		Ok(u16::from_le_bytes(self.take(2)?.try_into()?))
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+	}
+
+	/// Skip padding to 4 byte boundary after value of specified length
+	fn pad(&mut self, len: usize) -> Result<()> {
+		self.take((4 - len % 4) % 4)?;
+		Ok(())
+	}
+}
+
+/// String attribute, NUL terminated in some 8 bit codepage
+fn text(data: &[u8]) -> String {
+	let end = data.iter().position(|byte| *byte == 0).unwrap_or(data.len());
+	String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+/// Walk MAPI property list calling back with (property id, value) for
+/// binary and string values
+fn properties<F>(data: &[u8], mut found: F) -> Result<()>
+where F: FnMut(u16, &[u8]) {
+	let mut reader = Reader { data };
+	let count = reader.u32()?;
+	for _ in 0..count {
+		let kind = reader.u16()?;
+		let id = reader.u16()?;
+		// named properties carry GUID and name or number
+		if id >= 0x8000 {
+			reader.take(16)?;
+			match reader.u32()? {
+				0 => { reader.u32()?; },
+				_ => {
+					let len = reader.u32()? as usize;
+					reader.take(len)?;
+					reader.pad(len)?;
+				},
+			};
+		}
+		let multi = kind & 0x1000 != 0;
+		let values = if multi { reader.u32()? } else { 1 };
+		for _ in 0..values {
+			match kind & 0x0fff {
+				// 2 and 4 byte values take 4 bytes
+				0x0002 | 0x0003 | 0x0004 | 0x000a | 0x000b => { reader.take(4)?; },
+				0x0005 | 0x0006 | 0x0007 | 0x0014 | 0x0040 => { reader.take(8)?; },
+				0x0048 => { reader.take(16)?; },
+				// strings, binary and objects: count of values, then each sized
+				0x001e | 0x001f | 0x0102 | 0x000d => {
+					let values = if multi { 1 } else { reader.u32()? };
+					for _ in 0..values {
+						let len = reader.u32()? as usize;
+						let value = reader.take(len)?;
+						found(id, value);
+						reader.pad(len)?;
+					}
+				},
+				_ => bail!("Unknown MAPI property type {:#x}", kind),
+			};
+		}
+	}
+	Ok(())
+}
+
+/// Decompress RTF stored in PR_RTF_COMPRESSED
+pub fn decompress_rtf(data: &[u8]) -> Result<Vec<u8>> {
+	let mut reader = Reader { data };
+	let size = reader.u32()? as usize;
+	let raw_size = reader.u32()? as usize;
+	let magic = reader.u32()?;
+	reader.u32()?;
+	let body = reader.take(size.saturating_sub(12).min(reader.data.len()))?;
+	match magic {
+		MELA => return Ok(body[..raw_size.min(body.len())].to_vec()),
+		LZFU => {},
+		_ => bail!("Unknown compressed RTF format {:#x}", magic),
+	};
+	let mut dictionary = [0u8; 4096];
+	dictionary[..RTF_PREBUF.len()].copy_from_slice(RTF_PREBUF);
+	let mut write = RTF_PREBUF.len();
+	let mut output = Vec::with_capacity(raw_size);
+	let mut reader = Reader { data: body };
+	'outer: while !reader.data.is_empty() {
+		let flags = reader.u8()?;
+		for bit in 0..8 {
+			if reader.data.is_empty() {
+				break 'outer;
+			}
+			if flags & (1 << bit) == 0 {
+				let byte = reader.u8()?;
+				output.push(byte);
+				dictionary[write] = byte;
+				write = (write + 1) % 4096;
+			} else {
+				let token = u16::from_be_bytes(reader.take(2)?.try_into()?) as usize;
+				let offset = token >> 4;
+				// reference to current position ends the stream
+				if offset == write {
+					break 'outer;
+				}
+				for index in 0..(token & 0xf) + 2 {
+					let byte = dictionary[(offset + index) % 4096];
+					output.push(byte);
+					dictionary[write] = byte;
+					write = (write + 1) % 4096;
+				}
+			}
+		}
+	}
+	output.truncate(raw_size);
+	Ok(output)
+}
+
+/// Decode TNEF stream
+pub fn decode(data: &[u8]) -> Result<Decoded> {
+	let mut reader = Reader { data };
+	if reader.u32()? != SIGNATURE {
+		bail!("Not a TNEF stream");
+	}
+	reader.u16()?;
+	let mut decoded = Decoded::default();
+	let mut current: Option<File> = None;
+	while !reader.data.is_empty() {
+		let level = reader.u8()?;
+		let id = reader.u32()? as u16;
+		let len = reader.u32()? as usize;
+		let value = reader.take(len)?;
+		reader.u16()?;
+		match (level, id) {
+			(LEVEL_ATTACHMENT, ATT_ATTACH_REND_DATA) => {
+				if let Some(file) = current.replace(File::default()) {
+					decoded.files.push(file);
+				}
+			},
+			(LEVEL_ATTACHMENT, ATT_ATTACH_TITLE) => if let Some(file) = current.as_mut() {
+				file.name = text(value);
+			},
+			(LEVEL_ATTACHMENT, ATT_ATTACH_DATA) => if let Some(file) = current.as_mut() {
+				file.data = value.to_vec();
+			},
+			// title is 8.3 name, long one is in MAPI properties
+			(LEVEL_ATTACHMENT, ATT_ATTACHMENT) => if let Some(file) = current.as_mut() {
+				properties(value, |id, value| if id == PR_ATTACH_LONG_FILENAME {
+					file.name = text(value);
+				})?;
+			},
+			(LEVEL_MESSAGE, ATT_MSG_PROPS) => {
+				let mut rtf = None;
+				properties(value, |id, value| if id == PR_RTF_COMPRESSED {
+					rtf = Some(value.to_vec());
+				})?;
+				if let Some(rtf) = rtf {
+					decoded.rtf = Some(decompress_rtf(&rtf)?);
+				}
+			},
+			_ => {},
+		};
+	}
+	if let Some(file) = current {
+		decoded.files.push(file);
+	}
+	Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// TNEF attribute: level, id, length, value and checksum
+	fn attribute(level: u8, id: u16, value: &[u8]) -> Vec<u8> {
+		let mut data = vec![level];
+		data.extend_from_slice(&u32::from(id).to_le_bytes());
+		data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+		data.extend_from_slice(value);
+		data.extend_from_slice(&[0, 0]);
+		data
+	}
+
+	#[test]
+	fn decode_finds_files() {
+		let mut data = SIGNATURE.to_le_bytes().to_vec();
+		data.extend_from_slice(&[1, 0]);
+		for (name, contents) in [("a.txt\0", &b"first"[..]), ("b.exe\0", b"second")] {
+			data.extend(attribute(LEVEL_ATTACHMENT, ATT_ATTACH_REND_DATA, &[0; 14]));
+			data.extend(attribute(LEVEL_ATTACHMENT, ATT_ATTACH_TITLE, name.as_bytes()));
+			data.extend(attribute(LEVEL_ATTACHMENT, ATT_ATTACH_DATA, contents));
+		}
+		let decoded = decode(&data).unwrap();
+		let files: Vec<(&str, &[u8])> = decoded.files.iter().map(|file| (file.name.as_str(), file.data.as_slice())).collect();
+		assert_eq!(files, vec![("a.txt", &b"first"[..]), ("b.exe", b"second")]);
+		assert!(decoded.rtf.is_none());
+	}
+
+	#[test]
+	fn decode_refuses_garbage() {
+		assert!(decode(b"not tnef at all").is_err());
+		// attribute longer than stream
+		let mut data = SIGNATURE.to_le_bytes().to_vec();
+		data.extend_from_slice(&[1, 0]);
+		data.extend(attribute(LEVEL_ATTACHMENT, ATT_ATTACH_DATA, b"data"));
+		data.truncate(data.len() - 4);
+		assert!(decode(&data).is_err());
+	}
+
+	#[test]
+	fn rtf_is_decompressed() {
+		// example from MS-OXRTFCP
+		let compressed = [
+			0x2d, 0x00, 0x00, 0x00, 0x2b, 0x00, 0x00, 0x00, 0x4c, 0x5a, 0x46, 0x75, 0xf1, 0xc5, 0xc7, 0xa7,
+			0x03, 0x00, 0x0a, 0x00, 0x72, 0x63, 0x70, 0x67, 0x31, 0x32, 0x35, 0x42, 0x32, 0x0a, 0xf3, 0x20,
+			0x68, 0x65, 0x6c, 0x09, 0x00, 0x20, 0x62, 0x77, 0x05, 0xb0, 0x6c, 0x64, 0x7d, 0x0a, 0x80, 0x0f,
+			0xa0,
+		];
+		assert_eq!(decompress_rtf(&compressed).unwrap(), b"{\\rtf1\\ansi\\ansicpg1252\\pard hello world}\r\n");
+		let mut stored = vec![];
+		for word in [16, 4, MELA, 0] {
+			stored.extend_from_slice(&word.to_le_bytes());
+		}
+		stored.extend_from_slice(b"{\\rtf1}");
+		assert_eq!(decompress_rtf(&stored).unwrap(), b"{\\rt");
+		assert!(decompress_rtf(&[0; 16]).is_err());
+	}
+}
@@ -0,0 +1,196 @@
+//! Receiving updates from Telegram, either by long polling or through webhook
+//! served by built-in HTTP listener, and answering bot commands.
+
+use anyhow::{
+	anyhow,
+	bail,
+	Result,
+};
+use async_std::task;
+use teloxide::{
+	payloads::{
+		GetUpdatesSetters,
+		SetWebhookSetters,
+	},
+	prelude::Requester,
+	requests::HasPayload,
+	types::{
+		BotCommand,
+		ChatId,
+		Message,
+		Update,
+		UpdateKind,
+	},
+	utils::markdown,
+};
+
+use std::{
+	sync::Arc,
+	time::Duration,
+};
+
+use crate::{
+	http,
+	leader::Leader,
+	Tg,
+};
+
+/// Long polling timeout, should be less than HTTP client timeout
+const POLL_TIMEOUT: u32 = 10;
+
+/// `Mode` sets how updates are received
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mode {
+	Off,
+	Polling,
+	Webhook {
+		/// Address for HTTP listener
+		listen: String,
+		/// Public URL Telegram should post updates to
+		url: url::Url,
+		/// Value Telegram puts in X-Telegram-Bot-Api-Secret-Token header
+		secret: String,
+	},
+}
+
+impl Mode {
+	pub fn new(settings: &config::Config) -> Result<Mode> {
+		match settings.get_string("updates") {
+			Err(config::ConfigError::NotFound(_)) => Ok(Mode::Off),
+			Ok(value) => match value.as_str() {
+				"off" => Ok(Mode::Off),
+				"polling" => Ok(Mode::Polling),
+				"webhook" => {
+					let get = |name: &str| settings.get_string(name)
+						.map_err(|_| anyhow!("[smtp2tg.toml] webhook mode needs \"{}\"", name));
+					let secret = get("webhook.secret")?;
+					if secret.is_empty() || secret.len() > 256 || !secret.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+						bail!("[smtp2tg.toml] \"webhook.secret\" should be 1-256 characters of A-Z, a-z, 0-9, _ and -");
+					}
+					Ok(Mode::Webhook {
+						listen: get("webhook.listen")?,
+						url: get("webhook.url")?.parse()?,
+						secret,
+					})
+				},
+				_ => bail!("[smtp2tg.toml] \"updates\" should be either \"off\", \"polling\" or \"webhook\""),
+			},
+			Err(err) => bail!("[smtp2tg.toml] can't get \"updates\":\n {}", err),
+		}
+	}
+}
+
+/// `Gateway` is what bot commands report on
+pub trait Gateway: Send + Sync {
+	/// Chat allowed to see gateway state
+	fn admin(&self) -> ChatId;
+	/// Uptime and queue depth
+	fn status(&self) -> String;
+	/// Delivery counters
+	fn stats(&self) -> String;
+}
+
+/// Process one update
+async fn handle(tg: &Tg, gateway: &dyn Gateway, update: Update) {
+	if let Some(chat) = update.chat() {
+		info!("Update {} from chat {}", update.id.0, chat.id);
+	}
+	if let UpdateKind::Message(message) = &update.kind {
+		if let Err(err) = command(tg, gateway, message).await {
+			error!("Failed to answer command: {:?}", err);
+		}
+	}
+}
+
+/// Answer bot command, `/chatid` works in any chat so ids can be looked up
+/// for recipients tables, others only in default chat
+async fn command(tg: &Tg, gateway: &dyn Gateway, message: &Message) -> Result<()> {
+	let Some(command) = message.text().and_then(|text| text.split_whitespace().next()) else {
+		return Ok(());
+	};
+	// in groups commands can be addressed like "/stats@some_bot"
+	let command = command.split_once('@').map_or(command, |(command, _)| command);
+	let topic = message.thread_id.filter(|_| message.is_topic_message);
+	let reply = match command {
+		"/chatid" => match topic {
+			Some(topic) => format!("Chat id: {}, topic: {}", message.chat.id, topic),
+			None => format!("Chat id: {}", message.chat.id),
+		},
+		"/status" | "/stats" if message.chat.id != gateway.admin() => "Only default chat can see this".into(),
+		"/status" => gateway.status(),
+		"/stats" => gateway.stats(),
+		_ => return Ok(()),
+	};
+	let mut request = tg.send_message(message.chat.id, markdown::escape(&reply));
+	request.payload_mut().message_thread_id = topic;
+	request.await?;
+	Ok(())
+}
+
+/// Start receiving updates in background, only leader polls
+pub async fn start(tg: Tg, mode: Mode, leader: Leader, gateway: Arc<dyn Gateway>) -> Result<()> {
+	if mode != Mode::Off {
+		// menu is nice to have, commands work without it
+		if let Err(err) = tg.set_my_commands([
+			BotCommand::new("chatid", "Show id of this chat"),
+			BotCommand::new("status", "Show uptime and queue depth"),
+			BotCommand::new("stats", "Show delivery counters"),
+		]).await {
+			warn!("Failed to set bot commands: {:?}", err);
+		}
+	}
+	match mode {
+		Mode::Off => {},
+		Mode::Polling => {
+			tg.delete_webhook().await?;
+			task::spawn(poll(tg, leader, gateway));
+		},
+		Mode::Webhook { listen, url, secret } => {
+			let path = url.path().to_owned();
+			tg.set_webhook(url).secret_token(&secret).await?;
+			http::serve(&listen, move |request| {
+				if request.path != path {
+					return http::Reply::new(404, "Not found");
+				}
+				if request.method != "POST" {
+					return http::Reply::new(405, "Only POST is supported");
+				}
+				if request.headers.get("x-telegram-bot-api-secret-token") != Some(&secret) {
+					return http::Reply::new(401, "Bad secret token");
+				}
+				match serde_json::from_slice::<Update>(&request.body) {
+					Ok(update) => {
+						task::block_on(handle(&tg, gateway.as_ref(), update));
+						http::Reply::new(200, "")
+					},
+					Err(err) => http::Reply::new(400, format!("Can't parse update: {}", err)),
+				}
+			})?;
+		},
+	};
+	Ok(())
+}
+
+/// Fetch updates forever
+async fn poll(tg: Tg, leader: Leader, gateway: Arc<dyn Gateway>) {
+	let mut offset = 0;
+	loop {
+		// Telegram refuses concurrent polling, followers wait
+		if !leader.leading() {
+			task::sleep(Duration::from_secs(1)).await;
+			continue;
+		}
+		match tg.get_updates().offset(offset).timeout(POLL_TIMEOUT).await {
+			Ok(updates) => {
+				for update in updates {
+					offset = update.id.0 as i32 + 1;
+					handle(&tg, gateway.as_ref(), update).await;
+				}
+			},
+			Err(err) => {
+				error!("Failed to get updates: {:?}", err);
+				task::sleep(Duration::from_secs(POLL_TIMEOUT as u64)).await;
+			},
+		}
+	}
+}
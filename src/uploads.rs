@@ -0,0 +1,125 @@
+//! Upload volume per chat with optional monthly caps. Chat that reached it's
+//! cap only gets text with a list of files until next month.
+
+use teloxide::types::ChatId;
+
+use std::{
+	collections::HashMap,
+	time::SystemTime,
+};
+
+use crate::{
+	state::{
+		Store,
+		Upload,
+	},
+	stats,
+};
+
+/// Parse size like "500K", "20M" or "1G"
+fn parse_size(value: &str) -> Option<u64> {
+	let value = value.trim();
+	let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+	let (number, unit) = value.split_at(split);
+	let number: u64 = number.parse().ok()?;
+	let unit = match unit.trim().to_uppercase().as_str() {
+		"" | "B" => 1,
+		"K" | "KB" => 1 << 10,
+		"M" | "MB" => 1 << 20,
+		"G" | "GB" => 1 << 30,
+		_ => return None,
+	};
+	number.checked_mul(unit)
+}
+
+/// Read size setting, it can be a number of bytes or a string with unit
+pub fn size(value: config::Value, name: &str) -> u64 {
+	value.into_string().ok().as_deref().and_then(parse_size).unwrap_or_else(|| {
+		error!("[smtp2tg.toml] \"{}\" should be a size like \"500M\" or \"1G\".", name);
+		panic!("bad setting");
+	})
+}
+
+/// Current month, like 202610
+fn month() -> u32 {
+	let stamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+		.map_or(0, |stamp| stamp.as_secs());
+	let (year, month, _) = stats::civil(stamp);
+	year as u32 * 100 + month
+}
+
+/// `Uploads` counts volume in persistent state
+#[derive(Clone, Default)]
+pub struct Uploads {
+	store: Store,
+	/// Cap for every chat
+	cap: Option<u64>,
+	/// Caps for specific chats
+	caps: HashMap<i64, u64>,
+}
+
+impl Uploads {
+	/// Read `upload_cap` and `[upload_caps]`, volume is counted anyway
+	pub fn new(settings: &config::Config, store: Store) -> Uploads {
+		let cap = match settings.get("upload_cap") {
+			Ok(value) => Some(size(value, "upload_cap")),
+			Err(config::ConfigError::NotFound(_)) => None,
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"upload_cap\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		let caps = match settings.get_table("upload_caps") {
+			Ok(caps) => caps.into_iter().map(|(chat, value)| {
+				let name = format!("upload_caps.{}", chat);
+				let chat: i64 = chat.parse().unwrap_or_else(|_| {
+					error!("[smtp2tg.toml] \"upload_caps\" keys should be chat ids, not \"{}\".", chat);
+					panic!("bad setting");
+				});
+				(chat, size(value, &name))
+			}).collect(),
+			Err(config::ConfigError::NotFound(_)) => HashMap::new(),
+			Err(err) => {
+				error!("[smtp2tg.toml] can't get \"upload_caps\":\n {}", err);
+				panic!("bad setting");
+			},
+		};
+		Uploads {
+			store,
+			cap,
+			caps,
+		}
+	}
+
+	/// Bytes sent to chat this month
+	pub fn used(&self, chat: ChatId) -> u64 {
+		let month = month();
+		self.store.read(|snapshot| snapshot.uploads.iter()
+			.find(|upload| upload.chat == chat.0 && upload.month == month)
+			.map_or(0, |upload| upload.bytes))
+	}
+
+	/// Check whether chat can get that much more this month
+	pub fn allows(&self, chat: ChatId, bytes: usize) -> bool {
+		match self.caps.get(&chat.0).or(self.cap.as_ref()) {
+			Some(cap) => self.used(chat) + bytes as u64 <= *cap,
+			None => true,
+		}
+	}
+
+	/// Count bytes sent to chat, forgetting previous months
+	pub fn record(&self, chat: ChatId, bytes: usize) {
+		let month = month();
+		self.store.update(|snapshot| {
+			snapshot.uploads.retain(|upload| upload.month == month);
+			match snapshot.uploads.iter_mut().find(|upload| upload.chat == chat.0) {
+				Some(upload) => upload.bytes += bytes as u64,
+				None => snapshot.uploads.push(Upload {
+					chat: chat.0,
+					month,
+					bytes: bytes as u64,
+				}),
+			};
+		});
+	}
+}
@@ -0,0 +1,82 @@
+//! Just enough of X.509 to tell who client certificate belongs to. Chain is
+//! verified by rustls, here we only read subject CN and alternative names.
+
+/// commonName
+const CN: &[u8] = &[0x55, 0x04, 0x03];
+/// subjectAltName
+const SAN: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Split DER element into tag, contents and the rest
+pub fn element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+	let (&tag, data) = data.split_first()?;
+	let (&first, data) = data.split_first()?;
+	let (len, data) = match first {
+		0..=0x7f => (first as usize, data),
+		0x81..=0x84 => {
+			let (bytes, data) = data.split_at_checked((first & 0x7f) as usize)?;
+			(bytes.iter().fold(0, |len, byte| len << 8 | *byte as usize), data)
+		},
+		_ => return None,
+	};
+	let (contents, rest) = data.split_at_checked(len)?;
+	Some((tag, contents, rest))
+}
+
+/// Elements of SEQUENCE or SET
+fn elements(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+	std::iter::from_fn(move || {
+		let (tag, contents, rest) = element(data)?;
+		data = rest;
+		Some((tag, contents))
+	})
+}
+
+/// Subject CN
+fn common_name(subject: &[u8]) -> Option<String> {
+	elements(subject)
+		.flat_map(|(_, set)| elements(set))
+		.find_map(|(_, pair)| {
+			let mut pair = elements(pair);
+			match (pair.next(), pair.next()) {
+				(Some((0x06, CN)), Some((_, value))) => String::from_utf8(value.to_vec()).ok(),
+				_ => None,
+			}
+		})
+}
+
+/// Email and DNS names from subjectAltName extension
+fn alt_names(extensions: &[u8]) -> Vec<String> {
+	let Some((_, extensions, _)) = element(extensions) else {
+		return vec![];
+	};
+	elements(extensions)
+		.filter_map(|(_, extension)| {
+			let mut parts = elements(extension);
+			match parts.next() {
+				Some((0x06, SAN)) => parts.find(|(tag, _)| *tag == 0x04).map(|(_, value)| value),
+				_ => None,
+			}
+		})
+		.filter_map(|value| element(value).map(|(_, names, _)| names))
+		.flat_map(elements)
+		// rfc822Name and dNSName
+		.filter(|(tag, _)| matches!(tag, 0x81 | 0x82))
+		.filter_map(|(_, name)| String::from_utf8(name.to_vec()).ok())
+		.collect()
+}
+
+/// Identity of certificate owner: subject CN, or first email or DNS name
+/// when there's no CN
+pub fn identity(cert: &[u8]) -> Option<String> {
+	let (_, cert, _) = element(cert)?;
+	let (_, tbs, _) = element(cert)?;
+	let mut fields = elements(tbs).peekable();
+	// version is optional
+	fields.next_if(|(tag, _)| *tag == 0xa0);
+	// serial, signature, issuer, validity
+	let (_, subject) = fields.nth(4)?;
+	let extensions = fields.find(|(tag, _)| *tag == 0xa3).map_or(vec![], |(_, extensions)| alt_names(extensions));
+	common_name(subject)
+		.filter(|name| !name.is_empty())
+		.or(extensions.into_iter().next())
+}